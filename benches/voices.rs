@@ -0,0 +1,68 @@
+//! How long the synthesizer takes to render a frame as more voices play, each with its own
+//! envelopes and filter. Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use nocturne::{sawtooth_wave, Adsr, MidiMessageBytes, Source, Synthesizer, VoiceFilter};
+
+const SAMPLE_HZ: f32 = 44100.0;
+
+/// A synth holding `voices` notes across the first 8 channels, clear of the drum channel.
+fn synth_with_voices(voices: u8, envelope: Adsr) -> Synthesizer {
+    let mut synth = Synthesizer::new(SAMPLE_HZ, Source::from(sawtooth_wave()));
+    synth.set_voice_filter(VoiceFilter {
+        cutoff_hz: 800.0,
+        resonance: 0.5,
+        envelope,
+        envelope_octaves: 3.0,
+        key_tracking: 0.5,
+    });
+    for v in 0..voices {
+        let note_on = [0x90 | (v % 8), 36 + v, 100];
+        synth.handle_midi_message((0, MidiMessageBytes::from(note_on)));
+    }
+
+    synth
+}
+
+fn sample_notes(c: &mut Criterion) {
+    // A slow filter envelope is still sweeping in the first frame, so the filter needs new
+    // coefficients every sample.
+    let sweeping = Adsr {
+        attack_secs: 2.0,
+        ..Adsr::default()
+    };
+    // A fast one has settled by the time the setup has rendered a few frames.
+    let settled = Adsr {
+        attack_secs: 0.001,
+        decay_secs: 0.001,
+        ..Adsr::default()
+    };
+
+    let mut group = c.benchmark_group("sample_notes");
+    for &voices in &[1, 16, 64] {
+        group.bench_function(format!("{} voices, sweeping filter", voices), |b| {
+            b.iter_batched_ref(
+                || synth_with_voices(voices, sweeping),
+                |synth| synth.sample_notes(2),
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_function(format!("{} voices, settled filter", voices), |b| {
+            b.iter_batched_ref(
+                || {
+                    let mut synth = synth_with_voices(voices, settled);
+                    for _ in 0..8 {
+                        synth.sample_notes(2);
+                    }
+                    synth
+                },
+                |synth| synth.sample_notes(2),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sample_notes);
+criterion_main!(benches);
@@ -0,0 +1,72 @@
+//! Builds an instrument from scratch: an organ-like wave from a few harmonics, through an effect
+//! written outside the library. Plays the audition phrase with it, or renders it if given a path.
+//!
+//!     cargo run --example custom_sound
+//!     cargo run --example custom_sound -- organ.wav
+
+use nocturne::{
+    audition, from_harmonics, render_audition, AudioFrame, CancellationToken, Chorus, Effect,
+    EffectsChain, NocturneError, Source,
+};
+
+use std::f32::consts::PI;
+use std::path::PathBuf;
+
+/// Wobbles the volume, like a rotating speaker without the pitch shift.
+struct Tremolo {
+    rate_hz: f32,
+    depth: f32,
+    phase: f32,
+    phase_step: f32,
+    num_channels: usize,
+}
+
+impl Tremolo {
+    fn new(rate_hz: f32, depth: f32) -> Self {
+        Tremolo {
+            rate_hz,
+            depth,
+            phase: 0.0,
+            phase_step: 0.0,
+            num_channels: 1,
+        }
+    }
+}
+
+impl Effect for Tremolo {
+    fn prepare(&mut self, sample_hz: f32, num_channels: usize) {
+        self.phase_step = self.rate_hz / sample_hz;
+        self.num_channels = num_channels;
+    }
+
+    fn process(&mut self, frame: &mut AudioFrame) {
+        for sample_frame in frame.chunks_mut(self.num_channels) {
+            let gain = 1.0 - self.depth * 0.5 * (1.0 - (2.0 * PI * self.phase).cos());
+            for s in sample_frame.iter_mut() {
+                *s *= gain;
+            }
+            self.phase = (self.phase + self.phase_step).fract();
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), NocturneError> {
+    // Drawbar-style: the fundamental, an octave up, and a quieter twelfth.
+    let organ = Source::Wave(from_harmonics(&[(1, 1.0), (2, 0.6), (3, 0.3), (4, 0.2)]));
+    let effects = || {
+        EffectsChain::new()
+            .with(Tremolo::new(6.0, 0.4))
+            .with(Chorus::new())
+    };
+
+    match std::env::args_os().nth(1).map(PathBuf::from) {
+        Some(path) => {
+            render_audition(organ, effects(), &path)?;
+            println!("Wrote {:?}", path);
+        }
+        None => audition(organ, effects(), CancellationToken::new()).await?,
+    }
+
+    Ok(())
+}
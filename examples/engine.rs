@@ -0,0 +1,38 @@
+//! Plays a MIDI file through an `Engine`, recording it, and then plays one more note on top.
+//!
+//!     cargo run --example engine -- test_data/bwv1063.mid
+
+use nocturne::{sine_wave, Engine, MidiBytes, NocturneError, RecordingOptions, RecordingTarget};
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::delay_for;
+
+#[tokio::main]
+async fn main() -> Result<(), NocturneError> {
+    let midi_path = std::env::args()
+        .nth(1)
+        .expect("Give the path of a MIDI file to play");
+    let midi_bytes = MidiBytes::read_file(Path::new(&midi_path))?;
+
+    let mut engine = Engine::builder()
+        .output_default()
+        .instrument(sine_wave())
+        .record(RecordingTarget {
+            path: PathBuf::from("engine.wav"),
+            options: RecordingOptions::default(),
+        })
+        .build()?;
+    engine.start();
+
+    engine.play_file(&midi_bytes, 120.0).await;
+    engine.send((0, [0x90, 72, 100].into())).await;
+    delay_for(Duration::from_secs(2)).await;
+    engine.send((0, [0x80, 72, 0].into())).await;
+    delay_for(Duration::from_secs(1)).await;
+
+    engine.shutdown().await?;
+    println!("Recorded engine.wav");
+
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! Plays MIDI messages generated by the program itself on the audio device, and prints the note
+//! events the synthesizer publishes as it goes.
+//!
+//!     cargo run --example live_synth
+
+use nocturne::{play_midi, triangle_wave, CancellationToken, EffectsChain, NoteEvent};
+
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::delay_for;
+
+const NOTE_LENGTH: Duration = Duration::from_millis(300);
+
+#[tokio::main]
+async fn main() {
+    let (mut message_tx, message_rx) = mpsc::channel(16);
+    let (note_event_tx, mut note_event_rx) = broadcast::channel(64);
+
+    let melody = async move {
+        let start = Instant::now();
+        for &key in [60u8, 62, 64, 65, 67, 65, 64, 62, 60].iter() {
+            let timestamp = start.elapsed().as_micros() as u64;
+            if message_tx
+                .send((timestamp, [0x90, key, 100].into()))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            delay_for(NOTE_LENGTH).await;
+            let timestamp = start.elapsed().as_micros() as u64;
+            let _ = message_tx.send((timestamp, [0x80, key, 0].into())).await;
+        }
+        // The synth stops when its input ends, so give the last note time to fade first.
+        delay_for(Duration::from_secs(1)).await;
+    };
+
+    let printer = async move {
+        while let Ok(event) = note_event_rx.recv().await {
+            match event {
+                NoteEvent::NoteStarted { key, time, .. } => println!("{:?} {} on", time, key),
+                NoteEvent::NoteEnded { key, time, .. } => println!("{:?} {} off", time, key),
+            }
+        }
+    };
+
+    let synth = play_midi(
+        message_rx,
+        triangle_wave().into(),
+        EffectsChain::new(),
+        Vec::new(),
+        Some(note_event_tx),
+        CancellationToken::new(),
+    );
+
+    let (_, played, _) = futures::join!(melody, synth, printer);
+    if let Err(e) = played {
+        println!("Failed to play: {}", e);
+    }
+}
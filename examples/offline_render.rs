@@ -0,0 +1,43 @@
+//! Renders a short phrase to a WAV file without an audio device, by driving a `Synthesizer`
+//! directly.
+//!
+//!     cargo run --example offline_render -- out.wav
+
+use nocturne::{save_wav, sawtooth_wave, Synthesizer, Unison};
+
+use std::path::PathBuf;
+
+const SAMPLE_HZ: u32 = 44100;
+const CHANNELS: usize = 2;
+
+fn main() {
+    let path: PathBuf = std::env::args_os()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| "offline_render.wav".into());
+
+    let mut synth = Synthesizer::new(SAMPLE_HZ as f32, sawtooth_wave().into());
+    synth.set_unison(Unison {
+        voices: 3,
+        detune_cents: 15.0,
+        stereo_spread: 0.8,
+    });
+
+    // A quarter second per step of a minor arpeggio, each note held for half of it.
+    let mut step = vec![0.0; SAMPLE_HZ as usize / 4 * CHANNELS];
+    let held = step.len() / 2;
+    let mut output = Vec::new();
+    for &key in [57, 60, 64, 69, 64, 60, 57].iter() {
+        synth.note_on(0, key, 100);
+        synth.render(&mut step[..held], CHANNELS);
+        synth.note_off(0, key);
+        synth.render(&mut step[held..], CHANNELS);
+        output.extend_from_slice(&step);
+    }
+    // Let the last note fade.
+    synth.render(&mut step, CHANNELS);
+    output.extend_from_slice(&step);
+
+    save_wav(&path, &output, CHANNELS as u16, SAMPLE_HZ).expect("Failed to write WAV file");
+    println!("Wrote {:?}", path);
+}
@@ -0,0 +1,73 @@
+//! Controls a running nocturne over OSC: plays a few notes on the instrument while sweeping its
+//! filter cutoff. Start the synth first, then the client:
+//!
+//!     cargo run --bin cli -- play-device --port 0 --osc-port 9000
+//!     cargo run --example osc_client -- 127.0.0.1:9000
+
+use std::net::UdpSocket;
+use std::thread::sleep;
+use std::time::Duration;
+
+const NOTE_LENGTH: Duration = Duration::from_millis(300);
+
+/// The kinds of OSC argument the synth takes.
+enum Arg {
+    Int(i32),
+    Float(f32),
+}
+
+/// Strings end with a NUL, padded to a multiple of 4 bytes.
+fn push_string(packet: &mut Vec<u8>, s: &str) {
+    packet.extend_from_slice(s.as_bytes());
+    packet.push(0);
+    while packet.len() % 4 != 0 {
+        packet.push(0);
+    }
+}
+
+fn osc_message(address: &str, args: &[Arg]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    push_string(&mut packet, address);
+    let type_tags: String = std::iter::once(',')
+        .chain(args.iter().map(|arg| match arg {
+            Arg::Int(_) => 'i',
+            Arg::Float(_) => 'f',
+        }))
+        .collect();
+    push_string(&mut packet, &type_tags);
+    for arg in args {
+        match arg {
+            Arg::Int(i) => packet.extend_from_slice(&i.to_be_bytes()),
+            Arg::Float(f) => packet.extend_from_slice(&f.to_be_bytes()),
+        }
+    }
+
+    packet
+}
+
+fn main() -> std::io::Result<()> {
+    let server = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9000".to_string());
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(&server)?;
+
+    let keys = [48, 55, 60, 63, 67, 70, 72];
+    for (i, &key) in keys.iter().enumerate() {
+        let cutoff = i as f32 / (keys.len() - 1) as f32;
+        socket.send(&osc_message(
+            "/nocturne/synth/0/cutoff",
+            &[Arg::Float(cutoff)],
+        ))?;
+        socket.send(&osc_message(
+            "/nocturne/synth/0/note-on",
+            &[Arg::Int(key), Arg::Int(100)],
+        ))?;
+        sleep(NOTE_LENGTH);
+        socket.send(&osc_message("/nocturne/synth/0/note-off", &[Arg::Int(key)]))?;
+    }
+    // Back to where the synth started.
+    socket.send(&osc_message("/nocturne/synth/0/cutoff", &[Arg::Float(0.5)]))?;
+
+    Ok(())
+}
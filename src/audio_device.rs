@@ -1,10 +1,22 @@
-use crate::{AudioFrame, FRAME_SIZE};
+use crate::{
+    config::Config,
+    error::{NocturneError, Result},
+    TimedFrame, CHANNEL_MAX_BUFFER, MAX_FRAME_SIZE,
+};
 
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    Host, StreamConfig,
+    BufferSize, Host, SampleFormat, SampleRate, StreamConfig, SupportedBufferSize,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
 };
-use log::{info, trace, warn};
+use std::time::Duration;
 use tokio::sync::{
     broadcast::{self, TryRecvError},
     mpsc::{self, error::TrySendError},
@@ -13,170 +25,1130 @@ use tokio::sync::{
 pub struct AudioOutputDeviceStream {
     stream: cpal::Stream,
     config: StreamConfig,
+    device: <Host as HostTrait>::Device,
+    max_frame_age: Option<Duration>,
+    /// The device's own rate when the stream was built, to notice the OS changing it.
+    device_sample_hz: Option<u32>,
+    /// Until someone takes it with `take_error_receiver`.
+    error_rx: Option<mpsc::UnboundedReceiver<cpal::StreamError>>,
+    problems: Arc<CallbackProblems>,
+    frames_taken: Arc<AtomicUsize>,
+}
+
+/// The audio system devices are found and played on.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioHost {
+    /// The platform's usual one: ALSA, CoreAudio or WASAPI.
+    #[default]
+    Default,
+    /// A running JACK server. The output shows up in JACK as `nocturne:out_l` and `nocturne:out_r`
+    /// (as aliases of cpal's own port names), and plays at the server's rate and period.
+    #[cfg(feature = "jack")]
+    Jack,
+    /// Steinberg ASIO drivers on Windows, which most audio interfaces ship with for their lowest
+    /// latency. Building with them needs the ASIO SDK; see cpal's documentation.
+    #[cfg(all(windows, feature = "asio"))]
+    Asio,
+}
+
+impl FromStr for AudioHost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(AudioHost::Default),
+            #[cfg(feature = "jack")]
+            "jack" => Ok(AudioHost::Jack),
+            #[cfg(all(windows, feature = "asio"))]
+            "asio" => Ok(AudioHost::Asio),
+            other => Err(format!(
+                "Unknown audio host {:?}, expected one of {:?}",
+                other,
+                AudioHost::NAMES
+            )),
+        }
+    }
+}
+
+impl AudioHost {
+    /// The hosts this build can play through.
+    pub const NAMES: &'static [&'static str] = &[
+        "default",
+        #[cfg(feature = "jack")]
+        "jack",
+        #[cfg(all(windows, feature = "asio"))]
+        "asio",
+    ];
+}
+
+/// Set with `use_audio_host`, over the config file's.
+static AUDIO_HOST_OVERRIDE: Mutex<Option<AudioHost>> = Mutex::new(None);
+
+/// Plays through `host` from now on, instead of the one in the config file.
+pub fn use_audio_host(host: AudioHost) {
+    *AUDIO_HOST_OVERRIDE.lock().unwrap() = Some(host);
+}
+
+/// The host chosen with `use_audio_host`, or else the one in the config file.
+pub(crate) fn selected_audio_host() -> AudioHost {
+    AUDIO_HOST_OVERRIDE
+        .lock()
+        .unwrap()
+        .unwrap_or_else(|| Config::load_default().audio_host)
 }
 
-fn default_output_device() -> (<Host as HostTrait>::Device, StreamConfig) {
-    let host = cpal::default_host();
-    let device = host
-        .default_output_device()
-        .expect("no output device available");
-    let mut supported_configs_range = device
+/// The selected host, or the default one if that isn't available.
+pub(crate) fn audio_host() -> Host {
+    match selected_audio_host() {
+        AudioHost::Default => cpal::default_host(),
+        #[cfg(feature = "jack")]
+        AudioHost::Jack => cpal::host_from_id(cpal::HostId::Jack).unwrap_or_else(|e| {
+            warn!("JACK is unavailable, using the default audio host: {}", e);
+            cpal::default_host()
+        }),
+        #[cfg(all(windows, feature = "asio"))]
+        AudioHost::Asio => cpal::host_from_id(cpal::HostId::Asio).unwrap_or_else(|e| {
+            warn!("ASIO is unavailable, using the default audio host: {}", e);
+            cpal::default_host()
+        }),
+    }
+}
+
+/// How to reconnect the audio output after its stream fails, like when a USB interface is
+/// unplugged. Attempts back off from `first_delay_ms`, doubling up to `max_delay_ms`, until one
+/// succeeds or `max_attempts` have failed in a row.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ReconnectPolicy {
+    /// Keeps trying until playback stops if unset. `Some(0)` never reconnects.
+    pub max_attempts: Option<u32>,
+    pub first_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for ReconnectPolicy {
+    /// About two minutes of attempts, for a cable to be plugged back in.
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: Some(40),
+            first_delay_ms: 250,
+            max_delay_ms: 4000,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// How long to wait before attempt number `attempt`, counting from 0, or `None` if it's time
+    /// to give up.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| attempt >= max) {
+            return None;
+        }
+        let ms = self
+            .first_delay_ms
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay_ms);
+
+        Some(Duration::from_millis(ms))
+    }
+}
+
+/// Whatever the device would pick by itself, or the closest 32-bit float config to it.
+fn preferred_output_config(device: &<Host as HostTrait>::Device) -> Option<StreamConfig> {
+    let (sample_hz, channels) = device
+        .default_output_config()
+        .map(|c| (c.sample_rate().0, c.channels()))
+        .unwrap_or(FALLBACK_OUTPUT_CONFIG);
+
+    choose_output_config(device, sample_hz, channels).map(|c| c.profile.stream_config())
+}
+
+/// Which output device a stream plays on.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum OutputDevice {
+    /// The profile in the config file, as chosen by `audio-setup` or probed on first run.
+    #[default]
+    Configured,
+    /// The system's default output device.
+    Default,
+    /// The first device with this name in `list_audio_output_devices`.
+    Named(String),
+    /// The device at this position in `list_audio_output_devices`.
+    Index(usize),
+}
+
+impl std::str::FromStr for OutputDevice {
+    type Err = std::convert::Infallible;
+
+    /// A number is an index, and anything else a name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(index) => OutputDevice::Index(index),
+            Err(_) => OutputDevice::Named(s.to_string()),
+        })
+    }
+}
+
+impl OutputDevice {
+    /// The device and its preferred config, if this picks out a particular device that is there
+    /// and can play the synthesizer's samples.
+    fn find(&self) -> Option<(<Host as HostTrait>::Device, StreamConfig)> {
+        let host = audio_host();
+        let device = match self {
+            OutputDevice::Configured => None,
+            OutputDevice::Default => host.default_output_device(),
+            OutputDevice::Named(name) => host
+                .output_devices()
+                .ok()?
+                .find(|d| d.name().ok().as_deref() == Some(name.as_str())),
+            OutputDevice::Index(index) => host.output_devices().ok()?.nth(*index),
+        }?;
+        let config = preferred_output_config(&device)?;
+
+        Some((device, config))
+    }
+
+    /// Whether a stream would open on the device asked for, rather than falling back.
+    pub(crate) fn is_available(&self) -> bool {
+        match self {
+            OutputDevice::Configured => audio_host().default_output_device().is_some(),
+            other => other.find().is_some(),
+        }
+    }
+}
+
+/// The names of the output devices on the configured host, in the order `OutputDevice::Index`
+/// counts them.
+pub fn list_audio_output_devices() -> Vec<String> {
+    match audio_host().output_devices() {
+        Ok(devices) => devices.map(|d| d.name().unwrap_or_default()).collect(),
+        Err(e) => {
+            warn!("Failed to enumerate output devices: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+fn device_default_sample_hz(device: &<Host as HostTrait>::Device) -> Option<u32> {
+    device
+        .default_output_config()
+        .ok()
+        .map(|c| c.sample_rate().0)
+}
+
+/// The rate synthesizers render at for an output stream, whatever the device plays at, so a patch
+/// sounds the same on every machine and frames mean the same thing to every consumer. The stream
+/// resamples to the device's rate.
+pub const RENDER_SAMPLE_HZ: u32 = 48_000;
+
+/// What to ask for when the device won't say what it prefers, and the first thing to fall back to
+/// when it can't do what was asked for: 44.1 kHz stereo.
+const FALLBACK_OUTPUT_CONFIG: (u32, u16) = (44_100, 2);
+
+/// The config `choose_output_config` settled on, next to what was asked for.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutputConfigChoice {
+    pub profile: AudioDeviceProfile,
+    pub requested_sample_hz: u32,
+    pub requested_channels: u16,
+}
+
+impl OutputConfigChoice {
+    /// Whether the device couldn't do what was asked for.
+    pub fn is_fallback(&self) -> bool {
+        self.profile.sample_hz != self.requested_sample_hz
+            || self.profile.channels != self.requested_channels
+    }
+}
+
+/// Picks the supported 32-bit float config closest to `sample_hz` and `channels`. When there isn't
+/// an exact match, the channel count is kept if possible, then 44.1 or 48 kHz stereo is preferred,
+/// and a warning says what was chosen instead. Returns `None` if the device has no usable configs.
+pub fn choose_output_config(
+    device: &<Host as HostTrait>::Device,
+    sample_hz: u32,
+    channels: u16,
+) -> Option<OutputConfigChoice> {
+    let device_name = device.name().unwrap_or_default();
+    let ranges = match device.supported_output_configs() {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Failed to query configs for {}: {}", device_name, e);
+            return None;
+        }
+    };
+
+    let (fallback_hz, fallback_channels) = FALLBACK_OUTPUT_CONFIG;
+    let (chosen_hz, chosen_channels) = ranges
+        .filter(|r| r.sample_format() == SampleFormat::F32)
+        .map(|range| {
+            let (min_hz, max_hz) = (range.min_sample_rate().0, range.max_sample_rate().0);
+            let range_hz = std::iter::once(sample_hz)
+                .chain(std::iter::once(fallback_hz))
+                .chain(PREFERRED_SAMPLE_RATES.iter().copied())
+                .find(|hz| (min_hz..=max_hz).contains(hz))
+                .unwrap_or_else(|| sample_hz.clamp(min_hz, max_hz));
+
+            (range_hz, range.channels())
+        })
+        .min_by_key(|&(hz, c)| {
+            (
+                c != channels,
+                hz != sample_hz,
+                c != fallback_channels,
+                hz != fallback_hz,
+                (hz as i64 - sample_hz as i64).abs(),
+            )
+        })?;
+
+    let choice = OutputConfigChoice {
+        profile: AudioDeviceProfile {
+            device_name,
+            sample_hz: chosen_hz,
+            channels: chosen_channels,
+            buffer_frames: None,
+        },
+        requested_sample_hz: sample_hz,
+        requested_channels: channels,
+    };
+    if choice.is_fallback() {
+        warn!(
+            "{} can't play {} Hz with {} channels, using {} Hz with {} channels",
+            choice.profile.device_name, sample_hz, channels, chosen_hz, chosen_channels
+        );
+    }
+
+    Some(choice)
+}
+
+/// A concrete output device configuration, chosen by probing and persisted in the config file.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AudioDeviceProfile {
+    pub device_name: String,
+    pub sample_hz: u32,
+    pub channels: u16,
+    /// Frames per device callback, if the host lets us choose.
+    pub buffer_frames: Option<u32>,
+}
+
+/// Sample rates we'd rather run at, in order of preference, when a device supports a range.
+const PREFERRED_SAMPLE_RATES: [u32; 2] = [48_000, 44_100];
+
+/// Smaller device buffers than this just underrun, since the synthesizer renders `FRAME_SIZE`
+/// interleaved samples at a time unless the config file says otherwise.
+const MIN_PROFILE_BUFFER_FRAMES: u32 = 256;
+
+impl AudioDeviceProfile {
+    /// The latency added by one device buffer, if known.
+    pub fn buffer_latency(&self) -> Option<Duration> {
+        self.buffer_frames
+            .map(|f| Duration::from_secs_f64(f as f64 / self.sample_hz as f64))
+    }
+
+    fn stream_config(&self) -> StreamConfig {
+        StreamConfig {
+            channels: self.channels,
+            sample_rate: SampleRate(self.sample_hz),
+            buffer_size: self
+                .buffer_frames
+                .map(BufferSize::Fixed)
+                .unwrap_or(BufferSize::Default),
+        }
+    }
+
+    /// Lower is better: known small buffers first, then stereo, then higher sample rates.
+    fn rank(&self) -> (u32, bool, std::cmp::Reverse<u32>) {
+        let latency_us = self
+            .buffer_latency()
+            .map(|l| l.as_micros() as u32)
+            .unwrap_or(u32::MAX);
+
+        (
+            latency_us,
+            self.channels != 2,
+            std::cmp::Reverse(self.sample_hz),
+        )
+    }
+}
+
+/// Every usable output profile on the configured host, one per supported config range. Only
+/// 32-bit float configs are considered, since that's what the synthesizer produces.
+pub fn probe_audio_output_profiles() -> Vec<AudioDeviceProfile> {
+    let host = audio_host();
+    let devices = match host.output_devices() {
+        Ok(d) => d,
+        Err(e) => {
+            warn!("Failed to enumerate output devices: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut profiles = Vec::new();
+    for device in devices {
+        let device_name = match device.name() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let ranges = match device.supported_output_configs() {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Failed to query configs for {}: {}", device_name, e);
+                continue;
+            }
+        };
+        for range in ranges.filter(|r| r.sample_format() == SampleFormat::F32) {
+            let (min_hz, max_hz) = (range.min_sample_rate().0, range.max_sample_rate().0);
+            let sample_hz = PREFERRED_SAMPLE_RATES
+                .iter()
+                .copied()
+                .find(|hz| (min_hz..=max_hz).contains(hz))
+                .unwrap_or(max_hz);
+            let buffer_frames = match *range.buffer_size() {
+                SupportedBufferSize::Range { min, max } => {
+                    Some(MIN_PROFILE_BUFFER_FRAMES.max(min).min(max))
+                }
+                SupportedBufferSize::Unknown => None,
+            };
+            profiles.push(AudioDeviceProfile {
+                device_name: device_name.clone(),
+                sample_hz,
+                channels: range.channels(),
+                buffer_frames,
+            });
+        }
+    }
+    profiles.sort_by_key(AudioDeviceProfile::rank);
+    profiles.dedup();
+
+    profiles
+}
+
+/// The lowest latency profile available, if any.
+pub fn best_audio_output_profile() -> Option<AudioDeviceProfile> {
+    probe_audio_output_profiles().into_iter().next()
+}
+
+fn find_profile_device(profile: &AudioDeviceProfile) -> Option<<Host as HostTrait>::Device> {
+    audio_host()
+        .output_devices()
+        .ok()?
+        .find(|d| d.name().ok().as_ref() == Some(&profile.device_name))
+}
+
+/// The profile's device, in the profile's config if it still supports it, or else the closest one.
+fn find_profile_config(
+    profile: &AudioDeviceProfile,
+) -> Option<(<Host as HostTrait>::Device, StreamConfig)> {
+    let device = find_profile_device(profile)?;
+    // The device may have changed since the profile was made.
+    let choice = choose_output_config(&device, profile.sample_hz, profile.channels)?;
+    let stream_config = if choice.is_fallback() {
+        choice.profile.stream_config()
+    } else {
+        profile.stream_config()
+    };
+
+    Some((device, stream_config))
+}
+
+/// `config` with `frames` per callback, or as close as the device gets to it.
+fn with_buffer_frames(
+    device: &<Host as HostTrait>::Device,
+    mut config: StreamConfig,
+    frames: u32,
+) -> StreamConfig {
+    let hz = config.sample_rate.0;
+    let supported = device
         .supported_output_configs()
-        .expect("error while querying configs");
-    let supported_config = supported_configs_range
-        .next()
-        .expect("no supported config?!")
-        .with_max_sample_rate();
-    let config = supported_config.config();
+        .ok()
+        .and_then(|mut ranges| {
+            ranges
+                .find(|r| {
+                    r.sample_format() == SampleFormat::F32
+                        && r.channels() == config.channels
+                        && (r.min_sample_rate().0..=r.max_sample_rate().0).contains(&hz)
+                })
+                .map(|r| r.buffer_size().clone())
+        });
+    let frames = match supported {
+        Some(SupportedBufferSize::Range { min, max }) => {
+            let clamped = frames.clamp(min, max);
+            if clamped != frames {
+                warn!(
+                    "The device can't use {} frame buffers, using {} frames",
+                    frames, clamped
+                );
+            }
+            clamped
+        }
+        _ => frames,
+    };
+    config.buffer_size = BufferSize::Fixed(frames);
 
-    (device, config)
+    config
 }
 
 impl AudioOutputDeviceStream {
+    /// Connects using the profile in the config file. On first run, when there is no profile yet,
+    /// the best available profile is probed and saved for next time. Falls back to the default
+    /// device if the profile can't be used.
+    pub fn connect_configured(
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+    ) -> Result<AudioOutputDeviceStream> {
+        let mut config = Config::load_default();
+        if config.audio_output.is_none() {
+            if let Some(profile) = best_audio_output_profile() {
+                info!("Selected audio output profile {:?}", profile);
+                config.audio_output = Some(profile);
+                if let Err(e) = config.save_default() {
+                    warn!("Failed to save audio output profile: {}", e);
+                }
+            }
+        }
+
+        let configured = config.audio_output.as_ref().and_then(find_profile_config);
+        match configured {
+            Some((device, mut stream_config)) => {
+                if let Some(frames) = config.buffer_frames {
+                    stream_config = with_buffer_frames(&device, stream_config, frames);
+                }
+
+                Self::connect_device_with_max_frame_age(
+                    device,
+                    stream_config,
+                    frame_rx,
+                    buffer_request_tx,
+                    config.max_frame_age(),
+                )
+            }
+            None => {
+                warn!("Configured audio output is unavailable, using the default device");
+                Self::connect_default(frame_rx, buffer_request_tx)
+            }
+        }
+    }
+
+    /// Connects to `output`. Falls back to the configured profile, with a warning, if that device
+    /// isn't there.
+    pub fn connect_to(
+        output: &OutputDevice,
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+    ) -> Result<AudioOutputDeviceStream> {
+        if *output == OutputDevice::Configured {
+            return Self::connect_configured(frame_rx, buffer_request_tx);
+        }
+        match output.find() {
+            Some((device, config)) => {
+                Self::connect_found(device, config, frame_rx, buffer_request_tx)
+            }
+            None => {
+                warn!(
+                    "Audio output {:?} is unavailable, using the configured one",
+                    output
+                );
+                Self::connect_configured(frame_rx, buffer_request_tx)
+            }
+        }
+    }
+
+    /// Connects to the first output device with this name, in its preferred config.
+    pub fn connect_by_name(
+        name: &str,
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+    ) -> Result<AudioOutputDeviceStream> {
+        let output = OutputDevice::Named(name.to_string());
+        let (device, config) = output.find().ok_or(NocturneError::NoAudioOutput(output))?;
+
+        Self::connect_found(device, config, frame_rx, buffer_request_tx)
+    }
+
+    /// Connects to the output device at `index` in `list_audio_output_devices`, in its preferred
+    /// config.
+    pub fn connect_by_index(
+        index: usize,
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+    ) -> Result<AudioOutputDeviceStream> {
+        let output = OutputDevice::Index(index);
+        let (device, config) = output.find().ok_or(NocturneError::NoAudioOutput(output))?;
+
+        Self::connect_found(device, config, frame_rx, buffer_request_tx)
+    }
+
+    /// Connects to a device picked by `OutputDevice`, in the config file's latency mode and buffer
+    /// size.
+    fn connect_found(
+        device: <Host as HostTrait>::Device,
+        mut stream_config: StreamConfig,
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+    ) -> Result<AudioOutputDeviceStream> {
+        let config = Config::load_default();
+        if let Some(frames) = config.buffer_frames {
+            stream_config = with_buffer_frames(&device, stream_config, frames);
+        }
+
+        Self::connect_device_with_max_frame_age(
+            device,
+            stream_config,
+            frame_rx,
+            buffer_request_tx,
+            config.max_frame_age(),
+        )
+    }
+
+    pub fn connect_profile(
+        profile: &AudioDeviceProfile,
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+    ) -> Result<AudioOutputDeviceStream> {
+        let device = find_profile_device(profile).ok_or_else(|| {
+            NocturneError::NoAudioOutput(OutputDevice::Named(profile.device_name.clone()))
+        })?;
+
+        Self::connect_device(device, profile.stream_config(), frame_rx, buffer_request_tx)
+    }
+
+    /// Connects to the host's default output device, in its preferred config and the config file's
+    /// latency mode and buffer size.
     pub fn connect_default(
-        frame_rx: broadcast::Receiver<AudioFrame>,
+        frame_rx: broadcast::Receiver<TimedFrame>,
         buffer_request_tx: mpsc::Sender<()>,
-    ) -> AudioOutputDeviceStream {
-        let (device, config) = default_output_device();
+    ) -> Result<AudioOutputDeviceStream> {
+        let (device, config) = OutputDevice::Default
+            .find()
+            .ok_or(NocturneError::NoAudioOutput(OutputDevice::Default))?;
 
-        Self::connect_device(device, config, frame_rx, buffer_request_tx)
+        Self::connect_found(device, config, frame_rx, buffer_request_tx)
     }
 
     pub fn connect_device(
         device: <Host as HostTrait>::Device,
         config: StreamConfig,
-        mut frame_rx: broadcast::Receiver<AudioFrame>,
-        mut buffer_request_tx: mpsc::Sender<()>,
-    ) -> AudioOutputDeviceStream {
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+    ) -> Result<AudioOutputDeviceStream> {
+        Self::connect_device_with_max_frame_age(device, config, frame_rx, buffer_request_tx, None)
+    }
+
+    /// Like `connect_device`, but in bounded-latency mode if `max_frame_age` is given: frames that
+    /// were rendered longer ago than that are dropped instead of played, so the output catches back
+    /// up after the synthesizer stalls. Playback crossfades over the gap to hide the jump.
+    pub fn connect_device_with_max_frame_age(
+        device: <Host as HostTrait>::Device,
+        config: StreamConfig,
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+        max_frame_age: Option<Duration>,
+    ) -> Result<AudioOutputDeviceStream> {
         info!("Creating output device stream with config:\n{:?}", config);
 
-        let mut leftover_buffer = LeftoverBuffer::new();
-
-        let stream = device
-            .build_output_stream(
-                &config,
-                move |data: &mut [f32], _cb_info| {
-                    service_cpal_output_stream_callback(
-                        data,
-                        &mut leftover_buffer,
-                        &mut buffer_request_tx,
-                        &mut frame_rx,
-                    )
-                },
-                move |_err| {
-                    // TODO
-                },
-            )
-            .expect("Failed to build CPAL output stream");
+        let num_channels = config.channels as usize;
+        #[cfg(feature = "realtime-audit")]
+        let sample_hz = config.sample_rate.0;
+        let problems = Arc::new(CallbackProblems::default());
+        let frames_taken = Arc::new(AtomicUsize::new(0));
+        let mut frame_source = FrameSource::new(
+            frame_rx,
+            buffer_request_tx,
+            num_channels,
+            max_frame_age,
+            problems.clone(),
+            frames_taken.clone(),
+        );
+        let mut resampler = Resampler::new(num_channels, RENDER_SAMPLE_HZ, config.sample_rate.0);
+        let (error_tx, error_rx) = mpsc::unbounded_channel();
+
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _cb_info| {
+                #[cfg(feature = "realtime-audit")]
+                let _audit = crate::realtime_audit::CallbackAudit::start(
+                    data.len() / num_channels,
+                    sample_hz,
+                );
+                resampler.fill(data, &mut frame_source);
+            },
+            move |err| {
+                warn!("Audio output stream error: {}", err);
+                // Nobody may be listening.
+                let _ = error_tx.send(err);
+            },
+        )?;
+        #[cfg(feature = "jack")]
+        if selected_audio_host() == AudioHost::Jack {
+            crate::jack_ports::alias_output_ports();
+        }
+        // Some hosts switch the device to the stream's rate, so look after building it.
+        let device_sample_hz = device_default_sample_hz(&device);
+
+        Ok(AudioOutputDeviceStream {
+            stream,
+            config,
+            device,
+            max_frame_age,
+            device_sample_hz,
+            error_rx: Some(error_rx),
+            problems,
+            frames_taken,
+        })
+    }
 
-        AudioOutputDeviceStream { stream, config }
+    /// Frames the device callback has taken off its channel, played or dropped, since the stream
+    /// was built. Whatever sends the frames can tell from this how many are still queued.
+    pub fn frames_taken(&self) -> usize {
+        self.frames_taken.load(Ordering::Relaxed)
     }
 
+    /// Logs what went wrong in the device callback since the last call. The callback can't log for
+    /// itself, since logging can allocate and lock.
+    pub fn log_callback_problems(&self) {
+        let take = |count: &AtomicUsize| count.swap(0, Ordering::Relaxed);
+        let underruns = take(&self.problems.underruns);
+        if underruns > 0 {
+            warn!("No frames ready when requested, {} times", underruns);
+        }
+        let stale_frames = take(&self.problems.stale_frames);
+        if stale_frames > 0 {
+            warn!("Dropped {} stale frames to catch up", stale_frames);
+        }
+        let lagged_frames = take(&self.problems.lagged_frames);
+        if lagged_frames > 0 {
+            warn!(
+                "Device lagged behind audio frame producer by {} frames",
+                lagged_frames
+            );
+        }
+    }
+
+    /// Errors the device reports while the stream plays, like being unplugged. The stream is no
+    /// use after most of them, and has to be rebuilt with `reconnect`. The receiver can only be
+    /// taken once; errors are logged either way.
+    pub fn take_error_receiver(&mut self) -> Option<mpsc::UnboundedReceiver<cpal::StreamError>> {
+        self.error_rx.take()
+    }
+
+    /// What the stream plays on, to `reconnect` to once the stream is gone.
+    pub fn profile(&self) -> AudioDeviceProfile {
+        AudioDeviceProfile {
+            device_name: self.device.name().unwrap_or_default(),
+            sample_hz: self.config.sample_rate.0,
+            channels: self.config.channels,
+            buffer_frames: match self.config.buffer_size {
+                BufferSize::Fixed(frames) => Some(frames),
+                BufferSize::Default => None,
+            },
+        }
+    }
+
+    pub fn max_frame_age(&self) -> Option<Duration> {
+        self.max_frame_age
+    }
+
+    /// Opens a stream on the device named in `profile`, which may have come back as a new device
+    /// after being unplugged. The config is kept if the device still supports it. Fails if there
+    /// is no device with that name.
+    pub fn reconnect(
+        profile: &AudioDeviceProfile,
+        max_frame_age: Option<Duration>,
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+    ) -> Result<AudioOutputDeviceStream> {
+        let (device, config) = find_profile_config(profile).ok_or_else(|| {
+            NocturneError::NoAudioOutput(OutputDevice::Named(profile.device_name.clone()))
+        })?;
+
+        Self::connect_device_with_max_frame_age(
+            device,
+            config,
+            frame_rx,
+            buffer_request_tx,
+            max_frame_age,
+        )
+    }
+
+    /// The device's new rate, if the OS has changed it since the stream was built. The stream then
+    /// plays at the wrong pitch, or not at all, until it is rebuilt with `reconnect_at`.
+    pub fn changed_device_sample_hz(&self) -> Option<u32> {
+        let hz = device_default_sample_hz(&self.device)?;
+
+        (Some(hz) != self.device_sample_hz).then_some(hz)
+    }
+
+    /// Rebuilds the stream on the same device at (or as close as it supports to) `sample_hz`,
+    /// keeping the channel count and buffer size if possible. The new stream starts paused.
+    pub fn reconnect_at(
+        self,
+        sample_hz: u32,
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+    ) -> Result<AudioOutputDeviceStream> {
+        let AudioOutputDeviceStream {
+            stream,
+            config,
+            device,
+            max_frame_age,
+            ..
+        } = self;
+        // Let go of the device before opening it again.
+        drop(stream);
+        let config = match choose_output_config(&device, sample_hz, config.channels) {
+            Some(choice) => StreamConfig {
+                channels: choice.profile.channels,
+                sample_rate: SampleRate(choice.profile.sample_hz),
+                buffer_size: config.buffer_size,
+            },
+            None => config,
+        };
+
+        Self::connect_device_with_max_frame_age(
+            device,
+            config,
+            frame_rx,
+            buffer_request_tx,
+            max_frame_age,
+        )
+    }
+
+    /// The device's config. The frames it takes are at `RENDER_SAMPLE_HZ` with this many
+    /// channels.
     pub fn get_config(&self) -> &StreamConfig {
         &self.config
     }
 
-    pub fn play(&self) {
-        self.stream
-            .play()
-            .expect("Failed to play output device stream");
+    pub fn play(&self) -> Result<()> {
+        Ok(self.stream.play()?)
     }
 
-    pub fn pause(&self) {
-        self.stream
-            .pause()
-            .expect("Failed to pause output device stream");
+    pub fn pause(&self) -> Result<()> {
+        Ok(self.stream.pause()?)
     }
 }
 
-fn service_cpal_output_stream_callback(
-    data: &mut [f32],
-    leftover_buffer: &mut LeftoverBuffer,
-    buffer_request_tx: &mut mpsc::Sender<()>,
-    frame_rx: &mut broadcast::Receiver<AudioFrame>,
-) {
-    // Zero out the buffer for safety.
-    let zeroes = vec![0.0; data.len()];
-    data.copy_from_slice(&zeroes);
+/// What went wrong in the device callback, counted there and logged by
+/// `AudioOutputDeviceStream::log_callback_problems`.
+#[derive(Default)]
+struct CallbackProblems {
+    /// Callbacks that ran out of frames.
+    underruns: AtomicUsize,
+    /// Frames dropped for being older than the max frame age.
+    stale_frames: AtomicUsize,
+    /// Frames overwritten before the device got to them.
+    lagged_frames: AtomicUsize,
+}
 
-    let items_requested = data.len();
-    let mut items_fulfilled = 0;
-    let mut buffer_request_debt = 0;
-    while items_fulfilled < items_requested {
-        // Try to pay down our buffer request debt.
-        if buffer_request_debt > 0 {
-            match buffer_request_tx.try_send(()) {
-                Ok(_) => {
-                    buffer_request_debt -= 1;
-                }
-                Err(TrySendError::Full(_)) => (),
-                Err(TrySendError::Closed(_)) => {
-                    panic!("Audio device buffer request stream was closed");
+/// Frames a `FramePool` keeps hold of before letting go of the oldest, which something else must
+/// still be holding on to.
+const FRAME_POOL_MAX: usize = 2 * CHANNEL_MAX_BUFFER;
+
+/// Makes the sample buffers of frames for an output, and keeps a reference to each one it hands
+/// out. The device callback is then never the last to let go of a frame, which would free it on
+/// the audio thread. A buffer is reused once nothing else holds it.
+pub(crate) struct FramePool {
+    /// Oldest first.
+    sent: VecDeque<Arc<[f32]>>,
+}
+
+impl FramePool {
+    pub(crate) fn new() -> Self {
+        FramePool {
+            sent: VecDeque::with_capacity(FRAME_POOL_MAX + 1),
+        }
+    }
+
+    /// A buffer holding a copy of `samples`.
+    pub(crate) fn frame(&mut self, samples: &[f32]) -> Arc<[f32]> {
+        let mut reused = None;
+        while let Some(oldest) = self.sent.front_mut() {
+            match Arc::get_mut(oldest) {
+                Some(buffer) if buffer.len() == samples.len() => {
+                    buffer.copy_from_slice(samples);
+                    reused = self.sent.pop_front();
+                    break;
                 }
+                // From before the frame size changed.
+                Some(_) => drop(self.sent.pop_front()),
+                None => break,
             }
         }
+        let buffer = reused.unwrap_or_else(|| samples.into());
+        self.sent.push_back(buffer.clone());
+        if self.sent.len() > FRAME_POOL_MAX {
+            self.sent.pop_front();
+        }
 
-        if leftover_buffer.is_empty() {
-            // Tell the synthesizer that we're buffering so it knows to queue up more samples. This
-            // shouldn't block, so instead we accumulate a retry count and pay it down later.
-            match buffer_request_tx.try_send(()) {
-                Ok(_) => (),
-                Err(TrySendError::Full(_)) => {
-                    buffer_request_debt += 1;
-                }
-                Err(TrySendError::Closed(_)) => {
-                    // All we can really do is break, because this thread is out of our control.
-                    warn!("Audio device buffer request stream is closed during buffer callback");
-                    break;
+        buffer
+    }
+}
+
+/// Pulls interleaved samples from the synthesizer's frames, requesting more frames as they are
+/// consumed.
+///
+/// Frames are freed wherever their last reference is dropped, so whatever sends them should make
+/// them with a `FramePool`. The buffer request channel allocates its second block of slots the
+/// first time it fills one, and reuses them after that.
+struct FrameSource {
+    leftover_buffer: LeftoverBuffer,
+    buffer_request_tx: mpsc::Sender<()>,
+    frame_rx: broadcast::Receiver<TimedFrame>,
+    buffer_request_debt: usize,
+    num_channels: usize,
+    /// Frames older than this are dropped, if set.
+    max_frame_age: Option<Duration>,
+    /// Whether frames have been dropped since the last one played.
+    dropped_frames: bool,
+    problems: Arc<CallbackProblems>,
+    /// Counts every frame received, or missed for lagging.
+    frames_taken: Arc<AtomicUsize>,
+}
+
+impl FrameSource {
+    fn new(
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+        num_channels: usize,
+        max_frame_age: Option<Duration>,
+        problems: Arc<CallbackProblems>,
+        frames_taken: Arc<AtomicUsize>,
+    ) -> Self {
+        FrameSource {
+            leftover_buffer: LeftoverBuffer::new(),
+            buffer_request_tx,
+            frame_rx,
+            buffer_request_debt: 0,
+            num_channels,
+            max_frame_age,
+            dropped_frames: false,
+            problems,
+            frames_taken,
+        }
+    }
+
+    /// Fills as much of `data` as possible without blocking. Returns the number of items filled;
+    /// anything short of `data.len()` is an underrun. Nothing is logged here, on the audio thread.
+    /// Problems are counted in `problems` instead.
+    fn fill(&mut self, data: &mut [f32]) -> usize {
+        let items_requested = data.len();
+        let mut items_fulfilled = 0;
+        while items_fulfilled < items_requested {
+            // Try to pay down our buffer request debt.
+            if self.buffer_request_debt > 0 {
+                match self.buffer_request_tx.try_send(()) {
+                    Ok(_) => {
+                        self.buffer_request_debt -= 1;
+                    }
+                    Err(TrySendError::Full(_)) => (),
+                    // The synthesizer has stopped, and the rest is silence. It logs why itself.
+                    Err(TrySendError::Closed(_)) => break,
                 }
             }
 
-            // Replenish our buffer. We shouldn't block to receive samples from the synthesizer
-            // since this callback executes in a realtime priority thread. This means the
-            // synthesizer thread needs to queue up samples at least as quickly as CPAL can consume
-            // them, or else we'll play frames with gaps.
-            match frame_rx.try_recv() {
-                Ok(samples) => leftover_buffer.overwrite(&samples),
-                Err(TryRecvError::Empty) => {
-                    warn!("No frames ready when requested");
-                    break;
+            if self.leftover_buffer.is_empty() {
+                // Tell the synthesizer that we're buffering so it knows to queue up more samples.
+                // This shouldn't block, so instead we accumulate a retry count and pay it down
+                // later.
+                match self.buffer_request_tx.try_send(()) {
+                    Ok(_) => (),
+                    Err(TrySendError::Full(_)) => {
+                        self.buffer_request_debt += 1;
+                    }
+                    // The synthesizer has stopped, as above.
+                    Err(TrySendError::Closed(_)) => break,
                 }
-                Err(TryRecvError::Closed) => {
-                    // All we can really do is break, because this thread is out of our control.
-                    warn!("Audio device buffering stream is closed during buffer callback");
-                    break;
+
+                // Replenish our buffer. We shouldn't block to receive samples from the
+                // synthesizer since this callback executes in a realtime priority thread. This
+                // means the synthesizer thread needs to queue up samples at least as quickly as
+                // CPAL can consume them, or else we'll play frames with gaps.
+                match self.frame_rx.try_recv() {
+                    Ok(frame) => {
+                        self.frames_taken.fetch_add(1, Ordering::Relaxed);
+                        let stale = self
+                            .max_frame_age
+                            .is_some_and(|max_age| frame.rendered_at.elapsed() > max_age);
+                        if stale {
+                            self.problems.stale_frames.fetch_add(1, Ordering::Relaxed);
+                            self.dropped_frames = true;
+                            // Its buffer request has been made, so just move on to the next one.
+                            continue;
+                        }
+
+                        let held = self.leftover_buffer.last_sample_frame(self.num_channels);
+                        self.leftover_buffer.overwrite(&frame.samples);
+                        if self.dropped_frames {
+                            self.leftover_buffer
+                                .crossfade_from(&held[..self.num_channels]);
+                            self.dropped_frames = false;
+                        }
+                    }
+                    Err(TryRecvError::Empty) => {
+                        self.problems.underruns.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    }
+                    // The synthesizer has stopped, and the rest is silence.
+                    Err(TryRecvError::Closed) => break,
+                    Err(TryRecvError::Lagged(num_missed_frames)) => {
+                        self.frames_taken
+                            .fetch_add(num_missed_frames as usize, Ordering::Relaxed);
+                        self.problems
+                            .lagged_frames
+                            .fetch_add(num_missed_frames as usize, Ordering::Relaxed);
+                    }
                 }
-                Err(TryRecvError::Lagged(num_missed_frames)) => {
-                    warn!(
-                        "Device lagged behind audio frame producer by {} frames",
-                        num_missed_frames
-                    );
+            }
+
+            items_fulfilled += self.leftover_buffer.consume(&mut data[items_fulfilled..]);
+        }
+
+        items_fulfilled
+    }
+}
+
+/// Sample frames to crossfade over when playback jumps ahead past dropped frames.
+const CONCEALMENT_FRAMES: usize = 64;
+
+/// The most channels the resampler can hold without allocating on the audio thread.
+const MAX_RESAMPLER_CHANNELS: usize = 32;
+
+/// Source sample frames each output sample is interpolated from. More taps give a sharper cutoff
+/// at the device's Nyquist frequency, at the cost of this many multiplies per sample and half this
+/// many samples of latency.
+const SINC_TAPS: usize = 64;
+
+/// Positions between source sample frames with their own precomputed kernel. Positions in between
+/// blend the two nearest kernels.
+const SINC_PHASES: usize = 256;
+
+/// The kernel's passband as a fraction of the lower of the two Nyquist frequencies. The rest is
+/// the transition band, which keeps aliasing out when converting down.
+const SINC_PASSBAND: f64 = 0.9;
+
+/// Converts the synthesizer's frames from the render rate to the device rate by windowed sinc
+/// interpolation, at a fixed ratio. The device's callbacks ask for every frame, so the synthesizer
+/// renders on the device's clock, however far that is from nominal. Frames from another device,
+/// like the input in `monitor_audio_input`, come on that device's clock instead, so the drift
+/// between the two is taken up where they are made.
+struct Resampler {
+    num_channels: usize,
+    /// Source sample frames per output sample frame.
+    step: f64,
+    /// Fractional position past the middle of `history`.
+    position: f64,
+    /// `SINC_PHASES + 1` kernels of `SINC_TAPS` each, the last being the first shifted by one.
+    kernels: Vec<f32>,
+    /// The most recent `SINC_TAPS` source sample frames, stored twice over so the window is always
+    /// a contiguous run starting at `history_start`.
+    history: [[f32; MAX_RESAMPLER_CHANNELS]; 2 * SINC_TAPS],
+    history_start: usize,
+}
+
+impl Resampler {
+    fn new(num_channels: usize, source_hz: u32, device_hz: u32) -> Self {
+        assert!(num_channels <= MAX_RESAMPLER_CHANNELS);
+
+        // Relative to the source's Nyquist frequency.
+        let cutoff = SINC_PASSBAND * (device_hz as f64 / source_hz as f64).min(1.0);
+        let half = (SINC_TAPS / 2) as f64;
+        let mut kernels = Vec::with_capacity((SINC_PHASES + 1) * SINC_TAPS);
+        for phase in 0..=SINC_PHASES {
+            let fraction = phase as f64 / SINC_PHASES as f64;
+            for tap in 0..SINC_TAPS {
+                // Distance from the output position to this tap's source sample frame.
+                let t = tap as f64 - (half - 1.0) - fraction;
+                kernels.push((cutoff * sinc(cutoff * t) * blackman(t / half)) as f32);
+            }
+        }
+
+        Resampler {
+            num_channels,
+            step: source_hz as f64 / device_hz as f64,
+            position: 0.0,
+            kernels,
+            history: [[0.0; MAX_RESAMPLER_CHANNELS]; 2 * SINC_TAPS],
+            history_start: 0,
+        }
+    }
+
+    fn push_history(&mut self, sample_frame: &[f32; MAX_RESAMPLER_CHANNELS]) {
+        self.history[self.history_start] = *sample_frame;
+        self.history[self.history_start + SINC_TAPS] = *sample_frame;
+        self.history_start = (self.history_start + 1) % SINC_TAPS;
+    }
+
+    fn fill(&mut self, data: &mut [f32], source: &mut FrameSource) {
+        let n = self.num_channels;
+        // After an underrun, don't keep polling the source for the rest of this callback.
+        let mut underrun = false;
+        let mut next = [0.0; MAX_RESAMPLER_CHANNELS];
+        for out_frame in data.chunks_mut(n) {
+            let phase = self.position * SINC_PHASES as f64;
+            let index = (phase as usize).min(SINC_PHASES - 1);
+            let blend = (phase - index as f64) as f32;
+            let (low, high) =
+                self.kernels[index * SINC_TAPS..(index + 2) * SINC_TAPS].split_at(SINC_TAPS);
+            let mut kernel = [0.0; SINC_TAPS];
+            for ((k, l), h) in kernel.iter_mut().zip(low.iter()).zip(high.iter()) {
+                *k = l + blend * (h - l);
+            }
+
+            let window = &self.history[self.history_start..self.history_start + SINC_TAPS];
+            for (c, out) in out_frame.iter_mut().enumerate() {
+                *out = kernel
+                    .iter()
+                    .zip(window.iter())
+                    .map(|(k, sample_frame)| k * sample_frame[c])
+                    .sum();
+            }
+
+            self.position += self.step;
+            while self.position >= 1.0 {
+                self.position -= 1.0;
+                if underrun || source.fill(&mut next[..n]) < n {
+                    // Play silence rather than repeating stale samples.
+                    next = [0.0; MAX_RESAMPLER_CHANNELS];
+                    underrun = true;
                 }
+                self.push_history(&next);
             }
         }
+    }
+}
 
-        items_fulfilled += leftover_buffer.consume(&mut data[items_fulfilled..]);
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        let x = std::f64::consts::PI * x;
+        x.sin() / x
     }
+}
 
-    if items_fulfilled < items_requested {
-        trace!(
-            "Fulfilled {} of {} items requested",
-            items_fulfilled,
-            items_requested
-        );
+/// In [-1.0, 1.0], and 0 outside of it.
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
     }
+    let a = std::f64::consts::PI * (x + 1.0);
+
+    0.42 - 0.5 * a.cos() + 0.08 * (2.0 * a).cos()
 }
 
 struct LeftoverBuffer {
-    buffer: [f32; FRAME_SIZE],
+    /// The last frame, which can be any size up to `MAX_FRAME_SIZE`. Never reallocated, since it's
+    /// only used on the audio thread.
+    buffer: Vec<f32>,
     cursor: usize,
 }
 
 impl LeftoverBuffer {
     fn new() -> Self {
         LeftoverBuffer {
-            buffer: [0.0; FRAME_SIZE],
-            cursor: FRAME_SIZE,
+            buffer: Vec::with_capacity(MAX_FRAME_SIZE),
+            cursor: 0,
         }
     }
 
@@ -185,7 +1157,7 @@ impl LeftoverBuffer {
     }
 
     fn items_leftover(&self) -> usize {
-        FRAME_SIZE - self.cursor
+        self.buffer.len() - self.cursor
     }
 
     /// Returns the number of items consumed from self.
@@ -198,8 +1170,115 @@ impl LeftoverBuffer {
         copy_amt
     }
 
+    /// The last whole sample frame in the buffer, whether or not it has been consumed.
+    fn last_sample_frame(&self, num_channels: usize) -> [f32; MAX_RESAMPLER_CHANNELS] {
+        let mut sample_frame = [0.0; MAX_RESAMPLER_CHANNELS];
+        if self.buffer.len() < num_channels {
+            return sample_frame;
+        }
+        let start = (self.buffer.len() / num_channels - 1) * num_channels;
+        sample_frame[..num_channels].copy_from_slice(&self.buffer[start..start + num_channels]);
+
+        sample_frame
+    }
+
+    /// Fades the start of the buffer in from `held`, one sample per channel, so a jump in the
+    /// signal doesn't click.
+    fn crossfade_from(&mut self, held: &[f32]) {
+        let num_channels = held.len();
+        let fade_frames = CONCEALMENT_FRAMES.min(self.buffer.len() / num_channels);
+        for (i, sample_frame) in self.buffer[..fade_frames * num_channels]
+            .chunks_exact_mut(num_channels)
+            .enumerate()
+        {
+            let t = i as f32 / fade_frames as f32;
+            for (s, h) in sample_frame.iter_mut().zip(held.iter()) {
+                *s = h + t * (*s - h);
+            }
+        }
+    }
+
     fn overwrite(&mut self, data_in: &[f32]) {
-        self.buffer[..].copy_from_slice(data_in);
+        // Frames longer than the maximum can't be made, so this never allocates.
+        self.buffer.clear();
+        self.buffer.extend_from_slice(data_in);
         self.cursor = 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        realtime_audit::{assert_realtime_safe, realtime_violations, start_over, CallbackAudit},
+        FRAME_SIZE,
+    };
+    use std::time::Instant;
+
+    /// Callbacks played before the audit, until the buffer request channel has both its blocks.
+    const WARM_UP_CALLBACKS: usize = 50;
+    /// Device callbacks played in the audit test.
+    const AUDITED_CALLBACKS: usize = 200;
+    /// Sample frames the fake device asks for in each callback, which doesn't line up with
+    /// `FRAME_SIZE`.
+    const CALLBACK_SAMPLE_FRAMES: usize = 441;
+
+    /// Plays frames through a `FrameSource` and `Resampler` with no device: the test answers
+    /// buffer requests the way the synthesizer would, between callbacks, and each callback is
+    /// audited. Each is given a second to run, so only allocating or blocking fails it.
+    #[test]
+    fn resampling_frames_is_realtime_safe() {
+        let num_channels = 2;
+        let (frame_tx, frame_rx) = broadcast::channel(CHANNEL_MAX_BUFFER);
+        let (buffer_request_tx, mut buffer_request_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
+        let problems = Arc::new(CallbackProblems::default());
+        let mut frame_source = FrameSource::new(
+            frame_rx,
+            buffer_request_tx,
+            num_channels,
+            None,
+            problems.clone(),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let mut resampler = Resampler::new(num_channels, RENDER_SAMPLE_HZ, 48_000);
+        let mut frame_pool = FramePool::new();
+        let mut position = 0;
+        let mut send_frame = || {
+            let samples = (0..FRAME_SIZE)
+                .map(|i| ((position + i / num_channels) as f32 * 0.01).sin())
+                .collect::<Vec<_>>();
+            frame_tx
+                .send(TimedFrame {
+                    samples: frame_pool.frame(&samples),
+                    position: position as u64,
+                    rendered_at: Instant::now(),
+                })
+                .unwrap_or_else(|_| panic!("The frame source is gone"));
+            position += FRAME_SIZE / num_channels;
+        };
+        for _ in 0..2 {
+            send_frame();
+        }
+        let mut data = vec![0.0; CALLBACK_SAMPLE_FRAMES * num_channels];
+
+        let mut guard = None;
+        for i in 0..WARM_UP_CALLBACKS + AUDITED_CALLBACKS {
+            if i == WARM_UP_CALLBACKS {
+                guard = Some(start_over());
+            }
+            let audit = guard.as_ref().map(|_| CallbackAudit::start(48_000, 48_000));
+            resampler.fill(&mut data, &mut frame_source);
+            drop(audit);
+            while buffer_request_rx.try_recv().is_ok() {
+                send_frame();
+            }
+        }
+
+        assert_realtime_safe();
+        assert_eq!(realtime_violations().callbacks, AUDITED_CALLBACKS);
+        assert_eq!(problems.underruns.load(Ordering::Relaxed), 0);
+        // Something was played.
+        assert!(data.iter().any(|&s| s != 0.0));
+    }
+}
@@ -0,0 +1,222 @@
+//! Capturing an audio input, like a microphone or an interface's line in, as `TimedFrame`s. Input
+//! frames can go to the same recorders as the synth's, so a take can have vocals alongside it.
+
+use crate::{
+    audio_device::{audio_host, RENDER_SAMPLE_HZ},
+    config::Config,
+    error::{NocturneError, Result},
+    TimedFrame, CHANNEL_MAX_BUFFER,
+};
+
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    BufferSize, Host, SampleFormat, SampleRate, StreamConfig,
+};
+use log::{info, warn};
+use std::time::Instant;
+use tokio::sync::{broadcast, mpsc};
+
+/// Which input device a stream captures from.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum InputDevice {
+    /// The system's default input device.
+    #[default]
+    Default,
+    /// The first device with this name in `list_audio_input_devices`.
+    Named(String),
+    /// The device at this position in `list_audio_input_devices`.
+    Index(usize),
+}
+
+impl std::str::FromStr for InputDevice {
+    type Err = std::convert::Infallible;
+
+    /// A number is an index, and anything else a name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(index) => InputDevice::Index(index),
+            Err(_) => InputDevice::Named(s.to_string()),
+        })
+    }
+}
+
+impl InputDevice {
+    /// The device and the config to capture in, if the device is there and can capture 32-bit
+    /// float samples.
+    fn find(&self) -> Option<(<Host as HostTrait>::Device, StreamConfig)> {
+        let host = audio_host();
+        let device = match self {
+            InputDevice::Default => host.default_input_device(),
+            InputDevice::Named(name) => host
+                .input_devices()
+                .ok()?
+                .find(|d| d.name().ok().as_deref() == Some(name.as_str())),
+            InputDevice::Index(index) => host.input_devices().ok()?.nth(*index),
+        }?;
+        let config = preferred_input_config(&device)?;
+
+        Some((device, config))
+    }
+}
+
+/// The names of the input devices on the configured host, in the order `InputDevice::Index`
+/// counts them.
+pub fn list_audio_input_devices() -> Vec<String> {
+    match audio_host().input_devices() {
+        Ok(devices) => devices.map(|d| d.name().unwrap_or_default()).collect(),
+        Err(e) => {
+            warn!("Failed to enumerate input devices: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// The device's own channel count, at `RENDER_SAMPLE_HZ` if it can capture at that rate, so input
+/// frames can be mixed with the synth's without resampling.
+fn preferred_input_config(device: &<Host as HostTrait>::Device) -> Option<StreamConfig> {
+    let default_config = device.default_input_config().ok();
+    let channels = default_config.as_ref().map(|c| c.channels());
+    let range = device
+        .supported_input_configs()
+        .ok()?
+        .filter(|r| r.sample_format() == SampleFormat::F32)
+        .min_by_key(|r| {
+            let (min_hz, max_hz) = (r.min_sample_rate().0, r.max_sample_rate().0);
+            (
+                Some(r.channels()) != channels,
+                !(min_hz..=max_hz).contains(&RENDER_SAMPLE_HZ),
+            )
+        })?;
+    let sample_hz = RENDER_SAMPLE_HZ.clamp(range.min_sample_rate().0, range.max_sample_rate().0);
+
+    Some(StreamConfig {
+        channels: range.channels(),
+        sample_rate: SampleRate(sample_hz),
+        buffer_size: BufferSize::Default,
+    })
+}
+
+/// Captures from an input device and broadcasts what it hears as frames of the configured frame
+/// size, at the device's rate and channel count (see `get_config`). Positions count
+/// sample frames from when the stream started.
+pub struct AudioInputDeviceStream {
+    stream: cpal::Stream,
+    config: StreamConfig,
+    frame_tx: broadcast::Sender<TimedFrame>,
+    /// Until someone takes it with `take_error_receiver`.
+    error_rx: Option<mpsc::UnboundedReceiver<cpal::StreamError>>,
+}
+
+impl AudioInputDeviceStream {
+    /// Opens a stream on `input`. Nothing is captured until `play`.
+    pub fn connect(input: &InputDevice) -> Result<AudioInputDeviceStream> {
+        let (device, config) = input
+            .find()
+            .ok_or_else(|| NocturneError::NoAudioInput(input.clone()))?;
+
+        Self::connect_device(device, config)
+    }
+
+    pub fn connect_device(
+        device: <Host as HostTrait>::Device,
+        config: StreamConfig,
+    ) -> Result<AudioInputDeviceStream> {
+        info!("Creating input device stream with config:\n{:?}", config);
+
+        let (frame_tx, _) = broadcast::channel(CHANNEL_MAX_BUFFER);
+        let mut framer = InputFramer::new(
+            Config::load_default().frame_size(),
+            config.channels as usize,
+            frame_tx.clone(),
+        );
+        let (error_tx, error_rx) = mpsc::unbounded_channel();
+        let stream = device.build_input_stream(
+            &config,
+            move |data: &[f32], _cb_info| framer.push(data),
+            move |err| {
+                warn!("Audio input stream error: {}", err);
+                // Nobody may be listening.
+                let _ = error_tx.send(err);
+            },
+        )?;
+
+        Ok(AudioInputDeviceStream {
+            stream,
+            config,
+            frame_tx,
+            error_rx: Some(error_rx),
+        })
+    }
+
+    /// The captured frames from now on. Subscribe before `play` to get them all.
+    pub fn subscribe(&self) -> broadcast::Receiver<TimedFrame> {
+        self.frame_tx.subscribe()
+    }
+
+    /// The device's config, which the frames are in. Record them with its channel count and rate.
+    pub fn get_config(&self) -> &StreamConfig {
+        &self.config
+    }
+
+    /// Errors the device reports while capturing, like being unplugged. The receiver can only be
+    /// taken once; errors are logged either way.
+    pub fn take_error_receiver(&mut self) -> Option<mpsc::UnboundedReceiver<cpal::StreamError>> {
+        self.error_rx.take()
+    }
+
+    pub fn play(&self) -> Result<()> {
+        Ok(self.stream.play()?)
+    }
+
+    pub fn pause(&self) -> Result<()> {
+        Ok(self.stream.pause()?)
+    }
+}
+
+/// Gathers the device's buffers, whatever size they come in, into whole frames.
+struct InputFramer {
+    /// Only whole sample frames fit in a frame, like the synth's.
+    frame: Vec<f32>,
+    /// How much of `frame` is filled.
+    len: usize,
+    num_channels: usize,
+    position: u64,
+    frame_tx: broadcast::Sender<TimedFrame>,
+}
+
+impl InputFramer {
+    fn new(
+        frame_size: usize,
+        num_channels: usize,
+        frame_tx: broadcast::Sender<TimedFrame>,
+    ) -> Self {
+        InputFramer {
+            frame: vec![0.0; frame_size / num_channels * num_channels],
+            len: 0,
+            num_channels,
+            position: 0,
+            frame_tx,
+        }
+    }
+
+    fn push(&mut self, mut data: &[f32]) {
+        while !data.is_empty() {
+            let n = (self.frame.len() - self.len).min(data.len());
+            self.frame[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+            if self.len == self.frame.len() {
+                // Unlike the output, this allocates, but only once a frame.
+                let frame = TimedFrame {
+                    samples: self.frame.as_slice().into(),
+                    position: self.position,
+                    rendered_at: Instant::now(),
+                };
+                // Fails when nobody is subscribed, and then there's nobody to miss the frame.
+                let _ = self.frame_tx.send(frame);
+                self.position += (self.frame.len() / self.num_channels) as u64;
+                self.len = 0;
+            }
+        }
+    }
+}
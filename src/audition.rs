@@ -0,0 +1,66 @@
+//! A short built-in phrase for trying out sounds, live or rendered to a file.
+
+use crate::{
+    cancel::CancellationToken, effects::EffectsChain, error::Result,
+    instrument::play_timed_messages, midi::MidiMessageBytes, oscillator::Source,
+    render::render_timed_messages, wav::save_wav,
+};
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+const AUDITION_SAMPLE_HZ: u32 = 44100;
+const AUDITION_CHANNELS: u16 = 2;
+
+const SCALE_NOTE_LENGTH: Duration = Duration::from_millis(200);
+const CHORD_LENGTH: Duration = Duration::from_millis(900);
+const AUDITION_VELOCITY: u8 = 96;
+
+/// C major up and down an octave, then I-IV-V-I.
+pub fn audition_phrase() -> Vec<(Duration, MidiMessageBytes)> {
+    const SCALE: [u8; 15] = [60, 62, 64, 65, 67, 69, 71, 72, 71, 69, 67, 65, 64, 62, 60];
+    const CHORDS: [[u8; 3]; 4] = [[60, 64, 67], [60, 65, 69], [59, 62, 67], [60, 64, 67]];
+
+    let mut messages = Vec::new();
+    let mut time = Duration::from_secs(0);
+    for &key in SCALE.iter() {
+        messages.push((time, [0x90, key, AUDITION_VELOCITY].into()));
+        // Slightly detached, so each note's attack is heard.
+        messages.push((time + SCALE_NOTE_LENGTH * 9 / 10, [0x80, key, 0].into()));
+        time += SCALE_NOTE_LENGTH;
+    }
+    for chord in CHORDS.iter() {
+        for &key in chord {
+            messages.push((time, [0x90, key, AUDITION_VELOCITY].into()));
+        }
+        for &key in chord {
+            messages.push((time + CHORD_LENGTH * 9 / 10, [0x80, key, 0].into()));
+        }
+        time += CHORD_LENGTH;
+    }
+
+    messages
+}
+
+/// Plays the audition phrase on the audio device.
+pub async fn audition(
+    source: Source,
+    effects: EffectsChain,
+    cancel: CancellationToken,
+) -> Result<()> {
+    play_timed_messages(audition_phrase(), source, effects, cancel).await
+}
+
+/// Renders the audition phrase to a stereo 16-bit WAV file.
+pub fn render_audition(source: Source, mut effects: EffectsChain, path: &Path) -> io::Result<()> {
+    let samples = render_timed_messages(
+        &audition_phrase(),
+        source,
+        &mut effects,
+        AUDITION_SAMPLE_HZ,
+        AUDITION_CHANNELS as usize,
+    );
+
+    save_wav(path, &samples, AUDITION_CHANNELS, AUDITION_SAMPLE_HZ)
+}
@@ -1,92 +1,1419 @@
 use nocturne::{
-    list_midi_input_ports, play_all_midi_tracks, play_midi_device, wave_table, MidiBytes,
+    audition, list_audio_input_devices, list_audio_output_devices, list_midi_input_ports,
+    list_midi_output_ports, monitor_audio_input, play_all_midi_tracks_chasing_mtc,
+    play_all_midi_tracks_following_midi_clock, play_all_midi_tracks_with_transport, play_midi,
+    play_tracker_module, polyphony_stats, practice_midi_file, probe_audio_output_profiles,
+    recover_last_session_at_tempo, render_audition, render_midi_to_wav_with_options,
+    render_tracker_module, use_audio_host, wave_table, write_midi_spectrogram, Accompaniment,
+    AudioHost, AudioInputDeviceStream, CancellationToken, Chorus, Compressor, Config, EffectsChain,
+    Engine, EngineBuilder, EnsembleOptions, InputDevice, InstrumentMap, LoopRegion, MidiBytes,
+    MidiFilterConfig, MidiInputDeviceStream, MidiJournal, NocturneError, OscServer, OutputDevice,
+    PatchBank, PatchConstraints, Performance, PracticeOptions, RecordingOptions,
+    RecordingOutputStream, RecordingTarget, RenderOptions, Route, SequencerOptions, ShaperCurve,
+    SilenceAction, SilenceDetection, Source, SpectrogramOptions, StepPattern, StepSequencer,
+    SynthParameter, SynthPatch, TimecodeRate, TrackerModule, TransportCommand, VoiceLimits,
+    WavSampleFormat, Waveshaper,
 };
 
-use std::path::PathBuf;
+use std::io::{self, BufRead, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 use time_calc::Bpm;
-use tokio::{select, signal};
+use tokio::{select, signal, sync::mpsc};
+
+/// Commands typed faster than the sequencer takes them are dropped.
+const TRANSPORT_COMMAND_BUFFER: usize = 16;
+
+/// Counts what the audio callback allocates, for the realtime audit logged when playback stops.
+#[cfg(feature = "realtime-audit")]
+#[global_allocator]
+static ALLOCATOR: nocturne::AuditedAllocator = nocturne::AuditedAllocator;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "cli")]
+struct Cli {
+    /// The audio system to play through, like `jack`, or `asio` on Windows, instead of the one in
+    /// the config file. `audio-setup` saves it there for next time.
+    #[structopt(long = "host")]
+    host: Option<AudioHost>,
+
+    #[structopt(subcommand)]
+    command: Opt,
+}
+
+#[derive(StructOpt, Debug)]
+// Parsed once, so the size of the largest command doesn't matter.
+#[allow(clippy::large_enum_variant)]
 enum Opt {
     ListMidiPorts,
+    /// Print the audio output devices, by number, for `--audio-device`, and then the input devices
+    /// for `--audio-input`.
+    ListAudioDevices,
+    /// Probe audio output devices and choose which one to use from now on.
+    AudioSetup,
+    /// Print the peak polyphony of each track and channel of a MIDI file.
+    Info {
+        #[structopt(short = "m", long = "midi", parse(from_os_str))]
+        midi_path: PathBuf,
+    },
     PlayDevice {
-        #[structopt(short = "p", long = "port")]
-        midi_input_port: usize,
+        /// A MIDI input port from `list-midi-ports`. Give it more than once to play from several
+        /// inputs, numbered from 0 in routes in the order they are given.
+        #[structopt(
+            short = "p",
+            long = "port",
+            required_unless = "midi-input-port-names",
+            number_of_values = 1
+        )]
+        midi_input_ports: Vec<usize>,
 
+        /// A MIDI input port with this in its name, ignoring case, like `--port-name arturia`.
+        /// Can be given more than once, and with `--port`. These inputs come after the `--port`
+        /// ones in routes.
+        #[structopt(long = "port-name", number_of_values = 1)]
+        midi_input_port_names: Vec<String>,
+
+        /// Pass MIDI input on to this output port from `list-midi-ports`. Give it more than once for
+        /// several outputs, numbered from 0 in routes in the order they are given.
+        #[structopt(long = "midi-output", number_of_values = 1)]
+        midi_output_ports: Vec<usize>,
+
+        /// Another synth with this wave, like `--wave`, playing alongside the instrument. Layers
+        /// are synth 1 and up in routes, in the order they are given, and the instrument is synth 0.
+        #[structopt(long = "layer", number_of_values = 1, parse(try_from_str = parse_wave))]
+        layers: Vec<Source>,
+
+        /// Send an input to a synth or MIDI output, like `0>synth1`, optionally only some channels
+        /// (from 0) or kinds of message, like `1>out0/channels=0-3,9/kinds=note,controller`. Give
+        /// it once for each route. Without any, every input plays every synth and goes out on every
+        /// output.
+        #[structopt(long = "route", number_of_values = 1)]
+        routes: Vec<Route>,
+
+        /// Filter the MIDI input, after the config file's filters, before it reaches any synth or
+        /// output: channels=0-3,9, notes=36-59, velocity=0.8, transpose=-12, octave=-1 or
+        /// scale=D-dorian. Transpose by a controller with cc20 in place of the number. Give it once
+        /// for each filter, in order.
+        #[structopt(long = "midi-filter", number_of_values = 1)]
+        midi_filters: Vec<MidiFilterConfig>,
+
+        /// Play on the audio output device with this name or number from `list-audio-devices`,
+        /// instead of the one chosen with `audio-setup`.
+        #[structopt(long = "audio-device")]
+        audio_device: Option<OutputDevice>,
+
+        /// Record to this WAV file, or Opus if it ends in .opus. A directory gets a new file named
+        /// after the time, like `nocturne-2024-05-01T12-30-00.wav`. Give it more than once to
+        /// record several files at once.
         #[structopt(short = "r", long = "recording", parse(from_os_str))]
-        recording_path: Option<PathBuf>,
+        recording_paths: Vec<PathBuf>,
+
+        /// Also record SMPTE LTC at this frame rate (24, 25 or 30) to a `.ltc.wav` sidecar file.
+        #[structopt(long = "ltc")]
+        ltc_rate: Option<TimecodeRate>,
+
+        /// Finish recording after this many seconds of silence.
+        #[structopt(long = "stop-on-silence")]
+        stop_on_silence: Option<f64>,
+
+        /// Stop writing after this many seconds of silence, until there is sound again.
+        #[structopt(long = "pause-on-silence", conflicts_with = "stop-on-silence")]
+        pause_on_silence: Option<f64>,
+
+        #[structopt(flatten)]
+        format: RecordingFormatArgs,
+
+        /// Also record the audio input, like a microphone, to this file, alongside the synth.
+        #[structopt(long = "input-recording", parse(from_os_str))]
+        input_recording_path: Option<PathBuf>,
+
+        /// Capture from the audio input device with this name or number from
+        /// `list-audio-devices`, instead of the default one.
+        #[structopt(long = "audio-input", requires = "input-recording-path")]
+        audio_input: Option<InputDevice>,
+
+        /// A built-in wave (sine, square, sawtooth, triangle), noise (white-noise, pink-noise), a
+        /// single-cycle WAV file, an SF2 soundfont or a sampler key map (.toml).
+        #[structopt(short = "w", long = "wave", parse(try_from_str = parse_wave))]
+        wave: Option<Source>,
+
+        /// A synth patch file (TOML, or JSON if it ends in .json), for the wave and the settings
+        /// that go with it. Its effects come before the effect flags. A directory of patches is a
+        /// bank, switched by program changes, whose effects are left out.
+        #[structopt(long = "patch", parse(from_os_str), conflicts_with = "wave")]
+        patch_path: Option<PathBuf>,
+
+        /// Bind each of these parameters in turn to the next controller that moves, and save the
+        /// bindings in the patch: cutoff, resonance, gain, chorus-rate or chorus-mix.
+        #[structopt(
+            long = "learn",
+            number_of_values = 1,
+            requires = "patch-path",
+            parse(try_from_str = parse_synth_parameter)
+        )]
+        learn: Vec<SynthParameter>,
+
+        /// Take notes and parameter changes over OSC on this UDP port, like
+        /// `/nocturne/synth/0/cutoff 0.5` or `/nocturne/synth/1/note-on 60 100`.
+        #[structopt(long = "osc-port")]
+        osc_port: Option<u16>,
+
+        /// Listen for OSC on this address. Only this machine can send to the default; use
+        /// `0.0.0.0` to take commands from anywhere on the network.
+        #[structopt(long = "osc-bind", default_value = "127.0.0.1")]
+        osc_bind: IpAddr,
+
+        /// Effects on the output.
+        #[structopt(flatten)]
+        effects: EffectArgs,
+    },
+    /// Make a random synth patch and save it, for discovering new sounds.
+    RandomPatch {
+        /// Where to save the patch, as TOML, or JSON if it ends in .json.
+        #[structopt(parse(from_os_str))]
+        output_path: PathBuf,
+
+        /// The same seed always makes the same patch. Defaults to one from the clock.
+        #[structopt(long = "seed")]
+        seed: Option<u64>,
+
+        /// Play the audition phrase with the patch once it is saved.
+        #[structopt(long = "audition")]
+        audition: bool,
     },
     PlayFile {
+        /// A single-track (format 0) file is split into a track for each channel, so its channels
+        /// get instruments, voice limits and mutes of their own.
         #[structopt(short = "m", long = "midi", parse(from_os_str))]
         midi_path: PathBuf,
 
+        /// The tempo until the file's first tempo change, if it has any.
         #[structopt(short = "b", long = "bpm")]
         bpm: u32,
 
+        /// Record the mix of every track to this WAV file, or Opus if it ends in .opus. A directory
+        /// gets a new file named after the time. Not available with `--mtc-port` or
+        /// `--clock-port`.
         #[structopt(short = "r", long = "recording", parse(from_os_str))]
         recording_path: Option<PathBuf>,
+
+        /// Record each track that plays notes to its own WAV file, named after this path with the
+        /// track number added, like `out.track03.wav`. In a directory, the stems are named after
+        /// the time instead. Works with or without `--recording`.
+        #[structopt(long = "stems", parse(from_os_str))]
+        stems_path: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        format: RecordingFormatArgs,
+
+        /// Play every track with this wave, soundfont or sampler key map (.toml) instead of cycling
+        /// through the built-in waves. With a soundfont, or `general-midi` for the built-in General
+        /// MIDI patches, each track gets the General MIDI instrument it asks for.
+        #[structopt(short = "w", long = "wave", parse(try_from_str = parse_wave))]
+        wave: Option<Source>,
+
+        /// A synth patch file (TOML, or JSON if it ends in .json) to play every track with. Its
+        /// effects come before the effect flags. A directory of patches is a bank, switched by
+        /// each track's program changes, whose effects are left out.
+        #[structopt(long = "patch", parse(try_from_str = parse_patch), conflicts_with = "wave")]
+        patch: Option<Source>,
+
+        /// A TOML file choosing the instrument for each track. Tracks it leaves out play the
+        /// `--wave`, `--patch` or performance instruments as usual.
+        #[structopt(long = "instruments", parse(from_os_str))]
+        instrument_map_path: Option<PathBuf>,
+
+        /// Like `--wave general-midi`, with the patches this TOML file gives General MIDI families
+        /// and programs in place of the built-in ones.
+        #[structopt(long = "gm-map", parse(from_os_str), conflicts_with_all = &["wave", "patch"])]
+        general_midi_map: Option<PathBuf>,
+
+        /// Follow MIDI Time Code from this input port instead of starting playback immediately.
+        #[structopt(
+            long = "mtc-port",
+            conflicts_with_all = &["recording-path", "stems-path", "audio-device"]
+        )]
+        mtc_port: Option<usize>,
+
+        /// Keep time with the MIDI clock from this input port instead of `--bpm` and the file's
+        /// tempo, starting, stopping and relocating along with it.
+        #[structopt(
+            long = "clock-port",
+            conflicts_with_all = &["recording-path", "stems-path", "audio-device", "mtc-port"]
+        )]
+        clock_port: Option<usize>,
+
+        /// Also take transport commands over OSC on this UDP port, like
+        /// `/nocturne/transport/pause`.
+        #[structopt(long = "osc-port", conflicts_with_all = &["mtc-port", "clock-port"])]
+        osc_port: Option<u16>,
+
+        /// Listen for OSC on this address. Only this machine can send to the default; use
+        /// `0.0.0.0` to take commands from anywhere on the network.
+        #[structopt(long = "osc-bind", default_value = "127.0.0.1")]
+        osc_bind: IpAddr,
+
+        /// Play on the audio output device with this name or number from `list-audio-devices`,
+        /// instead of the one chosen with `audio-setup`. Not available with `--mtc-port` or
+        /// `--clock-port`.
+        #[structopt(long = "audio-device")]
+        audio_device: Option<OutputDevice>,
+
+        /// Use the instruments, levels and effects of a performance preset (orchestral, chiptune,
+        /// rock or ambient). `--wave` and the effect flags still apply on top of it.
+        #[structopt(long = "performance", parse(try_from_str = parse_performance))]
+        performance: Option<Performance>,
+
+        /// Most notes a track can sound at once, as TRACK=VOICES, like `3=8`. The oldest note is
+        /// cut off to make room for a new one. Can be given for any number of tracks.
+        #[structopt(long = "voice-limit", parse(try_from_str = parse_voice_limit))]
+        track_voice_limits: Vec<(usize, usize)>,
+
+        /// Most notes a channel can sound at once within each track, as CHANNEL=VOICES, counting
+        /// channels from 0 like `polyphony-stats` does.
+        #[structopt(long = "channel-voice-limit", parse(try_from_str = parse_voice_limit))]
+        channel_voice_limits: Vec<(usize, usize)>,
+
+        /// Seeds the noise and drums. The same seed always plays the same way.
+        #[structopt(long = "seed", default_value = "0")]
+        seed: u64,
+
+        /// Play at this multiple of the tempo, like 0.5 for half speed. While the file plays,
+        /// type a new speed, `pause`, `play`, `bar N` or `tick N` and press Enter to control it.
+        /// Not available with `--mtc-port` or `--clock-port`.
+        #[structopt(long = "speed", default_value = "1.0")]
+        speed: f64,
+
+        /// Play the whole file at `--bpm`, ignoring its own tempo changes.
+        #[structopt(long = "fixed-tempo")]
+        fixed_tempo: bool,
+
+        /// Play the file over and over until Ctrl-C.
+        #[structopt(long = "loop")]
+        loop_file: bool,
+
+        /// Play these bars over and over until Ctrl-C, as FIRST-LAST counting from 1, like `5-8`.
+        #[structopt(long = "loop-bars", parse(try_from_str = parse_bar_range), conflicts_with = "loop-file")]
+        loop_bars: Option<(u32, u32)>,
+
+        /// Don't play the notes of these tracks, counting from 0, like `--mute 2,5`. While the
+        /// file plays, type `mute N` or `unmute N` and press Enter to change them.
+        #[structopt(long = "mute", use_delimiter = true)]
+        muted_tracks: Vec<usize>,
+
+        /// Only play the notes of these tracks, counting from 0. While the file plays, type
+        /// `solo N` or `unsolo N` and press Enter to change them.
+        #[structopt(long = "solo", use_delimiter = true)]
+        soloed_tracks: Vec<usize>,
+
+        /// Effects on every track.
+        #[structopt(flatten)]
+        effects: EffectArgs,
+    },
+    /// Play the audio input, like a guitar or a microphone, through the effects until Ctrl-C.
+    Monitor {
+        /// Capture from the audio input device with this name or number from
+        /// `list-audio-devices`, instead of the default one.
+        #[structopt(long = "audio-input")]
+        audio_input: Option<InputDevice>,
+
+        /// Play on the audio output device with this name or number from `list-audio-devices`,
+        /// instead of the one chosen with `audio-setup`.
+        #[structopt(long = "audio-device")]
+        audio_device: Option<OutputDevice>,
+
+        /// Record what is played to this file. A directory gets a new file named after the time.
+        #[structopt(short = "r", long = "recording", parse(from_os_str))]
+        recording_paths: Vec<PathBuf>,
+
+        #[structopt(flatten)]
+        format: RecordingFormatArgs,
+
+        #[structopt(flatten)]
+        effects: EffectArgs,
+    },
+    /// Play a tracker module (MOD or XM).
+    PlayModule {
+        #[structopt(parse(from_os_str))]
+        module_path: PathBuf,
+
+        /// Render to this WAV file instead of playing.
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output_path: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        effects: EffectArgs,
+    },
+    /// Render a MIDI file to WAV, or Opus for a .opus output, as fast as possible and offline.
+    Render {
+        /// Split into a track per channel if it has only one, like `play-file --midi`.
+        #[structopt(parse(from_os_str))]
+        midi_path: PathBuf,
+
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output_path: PathBuf,
+
+        /// The tempo until the file's first tempo change, if it has any.
+        #[structopt(short = "b", long = "bpm", default_value = "120")]
+        bpm: u32,
+
+        /// Render every track with this wave instead of cycling through the built-in waves. With
+        /// `general-midi`, each track gets the General MIDI instrument it asks for.
+        #[structopt(short = "w", long = "wave", parse(try_from_str = parse_wave))]
+        wave: Option<Source>,
+
+        /// Seeds the noise and drums. Renders with the same seed are identical.
+        #[structopt(long = "seed", default_value = "0")]
+        seed: u64,
+
+        /// Render this many samples at a time. Bigger frames are faster, but place notes less
+        /// exactly.
+        #[structopt(long = "frame-size", default_value = "512")]
+        frame_size: usize,
+
+        /// A TOML file choosing the instrument for each track, like `play-file --instruments`.
+        /// Patches play without their effects.
+        #[structopt(long = "instruments", parse(from_os_str))]
+        instrument_map_path: Option<PathBuf>,
+
+        /// Like `play-file --gm-map`.
+        #[structopt(long = "gm-map", parse(from_os_str), conflicts_with = "wave")]
+        general_midi_map: Option<PathBuf>,
+    },
+    /// Render a MIDI file offline and save a spectrogram of it as a PNG.
+    Spectrogram {
+        #[structopt(parse(from_os_str))]
+        midi_path: PathBuf,
+
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output_path: PathBuf,
+
+        /// The tempo until the file's first tempo change, if it has any.
+        #[structopt(short = "b", long = "bpm", default_value = "120")]
+        bpm: u32,
+
+        /// Render every track with this wave instead of cycling through the built-in waves.
+        #[structopt(short = "w", long = "wave", parse(try_from_str = parse_wave))]
+        wave: Option<Source>,
+    },
+    /// Save the MIDI input of the last play-device session as a MIDI file, to play back at the
+    /// same BPM.
+    RecoverLastSession {
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output_path: PathBuf,
+
+        /// The tempo the session was played at, for lining the file's beats up with it.
+        #[structopt(short = "b", long = "bpm", default_value = "120")]
+        bpm: u32,
     },
+    /// Play a short scale and chord progression to try out a sound.
+    Audition {
+        /// A built-in wave (sine, square, sawtooth, triangle), noise (white-noise, pink-noise), a
+        /// single-cycle WAV file, an SF2 soundfont or a sampler key map (.toml).
+        #[structopt(short = "p", long = "preset", parse(try_from_str = parse_wave))]
+        preset: Option<Source>,
+
+        /// A synth patch file (TOML, or JSON if it ends in .json), for the wave and the settings
+        /// that go with it. Its effects come before the effect flags. A directory of patches is a
+        /// bank, switched by program changes, whose effects are left out.
+        #[structopt(long = "patch", parse(try_from_str = parse_patch), conflicts_with = "preset")]
+        patch: Option<Source>,
+
+        /// Render to this WAV file instead of playing.
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output_path: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        effects: EffectArgs,
+    },
+    /// Loop a 16-step pattern file (TOML) until Ctrl-C.
+    PlaySteps {
+        #[structopt(parse(from_os_str))]
+        pattern_path: PathBuf,
+
+        /// Play at this tempo instead of the pattern's.
+        #[structopt(short = "b", long = "bpm")]
+        bpm: Option<f64>,
+
+        /// A built-in wave (sine, square, sawtooth, triangle), noise (white-noise, pink-noise), a
+        /// single-cycle WAV file, an SF2 soundfont or a sampler key map (.toml).
+        #[structopt(short = "w", long = "wave", parse(try_from_str = parse_wave))]
+        wave: Option<Source>,
+
+        /// A synth patch file (TOML, or JSON if it ends in .json), for the wave and the settings
+        /// that go with it. Its effects come before the effect flags. A directory of patches is a
+        /// bank, switched by program changes, whose effects are left out.
+        #[structopt(long = "patch", parse(try_from_str = parse_patch), conflicts_with = "wave")]
+        patch: Option<Source>,
+
+        #[structopt(flatten)]
+        effects: EffectArgs,
+    },
+    /// Play along with a MIDI file and get scored on how closely you followed it.
+    Practice {
+        #[structopt(short = "m", long = "midi", parse(from_os_str))]
+        midi_path: PathBuf,
+
+        /// The tempo until the file's first tempo change, if it has any.
+        #[structopt(short = "b", long = "bpm")]
+        bpm: u32,
+
+        #[structopt(short = "p", long = "port", required_unless = "midi-input-port-name")]
+        midi_input_port: Option<usize>,
+
+        /// The MIDI input port with this in its name, ignoring case, instead of `--port`.
+        #[structopt(long = "port-name", conflicts_with = "midi-input-port")]
+        midi_input_port_name: Option<String>,
+
+        /// Only score against this track, e.g. the one part you are practicing.
+        #[structopt(short = "t", long = "track")]
+        track: Option<usize>,
+
+        /// Play a metronome instead of the file.
+        #[structopt(long = "click")]
+        click: bool,
+
+        /// The wave you play with.
+        #[structopt(short = "w", long = "wave", parse(try_from_str = parse_wave))]
+        wave: Option<Source>,
+
+        /// Output latency to compensate for, in milliseconds. Defaults to the configured audio
+        /// profile's buffer latency.
+        #[structopt(long = "latency-ms")]
+        latency_ms: Option<u64>,
+    },
+}
+
+#[derive(StructOpt, Debug, Clone, Copy)]
+struct RecordingFormatArgs {
+    /// Sample format of WAV recordings: int16, int24 or float32.
+    #[structopt(
+        long = "sample-format",
+        default_value = "int16",
+        parse(try_from_str = parse_sample_format)
+    )]
+    sample_format: WavSampleFormat,
+
+    /// Dither recordings with integer samples.
+    #[structopt(long = "dither")]
+    dither: bool,
+
+    /// Bitrate of Opus recordings, in kb/s. Defaults to 64 per channel.
+    #[structopt(long = "bitrate")]
+    bitrate_kbps: Option<u32>,
+}
+
+impl RecordingFormatArgs {
+    fn options(self) -> RecordingOptions {
+        RecordingOptions {
+            sample_format: self.sample_format,
+            dither: self.dither,
+            bitrate: self.bitrate_kbps.map(|kbps| kbps.saturating_mul(1000)),
+            ..Default::default()
+        }
+    }
+
+    fn target(self, path: PathBuf) -> RecordingTarget {
+        RecordingTarget {
+            path,
+            options: self.options(),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone, Copy)]
+struct EffectArgs {
+    /// Add a chorus.
+    #[structopt(long = "chorus")]
+    chorus: bool,
+
+    /// Distort with this curve (tanh, hard-clip or foldback).
+    #[structopt(long = "distortion", parse(try_from_str = parse_shaper_curve))]
+    distortion: Option<ShaperCurve>,
+
+    /// How hard to drive the distortion.
+    #[structopt(long = "drive", default_value = "4.0")]
+    drive: f32,
+
+    /// Even out the dynamics with a compressor, last in the chain.
+    #[structopt(long = "compressor")]
+    compressor: bool,
+}
+
+impl EffectArgs {
+    fn chain(self) -> EffectsChain {
+        let mut effects = EffectsChain::new();
+        if let Some(curve) = self.distortion {
+            effects.push(Waveshaper {
+                drive: self.drive,
+                ..Waveshaper::new(curve)
+            });
+        }
+        if self.chorus {
+            effects.push(Chorus::new());
+        }
+        if self.compressor {
+            effects.push(Compressor::new());
+        }
+
+        effects
+    }
+}
+
+fn parse_shaper_curve(s: &str) -> Result<ShaperCurve, String> {
+    ShaperCurve::by_name(s).ok_or_else(|| format!("{:?} is not a distortion curve", s))
+}
+
+fn parse_synth_parameter(s: &str) -> Result<SynthParameter, String> {
+    SynthParameter::by_name(s).ok_or_else(|| {
+        format!(
+            "{:?} is not a synth parameter, try one of {}",
+            s,
+            SynthParameter::NAMES.join(", ")
+        )
+    })
+}
+
+fn parse_performance(s: &str) -> Result<Performance, String> {
+    Performance::by_name(s).ok_or_else(|| {
+        format!(
+            "{:?} is not a performance, try one of {}",
+            s,
+            Performance::NAMES.join(", ")
+        )
+    })
+}
+
+/// Parses `INDEX=VOICES`.
+fn parse_voice_limit(s: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("{:?} is not INDEX=VOICES", s);
+    let (index, voices) = s.split_once('=').ok_or_else(invalid)?;
+    let index = index.trim().parse().map_err(|_| invalid())?;
+    let voices = voices.trim().parse().map_err(|_| invalid())?;
+
+    Ok((index, voices))
+}
+
+fn parse_bar_range(s: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("{:?} is not FIRST-LAST, like 5-8", s);
+    let (first, last) = s.split_once('-').ok_or_else(invalid)?;
+    let first: u32 = first.trim().parse().map_err(|_| invalid())?;
+    let last: u32 = last.trim().parse().map_err(|_| invalid())?;
+    if first == 0 || last < first {
+        return Err(format!("{:?} is not a range of bars, counting from 1", s));
+    }
+
+    Ok((first, last))
+}
+
+fn parse_sample_format(s: &str) -> Result<WavSampleFormat, String> {
+    WavSampleFormat::by_name(s).ok_or_else(|| {
+        format!(
+            "{:?} is not a sample format, try one of {}",
+            s,
+            WavSampleFormat::NAMES.join(", ")
+        )
+    })
+}
+
+fn parse_wave(s: &str) -> Result<Source, String> {
+    if s == "general-midi" {
+        return Ok(Source::PatchBank(PatchBank::general_midi()));
+    }
+
+    Source::by_name_or_path(s)
+}
+
+fn parse_patch(s: &str) -> Result<Source, String> {
+    let path = Path::new(s);
+    if path.is_dir() {
+        return PatchBank::load_dir(path)
+            .map(Source::PatchBank)
+            .map_err(|e| format!("{:?} is not a usable patch bank: {}", s, e));
+    }
+
+    SynthPatch::load(path)
+        .and_then(|patch| patch.instrument())
+        .map_err(|e| format!("{:?} is not a usable synth patch: {}", s, e))
+}
+
+/// The effects of a `--patch`, followed by the effect flags.
+fn effect_chain(source: Option<Source>, effects: EffectArgs) -> EffectsChain {
+    let mut chain = match source {
+        Some(Source::Patch(loaded)) => loaded.patch.effects(),
+        _ => EffectsChain::new(),
+    };
+    chain.push(effects.chain());
+
+    chain
 }
 
-// TODO: return Result
 fn main() {
     env_logger::init();
 
+    let cli = Cli::from_args();
+    if let Some(host) = cli.host {
+        use_audio_host(host);
+    }
+    if let Err(e) = run(cli.command, cli.host) {
+        eprintln!("{}", e);
+        if let Some(hint) = hint(&e) {
+            eprintln!("{}", hint);
+        }
+        process::exit(exit_code(&e));
+    }
+}
+
+fn run(opt: Opt, host: Option<AudioHost>) -> Result<(), NocturneError> {
     let mut runtime = tokio::runtime::Builder::new()
         .threaded_scheduler()
         .enable_all()
-        .build()
-        .unwrap();
+        .build()?;
 
-    let opt = Opt::from_args();
     match opt {
         Opt::ListMidiPorts => {
-            list_midi_input_ports();
+            list_midi_input_ports()?;
+            list_midi_output_ports()?;
+        }
+        Opt::ListAudioDevices => {
+            for (i, name) in list_audio_output_devices().iter().enumerate() {
+                println!("{}: {}", i, name);
+            }
+            println!("Inputs:");
+            for (i, name) in list_audio_input_devices().iter().enumerate() {
+                println!("{}: {}", i, name);
+            }
+        }
+        Opt::AudioSetup => audio_setup(host)?,
+        Opt::Info { midi_path } => {
+            let stats = polyphony_stats(&read_midi_tracks(&midi_path)?);
+            println!("Peak polyphony: {}", stats.total);
+            println!("--- Tracks ---");
+            for (track, peak) in stats.per_track.iter().enumerate() {
+                println!("{}: {}", track, peak);
+            }
+            println!("--- Channels ---");
+            for (channel, peak) in stats.per_channel.iter().enumerate() {
+                if *peak > 0 {
+                    println!("{}: {}", channel, peak);
+                }
+            }
         }
         Opt::PlayDevice {
-            midi_input_port,
-            recording_path,
+            midi_input_ports,
+            midi_input_port_names,
+            midi_output_ports,
+            layers,
+            routes,
+            midi_filters,
+            audio_device,
+            recording_paths,
+            ltc_rate,
+            stop_on_silence,
+            pause_on_silence,
+            format,
+            input_recording_path,
+            audio_input,
+            wave,
+            patch_path,
+            learn,
+            osc_port,
+            osc_bind,
+            effects,
         } => runtime.block_on(async move {
-            select! {
-                result = play_midi_device(
-                    midi_input_port, wave_table::triangle_wave(), recording_path
-                ) => {
-                    match result {
-                        Err(e) => {
-                            println!(
-                                "Failed to open midi port {}, try the list-midi-ports command: \
-                                 {}",
-                                midi_input_port,
-                                e,
-                            );
+            let patch = match &patch_path {
+                Some(path) if !learn.is_empty() && path.is_dir() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Learned controls are saved in a patch file, not a bank",
+                    )
+                    .into());
+                }
+                Some(path) => Some(
+                    parse_patch(&path.to_string_lossy())
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+                ),
+                None => None,
+            };
+            let wave = patch
+                .or(wave)
+                .unwrap_or_else(|| wave_table::triangle_wave().into());
+            let silence = match (stop_on_silence, pause_on_silence) {
+                (Some(secs), _) => Some((secs, SilenceAction::Stop)),
+                (None, Some(secs)) => Some((secs, SilenceAction::Pause)),
+                (None, None) => None,
+            }
+            .map(|(secs, action)| SilenceDetection::new(Duration::from_secs_f64(secs), action));
+            let recordings: Vec<_> = recording_paths
+                .into_iter()
+                .map(|path| RecordingTarget {
+                    path,
+                    options: RecordingOptions {
+                        timecode: ltc_rate,
+                        silence,
+                        ..format.options()
+                    },
+                })
+                .collect();
+            let builder = Engine::builder()
+                .output_device(audio_device.unwrap_or_default())
+                .instrument(wave)
+                .effects(effect_chain(patch, effects));
+            let builder = midi_input_ports
+                .into_iter()
+                .fold(builder, EngineBuilder::midi_input);
+            let builder = midi_input_port_names
+                .iter()
+                .fold(builder, |builder, name| builder.midi_input_by_name(name));
+            let builder = midi_output_ports
+                .into_iter()
+                .fold(builder, EngineBuilder::midi_output);
+            let builder = layers.into_iter().fold(builder, |builder, layer| {
+                builder.layer(layer, EffectsChain::new())
+            });
+            let builder = routes.into_iter().fold(builder, EngineBuilder::route);
+            let mut filters = Config::load_default().midi_filters;
+            filters.extend(midi_filters);
+            let builder = builder.midi_filter(MidiFilterConfig::chain(&filters));
+            let mut engine = recordings
+                .into_iter()
+                .fold(builder, EngineBuilder::record)
+                .build()?;
+            let input_recording = match input_recording_path {
+                Some(path) => {
+                    let input = AudioInputDeviceStream::connect(&audio_input.unwrap_or_default())?;
+                    let config = input.get_config();
+                    let recorder = RecordingOutputStream::connect_with_options(
+                        &path,
+                        config.channels,
+                        config.sample_rate.0,
+                        input.subscribe(),
+                        format.options(),
+                    )?;
+                    input.play()?;
+
+                    Some((input, recorder))
+                }
+                None => None,
+            };
+            let osc = match osc_port {
+                Some(port) => Some(bind_osc(osc_bind, port).await?),
+                None => None,
+            };
+            engine.start();
+            let learned = async {
+                for &parameter in learn.iter() {
+                    println!("Move the controller for {}", parameter.name());
+                    match engine.controls().learn(parameter).await {
+                        Ok(binding) => {
+                            println!("CC {} moves {}", binding.controller, parameter.name())
                         }
-                        Ok(()) => (),
+                        // Only if something else started learning.
+                        Err(_) => return Ok(()),
                     }
-                },
-                _ = signal::ctrl_c() => (),
+                }
+                match &patch_path {
+                    Some(path) if !learn.is_empty() => {
+                        SynthPatch::save_controls(path, &engine.controls().bindings())?;
+                        println!("Saved to {}", path.display());
+                    }
+                    _ => (),
+                }
+
+                Ok::<_, io::Error>(())
+            };
+            let stopped = async {
+                select! {
+                    learned = learned => {
+                        learned?;
+                        signal::ctrl_c().await
+                    }
+                    stopped = signal::ctrl_c() => stopped,
+                }
+            };
+            let controlled = async {
+                match osc {
+                    Some(osc) => {
+                        osc.control_engine(&engine, None, CancellationToken::new())
+                            .await
+                    }
+                    None => futures::future::pending().await,
+                }
+            };
+            select! {
+                stopped = stopped => stopped?,
+                controlled = controlled => controlled?,
             }
-        }),
+
+            let played = engine.shutdown().await;
+            let input_recorded = match input_recording {
+                Some((input, recorder)) => {
+                    drop(input);
+                    recorder.close().await
+                }
+                None => Ok(()),
+            };
+
+            played.and(input_recorded)
+        })?,
         Opt::PlayFile {
             midi_path,
             bpm,
-            recording_path, // TODO: support recording (requires mixing)
+            recording_path,
+            stems_path,
+            format,
+            wave,
+            patch,
+            instrument_map_path,
+            general_midi_map,
+            mtc_port,
+            clock_port,
+            osc_port,
+            osc_bind,
+            audio_device,
+            performance,
+            track_voice_limits,
+            channel_voice_limits,
+            seed,
+            speed,
+            fixed_tempo,
+            loop_file,
+            loop_bars,
+            muted_tracks,
+            soloed_tracks,
+            effects,
         } => {
-            let instruments = [
-                wave_table::sawtooth_wave(),
-                wave_table::sine_wave(),
-                wave_table::triangle_wave(),
-                wave_table::square_wave(),
-            ];
+            let loop_region = match (loop_file, loop_bars) {
+                (_, Some((first, last))) => Some(LoopRegion::Bars(first, last)),
+                (true, None) => Some(LoopRegion::WholeFile),
+                (false, None) => None,
+            };
+            let wave = match general_midi_map {
+                Some(path) => Some(general_midi_bank(&path)?),
+                None => patch.or(wave),
+            };
+            let instruments = match (&performance, wave) {
+                (Some(p), None) => p.track_instruments.clone(),
+                _ => track_instruments(wave),
+            };
+            let midi_bytes = read_midi_tracks(&midi_path)?;
+            let instruments =
+                mapped_instruments(instrument_map_path.as_deref(), &midi_bytes, instruments)?;
+            let mut track_limits = VoiceLimits::default();
+            for &(channel, voices) in channel_voice_limits.iter() {
+                match track_limits.per_channel.get_mut(channel) {
+                    Some(limit) => *limit = Some(voices),
+                    None => println!("There is no channel {}, counting from 0", channel),
+                }
+            }
+            let mut voice_limits = vec![track_limits; midi_bytes.parse().tracks.len()];
+            for &(track, voices) in track_voice_limits.iter() {
+                match voice_limits.get_mut(track) {
+                    Some(limits) => limits.total = Some(voices),
+                    None => println!("There is no track {} in {:?}", track, midi_path),
+                }
+            }
             runtime.block_on(async move {
-                select! {
-                    _ = play_all_midi_tracks(
-                        MidiBytes::read_file(&midi_path), bpm as Bpm, &instruments
-                    ) => (),
-                    _ = signal::ctrl_c() => (),
+                if let Some(port) = clock_port {
+                    let clock_input = MidiInputDeviceStream::connect(port)?;
+                    return play_all_midi_tracks_following_midi_clock(
+                        midi_bytes,
+                        &instruments,
+                        |track| effect_chain(Some(instruments[track % instruments.len()]), effects),
+                        clock_input.message_rx,
+                        cancel_on_ctrl_c(),
+                    )
+                    .await;
                 }
+                match mtc_port {
+                    Some(port) => {
+                        let mtc_input = MidiInputDeviceStream::connect(port)?;
+                        play_all_midi_tracks_chasing_mtc(
+                            midi_bytes,
+                            bpm as Bpm,
+                            &instruments,
+                            mtc_input.message_rx,
+                            cancel_on_ctrl_c(),
+                        )
+                        .await
+                    }
+                    None => {
+                        println!("{}", TRANSPORT_HELP);
+                        let cancel = cancel_on_ctrl_c();
+                        let (transport_tx, transport_rx) = mpsc::channel(TRANSPORT_COMMAND_BUFFER);
+                        read_transport_from_stdin(transport_tx.clone());
+                        if let Some(port) = osc_port {
+                            let osc = bind_osc(osc_bind, port).await?;
+                            tokio::spawn(osc.control_transport(transport_tx, cancel.clone()));
+                        }
+                        play_all_midi_tracks_with_transport(
+                            midi_bytes,
+                            bpm as Bpm,
+                            &instruments,
+                            |track| {
+                                let mut chain = performance
+                                    .as_ref()
+                                    .map(|p| p.track_effects(track))
+                                    .unwrap_or_default();
+                                // A patch's own effects, from --patch or the instrument map.
+                                let instrument = instruments[track % instruments.len()];
+                                chain.push(effect_chain(Some(instrument), effects));
+
+                                chain
+                            },
+                            EnsembleOptions {
+                                recordings: recording_path
+                                    .into_iter()
+                                    .map(|path| format.target(path))
+                                    .collect(),
+                                stems: stems_path.map(|path| format.target(path)),
+                                voice_limits,
+                                seed,
+                                output_device: audio_device.unwrap_or_default(),
+                                sequencer: SequencerOptions {
+                                    speed,
+                                    fixed_tempo,
+                                    loop_region,
+                                    muted_tracks,
+                                    soloed_tracks,
+                                },
+                            },
+                            transport_rx,
+                            cancel,
+                        )
+                        .await
+                    }
+                }
+            })?;
+        }
+        Opt::RandomPatch {
+            output_path,
+            seed,
+            audition: play,
+        } => {
+            let seed = seed.unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or_default()
             });
+            let patch = SynthPatch::randomize(seed, &PatchConstraints::default());
+            patch
+                .save(&output_path)
+                .map_err(|e| in_file(e, &output_path))?;
+            println!("Wrote {} (seed {})", output_path.display(), seed);
+            if play {
+                let instrument = patch.instrument()?;
+                runtime.block_on(async move {
+                    audition(instrument, patch.effects(), cancel_on_ctrl_c()).await
+                })?;
+            }
         }
+        Opt::PlayModule {
+            module_path,
+            output_path,
+            effects,
+        } => {
+            let module = TrackerModule::load(&module_path).map_err(|e| in_file(e, &module_path))?;
+            println!("{} ({:.0?})", module.title, module.duration());
+            match output_path {
+                Some(path) => {
+                    render_tracker_module(&module, effects.chain(), &path)
+                        .map_err(|e| in_file(e, &path))?;
+                    println!("Wrote {}", path.display());
+                }
+                None => runtime.block_on(async move {
+                    play_tracker_module(&module, effects.chain(), cancel_on_ctrl_c()).await
+                })?,
+            }
+        }
+        Opt::Render {
+            midi_path,
+            output_path,
+            bpm,
+            wave,
+            seed,
+            frame_size,
+            instrument_map_path,
+            general_midi_map,
+        } => {
+            let wave = match general_midi_map {
+                Some(path) => Some(general_midi_bank(&path)?),
+                None => wave,
+            };
+            let midi_bytes = read_midi_tracks(&midi_path)?;
+            let instruments = mapped_instruments(
+                instrument_map_path.as_deref(),
+                &midi_bytes,
+                track_instruments(wave),
+            )?;
+            render_midi_to_wav_with_options(
+                &midi_bytes,
+                bpm as Bpm,
+                &instruments,
+                RenderOptions { seed, frame_size },
+                &output_path,
+            )
+            .map_err(|e| in_file(e, &output_path))?;
+            println!("Wrote {}", output_path.display());
+        }
+        Opt::Spectrogram {
+            midi_path,
+            output_path,
+            bpm,
+            wave,
+        } => {
+            let midi_bytes = read_midi_tracks(&midi_path)?;
+            write_midi_spectrogram(
+                &midi_bytes,
+                bpm as Bpm,
+                &track_instruments(wave),
+                &output_path,
+                SpectrogramOptions::default(),
+            )
+            .map_err(|e| in_file(e, &output_path))?;
+            println!("Wrote {}", output_path.display());
+        }
+        Opt::RecoverLastSession { output_path, bpm } => {
+            let dir = MidiJournal::default_dir().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "No data directory on this platform",
+                )
+            })?;
+            let journal = recover_last_session_at_tempo(&dir, bpm as Bpm, &output_path)?;
+            println!(
+                "Recovered {} to {}",
+                journal.display(),
+                output_path.display()
+            );
+        }
+        Opt::Monitor {
+            audio_input,
+            audio_device,
+            recording_paths,
+            format,
+            effects,
+        } => runtime.block_on(async move {
+            let recordings = recording_paths
+                .into_iter()
+                .map(|path| format.target(path))
+                .collect();
+            monitor_audio_input(
+                audio_input.unwrap_or_default(),
+                effects.chain(),
+                audio_device.unwrap_or_default(),
+                recordings,
+                cancel_on_ctrl_c(),
+            )
+            .await
+        })?,
+        Opt::Audition {
+            preset,
+            patch,
+            output_path,
+            effects,
+        } => {
+            let preset = patch
+                .or(preset)
+                .unwrap_or_else(|| wave_table::triangle_wave().into());
+            match output_path {
+                Some(path) => {
+                    render_audition(preset, effect_chain(patch, effects), &path)
+                        .map_err(|e| in_file(e, &path))?;
+                    println!("Wrote {}", path.display());
+                }
+                None => runtime.block_on(async move {
+                    audition(preset, effect_chain(patch, effects), cancel_on_ctrl_c()).await
+                })?,
+            }
+        }
+        Opt::PlaySteps {
+            pattern_path,
+            bpm,
+            wave,
+            patch,
+            effects,
+        } => {
+            let mut pattern =
+                StepPattern::load(&pattern_path).map_err(|e| in_file(e, &pattern_path))?;
+            if let Some(bpm) = bpm {
+                pattern.bpm = bpm;
+            }
+            let wave = patch
+                .or(wave)
+                .unwrap_or_else(|| wave_table::triangle_wave().into());
+            runtime.block_on(async move {
+                let cancel = cancel_on_ctrl_c();
+                let steps = StepSequencer::new(pattern).start(cancel.clone());
+                play_midi(
+                    steps,
+                    wave,
+                    effect_chain(patch, effects),
+                    Vec::new(),
+                    None,
+                    cancel,
+                )
+                .await
+            })?;
+        }
+        Opt::Practice {
+            midi_path,
+            bpm,
+            midi_input_port,
+            midi_input_port_name,
+            track,
+            click,
+            wave,
+            latency_ms,
+        } => {
+            let midi_bytes = read_midi_file(&midi_path)?;
+            let live_input = match midi_input_port_name {
+                Some(name) => MidiInputDeviceStream::connect_by_name(&name)?,
+                None => MidiInputDeviceStream::connect(
+                    midi_input_port.expect("Either a port or a port name is required"),
+                )?,
+            };
+            let latency_compensation = match latency_ms {
+                Some(ms) => Duration::from_millis(ms),
+                None => Config::load_default()
+                    .audio_output
+                    .and_then(|p| p.buffer_latency())
+                    .unwrap_or_default(),
+            };
+            let options = PracticeOptions {
+                accompaniment: if click {
+                    Accompaniment::Click
+                } else {
+                    Accompaniment::File
+                },
+                track,
+                latency_compensation,
+                ..Default::default()
+            };
+            let wave = wave.unwrap_or_else(|| wave_table::triangle_wave().into());
+            let report = runtime.block_on(async move {
+                practice_midi_file(
+                    midi_bytes,
+                    bpm as Bpm,
+                    live_input.message_rx,
+                    wave,
+                    options,
+                    cancel_on_ctrl_c(),
+                )
+                .await
+            })?;
+            println!("{}", report);
+        }
+    }
+
+    Ok(())
+}
+
+/// Names the file in an error about it.
+fn in_file(e: io::Error, path: &Path) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+fn read_midi_file(path: &Path) -> Result<MidiBytes, NocturneError> {
+    MidiBytes::read_file(path).map_err(|e| match e {
+        NocturneError::Io(e) => in_file(e, path).into(),
+        e => e,
+    })
+}
+
+/// Like `read_midi_file`, with a single-track file split into a track per channel, for playing
+/// each track on its own instrument.
+fn read_midi_tracks(path: &Path) -> Result<MidiBytes, NocturneError> {
+    Ok(read_midi_file(path)?.split_channels())
+}
+
+/// Exit codes from BSD's sysexits.h, so scripts can tell a missing device from a bad file.
+fn exit_code(e: &NocturneError) -> i32 {
+    const EX_DATAERR: i32 = 65;
+    const EX_NOINPUT: i32 = 66;
+    const EX_UNAVAILABLE: i32 = 69;
+    const EX_IOERR: i32 = 74;
+
+    match e {
+        NocturneError::InvalidMidiFile(_) | NocturneError::UnsupportedMidiTiming => EX_DATAERR,
+        NocturneError::Io(e) if e.kind() == io::ErrorKind::NotFound => EX_NOINPUT,
+        NocturneError::Io(_) => EX_IOERR,
+        _ => EX_UNAVAILABLE,
+    }
+}
+
+fn hint(e: &NocturneError) -> Option<&'static str> {
+    match e {
+        NocturneError::NoMidiPort(_)
+        | NocturneError::NoMidiPortNamed(_)
+        | NocturneError::MidiConnect(_) => Some("Try the list-midi-ports command"),
+        NocturneError::NoAudioOutput(_) | NocturneError::NoAudioInput(_) => {
+            Some("Try the list-audio-devices command")
+        }
+        _ => None,
     }
 }
+
+/// Either `wave` for every track, or the built-in waves in turn.
+fn track_instruments(wave: Option<Source>) -> Vec<Source> {
+    match wave {
+        Some(wave) => vec![wave],
+        None => vec![
+            wave_table::sawtooth_wave().into(),
+            wave_table::sine_wave().into(),
+            wave_table::triangle_wave().into(),
+            wave_table::square_wave().into(),
+        ],
+    }
+}
+
+/// The General MIDI patches, with the ones from the map at `path`.
+fn general_midi_bank(path: &Path) -> Result<Source, NocturneError> {
+    Ok(PatchBank::load_general_midi(path)
+        .map(Source::PatchBank)
+        .map_err(|e| in_file(e, path))?)
+}
+
+/// The instrument for each track from the map at `path`, or `fallback` without one.
+fn mapped_instruments(
+    path: Option<&Path>,
+    midi_bytes: &MidiBytes,
+    fallback: Vec<Source>,
+) -> Result<Vec<Source>, NocturneError> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(fallback),
+    };
+    let num_tracks = midi_bytes.parse().tracks.len();
+
+    Ok(InstrumentMap::load(path)
+        .and_then(|map| map.track_instruments(num_tracks, &fallback))
+        .map_err(|e| in_file(e, path))?)
+}
+
+/// A token that is cancelled on Ctrl-C, so playback stops and recordings are finalized instead of
+/// being cut off.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let on_ctrl_c = cancel.clone();
+    tokio::spawn(async move {
+        signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
+        on_ctrl_c.cancel();
+    });
+
+    cancel
+}
+
+/// Listens for OSC on `port` of the interface with address `ip`.
+async fn bind_osc(ip: IpAddr, port: u16) -> io::Result<OscServer> {
+    let osc = OscServer::bind(SocketAddr::new(ip, port)).await?;
+    println!("Listening for OSC on {}", osc.local_addr()?);
+
+    Ok(osc)
+}
+
+/// Reads transport commands from the terminal, one per line, while a file plays.
+fn read_transport_from_stdin(mut tx: mpsc::Sender<TransportCommand>) {
+    // Reading the terminal blocks, so it gets a thread of its own, which ends with the process.
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            let command = match parse_transport_command(&line) {
+                Some(command) => command,
+                None => {
+                    println!("{}", TRANSPORT_HELP);
+                    continue;
+                }
+            };
+            if let Err(mpsc::error::TrySendError::Closed(_)) = tx.try_send(command) {
+                return;
+            }
+        }
+    });
+}
+
+const TRANSPORT_HELP: &str =
+    "Type a speed like 0.5, pause, play, bar N, tick N, mute N, unmute N, \
+    solo N or unsolo N, and press Enter to control playback";
+
+fn parse_transport_command(line: &str) -> Option<TransportCommand> {
+    let mut words = line.split_whitespace();
+    let command = match (words.next()?, words.next()) {
+        ("pause", None) => TransportCommand::Pause,
+        ("play", None) => TransportCommand::Play,
+        ("bar", Some(bar)) => TransportCommand::SeekToBar(bar.parse().ok()?),
+        ("tick", Some(tick)) => TransportCommand::SeekToTick(tick.parse().ok()?),
+        ("mute", Some(track)) => TransportCommand::SetMute(track.parse().ok()?, true),
+        ("unmute", Some(track)) => TransportCommand::SetMute(track.parse().ok()?, false),
+        ("solo", Some(track)) => TransportCommand::SetSolo(track.parse().ok()?, true),
+        ("unsolo", Some(track)) => TransportCommand::SetSolo(track.parse().ok()?, false),
+        (speed, None) => match speed.parse::<f64>() {
+            Ok(speed) if speed.is_finite() && speed > 0.0 => TransportCommand::SetSpeed(speed),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    if words.next().is_some() {
+        return None;
+    }
+
+    Some(command)
+}
+
+/// Saves `host` along with the profile, since device names belong to it.
+fn audio_setup(host: Option<AudioHost>) -> io::Result<()> {
+    let profiles = probe_audio_output_profiles();
+    if profiles.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No usable audio output devices found",
+        ));
+    }
+
+    println!("--- Available audio output profiles (best first) ---");
+    for (i, p) in profiles.iter().enumerate() {
+        let latency = p
+            .buffer_latency()
+            .map(|l| format!("{:.1} ms", l.as_secs_f64() * 1000.0))
+            .unwrap_or_else(|| "unknown latency".to_string());
+        println!(
+            "{}: {} @ {} Hz, {} channels, {}",
+            i, p.device_name, p.sample_hz, p.channels, latency
+        );
+    }
+    print!("Choose a profile [0]: ");
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    let choice = match line.trim() {
+        "" => 0,
+        s => match s.parse::<usize>() {
+            Ok(i) if i < profiles.len() => i,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid choice {:?}", s),
+                ))
+            }
+        },
+    };
+
+    let mut config = Config::load_default();
+    if let Some(host) = host {
+        config.audio_host = host;
+    }
+    config.audio_output = Some(profiles[choice].clone());
+    config.save_default()?;
+    println!("Saved {}", profiles[choice].device_name);
+
+    Ok(())
+}
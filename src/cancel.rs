@@ -0,0 +1,48 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// A shared stop signal. Every long-running playback, sequencing and recording task takes one, so a
+/// single `cancel` (e.g. from Ctrl-C) stops all of them promptly and lets them tear down cleanly.
+///
+/// Clones share the same signal. Once cancelled, a token stays cancelled.
+#[derive(Clone)]
+pub struct CancellationToken {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        let (tx, rx) = watch::channel(false);
+
+        CancellationToken {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        // Can't fail, since this token holds a receiver.
+        let _ = self.tx.broadcast(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Completes once the token is cancelled, immediately if it already has been.
+    pub async fn cancelled(&self) {
+        let mut rx = self.rx.clone();
+        while let Some(cancelled) = rx.recv().await {
+            if cancelled {
+                return;
+            }
+        }
+    }
+}
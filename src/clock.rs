@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// A source of time for anything that schedules events, so it can run against the real clock or
+/// be stepped deterministically.
+pub trait Clock: Clone + Send + Sync + 'static {
+    type Delay: Future<Output = ()> + Send + Unpin;
+
+    fn now(&self) -> Instant;
+
+    fn delay_for(&self, duration: Duration) -> Self::Delay;
+}
+
+/// The wall clock and tokio timers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    type Delay = tokio::time::Delay;
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay_for(&self, duration: Duration) -> Self::Delay {
+        tokio::time::delay_for(duration)
+    }
+}
+
+/// A clock that only moves when `advance` is called. Delays complete as soon as the clock has been
+/// advanced past their deadline, without any real waiting.
+#[derive(Clone)]
+pub struct ManualClock {
+    start: Instant,
+    state: Arc<Mutex<ManualClockState>>,
+}
+
+struct ManualClockState {
+    elapsed: Duration,
+    /// The pending delays' deadlines and wakers, by slot id, so they wake in the order they were
+    /// made. A delay keeps its slot while it's alive and frees it when dropped, so delays that are
+    /// polled again or abandoned don't pile up.
+    sleepers: BTreeMap<u64, (Duration, Waker)>,
+    next_slot: u64,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock {
+            start: Instant::now(),
+            state: Arc::new(Mutex::new(ManualClockState {
+                elapsed: Duration::from_secs(0),
+                sleepers: BTreeMap::new(),
+                next_slot: 0,
+            })),
+        }
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time advanced since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().elapsed
+    }
+
+    /// Moves time forward, waking every delay whose deadline has passed.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.elapsed += duration;
+        let now = state.elapsed;
+        state.sleepers.retain(|_, (deadline, waker)| {
+            if *deadline <= now {
+                waker.wake_by_ref();
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+impl Clock for ManualClock {
+    type Delay = ManualDelay;
+
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn delay_for(&self, duration: Duration) -> Self::Delay {
+        let mut state = self.state.lock().unwrap();
+        let slot = state.next_slot;
+        state.next_slot += 1;
+
+        ManualDelay {
+            deadline: state.elapsed + duration,
+            slot,
+            state: self.state.clone(),
+        }
+    }
+}
+
+pub struct ManualDelay {
+    deadline: Duration,
+    /// Where the delay's waker is kept while it's pending.
+    slot: u64,
+    state: Arc<Mutex<ManualClockState>>,
+}
+
+impl Future for ManualDelay {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.elapsed >= self.deadline {
+            Poll::Ready(())
+        } else {
+            state
+                .sleepers
+                .insert(self.slot, (self.deadline, cx.waker().clone()));
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for ManualDelay {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.sleepers.remove(&self.slot);
+        }
+    }
+}
+
+/// Lets tasks on a `ManualClock` run until they're all waiting on it again, for tests on tokio's
+/// single-threaded runtime.
+#[cfg(test)]
+pub(crate) async fn settle() {
+    for _ in 0..10 {
+        // tokio 0.2 marks `yield_now` as `must_use`, which now flags its unit output.
+        let () = tokio::task::yield_now().await;
+    }
+}
+
+/// Counts the samples (per output channel) rendered since playback started. This is the timeline
+/// that frames, note events and recordings are stamped with, so they agree on when things happened
+/// without consulting the wall clock.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SampleClock {
+    sample_hz: u32,
+    position: u64,
+}
+
+impl SampleClock {
+    pub fn new(sample_hz: u32) -> Self {
+        SampleClock {
+            sample_hz,
+            position: 0,
+        }
+    }
+
+    pub fn sample_hz(&self) -> u32 {
+        self.sample_hz
+    }
+
+    /// Switches to counting at a new rate from the current time on, so times stay continuous.
+    pub fn set_sample_hz(&mut self, sample_hz: u32) {
+        let time = self.time();
+        self.sample_hz = sample_hz;
+        self.position = self.position_at(time);
+    }
+
+    /// The next sample to be rendered.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn advance(&mut self, samples: u64) {
+        self.position += samples;
+    }
+
+    /// The time of the next sample to be rendered.
+    pub fn time(&self) -> Duration {
+        self.time_at(self.position)
+    }
+
+    pub fn time_at(&self, position: u64) -> Duration {
+        Duration::from_secs_f64(position as f64 / self.sample_hz as f64)
+    }
+
+    /// The first sample at or after `time`.
+    pub fn position_at(&self, time: Duration) -> u64 {
+        (time.as_secs_f64() * self.sample_hz as f64).ceil() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn delays_keep_one_waker_and_drop_it_with_themselves() {
+        let clock = ManualClock::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let sleepers = || clock.state.lock().unwrap().sleepers.len();
+
+        let mut delay = clock.delay_for(Duration::from_millis(10));
+        for _ in 0..3 {
+            assert!(Pin::new(&mut delay).poll(&mut cx).is_pending());
+        }
+        assert_eq!(sleepers(), 1);
+
+        // Like a `select!` that picks another branch, and makes a new delay next time around.
+        drop(delay);
+        assert_eq!(sleepers(), 0);
+
+        let mut delay = clock.delay_for(Duration::from_millis(10));
+        assert!(Pin::new(&mut delay).poll(&mut cx).is_pending());
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(sleepers(), 0);
+        assert!(Pin::new(&mut delay).poll(&mut cx).is_ready());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{
+    audio_device::{AudioDeviceProfile, AudioHost, ReconnectPolicy},
+    midi_filter::MidiFilterConfig,
+    FRAME_SIZE, MAX_FRAME_SIZE, MIN_FRAME_SIZE,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Persistent user settings, stored as TOML.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Config {
+    /// The audio system to play through, like `"jack"`, or `"asio"` on Windows. Device names and
+    /// profiles are that system's.
+    #[serde(default)]
+    pub audio_host: AudioHost,
+    pub audio_output: Option<AudioDeviceProfile>,
+    /// Bounded-latency mode: frames that reach the audio device more than this many milliseconds
+    /// after they were rendered are dropped. Keeps live playing responsive after a hiccup, at the
+    /// cost of a skip in the audio.
+    #[serde(default)]
+    pub max_frame_age_ms: Option<u64>,
+    /// Frames per audio callback on whichever device is played on, instead of the profile's or the
+    /// host's default. Smaller is more responsive to a MIDI keyboard but underruns sooner; shared
+    /// mode WASAPI defaults to about 10 ms, and low latency drivers can go a lot lower. Clamped to
+    /// what the device supports.
+    #[serde(default)]
+    pub buffer_frames: Option<u32>,
+    /// Samples in each frame the synth renders, across all channels. Shorter frames respond to a
+    /// MIDI keyboard sooner, and longer ones take less CPU. 512 if unset.
+    #[serde(default)]
+    pub frame_size: Option<usize>,
+    /// What to do when the audio output fails while playing.
+    #[serde(default)]
+    pub reconnect: ReconnectPolicy,
+    /// Filters on live MIDI input, in order, before it reaches any synth or MIDI output.
+    #[serde(default)]
+    pub midi_filters: Vec<MidiFilterConfig>,
+}
+
+impl Config {
+    pub fn max_frame_age(&self) -> Option<Duration> {
+        self.max_frame_age_ms.map(Duration::from_millis)
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+            .unwrap_or(FRAME_SIZE)
+            .clamp(MIN_FRAME_SIZE, MAX_FRAME_SIZE)
+    }
+
+    /// `$XDG_CONFIG_HOME/nocturne/config.toml` or the platform equivalent.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join("nocturne").join("config.toml"))
+    }
+
+    /// Loads the config from the default path. A missing or malformed file yields the default
+    /// config.
+    pub fn load_default() -> Self {
+        Self::default_path()
+            .map(|p| Self::load(&p))
+            .unwrap_or_default()
+    }
+
+    pub fn load(path: &Path) -> Self {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to read config file {:?}: {}", path, e);
+                }
+                return Self::default();
+            }
+        };
+
+        toml::from_str(&text).unwrap_or_else(|e| {
+            warn!("Ignoring malformed config file {:?}: {}", path, e);
+            Self::default()
+        })
+    }
+
+    pub fn save_default(&self) -> io::Result<()> {
+        let path = Self::default_path().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "No config directory on this platform",
+            )
+        })?;
+
+        self.save(&path)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text =
+            toml::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        fs::write(path, text)
+    }
+}
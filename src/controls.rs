@@ -0,0 +1,194 @@
+//! Parameters of a synth and its effects that can be reached by id, like `cutoff`, and the MIDI
+//! controllers that move them. MIDI learn binds the next controller that moves to a parameter, and
+//! patches keep their bindings:
+//!
+//! ```toml
+//! [[controls]]
+//! parameter = "cutoff"
+//! controller = 21
+//! ```
+
+use crate::oscillator::Source;
+
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Controllers from here on are channel mode messages, like All Notes Off, which can't be bound.
+const FIRST_CHANNEL_MODE_CONTROLLER: u8 = 120;
+
+/// Something a controller can move. Each takes a value from 0.0 to 1.0, like a controller's 0 to
+/// 127.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SynthParameter {
+    /// Moves every note's filter cutoff down or up, like brightness (CC74) on every channel at
+    /// once. The middle leaves it alone.
+    Cutoff,
+    /// Takes from or adds to every note's filter resonance. The middle leaves it alone.
+    Resonance,
+    /// The synth's output level, from silent to full at the top.
+    Gain,
+    /// How fast a chorus sweeps, from 0.05 to 10 Hz.
+    ChorusRate,
+    /// A chorus's wet level.
+    ChorusMix,
+}
+
+impl SynthParameter {
+    pub const NAMES: [&'static str; 5] =
+        ["cutoff", "resonance", "gain", "chorus-rate", "chorus-mix"];
+
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "cutoff" => Some(SynthParameter::Cutoff),
+            "resonance" => Some(SynthParameter::Resonance),
+            "gain" => Some(SynthParameter::Gain),
+            "chorus-rate" => Some(SynthParameter::ChorusRate),
+            "chorus-mix" => Some(SynthParameter::ChorusMix),
+            _ => None,
+        }
+    }
+
+    /// The parameter's id, which `by_name` takes.
+    pub fn name(self) -> &'static str {
+        match self {
+            SynthParameter::Cutoff => "cutoff",
+            SynthParameter::Resonance => "resonance",
+            SynthParameter::Gain => "gain",
+            SynthParameter::ChorusRate => "chorus-rate",
+            SynthParameter::ChorusMix => "chorus-mix",
+        }
+    }
+}
+
+/// A controller that moves a parameter.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ControlBinding {
+    pub parameter: SynthParameter,
+    /// The controller number, from 0 to 119.
+    pub controller: u8,
+    /// Only follow the controller on this channel, counting from 0, instead of on every channel.
+    #[serde(default)]
+    pub channel: Option<u8>,
+}
+
+impl ControlBinding {
+    fn matches(&self, channel: u8, controller: u8) -> bool {
+        self.controller == controller && self.channel.map_or(true, |c| c == channel)
+    }
+}
+
+/// The bindings a synth follows, shared with whatever changes them or moves its parameters while it
+/// plays. Clones share the same bindings.
+#[derive(Clone, Default)]
+pub struct ControlBindings {
+    state: Arc<Mutex<ControlState>>,
+}
+
+#[derive(Default)]
+struct ControlState {
+    bindings: Vec<ControlBinding>,
+    /// The parameter that the next controller to move is bound to.
+    learning: Option<(SynthParameter, oneshot::Sender<ControlBinding>)>,
+    /// Parameters moved by `set`, until the synth gets to them.
+    moved: Vec<(SynthParameter, f32)>,
+}
+
+impl ControlBindings {
+    pub fn new(bindings: Vec<ControlBinding>) -> Self {
+        ControlBindings {
+            state: Arc::new(Mutex::new(ControlState {
+                bindings,
+                learning: None,
+                moved: Vec::new(),
+            })),
+        }
+    }
+
+    /// The bindings saved in `source`'s patch, if it has one.
+    pub(crate) fn of_source(source: Source) -> Self {
+        match source {
+            Source::Patch(loaded) => loaded.patch.control_bindings(),
+            _ => Self::default(),
+        }
+    }
+
+    pub fn bindings(&self) -> Vec<ControlBinding> {
+        self.state.lock().unwrap().bindings.clone()
+    }
+
+    /// Binds `parameter` to a controller, in place of the one it had.
+    pub fn bind(&self, binding: ControlBinding) {
+        let mut state = self.state.lock().unwrap();
+        state.bindings.retain(|b| b.parameter != binding.parameter);
+        state.bindings.push(binding);
+    }
+
+    /// Binds `parameter` to the next controller that moves, on any channel. The binding comes back
+    /// once it's made, unless another parameter is learned first.
+    pub fn learn(&self, parameter: SynthParameter) -> oneshot::Receiver<ControlBinding> {
+        let (tx, rx) = oneshot::channel();
+        self.state.lock().unwrap().learning = Some((parameter, tx));
+
+        rx
+    }
+
+    /// Moves a parameter to `value`, from 0.0 to 1.0, as if by a controller bound to it. Takes
+    /// effect from the synth's next frame. NaN and the infinities are ignored.
+    pub fn set(&self, parameter: SynthParameter, value: f32) {
+        if !value.is_finite() {
+            return;
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .moved
+            .push((parameter, value.clamp(0.0, 1.0)));
+    }
+
+    /// Calls `set` with each parameter moved by `ControlBindings::set` since the last call.
+    pub(crate) fn take_moved(&self, mut set: impl FnMut(SynthParameter, f32)) {
+        let mut state = self.state.lock().unwrap();
+        for (parameter, value) in state.moved.drain(..) {
+            set(parameter, value);
+        }
+    }
+
+    /// Calls `set` with each parameter that the controller moves, after binding it if a parameter
+    /// is being learned. Returns whether the controller moves any, in which case it means nothing
+    /// else.
+    pub(crate) fn handle(
+        &self,
+        channel: u8,
+        controller: u8,
+        mut set: impl FnMut(SynthParameter),
+    ) -> bool {
+        if controller >= FIRST_CHANNEL_MODE_CONTROLLER {
+            return false;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if let Some((parameter, learned_tx)) = state.learning.take() {
+            let binding = ControlBinding {
+                parameter,
+                controller,
+                channel: None,
+            };
+            state.bindings.retain(|b| b.parameter != parameter);
+            state.bindings.push(binding);
+            log::info!("CC {} moves {}", controller, parameter.name());
+            // Nobody may be waiting to hear about it.
+            let _ = learned_tx.send(binding);
+        }
+        let mut bound = false;
+        for binding in state.bindings.iter() {
+            if binding.matches(channel, controller) {
+                set(binding.parameter);
+                bound = true;
+            }
+        }
+
+        bound
+    }
+}
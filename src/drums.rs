@@ -0,0 +1,272 @@
+//! A synthesized General MIDI percussion kit for channel 10, for when the instrument is a wave
+//! rather than a soundfont. Every drum is a pitch-swept sine for the body plus filtered noise for
+//! the rattle, with different settings per key.
+
+use crate::{
+    filters::{Biquad, BiquadCoefficients, BiquadKind},
+    oscillator::WhiteNoise,
+};
+
+use std::f32::consts::PI;
+
+/// Below this both parts are inaudible and the hit is over.
+const SILENT_LEVEL: f32 = 1e-4;
+
+#[derive(Clone, Copy)]
+struct DrumPatch {
+    /// The body's pitch sweeps from `start_hz` to `end_hz` over `sweep_secs`.
+    start_hz: f32,
+    end_hz: f32,
+    sweep_secs: f32,
+    tone_level: f32,
+    /// Time for the body to fall by 60 dB.
+    tone_decay_secs: f32,
+    noise_level: f32,
+    noise_decay_secs: f32,
+    noise_filter: (BiquadKind, f32),
+}
+
+const NOISE_Q: f32 = 0.707;
+
+const KICK: DrumPatch = DrumPatch {
+    start_hz: 150.0,
+    end_hz: 45.0,
+    sweep_secs: 0.05,
+    tone_level: 1.0,
+    tone_decay_secs: 0.5,
+    noise_level: 0.1,
+    noise_decay_secs: 0.02,
+    noise_filter: (BiquadKind::LowPass, 3000.0),
+};
+
+const SNARE: DrumPatch = DrumPatch {
+    start_hz: 250.0,
+    end_hz: 180.0,
+    sweep_secs: 0.02,
+    tone_level: 0.5,
+    tone_decay_secs: 0.15,
+    noise_level: 0.8,
+    noise_decay_secs: 0.25,
+    noise_filter: (BiquadKind::HighPass, 1500.0),
+};
+
+const SIDE_STICK: DrumPatch = DrumPatch {
+    start_hz: 800.0,
+    end_hz: 800.0,
+    sweep_secs: 0.0,
+    tone_level: 0.6,
+    tone_decay_secs: 0.05,
+    noise_level: 0.3,
+    noise_decay_secs: 0.03,
+    noise_filter: (BiquadKind::BandPass, 2500.0),
+};
+
+const CLAP: DrumPatch = DrumPatch {
+    start_hz: 0.0,
+    end_hz: 0.0,
+    sweep_secs: 0.0,
+    tone_level: 0.0,
+    tone_decay_secs: 0.0,
+    noise_level: 0.9,
+    noise_decay_secs: 0.3,
+    noise_filter: (BiquadKind::BandPass, 1200.0),
+};
+
+const CLOSED_HAT: DrumPatch = DrumPatch {
+    start_hz: 0.0,
+    end_hz: 0.0,
+    sweep_secs: 0.0,
+    tone_level: 0.0,
+    tone_decay_secs: 0.0,
+    noise_level: 0.5,
+    noise_decay_secs: 0.08,
+    noise_filter: (BiquadKind::HighPass, 7000.0),
+};
+
+const OPEN_HAT: DrumPatch = DrumPatch {
+    noise_decay_secs: 0.6,
+    ..CLOSED_HAT
+};
+
+const PEDAL_HAT: DrumPatch = DrumPatch {
+    noise_decay_secs: 0.12,
+    noise_level: 0.35,
+    ..CLOSED_HAT
+};
+
+const CRASH: DrumPatch = DrumPatch {
+    start_hz: 0.0,
+    end_hz: 0.0,
+    sweep_secs: 0.0,
+    tone_level: 0.0,
+    tone_decay_secs: 0.0,
+    noise_level: 0.6,
+    noise_decay_secs: 2.0,
+    noise_filter: (BiquadKind::HighPass, 4000.0),
+};
+
+const RIDE: DrumPatch = DrumPatch {
+    start_hz: 3200.0,
+    end_hz: 3200.0,
+    sweep_secs: 0.0,
+    tone_level: 0.1,
+    tone_decay_secs: 1.0,
+    noise_level: 0.35,
+    noise_decay_secs: 1.2,
+    noise_filter: (BiquadKind::BandPass, 6000.0),
+};
+
+const COWBELL: DrumPatch = DrumPatch {
+    start_hz: 560.0,
+    end_hz: 560.0,
+    sweep_secs: 0.0,
+    tone_level: 0.6,
+    tone_decay_secs: 0.3,
+    noise_level: 0.0,
+    noise_decay_secs: 0.0,
+    noise_filter: (BiquadKind::BandPass, 1000.0),
+};
+
+const TAMBOURINE: DrumPatch = DrumPatch {
+    noise_decay_secs: 0.25,
+    noise_filter: (BiquadKind::BandPass, 8000.0),
+    ..CLOSED_HAT
+};
+
+/// Toms from low floor (41) to high (50) share a patch at different pitches.
+fn tom(key: u8) -> DrumPatch {
+    let hz = 80.0 * ((key as f32 - 41.0) / 12.0).exp2();
+
+    DrumPatch {
+        start_hz: 1.5 * hz,
+        end_hz: hz,
+        sweep_secs: 0.1,
+        tone_level: 0.9,
+        tone_decay_secs: 0.6,
+        noise_level: 0.1,
+        noise_decay_secs: 0.05,
+        noise_filter: (BiquadKind::LowPass, 2000.0),
+    }
+}
+
+/// Latin percussion and anything else without its own patch: a short pitched knock.
+fn knock(key: u8) -> DrumPatch {
+    let hz = 440.0 * ((key as f32 - 69.0) / 12.0).exp2();
+
+    DrumPatch {
+        start_hz: 1.2 * hz,
+        end_hz: hz,
+        sweep_secs: 0.01,
+        tone_level: 0.6,
+        tone_decay_secs: 0.2,
+        noise_level: 0.2,
+        noise_decay_secs: 0.05,
+        noise_filter: (BiquadKind::BandPass, 2.0 * hz),
+    }
+}
+
+fn patch_for_key(key: u8) -> DrumPatch {
+    match key {
+        35 | 36 => KICK,
+        37 => SIDE_STICK,
+        38 | 40 => SNARE,
+        39 => CLAP,
+        41 | 43 | 45 | 47 | 48 | 50 => tom(key),
+        42 => CLOSED_HAT,
+        44 => PEDAL_HAT,
+        46 => OPEN_HAT,
+        49 | 52 | 55 | 57 => CRASH,
+        51 | 53 | 59 => RIDE,
+        54 => TAMBOURINE,
+        56 => COWBELL,
+        _ => knock(key),
+    }
+}
+
+/// One drum hit. It plays out by itself, since General MIDI percussion ignores note offs.
+pub(crate) struct DrumVoice {
+    sample_period: f32,
+    phase: f32,
+    hz: f32,
+    end_hz: f32,
+    /// Per-sample factor that moves `hz` toward `end_hz`.
+    sweep_factor: f32,
+    tone_level: f32,
+    tone_decay: f32,
+    noise: WhiteNoise,
+    noise_filter: Biquad,
+    noise_filter_params: (BiquadKind, f32),
+    noise_level: f32,
+    noise_decay: f32,
+}
+
+/// Per-sample factor that decays by 60 dB over `secs`.
+fn decay_factor(secs: f32, sample_hz: f32) -> f32 {
+    if secs <= 0.0 {
+        0.0
+    } else {
+        10f32.powf(-3.0 / (secs * sample_hz))
+    }
+}
+
+impl DrumVoice {
+    pub(crate) fn new(key: u8, sample_hz: f32, seed: u32) -> Self {
+        let patch = patch_for_key(key);
+        let sweep_factor = if patch.sweep_secs > 0.0 && patch.start_hz > 0.0 {
+            (patch.end_hz / patch.start_hz).powf(1.0 / (patch.sweep_secs * sample_hz))
+        } else {
+            1.0
+        };
+        let (kind, filter_hz) = patch.noise_filter;
+
+        DrumVoice {
+            sample_period: 1.0 / sample_hz,
+            phase: 0.0,
+            hz: patch.start_hz,
+            end_hz: patch.end_hz,
+            sweep_factor,
+            tone_level: patch.tone_level,
+            tone_decay: decay_factor(patch.tone_decay_secs, sample_hz),
+            noise: WhiteNoise::new(seed),
+            noise_filter: Biquad::new(kind, sample_hz, filter_hz, NOISE_Q),
+            noise_filter_params: patch.noise_filter,
+            noise_level: patch.noise_level,
+            noise_decay: decay_factor(patch.noise_decay_secs, sample_hz),
+        }
+    }
+
+    /// Carries on the hit at a new output rate, at the same pitch and speed.
+    pub(crate) fn set_sample_hz(&mut self, sample_hz: f32) {
+        let old_over_new = self.sample_period * sample_hz;
+        self.sample_period = 1.0 / sample_hz;
+        self.sweep_factor = self.sweep_factor.powf(old_over_new);
+        self.tone_decay = self.tone_decay.powf(old_over_new);
+        self.noise_decay = self.noise_decay.powf(old_over_new);
+        let (kind, filter_hz) = self.noise_filter_params;
+        self.noise_filter
+            .set_coefficients(BiquadCoefficients::new(kind, sample_hz, filter_hz, NOISE_Q));
+    }
+
+    pub(crate) fn sample(&mut self) -> f32 {
+        let tone = self.tone_level * (2.0 * PI * self.phase).sin();
+        let noise = self.noise_level * self.noise_filter.apply(self.noise.sample());
+
+        self.phase = (self.phase + self.hz * self.sample_period).fract();
+        self.hz *= self.sweep_factor;
+        // Stop the sweep at the end pitch, whichever way it's going.
+        if (self.sweep_factor < 1.0 && self.hz < self.end_hz)
+            || (self.sweep_factor > 1.0 && self.hz > self.end_hz)
+        {
+            self.hz = self.end_hz;
+            self.sweep_factor = 1.0;
+        }
+        self.tone_level *= self.tone_decay;
+        self.noise_level *= self.noise_decay;
+
+        tone + noise
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.tone_level < SILENT_LEVEL && self.noise_level < SILENT_LEVEL
+    }
+}
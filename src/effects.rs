@@ -0,0 +1,523 @@
+//! Effects that process the synthesizer's output, frame by frame, before it reaches the audio
+//! device and recorder.
+
+use crate::{controls::SynthParameter, AudioFrame};
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// An audio effect on interleaved frames.
+pub trait Effect: Send {
+    /// Called before the first frame, with the format of the frames to come. Effects that depend on
+    /// time or stereo position set themselves up here.
+    fn prepare(&mut self, _sample_hz: f32, _num_channels: usize) {}
+
+    /// Called whenever the tempo of the MIDI clock the synth follows is measured anew, for effects
+    /// that keep time with it.
+    fn set_tempo(&mut self, _bpm: f32) {}
+
+    /// Called when a controller bound to `parameter` moves, with a value from 0.0 to 1.0. Each
+    /// effect takes the parameters that are its own.
+    fn set_parameter(&mut self, _parameter: SynthParameter, _value: f32) {}
+
+    fn process(&mut self, frame: &mut AudioFrame);
+}
+
+/// Effects applied one after the other, in the order they were added.
+#[derive(Default)]
+pub struct EffectsChain {
+    effects: Vec<Box<dyn Effect>>,
+}
+
+impl EffectsChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push<E: Effect + 'static>(&mut self, effect: E) {
+        self.effects.push(Box::new(effect));
+    }
+
+    /// Builder-style `push`.
+    pub fn with<E: Effect + 'static>(mut self, effect: E) -> Self {
+        self.push(effect);
+
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+}
+
+impl Effect for EffectsChain {
+    fn prepare(&mut self, sample_hz: f32, num_channels: usize) {
+        for effect in self.effects.iter_mut() {
+            effect.prepare(sample_hz, num_channels);
+        }
+    }
+
+    fn set_tempo(&mut self, bpm: f32) {
+        for effect in self.effects.iter_mut() {
+            effect.set_tempo(bpm);
+        }
+    }
+
+    fn set_parameter(&mut self, parameter: SynthParameter, value: f32) {
+        for effect in self.effects.iter_mut() {
+            effect.set_parameter(parameter, value);
+        }
+    }
+
+    fn process(&mut self, frame: &mut AudioFrame) {
+        for effect in self.effects.iter_mut() {
+            effect.process(frame);
+        }
+    }
+}
+
+/// Scales every sample by a fixed factor.
+pub struct Gain {
+    pub gain: f32,
+}
+
+impl Gain {
+    pub fn from_db(db: f32) -> Self {
+        Gain {
+            gain: 10.0f32.powf(db / 20.0),
+        }
+    }
+}
+
+impl Effect for Gain {
+    fn process(&mut self, frame: &mut AudioFrame) {
+        for s in frame.iter_mut() {
+            *s *= self.gain;
+        }
+    }
+}
+
+/// Wraps another effect so it can be switched out of the signal path without removing it from
+/// the chain.
+pub struct Bypass<E> {
+    pub effect: E,
+    pub bypassed: bool,
+}
+
+impl<E: Effect> Bypass<E> {
+    pub fn new(effect: E) -> Self {
+        Bypass {
+            effect,
+            bypassed: false,
+        }
+    }
+}
+
+impl<E: Effect> Effect for Bypass<E> {
+    fn prepare(&mut self, sample_hz: f32, num_channels: usize) {
+        // Prepare even while bypassed, so the effect is ready when it is switched back in.
+        self.effect.prepare(sample_hz, num_channels);
+    }
+
+    fn set_tempo(&mut self, bpm: f32) {
+        self.effect.set_tempo(bpm);
+    }
+
+    fn set_parameter(&mut self, parameter: SynthParameter, value: f32) {
+        self.effect.set_parameter(parameter, value);
+    }
+
+    fn process(&mut self, frame: &mut AudioFrame) {
+        if !self.bypassed {
+            self.effect.process(frame);
+        }
+    }
+}
+
+/// Thickens the sound by mixing in copies of it through short delay lines whose lengths are slowly
+/// modulated, so each copy drifts slightly in pitch against the original. The voices are spread
+/// around the LFO cycle, and each channel is a quarter cycle further along, which also widens a
+/// stereo image.
+pub struct Chorus {
+    /// LFO speed.
+    pub rate_hz: f32,
+    /// Beats per LFO cycle, which takes over from `rate_hz` once a MIDI clock gives the tempo.
+    pub sync_beats: Option<f32>,
+    /// Delay of each voice at the center of its sweep.
+    pub delay_ms: f32,
+    /// How far each voice's delay sweeps either side of `delay_ms`.
+    pub depth_ms: f32,
+    /// Wet level in [0.0, 1.0]. At 0.5 the dry signal and the voices are mixed equally.
+    pub mix: f32,
+    pub voices: usize,
+    sample_hz: f32,
+    num_channels: usize,
+    /// One ring buffer per channel.
+    lines: Vec<Vec<f32>>,
+    write_i: usize,
+    lfo_phase: f32,
+    tempo_bpm: Option<f32>,
+}
+
+impl Default for Chorus {
+    fn default() -> Self {
+        Chorus {
+            rate_hz: 0.8,
+            sync_beats: None,
+            delay_ms: 15.0,
+            depth_ms: 3.0,
+            mix: 0.5,
+            voices: 2,
+            sample_hz: 0.0,
+            num_channels: 0,
+            lines: Vec::new(),
+            write_i: 0,
+            lfo_phase: 0.0,
+            tempo_bpm: None,
+        }
+    }
+}
+
+impl Chorus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads `delay` samples back from the write position, interpolating between samples.
+    fn read_delayed(line: &[f32], write_i: usize, delay: f32) -> f32 {
+        let len = line.len();
+        let whole = delay as usize;
+        let t = delay - whole as f32;
+        let a = line[(write_i + len - whole) % len];
+        let b = line[(write_i + 2 * len - whole - 1) % len];
+
+        a + t * (b - a)
+    }
+
+    /// LFO cycles per second, following the clock if synced to it.
+    fn lfo_hz(&self) -> f32 {
+        match (self.sync_beats, self.tempo_bpm) {
+            (Some(beats), Some(bpm)) if beats > 0.0 => bpm / 60.0 / beats,
+            _ => self.rate_hz,
+        }
+    }
+}
+
+impl Effect for Chorus {
+    fn prepare(&mut self, sample_hz: f32, num_channels: usize) {
+        self.sample_hz = sample_hz;
+        self.num_channels = num_channels;
+        let max_delay = (self.delay_ms + self.depth_ms.abs()) * 0.001 * sample_hz;
+        // Room for the interpolation to read one past the longest delay.
+        let len = max_delay.ceil() as usize + 2;
+        self.lines = vec![vec![0.0; len]; num_channels];
+        self.write_i = 0;
+    }
+
+    fn set_tempo(&mut self, bpm: f32) {
+        self.tempo_bpm = Some(bpm);
+    }
+
+    fn set_parameter(&mut self, parameter: SynthParameter, value: f32) {
+        match parameter {
+            // 0.05 to 10 Hz, evenly in octaves.
+            SynthParameter::ChorusRate => self.rate_hz = 0.05 * 200.0f32.powf(value),
+            SynthParameter::ChorusMix => self.mix = value,
+            _ => (),
+        }
+    }
+
+    fn process(&mut self, frame: &mut AudioFrame) {
+        if self.lines.is_empty() || self.voices == 0 {
+            return;
+        }
+
+        let center = self.delay_ms * 0.001 * self.sample_hz;
+        let sweep = self.depth_ms * 0.001 * self.sample_hz;
+        let max_delay = (self.lines[0].len() - 2) as f32;
+        let voice_gain = 1.0 / self.voices as f32;
+        let lfo_step = self.lfo_hz() / self.sample_hz;
+        let mix = self.mix.clamp(0.0, 1.0);
+        for sample_frame in frame.chunks_exact_mut(self.num_channels) {
+            for (channel, s) in sample_frame.iter_mut().enumerate() {
+                let line = &mut self.lines[channel];
+                line[self.write_i] = *s;
+
+                let mut wet = 0.0;
+                for voice in 0..self.voices {
+                    let phase = self.lfo_phase + voice as f32 * voice_gain + channel as f32 * 0.25;
+                    let lfo = (2.0 * std::f32::consts::PI * phase).sin();
+                    let delay = (center + sweep * lfo).clamp(1.0, max_delay);
+                    wet += Self::read_delayed(line, self.write_i, delay);
+                }
+                *s = (1.0 - mix) * *s + mix * voice_gain * wet;
+            }
+            self.write_i = (self.write_i + 1) % self.lines[0].len();
+            self.lfo_phase = (self.lfo_phase + lfo_step).fract();
+        }
+    }
+}
+
+/// The transfer curve of a `Waveshaper`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShaperCurve {
+    /// Smooth saturation that approaches ±1.0.
+    Tanh,
+    /// Flat at ±1.0, for harsh, buzzy distortion.
+    HardClip,
+    /// Reflects the signal back down from ±1.0 instead of clipping it, adding bright, metallic
+    /// harmonics the harder it's driven.
+    Foldback,
+}
+
+impl ShaperCurve {
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "tanh" => Some(ShaperCurve::Tanh),
+            "hard-clip" | "clip" => Some(ShaperCurve::HardClip),
+            "foldback" | "fold" => Some(ShaperCurve::Foldback),
+            _ => None,
+        }
+    }
+
+    fn shape(self, x: f32) -> f32 {
+        match self {
+            ShaperCurve::Tanh => x.tanh(),
+            ShaperCurve::HardClip => x.clamp(-1.0, 1.0),
+            ShaperCurve::Foldback => {
+                // A triangle wave of x with period 4, which is the identity on [-1.0, 1.0].
+                let folded = (x - 1.0).rem_euclid(4.0);
+
+                (folded - 2.0).abs() - 1.0
+            }
+        }
+    }
+}
+
+/// Distortion: each sample is amplified by `drive`, bent by the curve, then scaled by `output_gain`
+/// to bring the level back down.
+pub struct Waveshaper {
+    pub curve: ShaperCurve,
+    pub drive: f32,
+    pub output_gain: f32,
+}
+
+impl Waveshaper {
+    pub fn new(curve: ShaperCurve) -> Self {
+        Waveshaper {
+            curve,
+            drive: 1.0,
+            output_gain: 1.0,
+        }
+    }
+}
+
+impl Effect for Waveshaper {
+    fn process(&mut self, frame: &mut AudioFrame) {
+        for s in frame.iter_mut() {
+            *s = self.output_gain * self.curve.shape(self.drive * *s);
+        }
+    }
+}
+
+/// Keeps the output under a ceiling without clipping it. The input is delayed by the lookahead, so
+/// the gain can come down smoothly before a peak arrives instead of after. All channels share one
+/// gain, so the stereo image doesn't shift.
+pub struct Limiter {
+    /// The loudest the output can get, in dBFS.
+    pub ceiling_db: f32,
+    pub lookahead_ms: f32,
+    /// How quickly the gain recovers after a peak has passed.
+    pub release_ms: f32,
+    num_channels: usize,
+    lookahead_frames: usize,
+    /// Interleaved ring buffer of the last `lookahead_frames` sample frames.
+    delay: Vec<f32>,
+    delay_i: usize,
+    /// Candidates for the lowest required gain in the lookahead window, as (position, gain). Gains
+    /// increase from front to back.
+    window: VecDeque<(u64, f32)>,
+    position: u64,
+    gain: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Limiter {
+            ceiling_db: -0.3,
+            lookahead_ms: 5.0,
+            release_ms: 100.0,
+            num_channels: 0,
+            lookahead_frames: 0,
+            delay: Vec::new(),
+            delay_i: 0,
+            window: VecDeque::new(),
+            position: 0,
+            gain: 1.0,
+            attack_coeff: 0.0,
+            release_coeff: 0.0,
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The channel count from the last `prepare`, or 0 if it hasn't been prepared yet.
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    /// Like `process`, for any run of interleaved samples, which needn't be a whole frame.
+    pub fn process_samples(&mut self, frame: &mut [f32]) {
+        if self.delay.is_empty() {
+            return;
+        }
+
+        let ceiling = 10.0f32.powf(self.ceiling_db / 20.0);
+        for sample_frame in frame.chunks_exact_mut(self.num_channels) {
+            let peak = sample_frame.iter().fold(0.0f32, |p, s| p.max(s.abs()));
+            let required = if peak > ceiling { ceiling / peak } else { 1.0 };
+
+            // Sliding minimum over the lookahead window.
+            while self.window.back().is_some_and(|&(_, g)| g >= required) {
+                self.window.pop_back();
+            }
+            self.window.push_back((self.position, required));
+            while self
+                .window
+                .front()
+                .is_some_and(|&(p, _)| p + (self.lookahead_frames as u64) < self.position)
+            {
+                self.window.pop_front();
+            }
+            let target = self.window.front().map_or(1.0, |&(_, g)| g);
+
+            let coeff = if target < self.gain {
+                self.attack_coeff
+            } else {
+                self.release_coeff
+            };
+            self.gain = target + coeff * (self.gain - target);
+
+            let base = self.delay_i * self.num_channels;
+            for (s, delayed) in sample_frame
+                .iter_mut()
+                .zip(self.delay[base..base + self.num_channels].iter_mut())
+            {
+                let out = std::mem::replace(delayed, *s);
+                // The attack only gets most of the way down, so catch what's left.
+                *s = (self.gain * out).clamp(-ceiling, ceiling);
+            }
+            self.delay_i = (self.delay_i + 1) % self.lookahead_frames;
+            self.position += 1;
+        }
+    }
+}
+
+impl Effect for Limiter {
+    fn prepare(&mut self, sample_hz: f32, num_channels: usize) {
+        self.num_channels = num_channels;
+        self.lookahead_frames = ((self.lookahead_ms * 0.001 * sample_hz) as usize).max(1);
+        self.delay = vec![0.0; self.lookahead_frames * num_channels];
+        self.delay_i = 0;
+        self.window = VecDeque::with_capacity(self.lookahead_frames + 1);
+        self.position = 0;
+        self.gain = 1.0;
+        // Most of the way down by the time the peak comes out of the delay line.
+        self.attack_coeff = (-4.0 / self.lookahead_frames as f32).exp();
+        self.release_coeff = (-1.0 / (self.release_ms * 0.001 * sample_hz).max(1.0)).exp();
+    }
+
+    fn process(&mut self, frame: &mut AudioFrame) {
+        self.process_samples(frame);
+    }
+}
+
+/// Turns down loud passages. Above the threshold, every `ratio` dB of input only raises the output
+/// by 1 dB. The level is detected on the loudest channel, so all channels are turned down together.
+pub struct Compressor {
+    pub threshold_db: f32,
+    pub ratio: f32,
+    /// How quickly the gain comes down once the level is over the threshold.
+    pub attack_ms: f32,
+    /// How quickly the gain recovers once the level drops.
+    pub release_ms: f32,
+    /// Gain applied after compression, to make up for the lost level.
+    pub makeup_db: f32,
+    num_channels: usize,
+    attack_coeff: f32,
+    release_coeff: f32,
+    /// Current gain reduction in dB, as a positive number.
+    reduction_db: f32,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Compressor {
+            threshold_db: -18.0,
+            ratio: 4.0,
+            attack_ms: 10.0,
+            release_ms: 150.0,
+            makeup_db: 6.0,
+            num_channels: 0,
+            attack_coeff: 0.0,
+            release_coeff: 0.0,
+            reduction_db: 0.0,
+        }
+    }
+}
+
+impl Compressor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gain reduction in dB, as a positive number, for an input level in dBFS.
+    fn static_reduction_db(&self, level_db: f32) -> f32 {
+        let over = level_db - self.threshold_db;
+        if over <= 0.0 {
+            return 0.0;
+        }
+
+        over * (1.0 - 1.0 / self.ratio.max(1.0))
+    }
+}
+
+impl Effect for Compressor {
+    fn prepare(&mut self, sample_hz: f32, num_channels: usize) {
+        self.num_channels = num_channels;
+        self.attack_coeff = (-1.0 / (self.attack_ms * 0.001 * sample_hz).max(1.0)).exp();
+        self.release_coeff = (-1.0 / (self.release_ms * 0.001 * sample_hz).max(1.0)).exp();
+        self.reduction_db = 0.0;
+    }
+
+    fn process(&mut self, frame: &mut AudioFrame) {
+        if self.num_channels == 0 {
+            return;
+        }
+
+        for sample_frame in frame.chunks_exact_mut(self.num_channels) {
+            let peak = sample_frame.iter().fold(0.0f32, |p, s| p.max(s.abs()));
+            // Anything below -120 dBFS is silence as far as the detector is concerned.
+            let level_db = 20.0 * peak.max(1e-6).log10();
+            let target = self.static_reduction_db(level_db);
+            let coeff = if target > self.reduction_db {
+                self.attack_coeff
+            } else {
+                self.release_coeff
+            };
+            self.reduction_db = target + coeff * (self.reduction_db - target);
+
+            let gain = 10.0f32.powf((self.makeup_db - self.reduction_db) / 20.0);
+            for s in sample_frame.iter_mut() {
+                *s *= gain;
+            }
+        }
+    }
+}
@@ -0,0 +1,476 @@
+use crate::{
+    audio_device::OutputDevice,
+    cancel::CancellationToken,
+    controls::ControlBindings,
+    effects::EffectsChain,
+    error::{NocturneError, Result},
+    instrument::{append_to_journal, play_midi_mix, start_journal, MixTrack},
+    midi::{
+        single_timeline_of_events, MidiBytes, MidiInputDeviceStream, MidiMessageBytes,
+        MidiOutputDevice, RawMidiMessage, TempoMap,
+    },
+    midi_filter::{MidiFilter, MidiFilterChain},
+    oscillator::Source,
+    recording::RecordingTarget,
+    routing::{Route, RouteTarget, Router},
+    synthesizer::{NoteEvent, VoiceLimits},
+    wave_table::triangle_wave,
+    CHANNEL_MAX_BUFFER,
+};
+
+use futures::stream::{self, StreamExt};
+use log::warn;
+use std::time::{Duration, Instant};
+use time_calc::Bpm;
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+    task::{self, JoinHandle},
+    time::delay_until,
+};
+
+/// Note events are dropped for receivers that fall further behind than this.
+const NOTE_EVENT_BUFFER: usize = 256;
+
+/// Sets up an `Engine`. Everything has a default, so `Engine::builder().build()` plays a triangle
+/// wave on the configured output with no MIDI device.
+pub struct EngineBuilder {
+    output: OutputDevice,
+    instrument: Source,
+    effects: EffectsChain,
+    controls: Option<ControlBindings>,
+    layers: Vec<(Source, EffectsChain)>,
+    midi_input_ports: Vec<MidiInputPort>,
+    midi_output_ports: Vec<usize>,
+    routes: Vec<Route>,
+    midi_filters: MidiFilterChain,
+    recordings: Vec<RecordingTarget>,
+}
+
+impl EngineBuilder {
+    /// Play on the system's default output device, instead of the one in the config file.
+    pub fn output_default(mut self) -> Self {
+        self.output = OutputDevice::Default;
+        self
+    }
+
+    /// Play on the device with this name or position in `list_audio_output_devices`.
+    pub fn output_device(mut self, output: OutputDevice) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// A wave, noise, soundfont, key map or patch. Soundfonts and patch banks follow each
+    /// channel's program changes.
+    pub fn instrument(mut self, source: impl Into<Source>) -> Self {
+        self.instrument = source.into();
+        self
+    }
+
+    /// Applied to the synth's output before it is played or recorded.
+    pub fn effects(mut self, effects: EffectsChain) -> Self {
+        self.effects = effects;
+        self
+    }
+
+    /// The controllers that move the instrument's parameters, instead of the ones saved in its
+    /// patch. See `Engine::controls`.
+    pub fn controls(mut self, controls: ControlBindings) -> Self {
+        self.controls = Some(controls);
+        self
+    }
+
+    /// Another synth, with effects of its own, playing alongside the instrument. The instrument is
+    /// synth 0 in routes, and layers are numbered from 1 in the order they are added.
+    pub fn layer(mut self, source: impl Into<Source>, effects: EffectsChain) -> Self {
+        self.layers.push((source.into(), effects));
+        self
+    }
+
+    /// Also play, and journal, what comes in on this MIDI input port. See `list_midi_input_ports`.
+    /// Can be given any number of times, and the inputs are numbered from 0 in routes in the order
+    /// they are added.
+    pub fn midi_input(mut self, port: usize) -> Self {
+        self.midi_input_ports.push(MidiInputPort::Number(port));
+        self
+    }
+
+    /// Like `midi_input`, for the port with `name` in its name. See
+    /// `MidiInputDeviceStream::connect_by_name`.
+    pub fn midi_input_by_name(mut self, name: &str) -> Self {
+        self.midi_input_ports
+            .push(MidiInputPort::Name(name.to_string()));
+        self
+    }
+
+    /// Pass MIDI input on to this output port. See `list_midi_output_ports`. Can be given any
+    /// number of times, and the outputs are numbered from 0 in routes in the order they are added.
+    pub fn midi_output(mut self, port: usize) -> Self {
+        self.midi_output_ports.push(port);
+        self
+    }
+
+    /// Filter what comes in on the MIDI inputs, after any filters added before, and before it is
+    /// journaled or routed anywhere.
+    pub fn midi_filter<F: MidiFilter + 'static>(mut self, filter: F) -> Self {
+        self.midi_filters.push(filter);
+        self
+    }
+
+    /// Send the messages of a MIDI input to a synth or MIDI output. Without any routes, every input
+    /// plays every synth and goes out on every output.
+    pub fn route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Record the output. Can be given any number of times.
+    pub fn record(mut self, target: RecordingTarget) -> Self {
+        self.recordings.push(target);
+        self
+    }
+
+    /// Connects the MIDI inputs and outputs, and checks the routes and that there is an output to
+    /// play on. Nothing plays until `Engine::start`.
+    pub fn build(self) -> Result<Engine> {
+        if !self.output.is_available() {
+            return Err(NocturneError::NoAudioOutput(self.output));
+        }
+        let num_synths = self.layers.len() + 1;
+        let num_inputs = self.midi_input_ports.len();
+        let num_outputs = self.midi_output_ports.len();
+        let routes = if self.routes.is_empty() {
+            (0..num_inputs)
+                .flat_map(|input| {
+                    let synths = (0..num_synths).map(RouteTarget::Synth);
+                    let outputs = (0..num_outputs).map(RouteTarget::Output);
+
+                    synths
+                        .chain(outputs)
+                        .map(move |target| Route::new(input, target))
+                })
+                .collect()
+        } else {
+            for route in self.routes.iter() {
+                route.check(num_inputs, num_synths, num_outputs)?;
+            }
+            self.routes
+        };
+        let midi_inputs = self
+            .midi_input_ports
+            .iter()
+            .map(|port| match port {
+                MidiInputPort::Number(port) => MidiInputDeviceStream::connect(*port),
+                MidiInputPort::Name(name) => MidiInputDeviceStream::connect_by_name(name),
+            })
+            .collect::<Result<_>>()?;
+        let midi_outputs = self
+            .midi_output_ports
+            .iter()
+            .map(|&port| MidiOutputDevice::connect(port))
+            .collect::<Result<_>>()?;
+        let (synth_txs, synth_rxs) = (0..num_synths)
+            .map(|_| mpsc::channel(CHANNEL_MAX_BUFFER))
+            .unzip();
+        let (note_event_tx, _) = broadcast::channel(NOTE_EVENT_BUFFER);
+        let instrument = self.instrument;
+        let controls = std::iter::once(
+            self.controls
+                .unwrap_or_else(|| ControlBindings::of_source(instrument)),
+        )
+        .chain(
+            self.layers
+                .iter()
+                .map(|&(source, _)| ControlBindings::of_source(source)),
+        )
+        .collect();
+
+        Ok(Engine {
+            instrument,
+            controls,
+            setup: Some(EngineSetup {
+                output: self.output,
+                effects: self.effects,
+                layers: self.layers,
+                recordings: self.recordings,
+                midi_inputs,
+                midi_outputs,
+                routes,
+                midi_filters: self.midi_filters,
+                synth_rxs,
+            }),
+            synth_txs,
+            note_event_tx,
+            cancel: CancellationToken::new(),
+            synth_task: None,
+        })
+    }
+}
+
+/// A MIDI input to connect when the engine is built.
+enum MidiInputPort {
+    Number(usize),
+    Name(String),
+}
+
+/// What the synth takes over when the engine starts.
+struct EngineSetup {
+    output: OutputDevice,
+    effects: EffectsChain,
+    layers: Vec<(Source, EffectsChain)>,
+    recordings: Vec<RecordingTarget>,
+    midi_inputs: Vec<MidiInputDeviceStream>,
+    midi_outputs: Vec<MidiOutputDevice>,
+    routes: Vec<Route>,
+    midi_filters: MidiFilterChain,
+    /// What each synth plays, the instrument first.
+    synth_rxs: Vec<mpsc::Receiver<RawMidiMessage>>,
+}
+
+/// A synth with its effects, output device, recordings and MIDI inputs and outputs, managed as one
+/// object.
+///
+/// An engine is built, started, and then shut down once. Messages from `send` and `play_file` go to
+/// the instrument, messages from `send_to` go to any synth, and the MIDI inputs go wherever their
+/// routes take them. Every synth is mixed into the same recordings.
+pub struct Engine {
+    instrument: Source,
+    /// For each synth, the instrument first.
+    controls: Vec<ControlBindings>,
+    /// Until the engine starts.
+    setup: Option<EngineSetup>,
+    synth_txs: Vec<mpsc::Sender<RawMidiMessage>>,
+    note_event_tx: broadcast::Sender<NoteEvent>,
+    cancel: CancellationToken,
+    synth_task: Option<JoinHandle<Result<()>>>,
+}
+
+impl Engine {
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder {
+            output: OutputDevice::Configured,
+            instrument: triangle_wave().into(),
+            effects: EffectsChain::new(),
+            controls: None,
+            layers: Vec::new(),
+            midi_input_ports: Vec::new(),
+            midi_output_ports: Vec::new(),
+            routes: Vec::new(),
+            midi_filters: MidiFilterChain::new(),
+            recordings: Vec::new(),
+        }
+    }
+
+    /// Opens the output device and starts the synth and recordings. Must be called from within a
+    /// tokio runtime. Does nothing if the engine has already started.
+    pub fn start(&mut self) {
+        let setup = match self.setup.take() {
+            Some(setup) => setup,
+            None => {
+                warn!("The engine has already started");
+                return;
+            }
+        };
+        let EngineSetup {
+            output,
+            effects,
+            layers,
+            recordings,
+            midi_inputs,
+            midi_outputs,
+            routes,
+            midi_filters,
+            synth_rxs,
+        } = setup;
+        let sources_and_effects = std::iter::once((self.instrument, effects)).chain(layers);
+        let tracks: Vec<_> = sources_and_effects
+            .zip(synth_rxs)
+            .zip(self.controls.iter())
+            .enumerate()
+            .map(|(synth, (((source, effects), rx), controls))| MixTrack {
+                input: rx,
+                source,
+                effects,
+                recordings: Vec::new(),
+                voice_limits: VoiceLimits::default(),
+                seed: synth as u64,
+                controls: controls.clone(),
+            })
+            .collect();
+        let routing = if midi_inputs.is_empty() {
+            None
+        } else {
+            let router = Router::new(routes, self.synth_txs.clone(), midi_outputs);
+            Some(task::spawn(route_midi_inputs(
+                midi_inputs,
+                midi_filters,
+                router,
+                self.cancel.clone(),
+            )))
+        };
+
+        let note_event_tx = self.note_event_tx.clone();
+        let cancel = self.cancel.clone();
+        self.synth_task = Some(task::spawn(async move {
+            let result = play_midi_mix(
+                tracks,
+                recordings,
+                Some(note_event_tx),
+                output,
+                cancel.clone(),
+            )
+            .await;
+            // So the engine stops taking messages if the output failed.
+            cancel.cancel();
+            if let Some(routing) = routing {
+                routing.await.expect("Failed to join on the MIDI routing");
+            }
+
+            result
+        }));
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.synth_task.is_some() && !self.cancel.is_cancelled()
+    }
+
+    /// The instrument and the layers.
+    pub fn num_synths(&self) -> usize {
+        self.synth_txs.len()
+    }
+
+    /// Plays a message on the instrument, as if it came from a MIDI input. Dropped if the engine
+    /// isn't running.
+    pub async fn send(&self, message: RawMidiMessage) {
+        self.send_to(0, message).await;
+    }
+
+    /// Like `send`, for a synth numbered as in routes, with the instrument as 0 and the layers from
+    /// 1. Dropped if there is no such synth.
+    pub async fn send_to(&self, synth: usize, message: RawMidiMessage) {
+        if !self.is_running() {
+            return;
+        }
+        if let Some(synth_tx) = self.synth_txs.get(synth) {
+            // Only fails once the synth has stopped.
+            let _ = synth_tx.clone().send(message).await;
+        }
+    }
+
+    /// The controllers that move the instrument's parameters, which can be learned or bound while
+    /// it plays, and the parameters themselves.
+    pub fn controls(&self) -> &ControlBindings {
+        &self.controls[0]
+    }
+
+    /// Like `controls`, for a synth numbered as in `send_to`.
+    pub fn synth_controls(&self, synth: usize) -> Option<&ControlBindings> {
+        self.controls.get(synth)
+    }
+
+    /// When each note starts and ends, from every source of messages.
+    pub fn note_events(&self) -> broadcast::Receiver<NoteEvent> {
+        self.note_event_tx.subscribe()
+    }
+
+    /// Plays every track of the file on the engine's synth, at `bpm` until the file's first tempo
+    /// change, alongside anything else it is playing. Returns at the end of the file, or once the
+    /// engine stops.
+    pub async fn play_file(&self, midi_bytes: &MidiBytes, bpm: Bpm) {
+        if !self.is_running() {
+            warn!("Start the engine before playing a file");
+            return;
+        }
+        let messages: Vec<(Duration, MidiMessageBytes)> = {
+            let smf = midi_bytes.parse();
+            let tempo_map = match TempoMap::new(&smf, bpm) {
+                Ok(tempo_map) => tempo_map,
+                Err(e) => {
+                    warn!("Can't play the file: {}", e);
+                    return;
+                }
+            };
+            single_timeline_of_events(&smf)
+                .into_iter()
+                .map(|(t, _, event)| {
+                    (
+                        tempo_map.time_at(t),
+                        MidiMessageBytes::from_event(&event.kind),
+                    )
+                })
+                .collect()
+        };
+
+        let mut message_tx = self.synth_txs[0].clone();
+        let start = Instant::now();
+        for (time, message) in messages {
+            select! {
+                _ = delay_until((start + time).into()) => (),
+                _ = self.cancel.cancelled() => return,
+            }
+            if message_tx
+                .send((time.as_micros() as u64, message))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Stops the synth, `play_file` and the recordings. They wind down in the background; use
+    /// `shutdown` to wait for them.
+    pub fn stop(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Stops everything and waits until the recordings are finished and the devices are closed.
+    /// Fails if the output or a recording failed at any point while the engine was running.
+    pub async fn shutdown(mut self) -> Result<()> {
+        self.stop();
+        match self.synth_task.take() {
+            Some(synth_task) => synth_task
+                .await
+                .expect("Failed to join on the engine's synth"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Filters and journals what comes in on the MIDI inputs and sends it along the routes, until
+/// every input has closed or the engine stops. The connections stay open until then.
+async fn route_midi_inputs(
+    inputs: Vec<MidiInputDeviceStream>,
+    mut filters: MidiFilterChain,
+    mut router: Router,
+    cancel: CancellationToken,
+) {
+    let mut connections = Vec::with_capacity(inputs.len());
+    let mut streams = Vec::with_capacity(inputs.len());
+    for (i, input) in inputs.into_iter().enumerate() {
+        connections.push(input.connection);
+        streams.push(input.message_rx.map(move |message| (i, message)));
+    }
+    let mut messages = stream::select_all(streams);
+    let mut journal = start_journal();
+    loop {
+        let (input, message) = select! {
+            next = messages.next() => match next {
+                Some(next) => next,
+                None => break,
+            },
+            _ = cancel.cancelled() => break,
+        };
+        let message = match filters.filter(message) {
+            Some(message) => message,
+            None => continue,
+        };
+        append_to_journal(&mut journal, &message);
+        router.route(input, &message).await;
+    }
+
+    for connection in connections {
+        connection.close();
+    }
+    router.close();
+}
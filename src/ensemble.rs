@@ -1,42 +1,447 @@
 use crate::{
-    instrument::play_midi,
-    midi::{quantize_midi_tracks, MidiBytes},
-    wave_table::Wave,
+    audio_device::OutputDevice,
+    cancel::CancellationToken,
+    controls::ControlBindings,
+    effects::EffectsChain,
+    error::Result,
+    instrument::{play_midi_mix, MixTrack},
+    midi::{
+        chase_mtc_midi_tracks, follow_midi_clock_midi_tracks, polyphony_stats,
+        quantize_midi_tracks_with_transport, MidiBytes, RawMidiMessage, SequencerOptions,
+        TransportCommand,
+    },
+    naming::recording_base_path,
+    oscillator::Source,
+    recording::RecordingTarget,
+    rng::derive_seed,
+    synthesizer::VoiceLimits,
     CHANNEL_MAX_BUFFER,
 };
 
 use futures::future::join_all;
-use log::{debug, info};
+use log::{debug, info, warn};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 use time_calc::Bpm;
-use tokio::{sync::mpsc, task};
+use tokio::{
+    select,
+    stream::Stream,
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+
+/// Most messages held back for a track whose instrument has fallen behind. Past this, the oldest
+/// messages are dropped, except for note offs, which would leave notes stuck on.
+const TRACK_BACKLOG_MAX: usize = 4096;
+
+/// Plays every track of the file on its own instrument, until the file ends or `cancel` is
+/// cancelled.
+pub async fn play_all_midi_tracks(
+    midi_bytes: MidiBytes,
+    bpm: Bpm,
+    track_instruments: &[Source],
+    cancel: CancellationToken,
+) -> Result<()> {
+    play_all_midi_tracks_with_effects(
+        midi_bytes,
+        bpm,
+        track_instruments,
+        |_| EffectsChain::default(),
+        cancel,
+    )
+    .await
+}
+
+/// Like `play_all_midi_tracks`, but each track's instrument plays through the effects chain that
+/// `track_effects` returns for that track's index.
+pub async fn play_all_midi_tracks_with_effects<F>(
+    midi_bytes: MidiBytes,
+    bpm: Bpm,
+    track_instruments: &[Source],
+    track_effects: F,
+    cancel: CancellationToken,
+) -> Result<()>
+where
+    F: Fn(usize) -> EffectsChain,
+{
+    play_all_midi_tracks_with_options(
+        midi_bytes,
+        bpm,
+        track_instruments,
+        track_effects,
+        EnsembleOptions::default(),
+        cancel,
+    )
+    .await
+}
+
+/// What else to do while playing every track of a file.
+#[derive(Clone, Debug, Default)]
+pub struct EnsembleOptions {
+    /// Recordings of the mix of every track.
+    pub recordings: Vec<RecordingTarget>,
+    /// Records each track that plays any notes alone, after its effects, to a file named after
+    /// this target's path with the track number added, like `out.track03.wav`.
+    pub stems: Option<RecordingTarget>,
+    /// Track `i` plays within `voice_limits[i]`. Tracks past the end are unlimited.
+    pub voice_limits: Vec<VoiceLimits>,
+    /// Seeds everything random in the tracks' synthesizers, like noise and drums. Playing the
+    /// same file with the same seed makes the same sound.
+    pub seed: u64,
+    /// Where to play.
+    pub output_device: OutputDevice,
+    /// The playback speed, whether to follow the file's tempo changes, and what to loop.
+    pub sequencer: SequencerOptions,
+}
+
+/// Like `play_all_midi_tracks_with_effects`, with recordings and voice limits from `options`.
+///
+/// Fails like `play_midi`, in which case playback stops.
+pub async fn play_all_midi_tracks_with_options<F>(
+    midi_bytes: MidiBytes,
+    bpm: Bpm,
+    track_instruments: &[Source],
+    track_effects: F,
+    options: EnsembleOptions,
+    cancel: CancellationToken,
+) -> Result<()>
+where
+    F: Fn(usize) -> EffectsChain,
+{
+    // Nothing is ever sent, so playback goes on as the options have it.
+    let (_, transport) = mpsc::channel(1);
+    play_all_midi_tracks_with_transport(
+        midi_bytes,
+        bpm,
+        track_instruments,
+        track_effects,
+        options,
+        transport,
+        cancel,
+    )
+    .await
+}
+
+/// Like `play_all_midi_tracks_with_options`, and the commands on `transport` change the playback
+/// while it plays.
+pub async fn play_all_midi_tracks_with_transport<F>(
+    midi_bytes: MidiBytes,
+    bpm: Bpm,
+    track_instruments: &[Source],
+    track_effects: F,
+    options: EnsembleOptions,
+    transport: mpsc::Receiver<TransportCommand>,
+    cancel: CancellationToken,
+) -> Result<()>
+where
+    F: Fn(usize) -> EffectsChain,
+{
+    let sequencer_options = options.sequencer.clone();
+    let session = CancellationToken::new();
+    let TrackInstruments {
+        mut handles,
+        mix,
+        track_message_txs,
+    } = spawn_track_instruments(
+        &midi_bytes,
+        track_instruments,
+        track_effects,
+        options,
+        &session,
+    );
+
+    // One task produces the MIDI input streams for all tracks.
+    let sequencer_session = session.clone();
+    handles.push(task::spawn(async move {
+        quantize_midi_tracks_with_transport(
+            midi_bytes,
+            bpm,
+            sequencer_options,
+            track_message_txs,
+            transport,
+            sequencer_session,
+        )
+        .await;
+    }));
+
+    finish_session(handles, mix, session, cancel).await
+}
+
+/// Like `play_all_midi_tracks`, but the timeline follows the MIDI Time Code quarter frames on
+/// `mtc_stream` instead of the wall clock, so playback starts, stops and relocates with an external
+/// transport.
+pub async fn play_all_midi_tracks_chasing_mtc<S>(
+    midi_bytes: MidiBytes,
+    bpm: Bpm,
+    track_instruments: &[Source],
+    mtc_stream: S,
+    cancel: CancellationToken,
+) -> Result<()>
+where
+    S: Stream<Item = RawMidiMessage> + Send + Unpin + 'static,
+{
+    let session = CancellationToken::new();
+    let TrackInstruments {
+        mut handles,
+        mix,
+        track_message_txs,
+    } = spawn_track_instruments(
+        &midi_bytes,
+        track_instruments,
+        |_| EffectsChain::default(),
+        EnsembleOptions::default(),
+        &session,
+    );
+
+    let sequencer_session = session.clone();
+    handles.push(task::spawn(async move {
+        chase_mtc_midi_tracks(
+            midi_bytes,
+            bpm,
+            mtc_stream,
+            track_message_txs,
+            sequencer_session,
+        )
+        .await;
+    }));
+
+    finish_session(handles, mix, session, cancel).await
+}
+
+/// Like `play_all_midi_tracks_with_effects`, but the file keeps time with the MIDI clock on
+/// `clock_stream` instead of its own tempo, so playback starts, stops, relocates and changes tempo
+/// along with an external sequencer or drum machine. Effects that keep time follow the clock too.
+pub async fn play_all_midi_tracks_following_midi_clock<S, F>(
+    midi_bytes: MidiBytes,
+    track_instruments: &[Source],
+    track_effects: F,
+    clock_stream: S,
+    cancel: CancellationToken,
+) -> Result<()>
+where
+    S: Stream<Item = RawMidiMessage> + Send + Unpin + 'static,
+    F: Fn(usize) -> EffectsChain,
+{
+    let session = CancellationToken::new();
+    let TrackInstruments {
+        mut handles,
+        mix,
+        track_message_txs,
+    } = spawn_track_instruments(
+        &midi_bytes,
+        track_instruments,
+        track_effects,
+        EnsembleOptions::default(),
+        &session,
+    );
+
+    let sequencer_session = session.clone();
+    handles.push(task::spawn(async move {
+        follow_midi_clock_midi_tracks(
+            midi_bytes,
+            clock_stream,
+            track_message_txs,
+            sequencer_session,
+        )
+        .await;
+    }));
 
-pub async fn play_all_midi_tracks(midi_bytes: MidiBytes, bpm: Bpm, track_instruments: &[Wave]) {
+    finish_session(handles, mix, session, cancel).await
+}
+
+/// Waits for the mix and the tasks that feed it. The session is cancelled when the caller cancels,
+/// and also when the mix stops, so the sequencer doesn't play on after the output fails.
+async fn finish_session(
+    handles: Vec<JoinHandle<()>>,
+    mix: JoinHandle<Result<()>>,
+    session: CancellationToken,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let stop_session = {
+        let session = session.clone();
+        async move {
+            select! {
+                _ = cancel.cancelled() => (),
+                _ = session.cancelled() => (),
+            }
+            session.cancel();
+        }
+    };
+    let mix = async move {
+        let result = mix.await.expect("Failed to join on the mix");
+        session.cancel();
+
+        result
+    };
+
+    let (result, _) = futures::join!(mix, stop_session);
+    join_all(handles).await;
+
+    result
+}
+
+/// The tasks that play a file's tracks, and where to send each track's messages.
+struct TrackInstruments {
+    /// Relays between the sequencer and the instruments.
+    handles: Vec<JoinHandle<()>>,
+    mix: JoinHandle<Result<()>>,
+    track_message_txs: Vec<mpsc::Sender<RawMidiMessage>>,
+}
+
+/// Each track plays an instrument of its own. The instruments are mixed in one task, which is the
+/// only one that talks to the audio device.
+fn spawn_track_instruments<F>(
+    midi_bytes: &MidiBytes,
+    track_instruments: &[Source],
+    track_effects: F,
+    options: EnsembleOptions,
+    cancel: &CancellationToken,
+) -> TrackInstruments
+where
+    F: Fn(usize) -> EffectsChain,
+{
+    let EnsembleOptions {
+        recordings,
+        stems,
+        voice_limits,
+        seed,
+        output_device,
+        // Only the sequencer needs it.
+        sequencer: _,
+    } = options;
     let smf = midi_bytes.parse();
+    // Stems recorded into a directory share one timestamped name.
+    let stems = stems.map(|stem| RecordingTarget {
+        path: recording_base_path(&stem.path),
+        options: stem.options,
+    });
+    let track_polyphony = match stems {
+        Some(_) => polyphony_stats(midi_bytes).per_track,
+        None => Vec::new(),
+    };
 
     let mut handles = Vec::with_capacity(smf.tracks.len() + 1);
-
-    // Each track plays an instrument which runs in its own task.
     let mut track_message_txs = Vec::with_capacity(smf.tracks.len());
+    let mut tracks = Vec::with_capacity(smf.tracks.len());
     for (track_i, track) in smf.tracks.iter().enumerate() {
+        let (sequencer_tx, sequencer_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
         let (message_tx, message_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
         let instrument_i = track_i % track_instruments.len();
         info!(
             "Starting track {} with instrument {}",
             track_i, instrument_i
         );
-        let wave = track_instruments[instrument_i];
-        handles.push(task::spawn(async move {
-            play_midi(message_rx, wave, None).await;
-        }));
-        track_message_txs.push(message_tx);
+        let stem = stems
+            .as_ref()
+            .filter(|_| track_polyphony[track_i] > 0)
+            .map(|stem| RecordingTarget {
+                path: stem_path(&stem.path, track_i),
+                options: stem.options.clone(),
+            });
+        tracks.push(MixTrack {
+            input: message_rx,
+            source: track_instruments[instrument_i],
+            effects: track_effects(track_i),
+            recordings: stem.into_iter().collect(),
+            voice_limits: voice_limits.get(track_i).copied().unwrap_or_default(),
+            seed: derive_seed(seed, track_i as u64),
+            controls: ControlBindings::of_source(track_instruments[instrument_i]),
+        });
+        handles.push(task::spawn(relay_track_messages(
+            track_i,
+            sequencer_rx,
+            message_tx,
+        )));
+        track_message_txs.push(sequencer_tx);
 
         debug!("Track {} has {} events", track_i, track.len());
     }
+    let cancel = cancel.clone();
+    let mix =
+        task::spawn(
+            async move { play_midi_mix(tracks, recordings, None, output_device, cancel).await },
+        );
 
-    // One task produces the MIDI input streams for all tracks.
-    handles.push(task::spawn(async move {
-        quantize_midi_tracks(midi_bytes, bpm, track_message_txs).await;
-    }));
+    TrackInstruments {
+        handles,
+        mix,
+        track_message_txs,
+    }
+}
 
-    join_all(handles).await;
+/// `out.wav` becomes `out.track03.wav` for track 3.
+fn stem_path(path: &Path, track_i: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!(
+            "{}.track{:02}.{}",
+            stem,
+            track_i,
+            extension.to_string_lossy()
+        ),
+        None => format!("{}.track{:02}", stem, track_i),
+    };
+
+    path.with_file_name(file_name)
+}
+
+/// Forwards the sequencer's messages for one track to its instrument. It always takes messages
+/// from the sequencer as soon as they are sent, so a track whose instrument falls behind builds up
+/// a backlog of its own instead of holding up the timeline for every other track.
+async fn relay_track_messages(
+    track_i: usize,
+    mut from_sequencer: mpsc::Receiver<RawMidiMessage>,
+    mut to_instrument: mpsc::Sender<RawMidiMessage>,
+) {
+    let mut backlog: VecDeque<RawMidiMessage> = VecDeque::new();
+    let mut sequencer_done = false;
+    let mut dropped = 0;
+    loop {
+        let next = match backlog.front() {
+            Some(next) => next.clone(),
+            None if sequencer_done => break,
+            None => {
+                match from_sequencer.recv().await {
+                    Some(message) => backlog.push_back(message),
+                    None => sequencer_done = true,
+                }
+                continue;
+            }
+        };
+
+        select! {
+            maybe_message = from_sequencer.recv(), if !sequencer_done => match maybe_message {
+                Some(message) => {
+                    backlog.push_back(message);
+                    if backlog.len() > TRACK_BACKLOG_MAX {
+                        if dropped == 0 {
+                            warn!("Track {} is falling behind, dropping messages", track_i);
+                        }
+                        drop_oldest_droppable(&mut backlog);
+                        dropped += 1;
+                    }
+                }
+                None => sequencer_done = true,
+            },
+            result = to_instrument.send(next) => {
+                if result.is_err() {
+                    // The instrument has stopped.
+                    break;
+                }
+                backlog.pop_front();
+            },
+        }
+    }
+
+    if dropped > 0 {
+        warn!("Track {} dropped {} messages in total", track_i, dropped);
+    }
+}
+
+/// Removes the oldest message that isn't a note off, or the oldest message if they all are.
+fn drop_oldest_droppable(backlog: &mut VecDeque<RawMidiMessage>) {
+    let is_note_off =
+        |(_, m): &RawMidiMessage| m[0] & 0xF0 == 0x80 || (m[0] & 0xF0 == 0x90 && m[2] == 0);
+    let i = backlog.iter().position(|m| !is_note_off(m)).unwrap_or(0);
+    backlog.remove(i);
 }
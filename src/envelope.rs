@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+
+/// Attack, decay, sustain and release settings for an `Envelope`. Times are in seconds, and the
+/// sustain level is in [0.0, 1.0].
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Adsr {
+    pub attack_secs: f32,
+    pub decay_secs: f32,
+    pub sustain: f32,
+    pub release_secs: f32,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Adsr {
+            attack_secs: 0.01,
+            decay_secs: 0.3,
+            sustain: 0.5,
+            release_secs: 0.3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Done,
+}
+
+/// A linear ADSR envelope, advanced one sample at a time. It starts in the attack stage and holds
+/// the sustain level until `release` is called.
+#[derive(Clone, Copy)]
+pub struct Envelope {
+    adsr: Adsr,
+    sample_period: f32,
+    stage: Stage,
+    level: f32,
+    release_step: f32,
+}
+
+impl Envelope {
+    pub fn new(adsr: Adsr, sample_hz: f32) -> Self {
+        Envelope {
+            adsr: Adsr {
+                sustain: adsr.sustain.clamp(0.0, 1.0),
+                ..adsr
+            },
+            sample_period: 1.0 / sample_hz,
+            stage: Stage::Attack,
+            level: 0.0,
+            release_step: 0.0,
+        }
+    }
+
+    /// Moves to the release stage from wherever the envelope is. Calling it again has no effect.
+    pub fn release(&mut self) {
+        if self.stage == Stage::Release || self.stage == Stage::Done {
+            return;
+        }
+        self.stage = Stage::Release;
+        self.release_step = self.level * self.step(self.adsr.release_secs);
+    }
+
+    /// Keeps the envelope's progress, so it can follow a change of output rate mid-note.
+    pub fn set_sample_hz(&mut self, sample_hz: f32) {
+        let sample_period = 1.0 / sample_hz;
+        self.release_step *= sample_period / self.sample_period;
+        self.sample_period = sample_period;
+    }
+
+    /// Whether the release has finished, so every further level is zero.
+    pub fn is_done(&self) -> bool {
+        self.stage == Stage::Done
+    }
+
+    /// Returns the level for this sample and advances to the next one.
+    pub fn next_level(&mut self) -> f32 {
+        let level = self.level;
+        match self.stage {
+            Stage::Attack => {
+                self.level += self.step(self.adsr.attack_secs);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level -= (1.0 - self.adsr.sustain) * self.step(self.adsr.decay_secs);
+                if self.level <= self.adsr.sustain {
+                    self.level = self.adsr.sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => (),
+            Stage::Release => {
+                self.level -= self.release_step;
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = Stage::Done;
+                }
+            }
+            Stage::Done => (),
+        }
+
+        level
+    }
+
+    /// Fills `levels` with the next `levels.len()` levels, the same as calling `next_level` for
+    /// each, up to rounding, which can move the end of a stage by a sample. Each stage is a
+    /// straight line, so the levels in it are written in one loop without branches, which the
+    /// compiler vectorizes.
+    pub fn fill_levels(&mut self, levels: &mut [f32]) {
+        let mut filled = 0;
+        while filled < levels.len() {
+            let rest = &mut levels[filled..];
+            // Where the stage ends, how far the level moves each sample, and what comes next.
+            let (target, step, next_stage) = match self.stage {
+                Stage::Attack => (1.0, self.step(self.adsr.attack_secs), Stage::Decay),
+                Stage::Decay => (
+                    self.adsr.sustain,
+                    -(1.0 - self.adsr.sustain) * self.step(self.adsr.decay_secs),
+                    Stage::Sustain,
+                ),
+                Stage::Release => (0.0, -self.release_step, Stage::Done),
+                Stage::Sustain | Stage::Done => {
+                    rest.fill(self.level);
+                    return;
+                }
+            };
+            // The stage lasts until the step that reaches the target, and always at least a
+            // sample. A step of zero, from a level already at the target, makes this NaN, and
+            // NaN casts to 0.
+            let stage_len = (((target - self.level) / step).ceil() as usize).max(1);
+            let len = stage_len.min(rest.len());
+            let start = self.level;
+            for (i, level) in rest[..len].iter_mut().enumerate() {
+                *level = start + step * i as f32;
+            }
+            if len == stage_len {
+                self.level = target;
+                self.stage = next_stage;
+            } else {
+                self.level = start + step * len as f32;
+            }
+            filled += len;
+        }
+    }
+
+    /// The fraction of a stage covered by one sample. Zero-length stages finish in one sample.
+    fn step(&self, secs: f32) -> f32 {
+        if secs <= self.sample_period {
+            1.0
+        } else {
+            self.sample_period / secs
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_levels_follows_next_level() {
+        let adsr = Adsr {
+            attack_secs: 0.01,
+            decay_secs: 0.02,
+            sustain: 0.6,
+            release_secs: 0.015,
+        };
+        let mut stepped = Envelope::new(adsr, 8000.0);
+        let mut filled = Envelope::new(adsr, 8000.0);
+        // Uneven blocks, so stages end in the middle of some of them.
+        let mut levels = [0.0; 37];
+        for block in 0..12 {
+            if block == 8 {
+                stepped.release();
+                filled.release();
+            }
+            filled.fill_levels(&mut levels);
+            // Adding up steps one at a time can leave a stage a rounding error short of its
+            // target and a sample longer, so the two can be a step apart: at most the attack's
+            // 1/80.
+            for &level in levels.iter() {
+                assert!((level - stepped.next_level()).abs() <= 0.0125 + 1e-4);
+            }
+        }
+        assert!(stepped.is_done());
+        assert!(filled.is_done());
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{audio_device::OutputDevice, audio_input::InputDevice};
+
+use std::io;
+use thiserror::Error;
+
+/// Everything that can go wrong opening devices, reading files and recording.
+#[derive(Debug, Error)]
+pub enum NocturneError {
+    #[error("There is no audio output {0:?} that can play 32-bit float samples")]
+    NoAudioOutput(OutputDevice),
+    #[error("There is no audio input {0:?} that can capture 32-bit float samples")]
+    NoAudioInput(InputDevice),
+    #[error("Failed to open the audio device: {0}")]
+    BuildStream(#[from] cpal::BuildStreamError),
+    #[error("Failed to start the audio device: {0}")]
+    PlayStream(#[from] cpal::PlayStreamError),
+    #[error("Failed to pause the audio device: {0}")]
+    PauseStream(#[from] cpal::PauseStreamError),
+    #[error("The audio device failed: {0}")]
+    Stream(#[from] cpal::StreamError),
+    #[error("Failed to load MIDI input: {0}")]
+    MidiInit(#[from] midir::InitError),
+    #[error("There is no MIDI input port {0}")]
+    NoMidiPort(usize),
+    #[error("There is no MIDI input port with {0:?} in its name")]
+    NoMidiPortNamed(String),
+    #[error("Failed to open MIDI input port: {0}")]
+    MidiConnect(midir::ConnectErrorKind),
+    #[error("Failed to load MIDI output: {0}")]
+    MidiOutputInit(midir::InitError),
+    #[error("There is no MIDI output port {0}")]
+    NoMidiOutputPort(usize),
+    #[error("Failed to open MIDI output port: {0}")]
+    MidiOutputConnect(midir::ConnectErrorKind),
+    #[error("Invalid MIDI route {0}")]
+    InvalidRoute(String),
+    #[error("Failed to parse MIDI file: {0}")]
+    InvalidMidiFile(midly::ErrorKind),
+    #[error("MIDI files timed in SMPTE frames aren't supported")]
+    UnsupportedMidiTiming,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<midir::ConnectError<midir::MidiInput>> for NocturneError {
+    fn from(e: midir::ConnectError<midir::MidiInput>) -> Self {
+        // The error gives back the input, which can't be sent between threads.
+        NocturneError::MidiConnect(e.kind())
+    }
+}
+
+impl From<midir::ConnectError<midir::MidiOutput>> for NocturneError {
+    fn from(e: midir::ConnectError<midir::MidiOutput>) -> Self {
+        NocturneError::MidiOutputConnect(e.kind())
+    }
+}
+
+impl From<midly::Error> for NocturneError {
+    fn from(e: midly::Error) -> Self {
+        NocturneError::InvalidMidiFile(e.kind())
+    }
+}
+
+pub type Result<T, E = NocturneError> = std::result::Result<T, E>;
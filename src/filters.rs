@@ -1,4 +1,7 @@
+use wide::f32x8;
+
 /// Low-pass filter, AKA exponential smoothing. The discretized version of an RC low-pass filter.
+#[derive(Clone, Copy)]
 pub struct ExponentialSmoothing {
     smoothed_value: f32,
     factor: f32,
@@ -6,8 +9,12 @@ pub struct ExponentialSmoothing {
 
 impl ExponentialSmoothing {
     pub fn new(factor: f32) -> Self {
+        Self::with_initial_value(0.0, factor)
+    }
+
+    pub fn with_initial_value(initial_value: f32, factor: f32) -> Self {
         ExponentialSmoothing {
-            smoothed_value: 0.0,
+            smoothed_value: initial_value,
             factor,
         }
     }
@@ -17,4 +24,377 @@ impl ExponentialSmoothing {
 
         self.smoothed_value
     }
+
+    /// Whether `apply(target)` would leave the value exactly where it is, so skipping it changes
+    /// nothing.
+    pub fn is_settled(&self, target: f32) -> bool {
+        self.factor * target + (1.0 - self.factor) * self.smoothed_value == self.smoothed_value
+    }
+}
+
+/// Cutoff of `DcBlocker`. Low enough to leave the bottom of the audible range alone.
+const DC_BLOCKER_CUTOFF_HZ: f32 = 10.0;
+
+/// One-pole high-pass filter that removes DC offset.
+#[derive(Clone, Copy)]
+pub struct DcBlocker {
+    pole: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl DcBlocker {
+    pub fn new(sample_hz: f32) -> Self {
+        DcBlocker {
+            pole: (-2.0 * std::f32::consts::PI * DC_BLOCKER_CUTOFF_HZ / sample_hz).exp(),
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    pub fn apply(&mut self, sample: f32) -> f32 {
+        let output = sample - self.prev_input + self.pole * self.prev_output;
+        self.prev_input = sample;
+        self.prev_output = output;
+
+        output
+    }
+}
+
+/// Resonance of 1.0 maps to this damping, which rings for a long time but never self-oscillates.
+const MIN_SVF_DAMPING: f32 = 0.02;
+
+/// The most samples `SvfCoefficients` covers.
+pub const SVF_BLOCK: usize = 64;
+
+/// Samples `SvfCoefficients` works out at once.
+const SVF_LANES: usize = 8;
+
+/// Resonant 12 dB/octave low-pass filter, implemented as a trapezoidal state-variable filter
+/// (Andrew Simper's "SvfLinearTrapOptimised2"). Unlike a biquad, it stays stable and doesn't zipper
+/// when its cutoff is modulated every sample. The cutoff and resonance come with each block, in
+/// `SvfCoefficients`.
+///
+/// Stereo, since each sample depends on the previous one: filtering both channels in the same loop
+/// lets one channel's sample overlap with the other's, rather than each waiting on the last.
+#[derive(Clone, Copy, Default)]
+pub struct ResonantLowPass {
+    left: SvfState,
+    right: SvfState,
+}
+
+impl ResonantLowPass {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Filters `left` and `right` in place, each sample with its own coefficients. Samples past the
+    /// end of `coefficients` are left alone.
+    pub fn apply_block(
+        &mut self,
+        coefficients: &SvfCoefficients,
+        left: &mut [f32],
+        right: &mut [f32],
+    ) {
+        let len = coefficients.len;
+        let samples = left.iter_mut().zip(right.iter_mut()).take(len);
+        if coefficients.settled {
+            let (a1, a2, a3) = (coefficients.a1[0], coefficients.a2[0], coefficients.a3[0]);
+            for (l, r) in samples {
+                *l = self.left.tick(a1, a2, a3, *l);
+                *r = self.right.tick(a1, a2, a3, *r);
+            }
+
+            return;
+        }
+        for (((l, r), a1), (a2, a3)) in samples
+            .zip(coefficients.a1.iter())
+            .zip(coefficients.a2.iter().zip(coefficients.a3.iter()))
+        {
+            *l = self.left.tick(*a1, *a2, *a3, *l);
+            *r = self.right.tick(*a1, *a2, *a3, *r);
+        }
+    }
+}
+
+/// One channel of `ResonantLowPass`.
+#[derive(Clone, Copy, Default)]
+struct SvfState {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl SvfState {
+    fn tick(&mut self, a1: f32, a2: f32, a3: f32, sample: f32) -> f32 {
+        let v3 = sample - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        v2
+    }
+}
+
+/// `ResonantLowPass` coefficients for each sample of a block, for a cutoff and resonance that can
+/// move every sample. Each sample's coefficients take an `exp` and a `tan`, so they're worked out
+/// `SVF_LANES` samples at a time with SIMD.
+pub struct SvfCoefficients {
+    a1: [f32; SVF_BLOCK],
+    a2: [f32; SVF_BLOCK],
+    a3: [f32; SVF_BLOCK],
+    len: usize,
+    /// Every sample has the first sample's coefficients, and only those are worked out.
+    settled: bool,
+}
+
+impl Default for SvfCoefficients {
+    fn default() -> Self {
+        SvfCoefficients {
+            a1: [0.0; SVF_BLOCK],
+            a2: [0.0; SVF_BLOCK],
+            a3: [0.0; SVF_BLOCK],
+            len: 0,
+            settled: false,
+        }
+    }
+}
+
+impl SvfCoefficients {
+    /// Each sample's cutoff is `cutoff_hz` moved by that sample's `cutoff_octaves`, and its
+    /// resonance is from `resonance`, which ranges from 0.0 (no peak at the cutoff) to 1.0 (a
+    /// sharp, ringing peak). Covers the first `SVF_BLOCK` samples at most. Replaces whatever
+    /// block the coefficients were for before.
+    pub fn update(
+        &mut self,
+        sample_hz: f32,
+        cutoff_hz: f32,
+        cutoff_octaves: &[f32],
+        resonance: &[f32],
+    ) {
+        let len = cutoff_octaves.len().min(resonance.len()).min(SVF_BLOCK);
+        self.len = len;
+        // With the envelope and controllers settled, every sample has the same parameters, which
+        // only need working out once.
+        let settled = cutoff_octaves[..len]
+            .iter()
+            .all(|&o| o == cutoff_octaves[0])
+            && resonance[..len].iter().all(|&r| r == resonance[0]);
+        self.settled = settled && len > 0;
+        if self.settled {
+            let (a1, a2, a3) = svf_lanes(
+                sample_hz,
+                cutoff_hz,
+                f32x8::splat(cutoff_octaves[0]),
+                f32x8::splat(resonance[0]),
+            );
+            self.a1[0] = a1.to_array()[0];
+            self.a2[0] = a2.to_array()[0];
+            self.a3[0] = a3.to_array()[0];
+
+            return;
+        }
+
+        for start in (0..len).step_by(SVF_LANES) {
+            let end = (start + SVF_LANES).min(len);
+            let lanes = end - start;
+            let mut octaves = [0.0; SVF_LANES];
+            let mut resonances = [0.0; SVF_LANES];
+            octaves[..lanes].copy_from_slice(&cutoff_octaves[start..end]);
+            resonances[..lanes].copy_from_slice(&resonance[start..end]);
+            let (a1, a2, a3) = svf_lanes(
+                sample_hz,
+                cutoff_hz,
+                f32x8::from(octaves),
+                f32x8::from(resonances),
+            );
+            self.a1[start..end].copy_from_slice(&a1.to_array()[..lanes]);
+            self.a2[start..end].copy_from_slice(&a2.to_array()[..lanes]);
+            self.a3[start..end].copy_from_slice(&a3.to_array()[..lanes]);
+        }
+    }
+}
+
+/// `SvfCoefficients` for `SVF_LANES` samples at once.
+fn svf_lanes(
+    sample_hz: f32,
+    cutoff_hz: f32,
+    cutoff_octaves: f32x8,
+    resonance: f32x8,
+) -> (f32x8, f32x8, f32x8) {
+    let one = f32x8::splat(1.0);
+    let cutoff = (f32x8::splat(cutoff_hz)
+        * (cutoff_octaves * f32x8::splat(std::f32::consts::LN_2)).exp())
+    .max(f32x8::splat(20.0))
+    .min(f32x8::splat(0.49 * sample_hz));
+    let g = (cutoff * f32x8::splat(std::f32::consts::PI / sample_hz)).tan();
+    let resonance = resonance.max(f32x8::ZERO).min(one);
+    // k = 1/Q, from 2.0 (Q = 0.5, critically damped) down to nearly 0.
+    let k = f32x8::splat(2.0) - f32x8::splat(2.0 - MIN_SVF_DAMPING) * resonance;
+    let a1 = one / (one + g * (g + k));
+    let a2 = g * a1;
+    let a3 = g * a2;
+
+    (a1, a2, a3)
+}
+
+/// The filter shapes from Robert Bristow-Johnson's "Audio EQ Cookbook". Shelf and peak gains are in
+/// decibels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BiquadKind {
+    LowPass,
+    HighPass,
+    /// Constant 0 dB peak gain.
+    BandPass,
+    Notch,
+    LowShelf {
+        gain_db: f32,
+    },
+    HighShelf {
+        gain_db: f32,
+    },
+    /// Peaking EQ.
+    Peak {
+        gain_db: f32,
+    },
+}
+
+/// Normalized coefficients (a0 = 1). Computing them is relatively expensive, so compute them once
+/// and share them between channels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BiquadCoefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl BiquadCoefficients {
+    /// `hz` is the cutoff, center or shelf midpoint frequency, depending on the kind. `q` sets the
+    /// bandwidth; 0.707 gives the flattest pass band for the low- and high-pass filters, and the
+    /// steepest shelf without overshoot for the shelves.
+    pub fn new(kind: BiquadKind, sample_hz: f32, hz: f32, q: f32) -> Self {
+        let w0 = 2.0 * std::f32::consts::PI * hz.clamp(1.0, 0.49 * sample_hz) / sample_hz;
+        let (sin_w0, cos_w0) = w0.sin_cos();
+        let alpha = sin_w0 / (2.0 * q.max(0.01));
+
+        let (b0, b1, b2, a0, a1, a2) = match kind {
+            BiquadKind::LowPass => (
+                (1.0 - cos_w0) / 2.0,
+                1.0 - cos_w0,
+                (1.0 - cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            BiquadKind::HighPass => (
+                (1.0 + cos_w0) / 2.0,
+                -(1.0 + cos_w0),
+                (1.0 + cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            BiquadKind::BandPass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha),
+            BiquadKind::Notch => (
+                1.0,
+                -2.0 * cos_w0,
+                1.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            BiquadKind::Peak { gain_db } => {
+                let a = shelf_amplitude(gain_db);
+                (
+                    1.0 + alpha * a,
+                    -2.0 * cos_w0,
+                    1.0 - alpha * a,
+                    1.0 + alpha / a,
+                    -2.0 * cos_w0,
+                    1.0 - alpha / a,
+                )
+            }
+            BiquadKind::LowShelf { gain_db } => {
+                let a = shelf_amplitude(gain_db);
+                let k = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 + k),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 - k),
+                    (a + 1.0) + (a - 1.0) * cos_w0 + k,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    (a + 1.0) + (a - 1.0) * cos_w0 - k,
+                )
+            }
+            BiquadKind::HighShelf { gain_db } => {
+                let a = shelf_amplitude(gain_db);
+                let k = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 + k),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 - k),
+                    (a + 1.0) - (a - 1.0) * cos_w0 + k,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    (a + 1.0) - (a - 1.0) * cos_w0 - k,
+                )
+            }
+        };
+
+        BiquadCoefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+}
+
+/// The cookbook's "A": the square root of the linear gain.
+fn shelf_amplitude(gain_db: f32) -> f32 {
+    10.0f32.powf(gain_db / 40.0)
+}
+
+/// One channel of a biquad filter, in transposed direct form II. Run one per channel, per voice or
+/// on the mixed output.
+#[derive(Clone, Copy)]
+pub struct Biquad {
+    coefficients: BiquadCoefficients,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    pub fn new(kind: BiquadKind, sample_hz: f32, hz: f32, q: f32) -> Self {
+        Self::from_coefficients(BiquadCoefficients::new(kind, sample_hz, hz, q))
+    }
+
+    pub fn from_coefficients(coefficients: BiquadCoefficients) -> Self {
+        Biquad {
+            coefficients,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    /// Changes the response while keeping the filter's state, so a playing signal doesn't click.
+    pub fn set_coefficients(&mut self, coefficients: BiquadCoefficients) {
+        self.coefficients = coefficients;
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+
+    pub fn apply(&mut self, sample: f32) -> f32 {
+        let c = &self.coefficients;
+        let out = c.b0 * sample + self.z1;
+        self.z1 = c.b1 * sample - c.a1 * out + self.z2;
+        self.z2 = c.b2 * sample - c.a2 * out;
+
+        out
+    }
 }
@@ -0,0 +1,381 @@
+//! Synth patches for the 128 General MIDI programs, so files written for General MIDI play each
+//! channel with a sound close to the one it asks for. The programs come in 16 families of 8
+//! (pianos, organs, strings, ...), and each family has one built-in patch. A TOML map can give
+//! whole families or single programs patches of their own:
+//!
+//! ```toml
+//! [families]
+//! piano = "patches/grand.toml"
+//! synth-pad = "patches/warm pad.toml"
+//!
+//! # Programs count from 0, so 40 is the violin.
+//! [programs]
+//! 40 = "patches/violin.toml"
+//! ```
+//!
+//! A program's own patch comes before its family's, and the built-in patch is used for anything
+//! the map leaves out. Paths are relative to the map.
+
+use crate::{
+    envelope::Adsr,
+    patch::{LoadedPatch, OscillatorPatch, PatchBank, SynthPatch},
+    synthesizer::{Unison, VoiceFilter},
+};
+
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The General MIDI program families, in program order. Program `p` is in family `p / 8`.
+pub const GENERAL_MIDI_FAMILIES: [&str; 16] = [
+    "piano",
+    "chromatic-percussion",
+    "organ",
+    "guitar",
+    "bass",
+    "strings",
+    "ensemble",
+    "brass",
+    "reed",
+    "pipe",
+    "synth-lead",
+    "synth-pad",
+    "synth-effects",
+    "ethnic",
+    "percussive",
+    "sound-effects",
+];
+
+const PROGRAMS_PER_FAMILY: u8 = 8;
+
+/// The name of the family that `program` (from 0) is in.
+pub fn general_midi_family(program: u8) -> &'static str {
+    GENERAL_MIDI_FAMILIES[(program.min(127) / PROGRAMS_PER_FAMILY) as usize]
+}
+
+/// The settings a built-in family patch differs in.
+struct FamilySound {
+    wave: &'static str,
+    unison: (usize, f32, f32),
+    /// Attack, decay, sustain and release.
+    envelope: (f32, f32, f32, f32),
+    cutoff_hz: f32,
+    resonance: f32,
+    /// Octaves the cutoff opens by at the start of each note, falling back over the decay.
+    brightness_octaves: f32,
+}
+
+const FAMILY_SOUNDS: [FamilySound; 16] = [
+    // Piano
+    FamilySound {
+        wave: "triangle",
+        unison: (1, 0.0, 0.0),
+        envelope: (0.005, 1.5, 0.2, 0.4),
+        cutoff_hz: 1200.0,
+        resonance: 0.0,
+        brightness_octaves: 1.5,
+    },
+    // Chromatic percussion
+    FamilySound {
+        wave: "sine",
+        unison: (1, 0.0, 0.0),
+        envelope: (0.002, 0.8, 0.0, 0.6),
+        cutoff_hz: 4000.0,
+        resonance: 0.0,
+        brightness_octaves: 0.0,
+    },
+    // Organ
+    FamilySound {
+        wave: "square",
+        unison: (2, 4.0, 0.3),
+        envelope: (0.01, 0.1, 1.0, 0.08),
+        cutoff_hz: 2500.0,
+        resonance: 0.0,
+        brightness_octaves: 0.0,
+    },
+    // Guitar
+    FamilySound {
+        wave: "sawtooth",
+        unison: (1, 0.0, 0.0),
+        envelope: (0.003, 1.0, 0.15, 0.3),
+        cutoff_hz: 900.0,
+        resonance: 0.1,
+        brightness_octaves: 2.0,
+    },
+    // Bass
+    FamilySound {
+        wave: "sawtooth",
+        unison: (1, 0.0, 0.0),
+        envelope: (0.005, 0.4, 0.6, 0.12),
+        cutoff_hz: 400.0,
+        resonance: 0.2,
+        brightness_octaves: 1.5,
+    },
+    // Strings
+    FamilySound {
+        wave: "sawtooth",
+        unison: (3, 10.0, 0.4),
+        envelope: (0.15, 0.3, 0.8, 0.5),
+        cutoff_hz: 2000.0,
+        resonance: 0.0,
+        brightness_octaves: 0.0,
+    },
+    // Ensemble
+    FamilySound {
+        wave: "sawtooth",
+        unison: (5, 16.0, 0.7),
+        envelope: (0.3, 0.3, 0.8, 0.8),
+        cutoff_hz: 1600.0,
+        resonance: 0.0,
+        brightness_octaves: 0.0,
+    },
+    // Brass
+    FamilySound {
+        wave: "sawtooth",
+        unison: (2, 6.0, 0.2),
+        envelope: (0.05, 0.2, 0.75, 0.2),
+        cutoff_hz: 800.0,
+        resonance: 0.1,
+        brightness_octaves: 1.5,
+    },
+    // Reed
+    FamilySound {
+        wave: "square",
+        unison: (1, 0.0, 0.0),
+        envelope: (0.03, 0.2, 0.8, 0.15),
+        cutoff_hz: 1800.0,
+        resonance: 0.2,
+        brightness_octaves: 0.5,
+    },
+    // Pipe
+    FamilySound {
+        wave: "triangle",
+        unison: (1, 0.0, 0.0),
+        envelope: (0.06, 0.2, 0.9, 0.2),
+        cutoff_hz: 3000.0,
+        resonance: 0.0,
+        brightness_octaves: 0.0,
+    },
+    // Synth lead
+    FamilySound {
+        wave: "square",
+        unison: (2, 8.0, 0.3),
+        envelope: (0.01, 0.3, 0.8, 0.2),
+        cutoff_hz: 3000.0,
+        resonance: 0.3,
+        brightness_octaves: 1.0,
+    },
+    // Synth pad
+    FamilySound {
+        wave: "sawtooth",
+        unison: (4, 20.0, 0.8),
+        envelope: (0.8, 0.5, 0.8, 1.5),
+        cutoff_hz: 1200.0,
+        resonance: 0.1,
+        brightness_octaves: 0.0,
+    },
+    // Synth effects
+    FamilySound {
+        wave: "sawtooth",
+        unison: (3, 30.0, 0.9),
+        envelope: (0.5, 1.0, 0.6, 1.5),
+        cutoff_hz: 600.0,
+        resonance: 0.6,
+        brightness_octaves: 2.0,
+    },
+    // Ethnic
+    FamilySound {
+        wave: "triangle",
+        unison: (1, 0.0, 0.0),
+        envelope: (0.003, 0.6, 0.1, 0.3),
+        cutoff_hz: 1500.0,
+        resonance: 0.2,
+        brightness_octaves: 1.5,
+    },
+    // Percussive
+    FamilySound {
+        wave: "sine",
+        unison: (1, 0.0, 0.0),
+        envelope: (0.001, 0.3, 0.0, 0.2),
+        cutoff_hz: 3000.0,
+        resonance: 0.0,
+        brightness_octaves: 0.0,
+    },
+    // Sound effects
+    FamilySound {
+        wave: "white-noise",
+        unison: (1, 0.0, 0.0),
+        envelope: (0.05, 0.5, 0.3, 0.5),
+        cutoff_hz: 2000.0,
+        resonance: 0.3,
+        brightness_octaves: 1.0,
+    },
+];
+
+impl FamilySound {
+    fn patch(&self, family: &str) -> SynthPatch {
+        let (attack_secs, decay_secs, sustain, release_secs) = self.envelope;
+        let (voices, detune_cents, stereo_spread) = self.unison;
+
+        SynthPatch {
+            name: format!("General MIDI {}", family),
+            oscillator: OscillatorPatch {
+                wave: self.wave.to_string(),
+                unison: Unison {
+                    voices,
+                    detune_cents,
+                    stereo_spread,
+                },
+            },
+            envelope: Some(Adsr {
+                attack_secs,
+                decay_secs,
+                sustain,
+                release_secs,
+            }),
+            filter: VoiceFilter {
+                cutoff_hz: self.cutoff_hz,
+                resonance: self.resonance,
+                envelope: Adsr {
+                    attack_secs,
+                    decay_secs,
+                    sustain: 0.0,
+                    release_secs,
+                },
+                envelope_octaves: self.brightness_octaves,
+                key_tracking: 0.5,
+            },
+            effects: Vec::new(),
+            controls: Vec::new(),
+        }
+    }
+}
+
+/// The built-in patch for `program`'s family.
+pub fn general_midi_patch(program: u8) -> SynthPatch {
+    let family = (program.min(127) / PROGRAMS_PER_FAMILY) as usize;
+
+    FAMILY_SOUNDS[family].patch(GENERAL_MIDI_FAMILIES[family])
+}
+
+/// Which patches replace the built-in ones, by family name or program number.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GeneralMidiMap {
+    pub families: BTreeMap<String, PathBuf>,
+    pub programs: BTreeMap<u8, PathBuf>,
+}
+
+/// The file as written, with programs still as strings, since TOML keys always are.
+#[derive(Deserialize)]
+struct GeneralMidiMapFile {
+    #[serde(default)]
+    families: BTreeMap<String, PathBuf>,
+    #[serde(default)]
+    programs: BTreeMap<String, PathBuf>,
+}
+
+impl GeneralMidiMap {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let file: GeneralMidiMapFile =
+            toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let mut families = BTreeMap::new();
+        for (family, patch) in file.families {
+            if !GENERAL_MIDI_FAMILIES.contains(&family.as_str()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{:?} is not a General MIDI family, try one of {}",
+                        family,
+                        GENERAL_MIDI_FAMILIES.join(", ")
+                    ),
+                ));
+            }
+            families.insert(family, dir.join(patch));
+        }
+        let mut programs = BTreeMap::new();
+        for (program, patch) in file.programs {
+            let program = program
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&p: &u8| p < 128)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{:?} is not a program number from 0 to 127", program),
+                    )
+                })?;
+            programs.insert(program, dir.join(patch));
+        }
+
+        Ok(GeneralMidiMap { families, programs })
+    }
+
+    /// The patch file for `program`, if the map has one.
+    pub fn patch_path(&self, program: u8) -> Option<&Path> {
+        self.programs
+            .get(&program)
+            .or_else(|| self.families.get(general_midi_family(program)))
+            .map(PathBuf::as_path)
+    }
+}
+
+impl PatchBank {
+    /// A bank of the built-in patches, one for each General MIDI program. Loaded on first use and
+    /// shared after that.
+    pub fn general_midi() -> &'static PatchBank {
+        static BANK: OnceCell<&'static PatchBank> = OnceCell::new();
+
+        BANK.get_or_init(|| {
+            Self::general_midi_with_map(&GeneralMidiMap::default())
+                .expect("The built-in patches only use built-in waves")
+        })
+    }
+
+    /// Like `general_midi`, with the map's patches in place of the built-in ones. Each patch file
+    /// is loaded once, however many programs play it. Like `SoundFont::load`, the bank lives for
+    /// the rest of the program.
+    pub fn general_midi_with_map(map: &GeneralMidiMap) -> io::Result<&'static PatchBank> {
+        let mut files: Vec<(&Path, LoadedPatch)> = Vec::new();
+        let mut patches = Vec::with_capacity(128);
+        for program in 0..128 {
+            let loaded = match map.patch_path(program) {
+                Some(path) => match files.iter().find(|(p, _)| *p == path) {
+                    Some((_, loaded)) => copy_loaded(loaded),
+                    None => {
+                        let loaded = SynthPatch::load(path)
+                            .and_then(|patch| patch.loaded())
+                            .map_err(|e| {
+                                io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+                            })?;
+                        files.push((path, copy_loaded(&loaded)));
+
+                        loaded
+                    }
+                },
+                None => general_midi_patch(program).loaded()?,
+            };
+            patches.push(loaded);
+        }
+
+        Ok(PatchBank::from_patches(patches))
+    }
+
+    /// Loads the map at `path` for `general_midi_with_map`.
+    pub fn load_general_midi(path: &Path) -> io::Result<&'static PatchBank> {
+        Self::general_midi_with_map(&GeneralMidiMap::load(path)?)
+    }
+}
+
+fn copy_loaded(loaded: &LoadedPatch) -> LoadedPatch {
+    LoadedPatch {
+        patch: loaded.patch.clone(),
+        source: loaded.source,
+    }
+}
@@ -1,25 +1,54 @@
 use crate::{
-    audio_device::AudioOutputDeviceStream,
-    midi::{MidiInputDeviceStream, RawMidiMessage},
-    recording::RecordingOutputStream,
-    synthesizer::Synthesizer,
-    wave_table::Wave,
-    CHANNEL_MAX_BUFFER,
+    audio_device::{
+        AudioDeviceProfile, AudioOutputDeviceStream, FramePool, OutputDevice, RENDER_SAMPLE_HZ,
+    },
+    cancel::CancellationToken,
+    config::Config,
+    controls::ControlBindings,
+    effects::{Effect, EffectsChain, Limiter},
+    error::Result,
+    journal::MidiJournal,
+    midi::{MidiInputDeviceStream, MidiMessageBytes, RawMidiMessage},
+    oscillator::Source,
+    recording::{RecorderSet, RecordingTarget},
+    synthesizer::{NoteEvent, Synthesizer, VoiceLimits},
+    timecode::MidiClockTempo,
+    AudioFrame, TimedFrame, CHANNEL_MAX_BUFFER,
 };
 
-use cpal::{SampleRate, StreamConfig};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use cpal::StreamConfig;
+use futures::{future::join_all, stream::select_all, FutureExt};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use tokio::{
     select,
     stream::{Stream, StreamExt},
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, mpsc::error::TryRecvError, oneshot},
+    time::{delay_for, delay_until, interval},
 };
 
+/// Most MIDI messages to apply between checks for buffer requests.
+const MIDI_BATCH_MAX: usize = 64;
+
+/// How often to check whether the OS has changed the output device's sample rate.
+const SAMPLE_RATE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Errors reported by a device stream, until it is rebuilt.
+pub(crate) type StreamErrors = Option<mpsc::UnboundedReceiver<cpal::StreamError>>;
+
+/// Frames the synthesizer queues ahead of the audio output thread when a stream starts. With the
+/// default frame size, this represents an additional fixed latency of:
+///     2 buffers * 256 stereo samples * (1 / 48000) seconds = 0.01 seconds
+const BUFFERS_AHEAD: u32 = 2;
+
+/// How long `play_timed_messages` lets the last notes ring out.
+const TIMED_MESSAGES_TAIL: Duration = Duration::from_secs(1);
+
 /// Need to synchronize access to the stream, since it is !Send, and we want to use it across
 /// awaits (threads).
 struct SafeAudioStream {
-    stream: Arc<Mutex<AudioOutputDeviceStream>>,
+    /// Only empty while the stream is being rebuilt.
+    stream: Mutex<Option<AudioOutputDeviceStream>>,
 }
 
 unsafe impl Send for SafeAudioStream {}
@@ -27,101 +56,869 @@ unsafe impl Send for SafeAudioStream {}
 impl SafeAudioStream {
     fn new(stream: AudioOutputDeviceStream) -> Self {
         SafeAudioStream {
-            stream: Arc::new(Mutex::new(stream)),
+            stream: Mutex::new(Some(stream)),
+        }
+    }
+
+    fn with_stream<T>(&self, f: impl FnOnce(&AudioOutputDeviceStream) -> T) -> T {
+        f(self
+            .stream
+            .lock()
+            .unwrap()
+            .as_ref()
+            .expect("Audio stream is missing"))
+    }
+
+    fn play(&self) -> Result<()> {
+        self.with_stream(|s| s.play())
+    }
+
+    /// Does nothing if the stream failed and hasn't been rebuilt.
+    fn pause(&self) -> Result<()> {
+        match self.stream.lock().unwrap().as_ref() {
+            Some(stream) => stream.pause(),
+            None => Ok(()),
+        }
+    }
+
+    fn changed_device_sample_hz(&self) -> Option<u32> {
+        self.with_stream(|s| s.changed_device_sample_hz())
+    }
+
+    /// Does nothing if the stream failed and hasn't been rebuilt.
+    fn log_callback_problems(&self) {
+        if let Some(stream) = self.stream.lock().unwrap().as_ref() {
+            stream.log_callback_problems();
         }
     }
 
-    fn play(&self) {
-        self.stream.lock().unwrap().play();
+    fn take_error_receiver(&self) -> StreamErrors {
+        self.stream
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|s| s.take_error_receiver())
+    }
+
+    /// Drops a failed stream, and returns what it played on for `reconnect`.
+    fn close(&self) -> (AudioDeviceProfile, Option<Duration>) {
+        let stream = self
+            .stream
+            .lock()
+            .unwrap()
+            .take()
+            .expect("Audio stream is missing");
+
+        (stream.profile(), stream.max_frame_age())
+    }
+
+    /// Opens the stream again after `close`, and returns its config.
+    fn reconnect(
+        &self,
+        failed: &FailedOutput,
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+    ) -> Result<StreamConfig> {
+        let reconnected = AudioOutputDeviceStream::reconnect(
+            &failed.profile,
+            failed.max_frame_age,
+            frame_rx,
+            buffer_request_tx,
+        )?;
+        let config = reconnected.get_config().clone();
+        *self.stream.lock().unwrap() = Some(reconnected);
+
+        Ok(config)
     }
 
-    fn pause(&self) {
-        self.stream.lock().unwrap().pause();
+    /// Rebuilds the stream at `sample_hz` and returns its config. If that fails, the stream is
+    /// gone for good.
+    fn reconnect_at(
+        &self,
+        sample_hz: u32,
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        buffer_request_tx: mpsc::Sender<()>,
+    ) -> Result<StreamConfig> {
+        let mut stream = self.stream.lock().unwrap();
+        let reconnected = stream
+            .take()
+            .expect("Audio stream is missing")
+            .reconnect_at(sample_hz, frame_rx, buffer_request_tx)?;
+        let config = reconnected.get_config().clone();
+        *stream = Some(reconnected);
+
+        Ok(config)
     }
 }
 
+/// An output stream that failed, while it is being reconnected.
+struct FailedOutput {
+    profile: AudioDeviceProfile,
+    max_frame_age: Option<Duration>,
+    /// Failed attempts so far.
+    attempt: u32,
+    next_attempt: Instant,
+}
+
+/// Waits for the next error from a stream, or forever if there is no stream.
+pub(crate) async fn next_stream_error(errors: &mut StreamErrors) -> Option<cpal::StreamError> {
+    match errors {
+        Some(errors) => errors.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Plays a MIDI input port like `play_midi`. The input is also journaled, so the performance can be
+/// recovered with `recover_last_session` even if it wasn't recorded.
 pub async fn play_midi_device(
     midi_input_port: usize,
-    wave: Wave,
-    recording_path: Option<PathBuf>,
-) -> Result<(), midir::ConnectError<midir::MidiInput>> {
+    source: Source,
+    effects: EffectsChain,
+    recordings: Vec<RecordingTarget>,
+    cancel: CancellationToken,
+) -> Result<()> {
     let midi_input = MidiInputDeviceStream::connect(midi_input_port)?;
+    let input = journaled(midi_input.message_rx);
+
+    play_midi(input, source, effects, recordings, None, cancel).await
+}
+
+/// Passes `input` through while appending it to a new session journal, so the performance is kept
+/// even if nobody thought to record it.
+pub(crate) fn journaled<S>(input: S) -> impl Stream<Item = RawMidiMessage>
+where
+    S: Stream<Item = RawMidiMessage>,
+{
+    let mut journal = start_journal();
+
+    input.map(move |message| {
+        append_to_journal(&mut journal, &message);
 
-    play_midi(midi_input.message_rx, wave, recording_path).await;
+        message
+    })
+}
 
-    Ok(())
+/// A new session journal, or `None` if one can't be written.
+pub(crate) fn start_journal() -> Option<MidiJournal> {
+    MidiJournal::create_default()
+        .map_err(|e| log::warn!("Not journaling MIDI input: {}", e))
+        .ok()
 }
 
-/// Plays the MIDI input on a synth until there is no input left.
-pub async fn play_midi<S>(mut midi_input_stream: S, wave: Wave, recording_path: Option<PathBuf>)
+/// Stops journaling if the journal can't be written to anymore.
+pub(crate) fn append_to_journal(journal: &mut Option<MidiJournal>, message: &RawMidiMessage) {
+    if let Some(j) = journal.as_mut() {
+        if let Err(e) = j.append(message) {
+            log::warn!("Stopped journaling MIDI input to {:?}: {}", j.path(), e);
+            *journal = None;
+        }
+    }
+}
+
+/// Plays `messages`, timed from the start, on a synth like `play_midi`.
+pub(crate) async fn play_timed_messages(
+    messages: Vec<(Duration, MidiMessageBytes)>,
+    source: Source,
+    effects: EffectsChain,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let (mut message_tx, message_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
+    let sequencer = async move {
+        let start = Instant::now();
+        for (time, message) in messages {
+            delay_until((start + time).into()).await;
+            // Stop once the synth has hung up.
+            if message_tx
+                .send((time.as_micros() as u64, message))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+        // Ending the input stops the synth, so hold it open while the last notes fade.
+        delay_for(TIMED_MESSAGES_TAIL).await;
+    };
+    let synth = play_midi(message_rx, source, effects, Vec::new(), None, cancel);
+
+    futures::join!(sequencer, synth).1
+}
+
+/// Plays the MIDI input on a synth until there is no input left or `cancel` is cancelled.
+///
+/// The synth's output goes through `effects` before it is played or recorded. If `note_event_tx`
+/// is given, the synth publishes when each note starts and ends on it.
+///
+/// Fails if the output device or a recording can't be opened, or if the device or a recording
+/// fails along the way. Recordings are finished either way.
+pub async fn play_midi<S>(
+    midi_input_stream: S,
+    source: Source,
+    effects: EffectsChain,
+    recordings: Vec<RecordingTarget>,
+    note_event_tx: Option<broadcast::Sender<NoteEvent>>,
+    cancel: CancellationToken,
+) -> Result<()>
 where
     S: Stream<Item = RawMidiMessage> + Unpin,
 {
+    let track = MixTrack {
+        input: midi_input_stream,
+        source,
+        effects,
+        recordings: Vec::new(),
+        voice_limits: VoiceLimits::default(),
+        seed: 0,
+        controls: ControlBindings::of_source(source),
+    };
+    play_midi_mix(
+        vec![track],
+        recordings,
+        note_event_tx,
+        OutputDevice::Configured,
+        cancel,
+    )
+    .await
+}
+
+/// One input of `play_midi_mix`, and what plays it.
+pub(crate) struct MixTrack<S> {
+    pub(crate) input: S,
+    pub(crate) source: Source,
+    pub(crate) effects: EffectsChain,
+    /// Recordings of this track alone, after its effects.
+    pub(crate) recordings: Vec<RecordingTarget>,
+    pub(crate) voice_limits: VoiceLimits,
+    /// For the track's synthesizer, see `Synthesizer::set_seed`.
+    pub(crate) seed: u64,
+    /// Controllers that move the track's synth and effects parameters.
+    pub(crate) controls: ControlBindings,
+}
+
+/// Plays several MIDI inputs, each on its own synth and effects, summed into one output stream like
+/// `play_midi`. Recordings get the mix. Stops once every input has ended or `cancel` is cancelled.
+pub(crate) async fn play_midi_mix<S>(
+    tracks: Vec<MixTrack<S>>,
+    recordings: Vec<RecordingTarget>,
+    note_event_tx: Option<broadcast::Sender<NoteEvent>>,
+    output: OutputDevice,
+    cancel: CancellationToken,
+) -> Result<()>
+where
+    S: Stream<Item = RawMidiMessage> + Unpin,
+{
+    if tracks.is_empty() {
+        return Ok(());
+    }
+
     // Audio output can have many subscribers.
     let (frame_tx, device_frame_rx) = broadcast::channel(CHANNEL_MAX_BUFFER);
-    let (buffer_request_tx, mut buffer_request_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
+    let (buffer_request_tx, buffer_request_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
+    // For rebuilding the stream if the device changes rate.
+    let reconnect_buffer_request_tx = buffer_request_tx.clone();
+
+    let mut inputs = Vec::with_capacity(tracks.len());
+    let mut voices = Vec::with_capacity(tracks.len());
+    for track in tracks {
+        inputs.push(track.input);
+        voices.push(MixTrack {
+            input: (),
+            source: track.source,
+            effects: track.effects,
+            recordings: track.recordings,
+            voice_limits: track.voice_limits,
+            seed: track.seed,
+            controls: track.controls,
+        });
+    }
+    let midi_input_stream = select_all(
+        inputs
+            .into_iter()
+            .enumerate()
+            .map(|(track_i, input)| input.map(move |message| (track_i, message))),
+    );
 
-    // Create the synth and output stream.
-    let (mut synth, recorder, audio_output_stream, num_channels) = {
+    // Create the synths and output stream.
+    let (mut scheduler, recorders, stem_recorders, mut audio_output_stream) = {
         // Unsafe stream needs to stay in this scope to keep this async function Send.
         let audio_output_stream =
-            AudioOutputDeviceStream::connect_default(device_frame_rx, buffer_request_tx);
-        let &StreamConfig {
-            channels: num_channels,
-            sample_rate: SampleRate(sample_hz),
-            ..
-        } = audio_output_stream.get_config();
-        let recorder = recording_path.as_ref().map(|p| {
-            let recorder_frame_rx = frame_tx.subscribe();
-
-            RecordingOutputStream::connect(p, num_channels, sample_hz, recorder_frame_rx)
-        });
-        let mut synth = Synthesizer::new(sample_hz as f32, wave);
+            AudioOutputDeviceStream::connect_to(&output, device_frame_rx, buffer_request_tx)?;
+        // The stream resamples to the device's rate.
+        let sample_hz = RENDER_SAMPLE_HZ;
+        let num_channels = audio_output_stream.get_config().channels;
+        let recorders = RecorderSet::connect(recordings, num_channels, sample_hz, &frame_tx)?;
+        let mut stem_recorders = Vec::new();
+        let mut bus_voices = Vec::with_capacity(voices.len());
+        for mut voice in voices {
+            let stem_tx = if voice.recordings.is_empty() {
+                None
+            } else {
+                let (stem_tx, _) = broadcast::channel(CHANNEL_MAX_BUFFER);
+                stem_recorders.push(RecorderSet::connect(
+                    std::mem::take(&mut voice.recordings),
+                    num_channels,
+                    sample_hz,
+                    &stem_tx,
+                )?);
 
-        // Get ahead of the CPAL buffering.
-        // The synthesizer thread will attempt to queue samples ahead of the audio output
-        // thread. This represents an additional fixed latency of:
-        //     2 buffers * 512 samples per channel * (1 / 44100) seconds = 0.02 seconds
-        const BUFFERS_AHEAD: u32 = 2;
-        for _ in 0..BUFFERS_AHEAD {
-            let frame = synth.sample_notes(num_channels as usize);
-            if frame_tx.send(frame).is_err() {
-                panic!("Failed to send audio frame");
-            }
+                Some(stem_tx)
+            };
+            bus_voices.push((voice, stem_tx));
         }
+        let frame_size = Config::load_default().frame_size();
+        let mut bus = MixBus::new(bus_voices, sample_hz as f32, frame_size, note_event_tx);
+        bus.prepare(sample_hz as f32, num_channels);
+        let has_recordings = !recorders.is_empty() || !stem_recorders.is_empty();
+        let mut scheduler = MixScheduler {
+            bus,
+            midi_input_stream,
+            buffer_request_rx,
+            frame_tx: frame_tx.clone(),
+            num_channels,
+            has_recordings,
+        };
+
+        // Get ahead of the CPAL buffering.
+        scheduler.get_ahead();
 
         (
-            synth,
-            recorder,
+            scheduler,
+            recorders,
+            stem_recorders,
             SafeAudioStream::new(audio_output_stream),
-            num_channels,
         )
     };
 
-    audio_output_stream.play();
+    // Whatever stopped playback early. The recordings are still finished.
+    let mut result = audio_output_stream.play();
+    if result.is_ok() {
+        let (restart_tx, restart_rx) = mpsc::unbounded_channel();
+        select! {
+            _ = scheduler.run(restart_rx) => (),
+            output_result = supervise_output(
+                &mut audio_output_stream,
+                frame_tx,
+                reconnect_buffer_request_tx,
+                restart_tx,
+            ) => result = output_result,
+            _ = cancel.cancelled() => (),
+        };
+    }
+    let paused = audio_output_stream.pause();
+    audio_output_stream.log_callback_problems();
+
+    scheduler.bus.log_peak_polyphony();
+    #[cfg(feature = "realtime-audit")]
+    crate::realtime_audit::log_violations();
+
+    // Tear down.
+    if !recorders.is_empty() {
+        log::debug!("Waiting for {} recorders to drain", recorders.len());
+    }
+    let recorded = recorders.close().await;
+    let stems_recorded: Result<()> = join_all(stem_recorders.into_iter().map(RecorderSet::close))
+        .await
+        .into_iter()
+        .collect();
+
+    result.and(paused).and(recorded).and(stems_recorded)
+}
+
+/// Watches the output stream, rebuilding it if the device changes rate, or fails and the config's
+/// reconnect policy allows another try. Each rebuilt stream goes to the scheduler on `restart_tx`,
+/// and plays once the scheduler has frames ready for it. Only returns once the output has failed
+/// for good.
+async fn supervise_output(
+    audio_output_stream: &mut SafeAudioStream,
+    frame_tx: broadcast::Sender<TimedFrame>,
+    buffer_request_tx: mpsc::Sender<()>,
+    restart_tx: mpsc::UnboundedSender<Restart>,
+) -> Result<()> {
+    let restart = |config: StreamConfig| async {
+        let (ready_tx, ready_rx) = oneshot::channel();
+        // The scheduler only hangs up when playback is over anyway.
+        if restart_tx.send((config, ready_tx)).is_ok() {
+            let _ = ready_rx.await;
+        }
+    };
+    let mut sample_rate_poll = interval(SAMPLE_RATE_POLL_INTERVAL);
+    let reconnect_policy = Config::load_default().reconnect;
+    let mut stream_errors = audio_output_stream.take_error_receiver();
+    // While there's no stream, nothing asks for frames, so the synths wait where they are.
+    let mut failed_output: Option<FailedOutput> = None;
     loop {
         select! {
-            maybe_raw_message = midi_input_stream.next() => {
-                if let Some(raw_message) = maybe_raw_message {
-                    synth.handle_midi_message(raw_message);
-                } else {
-                    break;
+            _ = sample_rate_poll.tick(), if failed_output.is_none() => {
+                audio_output_stream.log_callback_problems();
+                if let Some(sample_hz) = audio_output_stream.changed_device_sample_hz() {
+                    log::warn!("Output device changed to {} Hz, reconnecting", sample_hz);
+                    let config = audio_output_stream.reconnect_at(
+                        sample_hz,
+                        frame_tx.subscribe(),
+                        buffer_request_tx.clone(),
+                    )?;
+                    stream_errors = audio_output_stream.take_error_receiver();
+                    restart(config).await;
+                    audio_output_stream.play()?;
                 }
             },
-            item = buffer_request_rx.recv() => {
-                item.expect("Couldn't receive buffer request.");
-                let frame = synth.sample_notes(num_channels as usize);
-                if frame_tx.send(frame).is_err() {
-                    panic!("Failed to send audio frame");
+            Some(e) = next_stream_error(&mut stream_errors), if failed_output.is_none() => {
+                let (profile, max_frame_age) = audio_output_stream.close();
+                stream_errors = None;
+                let delay = reconnect_policy.delay(0).ok_or(e)?;
+                log::warn!("Lost {}, reconnecting", profile.device_name);
+                failed_output = Some(FailedOutput {
+                    profile,
+                    max_frame_age,
+                    attempt: 0,
+                    next_attempt: Instant::now() + delay,
+                });
+            },
+            _ = delay_until(
+                failed_output.as_ref().map_or_else(Instant::now, |f| f.next_attempt).into()
+            ), if failed_output.is_some() => {
+                let failed = failed_output.as_mut().expect("No failed output to reconnect");
+                match audio_output_stream.reconnect(
+                    failed,
+                    frame_tx.subscribe(),
+                    buffer_request_tx.clone(),
+                ) {
+                    Ok(config) => {
+                        log::info!(
+                            "Reconnected to {} after {} attempts",
+                            failed.profile.device_name,
+                            failed.attempt + 1
+                        );
+                        failed_output = None;
+                        stream_errors = audio_output_stream.take_error_receiver();
+                        restart(config).await;
+                        audio_output_stream.play()?;
+                    }
+                    Err(e) => {
+                        failed.attempt += 1;
+                        let delay = reconnect_policy.delay(failed.attempt).ok_or(e)?;
+                        failed.next_attempt = Instant::now() + delay;
+                    }
                 }
             },
         };
     }
-    audio_output_stream.pause();
+}
 
-    // Tear down.
-    if let Some(r) = recorder {
-        log::debug!("Waiting for recorder to drain");
-        r.close().await;
+/// A rebuilt output stream's config, and where to say there are frames ready for it.
+type Restart = (StreamConfig, oneshot::Sender<()>);
+
+/// Answers the output's buffer requests with frames from the mix, and applies MIDI in between.
+/// It has no device of its own, so anything that asks for frames can drive it.
+struct MixScheduler<S> {
+    bus: MixBus,
+    midi_input_stream: S,
+    buffer_request_rx: mpsc::Receiver<()>,
+    frame_tx: broadcast::Sender<TimedFrame>,
+    num_channels: u16,
+    has_recordings: bool,
+}
+
+impl<S> MixScheduler<S>
+where
+    S: Stream<Item = (usize, RawMidiMessage)> + Unpin,
+{
+    /// Queues frames ahead of the output's first requests.
+    fn get_ahead(&mut self) {
+        for _ in 0..BUFFERS_AHEAD {
+            self.bus.send_frame(&self.frame_tx, self.num_channels);
+        }
+    }
+
+    /// Returns false if nothing is listening for frames anymore.
+    fn send_frame(&mut self) -> bool {
+        let sent = self.bus.send_frame(&self.frame_tx, self.num_channels);
+        if !sent {
+            log::warn!("Nothing is taking audio frames anymore, stopping");
+        }
+
+        sent
+    }
+
+    /// Sends a frame for every buffer request waiting. Returns false if the output has stopped
+    /// asking for or taking frames.
+    fn answer_buffer_requests(&mut self) -> bool {
+        loop {
+            match self.buffer_request_rx.try_recv() {
+                Ok(()) => {
+                    if !self.send_frame() {
+                        return false;
+                    }
+                }
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Closed) => {
+                    log::warn!("The audio output stopped asking for frames, stopping");
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Runs until the MIDI input ends or the output stops asking for or taking frames. Streams
+    /// rebuilt by `supervise_output` arrive on `restart_rx`.
+    async fn run(&mut self, mut restart_rx: mpsc::UnboundedReceiver<Restart>) {
+        loop {
+            // Frames come first. A late frame is an audible dropout, while a late MIDI message is
+            // only late by a fraction of a frame.
+            if !self.answer_buffer_requests() {
+                return;
+            }
+
+            select! {
+                maybe_raw_message = self.midi_input_stream.next() => {
+                    // The select may have picked the MIDI over a request that was also waiting.
+                    if !self.answer_buffer_requests() {
+                        return;
+                    }
+                    match maybe_raw_message {
+                        Some((track_i, raw_message)) => {
+                            self.bus.handle_midi_message(track_i, raw_message)
+                        }
+                        None => return,
+                    }
+                    // Apply everything that has already arrived in one go, so a burst lands in
+                    // the same frame. The batch is capped so a flood can't hold off the next
+                    // buffer request for long.
+                    for _ in 0..MIDI_BATCH_MAX {
+                        match self.midi_input_stream.next().now_or_never() {
+                            Some(Some((track_i, raw_message))) => {
+                                self.bus.handle_midi_message(track_i, raw_message)
+                            }
+                            Some(None) => return,
+                            None => break,
+                        }
+                    }
+                },
+                item = self.buffer_request_rx.recv() => {
+                    if item.is_none() {
+                        log::warn!("The audio output stopped asking for frames, stopping");
+                        return;
+                    }
+                    if !self.send_frame() {
+                        return;
+                    }
+                },
+                Some((config, ready_tx)) = restart_rx.recv() => {
+                    self.num_channels = self.bus.restart(
+                        &config,
+                        &self.frame_tx,
+                        self.num_channels,
+                        self.has_recordings,
+                    );
+                    let _ = ready_tx.send(());
+                },
+            };
+        }
+    }
+}
+
+/// Synths, each with its own effects, whose outputs are summed into one stream of frames.
+struct MixBus {
+    tracks: Vec<(Synthesizer, EffectsChain)>,
+    /// The tempo of the MIDI clock on each track's input, for its effects.
+    clock_tempos: Vec<MidiClockTempo>,
+    controls: Vec<ControlBindings>,
+    /// Where each track's own frames go, if it is being recorded alone.
+    stem_txs: Vec<Option<broadcast::Sender<TimedFrame>>>,
+    /// Only for a mix of two or more tracks, which can add up past full scale.
+    limiter: Option<Limiter>,
+    frame_pool: FramePool,
+}
+
+impl MixBus {
+    fn new(
+        voices: Vec<(MixTrack<()>, Option<broadcast::Sender<TimedFrame>>)>,
+        sample_hz: f32,
+        frame_size: usize,
+        note_event_tx: Option<broadcast::Sender<NoteEvent>>,
+    ) -> Self {
+        let limiter = if voices.len() > 1 {
+            Some(Limiter::new())
+        } else {
+            None
+        };
+        let mut tracks = Vec::with_capacity(voices.len());
+        let mut stem_txs = Vec::with_capacity(voices.len());
+        let mut controls = Vec::with_capacity(voices.len());
+        for (voice, stem_tx) in voices {
+            let mut synth = Synthesizer::new(sample_hz, voice.source);
+            synth.set_frame_size(frame_size);
+            synth.set_voice_limits(voice.voice_limits);
+            synth.set_seed(voice.seed);
+            if let Some(tx) = &note_event_tx {
+                synth.set_note_event_sender(tx.clone());
+            }
+            tracks.push((synth, voice.effects));
+            controls.push(voice.controls);
+            stem_txs.push(stem_tx);
+        }
+
+        MixBus {
+            clock_tempos: vec![MidiClockTempo::new(); tracks.len()],
+            tracks,
+            controls,
+            stem_txs,
+            limiter,
+            frame_pool: FramePool::new(),
+        }
+    }
+
+    fn prepare(&mut self, sample_hz: f32, num_channels: u16) {
+        for (synth, effects) in self.tracks.iter_mut() {
+            synth.set_sample_hz(sample_hz);
+            effects.prepare(sample_hz, num_channels as usize);
+        }
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.prepare(sample_hz, num_channels as usize);
+        }
+    }
+
+    /// Starts over on a rebuilt output stream, which may have a different number of channels than
+    /// `num_channels`. Returns the new number of channels.
+    fn restart(
+        &mut self,
+        config: &StreamConfig,
+        frame_tx: &broadcast::Sender<TimedFrame>,
+        num_channels: u16,
+        has_recordings: bool,
+    ) -> u16 {
+        // The render rate stays the same, but the device may have fewer channels.
+        if config.channels != num_channels && has_recordings {
+            log::warn!(
+                "Recordings keep their original format, so they won't match the audio from here on"
+            );
+        }
+        self.prepare(RENDER_SAMPLE_HZ as f32, config.channels);
+        for _ in 0..BUFFERS_AHEAD {
+            self.send_frame(frame_tx, config.channels);
+        }
+
+        config.channels
+    }
+
+    fn handle_midi_message(&mut self, track_i: usize, raw_message: RawMidiMessage) {
+        let (timestamp, message) = &raw_message;
+        let clock_tempo = &mut self.clock_tempos[track_i];
+        match message.first() {
+            // Timing Clock, stamped in microseconds.
+            Some(0xF8) => {
+                if let Some(bpm) = clock_tempo.pulse(Duration::from_micros(*timestamp)) {
+                    self.tracks[track_i].1.set_tempo(bpm as f32);
+                }
+            }
+            // Stop
+            Some(0xFC) => clock_tempo.pause(),
+            // Control Change, which is the bound parameter's instead of the synth's if bound.
+            Some(status @ 0xB0..=0xBF) if message.len() == 3 => {
+                let (synth, effects) = &mut self.tracks[track_i];
+                let value = message[2] as f32 / 127.0;
+                let bound = self.controls[track_i].handle(status & 0x0F, message[1], |parameter| {
+                    synth.set_parameter(parameter, value);
+                    effects.set_parameter(parameter, value);
+                });
+                if bound {
+                    return;
+                }
+            }
+            _ => (),
+        }
+        self.tracks[track_i].0.handle_midi_message(raw_message);
+    }
+
+    /// Returns false if nothing is listening for frames anymore.
+    fn send_frame(&mut self, frame_tx: &broadcast::Sender<TimedFrame>, num_channels: u16) -> bool {
+        // Every synth renders the same frames, so any of their clocks will do.
+        let position = self.tracks[0].0.clock().position();
+        let rendered_at = Instant::now();
+        let mut mix: Option<AudioFrame> = None;
+        for (((synth, effects), controls), stem_tx) in self
+            .tracks
+            .iter_mut()
+            .zip(self.controls.iter())
+            .zip(self.stem_txs.iter())
+        {
+            controls.take_moved(|parameter, value| {
+                synth.set_parameter(parameter, value);
+                effects.set_parameter(parameter, value);
+            });
+            let mut samples = synth.sample_notes(num_channels as usize);
+            effects.process(&mut samples);
+            if let Some(stem_tx) = stem_tx {
+                let frame = TimedFrame {
+                    samples: samples.as_slice().into(),
+                    position,
+                    rendered_at,
+                };
+                // Nothing else listens, so this only fails once its recorders have stopped.
+                let _ = stem_tx.send(frame);
+            }
+            match mix.as_mut() {
+                Some(mix) => {
+                    for (m, s) in mix.iter_mut().zip(samples.iter()) {
+                        *m += s;
+                    }
+                }
+                None => mix = Some(samples),
+            }
+        }
+        let mut samples = mix.expect("Mix has no tracks");
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.process(&mut samples);
+        }
+        let frame = TimedFrame {
+            samples: self.frame_pool.frame(&samples),
+            position,
+            rendered_at,
+        };
+
+        frame_tx.send(frame).is_ok()
+    }
+
+    fn log_peak_polyphony(&self) {
+        let multiple_tracks = self.tracks.len() > 1;
+        for (track_i, (synth, _)) in self.tracks.iter().enumerate() {
+            for (channel, peak) in synth.peak_polyphony().iter().enumerate() {
+                if *peak == 0 {
+                    continue;
+                }
+                if multiple_tracks {
+                    log::info!(
+                        "Track {} channel {} peak polyphony: {}",
+                        track_i,
+                        channel,
+                        peak
+                    );
+                } else {
+                    log::info!("Channel {} peak polyphony: {}", channel, peak);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{wave_table::sine_wave, FRAME_SIZE};
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::task::Poll;
+
+    /// Frames the fake device plays in the flood test.
+    const FLOOD_FRAMES: usize = 200;
+    /// MIDI messages that arrive in each frame period of the flood, more than fit in one batch.
+    const FLOOD_MESSAGES_PER_FRAME: usize = 3 * MIDI_BATCH_MAX;
+
+    /// A scheduler for one sine synth, with its MIDI input already applied.
+    fn scheduler<S>(
+        midi_input_stream: S,
+        buffer_request_rx: mpsc::Receiver<()>,
+        frame_tx: broadcast::Sender<TimedFrame>,
+    ) -> MixScheduler<S> {
+        let source = Source::from(sine_wave());
+        let track = MixTrack {
+            input: (),
+            source,
+            effects: EffectsChain::new(),
+            recordings: Vec::new(),
+            voice_limits: VoiceLimits::default(),
+            seed: 0,
+            controls: ControlBindings::of_source(source),
+        };
+        let mut bus = MixBus::new(
+            vec![(track, None)],
+            RENDER_SAMPLE_HZ as f32,
+            FRAME_SIZE,
+            None,
+        );
+        bus.prepare(RENDER_SAMPLE_HZ as f32, 2);
+
+        MixScheduler {
+            bus,
+            midi_input_stream,
+            buffer_request_rx,
+            frame_tx,
+            num_channels: 2,
+            has_recordings: false,
+        }
+    }
+
+    /// MIDI that is always ready floods in, and the fake device steps once every
+    /// `FLOOD_MESSAGES_PER_FRAME` messages rather than once a frame period: it takes a frame and
+    /// asks for another. Every frame has to be there when the device wants it, and every request
+    /// has to be answered before the next batch of MIDI. The first message after a step may
+    /// already be taken before the scheduler sees the request, so the second one is where the
+    /// answer has to be.
+    #[tokio::test]
+    async fn answers_buffer_requests_under_a_midi_flood() {
+        let (frame_tx, mut frame_rx) = broadcast::channel(CHANNEL_MAX_BUFFER);
+        // Sees every frame the scheduler sends, to count the answered requests.
+        let mut answer_rx = frame_tx.subscribe();
+        let (mut buffer_request_tx, buffer_request_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
+        let underruns = Rc::new(Cell::new(0));
+        let unanswered = Rc::new(Cell::new(0));
+        let requests = Rc::new(Cell::new(0));
+        let (device_underruns, device_unanswered, device_requests) =
+            (underruns.clone(), unanswered.clone(), requests.clone());
+
+        // Note on and off for a few keys, over and over.
+        let mut flood = (0..8u8)
+            .flat_map(|key| vec![[0x90, 60 + key, 100], [0x80, 60 + key, 0]])
+            .cycle();
+        let mut answered = 0;
+        let mut messages = 0;
+        let mut next_step = 0;
+        let mut check_at = None;
+        let midi_input_stream = futures::stream::poll_fn(move |cx| {
+            if messages == next_step {
+                if device_requests.get() == FLOOD_FRAMES {
+                    return Poll::Ready(None);
+                }
+
+                // One step of the device, then a pause in the MIDI so the scheduler can answer.
+                if frame_rx.try_recv().is_err() {
+                    device_underruns.set(device_underruns.get() + 1);
+                }
+                buffer_request_tx
+                    .try_send(())
+                    .expect("The scheduler stopped taking buffer requests");
+                device_requests.set(device_requests.get() + 1);
+                next_step += FLOOD_MESSAGES_PER_FRAME;
+                check_at = Some(messages + 1);
+                cx.waker().wake_by_ref();
+
+                return Poll::Pending;
+            }
+            if check_at == Some(messages) {
+                while answer_rx.try_recv().is_ok() {
+                    answered += 1;
+                }
+                if answered < BUFFERS_AHEAD as usize + device_requests.get() {
+                    device_unanswered.set(device_unanswered.get() + 1);
+                }
+            }
+            messages += 1;
+
+            Poll::Ready(Some((
+                0,
+                (0, MidiMessageBytes::from(flood.next().unwrap())),
+            )))
+        });
+        let mut scheduler = scheduler(midi_input_stream, buffer_request_rx, frame_tx);
+        scheduler.get_ahead();
+
+        let (_, restart_rx) = mpsc::unbounded_channel();
+        scheduler.run(restart_rx).await;
+
+        assert_eq!(requests.get(), FLOOD_FRAMES);
+        assert_eq!(underruns.get(), 0);
+        assert_eq!(unanswered.get(), 0);
     }
 }
@@ -0,0 +1,131 @@
+//! Which instrument plays each track of a file, chosen in a TOML file like:
+//!
+//! ```toml
+//! # For tracks without their own instrument. Without a default, they cycle through the usual
+//! # instruments.
+//! default = "triangle"
+//!
+//! [tracks]
+//! 0 = "sawtooth"
+//! 1 = { patch = "patches/lead.toml" }
+//! 2 = "piano.sf2"
+//! ```
+//!
+//! Tracks count from 0. An instrument is anything `--wave` takes: a built-in wave, a single-cycle
+//! WAV file, a soundfont or a sampler key map. Synth patches are written as `{ patch = "..." }`,
+//! since they are TOML too. Paths are relative to the map.
+
+use crate::{oscillator::Source, patch::SynthPatch};
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InstrumentMap {
+    pub default: Option<InstrumentChoice>,
+    pub tracks: BTreeMap<usize, InstrumentChoice>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum InstrumentChoice {
+    /// Anything `Source::by_name_or_path` takes.
+    Source(String),
+    /// A synth patch file, whose effects go on the track.
+    Patch { patch: PathBuf },
+}
+
+/// The file as written, with tracks still as strings, since TOML keys always are.
+#[derive(Deserialize)]
+struct InstrumentMapFile {
+    #[serde(default)]
+    default: Option<InstrumentChoice>,
+    #[serde(default)]
+    tracks: BTreeMap<String, InstrumentChoice>,
+}
+
+impl InstrumentMap {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let file: InstrumentMapFile =
+            toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let mut tracks = BTreeMap::new();
+        for (track, choice) in file.tracks {
+            let track = track.trim().parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} is not a track number", track),
+                )
+            })?;
+            tracks.insert(track, choice.relative_to(dir));
+        }
+
+        Ok(InstrumentMap {
+            default: file.default.map(|choice| choice.relative_to(dir)),
+            tracks,
+        })
+    }
+
+    /// One instrument for each of `num_tracks` tracks, for `play_all_midi_tracks`. Tracks without
+    /// an instrument of their own get the default, or `fallback[i % fallback.len()]` for track `i`
+    /// if there isn't one. Every instrument is loaded once, however many tracks play it.
+    pub fn track_instruments(
+        &self,
+        num_tracks: usize,
+        fallback: &[Source],
+    ) -> io::Result<Vec<Source>> {
+        let mut loaded = Vec::new();
+        let default = match &self.default {
+            Some(choice) => Some(load_once(&mut loaded, choice)?),
+            None => None,
+        };
+        (0..num_tracks)
+            .map(|track| match (self.tracks.get(&track), default) {
+                (Some(choice), _) => load_once(&mut loaded, choice),
+                (None, Some(default)) => Ok(default),
+                (None, None) => Ok(fallback[track % fallback.len()]),
+            })
+            .collect()
+    }
+}
+
+/// Loads `choice`, unless it is already in `loaded`.
+fn load_once<'a>(
+    loaded: &mut Vec<(&'a InstrumentChoice, Source)>,
+    choice: &'a InstrumentChoice,
+) -> io::Result<Source> {
+    if let Some((_, source)) = loaded.iter().find(|(c, _)| *c == choice) {
+        return Ok(*source);
+    }
+    let source = choice.load()?;
+    loaded.push((choice, source));
+
+    Ok(source)
+}
+
+impl InstrumentChoice {
+    fn relative_to(self, dir: &Path) -> Self {
+        match self {
+            InstrumentChoice::Source(s) if Source::by_name(&s).is_none() => {
+                InstrumentChoice::Source(dir.join(s).to_string_lossy().into_owned())
+            }
+            InstrumentChoice::Patch { patch } => InstrumentChoice::Patch {
+                patch: dir.join(patch),
+            },
+            other => other,
+        }
+    }
+
+    fn load(&self) -> io::Result<Source> {
+        match self {
+            InstrumentChoice::Source(s) => Source::by_name_or_path(s)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            InstrumentChoice::Patch { patch } => SynthPatch::load(patch)?.instrument(),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Names for the JACK ports the synth plays on, so patchbays show `nocturne:out_l` and
+//! `nocturne:out_r`.
+//!
+//! cpal registers its output as a client called `cpal_client_out`, with ports `out_0`, `out_1` and
+//! so on, and doesn't let either be renamed. JACK lets any client give a port up to two aliases
+//! though, so nocturne's names go there. Patchbays like qjackctl and Carla can show aliases, as can
+//! `jack_lsp -A`, and `jack_connect` accepts them.
+//!
+//! Under JACK the server decides the latency. The stream runs at the server's rate and buffer size,
+//! whatever the profile in the config file says, so the synth is resampled to the server's rate and
+//! the latency is the server's period plus the synth's own frame.
+
+use jack::{Client, ClientOptions, PortFlags};
+use log::warn;
+
+/// cpal's output ports. JACK adds a suffix to the client name if another nocturne is running.
+const CPAL_OUTPUT_PORTS: &str = "^cpal_client_out[^:]*:out_[0-9]+$";
+
+/// Aliases every output port cpal has registered. Ports only exist once the stream is built.
+pub(crate) fn alias_output_ports() {
+    // Only for as long as it takes to set the aliases, which stay with the ports.
+    let client = match Client::new("nocturne_ports", ClientOptions::NO_START_SERVER) {
+        Ok((client, _status)) => client,
+        Err(e) => {
+            warn!("Failed to name the JACK output ports: {}", e);
+            return;
+        }
+    };
+    let mut names = client.ports(Some(CPAL_OUTPUT_PORTS), None, PortFlags::IS_OUTPUT);
+    // By number, so out_10 comes after out_9.
+    names.sort_by_key(|name| port_number(name));
+
+    let num_ports = names.len();
+    for (i, name) in names.iter().enumerate() {
+        let mut port = match client.port_by_name(name) {
+            Some(port) => port,
+            None => continue,
+        };
+        let alias = format!("nocturne:{}", channel_name(i, num_ports));
+        if let Err(e) = port.set_alias(&alias) {
+            warn!("Failed to alias JACK port {} as {}: {}", name, alias, e);
+        }
+    }
+}
+
+fn port_number(name: &str) -> usize {
+    name.rsplit('_')
+        .next()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(usize::MAX)
+}
+
+/// `out_l` and `out_r` for stereo, and numbered from 1 otherwise.
+fn channel_name(channel: usize, num_channels: usize) -> String {
+    match (num_channels, channel) {
+        (1, _) => "out".to_string(),
+        (2, 0) => "out_l".to_string(),
+        (2, 1) => "out_r".to_string(),
+        _ => format!("out_{}", channel + 1),
+    }
+}
@@ -0,0 +1,198 @@
+//! A journal of live MIDI input, written whether or not the audio is being recorded, so a good take
+//! can be recovered and rendered afterwards.
+//!
+//! Each session gets its own file of records: the message's timestamp in microseconds (little
+//! endian `u64`), its length (little endian `u32`) and then its bytes, so SysEx is kept whole.
+//! Records are flushed as they arrive, so a journal survives the process dying. Journals from
+//! before SysEx was kept, whose records are all three bytes with no length, can still be read.
+
+use crate::midi::{save_timed_messages_at_tempo, MidiMessageBytes, RawMidiMessage};
+
+use log::{info, warn};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use time_calc::Bpm;
+
+const JOURNAL_EXTENSION: &str = "midilog";
+const JOURNAL_MAGIC: &[u8; 8] = b"NOCJRNL2";
+const JOURNAL_HEADER_SIZE: usize = 12;
+/// Fixed-size records of a timestamp and three bytes, with shorter messages padded with zeros.
+const JOURNAL_V1_MAGIC: &[u8; 8] = b"NOCJRNL1";
+const JOURNAL_V1_RECORD_SIZE: usize = 11;
+
+/// Older sessions are deleted when a new one starts.
+const JOURNAL_SESSIONS_KEPT: usize = 20;
+
+pub struct MidiJournal {
+    writer: BufWriter<File>,
+    path: PathBuf,
+}
+
+impl MidiJournal {
+    /// `$XDG_DATA_HOME/nocturne/journal` or the platform equivalent.
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::data_local_dir().map(|d| d.join("nocturne").join("journal"))
+    }
+
+    /// Starts a new session journal in the default directory.
+    pub fn create_default() -> io::Result<Self> {
+        let dir = Self::default_dir().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "No data directory on this platform",
+            )
+        })?;
+
+        Self::create_in(&dir)
+    }
+
+    /// Starts a new session journal in `dir`, deleting the oldest sessions there so only the most
+    /// recent `JOURNAL_SESSIONS_KEPT` remain.
+    pub fn create_in(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut sessions = journal_files(dir)?;
+        while sessions.len() >= JOURNAL_SESSIONS_KEPT {
+            let oldest = sessions.remove(0);
+            if let Err(e) = fs::remove_file(&oldest) {
+                warn!("Failed to remove old MIDI journal {:?}: {}", oldest, e);
+            }
+        }
+
+        // Zero-padded so the names sort in the order the sessions started.
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = dir.join(format!("{:016}.{}", started, JOURNAL_EXTENSION));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        writer.write_all(JOURNAL_MAGIC)?;
+        writer.flush()?;
+        info!("Journaling MIDI input to {:?}", path);
+
+        Ok(MidiJournal { writer, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&mut self, (timestamp, message): &RawMidiMessage) -> io::Result<()> {
+        self.writer.write_all(&timestamp.to_le_bytes())?;
+        self.writer
+            .write_all(&(message.len() as u32).to_le_bytes())?;
+        self.writer.write_all(message)?;
+
+        self.writer.flush()
+    }
+}
+
+/// Session journals in `dir`, oldest first.
+fn journal_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == JOURNAL_EXTENSION))
+        .collect();
+    files.sort();
+
+    Ok(files)
+}
+
+/// The most recently started session journal in `dir`, if there are any.
+pub fn last_session_journal(dir: &Path) -> io::Result<Option<PathBuf>> {
+    Ok(journal_files(dir)?.pop())
+}
+
+/// Reads every complete record of a journal. A truncated final record is dropped.
+pub fn read_journal(path: &Path) -> io::Result<Vec<RawMidiMessage>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.starts_with(JOURNAL_V1_MAGIC) {
+        return Ok(read_v1_records(&bytes[JOURNAL_V1_MAGIC.len()..]));
+    }
+    if !bytes.starts_with(JOURNAL_MAGIC) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a nocturne MIDI journal",
+        ));
+    }
+
+    let mut messages = Vec::new();
+    let mut records = &bytes[JOURNAL_MAGIC.len()..];
+    while records.len() >= JOURNAL_HEADER_SIZE {
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&records[..8]);
+        let mut len = [0; 4];
+        len.copy_from_slice(&records[8..JOURNAL_HEADER_SIZE]);
+        let end = JOURNAL_HEADER_SIZE + u32::from_le_bytes(len) as usize;
+        if records.len() < end {
+            break;
+        }
+        messages.push((
+            u64::from_le_bytes(timestamp),
+            MidiMessageBytes::new(&records[JOURNAL_HEADER_SIZE..end]),
+        ));
+        records = &records[end..];
+    }
+
+    Ok(messages)
+}
+
+fn read_v1_records(records: &[u8]) -> Vec<RawMidiMessage> {
+    records
+        .chunks_exact(JOURNAL_V1_RECORD_SIZE)
+        .map(|record| {
+            let mut timestamp = [0; 8];
+            timestamp.copy_from_slice(&record[..8]);
+            let message = &record[8..];
+            let len = padded_message_len(message[0]);
+
+            (
+                u64::from_le_bytes(timestamp),
+                MidiMessageBytes::new(&message[..len]),
+            )
+        })
+        .collect()
+}
+
+/// How many of a padded message's three bytes are its own.
+fn padded_message_len(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 2,
+        0x80..=0xEF | 0xF2 => 3,
+        _ => 1,
+    }
+}
+
+/// Converts the most recent session journal in `dir` to a MIDI file at `output_path`, timed from
+/// the first message, and returns the journal it used. Play the file back at 120 BPM to hear the
+/// take as it was played.
+pub fn recover_last_session(dir: &Path, output_path: &Path) -> io::Result<PathBuf> {
+    recover_last_session_at_tempo(dir, 120.0, output_path)
+}
+
+/// Like `recover_last_session`, for a session played at `bpm`. The file's beats line up with the
+/// session's, and playing it back at `bpm` gives the take as it was played, bends and all.
+pub fn recover_last_session_at_tempo(
+    dir: &Path,
+    bpm: Bpm,
+    output_path: &Path,
+) -> io::Result<PathBuf> {
+    let journal = last_session_journal(dir)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "No MIDI journals have been written",
+        )
+    })?;
+    let messages = read_journal(&journal)?;
+    let start = messages.first().map_or(0, |(t, _)| *t);
+    let timed: Vec<(Duration, MidiMessageBytes)> = messages
+        .into_iter()
+        .map(|(t, m)| (Duration::from_micros(t.saturating_sub(start)), m))
+        .collect();
+    save_timed_messages_at_tempo(&timed, bpm, output_path)?;
+
+    Ok(journal)
+}
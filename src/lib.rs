@@ -1,26 +1,158 @@
 mod audio_device;
+mod audio_input;
+mod audition;
+mod cancel;
+mod clock;
+mod config;
+mod controls;
+mod drums;
+mod effects;
+mod engine;
 mod ensemble;
+mod envelope;
+mod error;
 mod filters;
+mod general_midi;
 mod instrument;
+mod instrument_map;
+#[cfg(feature = "jack")]
+mod jack_ports;
+mod journal;
 mod midi;
+mod midi_filter;
+mod monitor;
+mod naming;
+#[cfg(feature = "opus")]
+mod opus;
+mod osc;
+pub mod oscillator;
+mod patch;
+mod performance;
+mod practice;
+#[cfg(any(test, feature = "realtime-audit"))]
+mod realtime_audit;
 mod recording;
+mod render;
+mod rng;
+mod routing;
+mod sampler;
+mod soundfont;
+mod spectrogram;
+mod step_sequencer;
 mod synthesizer;
+mod timecode;
+mod tracker;
+mod voice_pool;
+mod wav;
 pub mod wave_table;
 
-/// Static sized frames for all internal audio buffering. (External frames are configurable by the
-/// audio devices).
-const FRAME_SIZE: usize = 512;
-type AudioFrame = [f32; FRAME_SIZE];
+/// The usual length of internal audio frames, in interleaved samples. (External frames are
+/// configurable by the audio devices.) Live playing can use shorter frames for less latency, with
+/// `Config::frame_size`, and offline rendering longer ones to go faster, with
+/// `RenderOptions::frame_size`.
+pub const FRAME_SIZE: usize = 512;
+/// Frame sizes are clamped to this range.
+pub const MIN_FRAME_SIZE: usize = 32;
+pub const MAX_FRAME_SIZE: usize = 8192;
+
+/// Interleaved samples, as many whole sample frames as fit in the frame size.
+pub type AudioFrame = Vec<f32>;
+
+/// A frame on its way from a synthesizer to the audio device and recorders. Shared, so the audio
+/// thread never has to allocate or free one.
+#[derive(Clone)]
+pub struct TimedFrame {
+    pub samples: std::sync::Arc<[f32]>,
+    /// The `SampleClock` position of the frame's first sample. Consecutive frames follow on from
+    /// each other, so a jump means frames were lost in between.
+    pub position: u64,
+    /// When the frame was rendered, so consumers can tell how stale it is.
+    pub rendered_at: std::time::Instant,
+}
 
 const CHANNEL_MAX_BUFFER: usize = 50;
 
-pub use audio_device::AudioOutputDeviceStream;
-pub use ensemble::play_all_midi_tracks;
+pub use audio_device::{
+    best_audio_output_profile, choose_output_config, list_audio_output_devices,
+    probe_audio_output_profiles, use_audio_host, AudioDeviceProfile, AudioHost,
+    AudioOutputDeviceStream, OutputConfigChoice, OutputDevice, ReconnectPolicy, RENDER_SAMPLE_HZ,
+};
+pub use audio_input::{list_audio_input_devices, AudioInputDeviceStream, InputDevice};
+pub use audition::{audition, audition_phrase, render_audition};
+pub use cancel::CancellationToken;
+pub use clock::{Clock, ManualClock, ManualDelay, SampleClock, SystemClock};
+pub use config::Config;
+pub use controls::{ControlBinding, ControlBindings, SynthParameter};
+pub use effects::{
+    Bypass, Chorus, Compressor, Effect, EffectsChain, Gain, Limiter, ShaperCurve, Waveshaper,
+};
+pub use engine::{Engine, EngineBuilder};
+pub use ensemble::{
+    play_all_midi_tracks, play_all_midi_tracks_chasing_mtc,
+    play_all_midi_tracks_following_midi_clock, play_all_midi_tracks_with_effects,
+    play_all_midi_tracks_with_options, play_all_midi_tracks_with_transport, EnsembleOptions,
+};
+pub use envelope::Adsr;
+pub use error::{NocturneError, Result};
+pub use filters::{Biquad, BiquadCoefficients, BiquadKind};
+pub use general_midi::{
+    general_midi_family, general_midi_patch, GeneralMidiMap, GENERAL_MIDI_FAMILIES,
+};
 pub use instrument::{play_midi, play_midi_device};
+pub use instrument_map::{InstrumentChoice, InstrumentMap};
+pub use journal::{
+    last_session_journal, read_journal, recover_last_session, recover_last_session_at_tempo,
+    MidiJournal,
+};
 pub use midi::{
-    list_midi_input_ports, quantize_midi_tracks, single_timeline_of_events, ticks_to_duration,
-    MidiBytes, MidiInputDeviceStream, RawMidiMessage,
+    chase_mtc_midi_tracks, follow_midi_clock_midi_tracks, list_midi_input_ports,
+    list_midi_output_ports, polyphony_stats, quantize_midi_tracks, quantize_midi_tracks_with_clock,
+    quantize_midi_tracks_with_transport, save_timed_messages, save_timed_messages_at_tempo,
+    single_timeline_of_events, ticks_to_duration, LoopRegion, MidiBytes, MidiInputDeviceStream,
+    MidiMessageBytes, MidiOutputDevice, PolyphonyStats, RawMidiMessage, SequencerOptions, TempoMap,
+    TransportCommand,
+};
+pub use midi_filter::{
+    filter_midi, ChannelFilter, MidiFilter, MidiFilterChain, MidiFilterConfig, NoteRange,
+    PitchClass, Scale, ScaleQuantize, Transpose, TransposeControl, VelocityScale,
+};
+pub use monitor::monitor_audio_input;
+pub use osc::{OscCommand, OscServer};
+pub use oscillator::Source;
+pub use patch::{
+    EffectPatch, LoadedPatch, OscillatorPatch, PatchBank, PatchConstraints, SynthPatch,
+};
+pub use performance::Performance;
+pub use practice::{
+    expected_notes, practice_midi_file, score_performance, Accompaniment, ExpectedNote, PlayedNote,
+    PracticeOptions, PracticeReport,
+};
+#[cfg(feature = "realtime-audit")]
+pub use realtime_audit::{
+    assert_realtime_safe, realtime_violations, AuditedAllocator, RealtimeViolations,
+};
+pub use recording::{
+    RecorderSet, RecordingOptions, RecordingOutputStream, RecordingTarget, SilenceAction,
+    SilenceDetection,
+};
+pub use render::{
+    render_midi_to_wav, render_midi_to_wav_with_options, render_midi_to_wav_with_seed,
+    RenderOptions,
+};
+pub use routing::{MessageKind, Route, RouteFilter, RouteTarget};
+pub use sampler::{KeyMap, KeyMapLoopMode, KeyMapZone};
+pub use soundfont::SoundFont;
+pub use spectrogram::{write_midi_spectrogram, SpectrogramOptions};
+pub use step_sequencer::{Step, StepPattern, StepSequencer, STEPS};
+pub use synthesizer::{
+    NoteEvent, PressureDestination, Synthesizer, Unison, VoiceFilter, VoiceLimits,
+};
+pub use timecode::{
+    LtcEncoder, MidiClockTempo, MtcDecoder, Timecode, TimecodeRate, MIDI_CLOCK_PULSES_PER_BEAT,
+};
+pub use tracker::{play_tracker_module, render_tracker_module, TrackerModule};
+pub use wav::{save_wav, WavSampleFormat};
+pub use wave_table::{
+    from_harmonics, load_wave_from_wav, sawtooth_wave, sine_wave, square_wave, triangle_wave,
+    wave_by_name, Wave,
 };
-pub use recording::RecordingOutputStream;
-pub use synthesizer::Synthesizer;
-pub use wave_table::{sawtooth_wave, sine_wave, square_wave, triangle_wave, Wave};
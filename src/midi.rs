@@ -1,37 +1,220 @@
-use crate::CHANNEL_MAX_BUFFER;
+use crate::{
+    cancel::CancellationToken,
+    clock::{Clock, SystemClock},
+    error::{NocturneError, Result},
+    timecode::{MidiClockTempo, MtcDecoder, MIDI_CLOCK_PULSES_PER_BEAT},
+    CHANNEL_MAX_BUFFER,
+};
 
-use futures::executor::block_on;
-use log::{info, trace};
-use midly::Smf;
+use futures::{executor::block_on, future};
+use log::{info, warn};
+use midly::{EventKind, MetaMessage, MidiMessage, Smf};
 use pitch_calc::Step;
+use std::collections::HashSet;
+use std::fmt;
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read};
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use time_calc::{Bpm, Ppqn, Ticks};
-use tokio::{sync::mpsc, time::delay_for};
+use tokio::{
+    select,
+    stream::{Stream, StreamExt},
+    sync::mpsc,
+    time::{delay_for, timeout},
+};
 
 pub fn get_midi_key_hz(key: wmidi::Note) -> f32 {
     // PERF: compute note frequencies on synth creation.
-    Step(u8::from(key) as f32).to_hz().0 as f32
+    Step(u8::from(key) as f32).to_hz().0
 }
 
-pub fn list_midi_input_ports() {
-    let midi_in =
-        midir::MidiInput::new("nocturne_midi_temporary").expect("Failed to load MIDI input");
+pub fn list_midi_input_ports() -> Result<()> {
+    let midi_in = midir::MidiInput::new("nocturne_midi_temporary")?;
     println!("--- Available MIDI input ports ---");
     for (port_number, port) in midi_in.ports().iter().enumerate() {
-        println!(
-            "{}: {}",
-            port_number,
-            midi_in
-                .port_name(&port)
-                .expect("Failed to get MIDI port name")
-        );
+        // The port may have gone away since it was listed.
+        if let Ok(name) = midi_in.port_name(port) {
+            println!("{}: {}", port_number, name);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn list_midi_output_ports() -> Result<()> {
+    let midi_out =
+        midir::MidiOutput::new("nocturne_midi_temporary").map_err(NocturneError::MidiOutputInit)?;
+    println!("--- Available MIDI output ports ---");
+    for (port_number, port) in midi_out.ports().iter().enumerate() {
+        // The port may have gone away since it was listed.
+        if let Ok(name) = midi_out.port_name(port) {
+            println!("{}: {}", port_number, name);
+        }
+    }
+
+    Ok(())
+}
+
+/// A message and its timestamp in microseconds.
+pub type RawMidiMessage = (u64, MidiMessageBytes);
+
+/// The bytes of one MIDI message, however long: 1 to 3 for channel and system messages, or any
+/// number for SysEx (`0xF0` up to and including `0xF7`). Meta events from files are kept as they are
+/// stored there (`0xFF`, their type, their length and their data), which a live System Reset
+/// (a lone `0xFF`) can't be confused with.
+///
+/// Messages of up to 3 bytes are kept inline, so only SysEx and meta events allocate.
+#[derive(Clone, Eq, Hash, PartialEq)]
+pub struct MidiMessageBytes(MessageRepr);
+
+#[derive(Clone, Eq, Hash, PartialEq)]
+enum MessageRepr {
+    /// Only the first `len` bytes are used.
+    Short {
+        len: u8,
+        bytes: [u8; 3],
+    },
+    Long(Box<[u8]>),
+}
+
+impl MidiMessageBytes {
+    pub fn new(bytes: &[u8]) -> Self {
+        if bytes.len() <= 3 {
+            let mut short = [0; 3];
+            short[..bytes.len()].copy_from_slice(bytes);
+
+            MidiMessageBytes(MessageRepr::Short {
+                len: bytes.len() as u8,
+                bytes: short,
+            })
+        } else {
+            MidiMessageBytes(MessageRepr::Long(bytes.into()))
+        }
+    }
+
+    /// The wire bytes of a file event. Meta events keep their file encoding, and escapes give their
+    /// data, which is whatever the file wants sent as is.
+    pub fn from_event(kind: &EventKind<'_>) -> Self {
+        match *kind {
+            EventKind::SysEx(data) => {
+                let mut bytes = Vec::with_capacity(data.len() + 1);
+                bytes.push(0xF0);
+                bytes.extend_from_slice(data);
+
+                MidiMessageBytes(MessageRepr::Long(bytes.into()))
+            }
+            EventKind::Escape(data) => Self::new(data),
+            _ => {
+                let mut bytes = Vec::with_capacity(3);
+                kind.write(&mut None, &mut bytes)
+                    .expect("Failed to serialize MIDI message");
+
+                Self::new(&bytes)
+            }
+        }
+    }
+
+    pub fn is_sysex(&self) -> bool {
+        self.first() == Some(&0xF0)
+    }
+
+    /// A meta event from a file, rather than a System Reset.
+    pub fn is_meta(&self) -> bool {
+        self.len() > 1 && self[0] == 0xFF
+    }
+
+    /// Parses the message back into a file event, or `None` for messages that have no place in a
+    /// file, like system real-time messages.
+    pub fn to_event(&self) -> Option<EventKind<'_>> {
+        match self.first()? {
+            0x80..=0xEF => EventKind::parse(&mut &self[..], &mut None).ok(),
+            0xF0 => Some(EventKind::SysEx(&self[1..])),
+            0xFF if self.is_meta() => EventKind::parse(&mut &self[..], &mut None).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl std::ops::Deref for MidiMessageBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            MessageRepr::Short { len, bytes } => &bytes[..*len as usize],
+            MessageRepr::Long(bytes) => bytes,
+        }
+    }
+}
+
+impl AsRef<[u8]> for MidiMessageBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for MidiMessageBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02X?}", &self[..])
+    }
+}
+
+impl From<&[u8]> for MidiMessageBytes {
+    fn from(bytes: &[u8]) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for MidiMessageBytes {
+    fn from(bytes: [u8; N]) -> Self {
+        Self::new(&bytes)
+    }
+}
+
+impl From<Vec<u8>> for MidiMessageBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        if bytes.len() <= 3 {
+            Self::new(&bytes)
+        } else {
+            MidiMessageBytes(MessageRepr::Long(bytes.into_boxed_slice()))
+        }
     }
 }
 
-pub type RawMidiMessage = (u64, [u8; 3]);
+/// A MIDI output port, for passing messages on to other instruments.
+pub struct MidiOutputDevice {
+    connection: midir::MidiOutputConnection,
+}
+
+impl MidiOutputDevice {
+    pub fn connect(port_number: usize) -> Result<Self> {
+        let midi_out = midir::MidiOutput::new(&format!("nocturne_midi_out_{}", port_number))
+            .map_err(NocturneError::MidiOutputInit)?;
+        let ports = midi_out.ports();
+        let port = ports
+            .get(port_number)
+            .ok_or(NocturneError::NoMidiOutputPort(port_number))?;
+        let connection = midi_out.connect(port, "midi_output_connection")?;
+
+        Ok(MidiOutputDevice { connection })
+    }
+
+    /// Meta events only mean something in files, so they aren't sent.
+    pub fn send(
+        &mut self,
+        message: &MidiMessageBytes,
+    ) -> std::result::Result<(), midir::SendError> {
+        if message.is_meta() {
+            return Ok(());
+        }
+
+        self.connection.send(message)
+    }
+
+    pub fn close(self) {
+        self.connection.close();
+    }
+}
 
 pub struct MidiInputDeviceStream {
     pub connection: midir::MidiInputConnection<()>,
@@ -39,28 +222,70 @@ pub struct MidiInputDeviceStream {
 }
 
 impl MidiInputDeviceStream {
-    pub fn connect(port_number: usize) -> Result<Self, midir::ConnectError<midir::MidiInput>> {
-        let (mut message_tx, message_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
+    pub fn connect(port_number: usize) -> Result<Self> {
+        let midi_in = midir::MidiInput::new(&format!("nocturne_midi_{}", port_number))?;
+        let port = midi_in
+            .ports()
+            .get(port_number)
+            .cloned()
+            .ok_or(NocturneError::NoMidiPort(port_number))?;
 
-        let mut midi_in = midir::MidiInput::new(&format!("nocturne_midi_{}", port_number))
-            .expect("Failed to initialize MidiInput");
-        midi_in.ignore(midir::Ignore::None);
+        Self::connect_to(midi_in, &port)
+    }
 
-        let ports = midi_in.ports();
+    /// Connects to the port whose name has `name` in it, ignoring case, so a device can be found
+    /// however the port numbers have shifted since it was plugged in. A port named exactly `name`
+    /// comes first, and then the first port in `list_midi_input_ports` order.
+    pub fn connect_by_name(name: &str) -> Result<Self> {
+        let midi_in = midir::MidiInput::new("nocturne_midi_by_name")?;
+        let wanted = name.to_lowercase();
+        let mut matches = Vec::new();
+        for port in midi_in.ports() {
+            // The port may have gone away since it was listed.
+            if let Ok(port_name) = midi_in.port_name(&port) {
+                let port_name = port_name.to_lowercase();
+                if port_name.contains(&wanted) {
+                    matches.push((port_name == wanted, port));
+                }
+            }
+        }
+        let port = match matches.iter().position(|(exact, _)| *exact) {
+            Some(i) => matches.swap_remove(i).1,
+            None if matches.is_empty() => {
+                return Err(NocturneError::NoMidiPortNamed(name.to_string()));
+            }
+            None => {
+                if matches.len() > 1 {
+                    warn!(
+                        "{} MIDI input ports have {:?} in their names, using the first",
+                        matches.len(),
+                        name
+                    );
+                }
+                matches.swap_remove(0).1
+            }
+        };
+
+        Self::connect_to(midi_in, &port)
+    }
 
+    fn connect_to(mut midi_in: midir::MidiInput, port: &midir::MidiInputPort) -> Result<Self> {
+        let (mut message_tx, message_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
+        midi_in.ignore(midir::Ignore::None);
+
+        let port_name = midi_in.port_name(port).unwrap_or_default();
         // QUESTION: do MIDI messages arrive in timestamp order?
         let connection = midi_in.connect(
-            &ports[port_number],
+            port,
             "midi_input_connection",
             move |timestamp, message, _| {
-                let mut message_copy: [u8; 3] = [0; 3];
-                message_copy.copy_from_slice(&message);
-                block_on(message_tx.send((timestamp, message_copy)))
-                    .expect("Failed to send MIDI message");
+                // Only fails once the stream is dropped, which closes the connection too, so the
+                // message isn't wanted anyway.
+                let _ = block_on(message_tx.send((timestamp, MidiMessageBytes::new(message))));
             },
             (),
         )?;
-        println!("CONNECTED");
+        info!("Connected to MIDI input {:?}", port_name);
 
         Ok(MidiInputDeviceStream {
             connection,
@@ -75,86 +300,1017 @@ pub struct MidiBytes {
 }
 
 impl MidiBytes {
-    pub fn read_file(midi_file_path: &Path) -> Self {
+    /// Checks that the bytes are a Standard MIDI File with metrical timing, so `parse` can't fail
+    /// later.
+    pub fn new(bytes: Vec<u8>) -> Result<Self> {
+        let smf = Smf::parse(&bytes)?;
+        if let midly::Timing::Timecode(_, _) = smf.header.timing {
+            return Err(NocturneError::UnsupportedMidiTiming);
+        }
+
+        Ok(MidiBytes { bytes })
+    }
+
+    pub fn read_file(midi_file_path: &Path) -> Result<Self> {
         let mut bytes = Vec::new();
-        let mut file = fs::File::open(midi_file_path).unwrap();
-        file.read_to_end(&mut bytes)
-            .expect("Failed to read MIDI file");
+        fs::File::open(midi_file_path)?.read_to_end(&mut bytes)?;
 
-        MidiBytes { bytes }
+        Self::new(bytes)
     }
 
     pub fn parse(&self) -> Smf<'_> {
-        Smf::parse(&self.bytes).unwrap()
+        Smf::parse(&self.bytes).expect("MIDI bytes were checked when they were read")
+    }
+
+    /// Splits a single-track (format 0) file into one track for each channel it uses, in channel
+    /// order, so every channel can have its own instrument. Tempo, time signature and the other
+    /// events without a channel go on the first track. Other files, and those with fewer than two
+    /// channels, are returned unchanged.
+    pub fn split_channels(&self) -> Self {
+        use midly::{number::u28, Event, Format};
+
+        let smf = self.parse();
+        if smf.header.format != Format::SingleTrack || smf.tracks.len() != 1 {
+            return self.clone();
+        }
+        let channel_of = |event: &Event<'_>| match event.kind {
+            EventKind::Midi { channel, .. } => Some(channel.as_int() as usize),
+            _ => None,
+        };
+        let mut channels: Vec<usize> = smf.tracks[0].iter().filter_map(channel_of).collect();
+        channels.sort_unstable();
+        channels.dedup();
+        if channels.len() < 2 {
+            return self.clone();
+        }
+
+        let mut tracks: Vec<Vec<Event<'_>>> = vec![Vec::new(); channels.len()];
+        // The absolute tick of the last event on each track, for the deltas.
+        let mut last_ticks = vec![0; channels.len()];
+        let mut tick = 0;
+        for event in smf.tracks[0].iter() {
+            tick += event.delta.as_int();
+            if let EventKind::Meta(MetaMessage::EndOfTrack) = event.kind {
+                continue;
+            }
+            let track = match channel_of(event) {
+                Some(channel) => channels
+                    .binary_search(&channel)
+                    .expect("Every channel is listed"),
+                None => 0,
+            };
+            tracks[track].push(Event {
+                delta: u28::from(tick - last_ticks[track]),
+                kind: event.kind,
+            });
+            last_ticks[track] = tick;
+        }
+        // Every track ends together, where the file did.
+        for (track, last_tick) in tracks.iter_mut().zip(last_ticks) {
+            track.push(Event {
+                delta: u28::from(tick - last_tick),
+                kind: EventKind::Meta(MetaMessage::EndOfTrack),
+            });
+        }
+
+        let header = midly::Header::new(Format::Parallel, smf.header.timing);
+        let mut bytes = Vec::new();
+        let split = Smf::new(header, tracks).expect("Failed to build the split MIDI file");
+        split
+            .write(&mut bytes)
+            .expect("Failed to write the split MIDI file");
+
+        MidiBytes { bytes }
+    }
+}
+
+/// Slowest and fastest playback speeds, as multiples of the file's tempo.
+const MIN_SPEED: f64 = 0.05;
+const MAX_SPEED: f64 = 8.0;
+
+/// How a MIDI file's events are timed, and which tracks are heard.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SequencerOptions {
+    /// Multiplies the tempo: 0.5 plays at half speed, for practicing a difficult passage.
+    pub speed: f64,
+    /// Plays the whole file at the given tempo, ignoring its own tempo changes.
+    pub fixed_tempo: bool,
+    /// Plays this part of the file over and over, until cancelled, instead of playing it once.
+    pub loop_region: Option<LoopRegion>,
+    /// Tracks, counting from 0, whose notes aren't played.
+    pub muted_tracks: Vec<usize>,
+    /// When any tracks are soloed, only their notes are played, muted or not.
+    pub soloed_tracks: Vec<usize>,
+}
+
+impl Default for SequencerOptions {
+    fn default() -> Self {
+        SequencerOptions {
+            speed: 1.0,
+            fixed_tempo: false,
+            loop_region: None,
+            muted_tracks: Vec::new(),
+            soloed_tracks: Vec::new(),
+        }
     }
 }
 
-/// Sequences, in real time, every MIDI event for every track in the SMF.
+/// A part of a MIDI file to loop. Every note is silenced at the end of each pass, and each pass
+/// keeps the file's tempo and time signature changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopRegion {
+    /// Up to the file's last event, which is usually its End of Track.
+    WholeFile,
+    /// From the first tick up to, but not including, the second.
+    Ticks(i64, i64),
+    /// From the start of the first bar to the end of the second, counting from 1, so `Bars(5, 8)`
+    /// loops four bars.
+    Bars(u32, u32),
+}
+
+impl LoopRegion {
+    /// The first tick, and the tick just past the end.
+    fn ticks(self, tempo_map: &TempoMap, last_tick: i64) -> (i64, i64) {
+        match self {
+            LoopRegion::WholeFile => (0, last_tick),
+            LoopRegion::Ticks(start, end) => (start.max(0), end),
+            LoopRegion::Bars(first, last) => (
+                tempo_map.tick_at_bar(first),
+                tempo_map.tick_at_bar(last.saturating_add(1)),
+            ),
+        }
+    }
+}
+
+/// Changes to MIDI file playback while it plays.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransportCommand {
+    /// Like `SequencerOptions::speed`. Takes effect straight away, even between two events.
+    SetSpeed(f64),
+    /// Stops where it is, silencing every note, until `Play`.
+    Pause,
+    Play,
+    /// Carries on from this tick, silencing every note first. Paused playback stays paused.
+    SeekToTick(i64),
+    /// Like `SeekToTick`, to the start of a bar, counting from 1.
+    SeekToBar(u32),
+    /// Mutes or unmutes a track, counting from 0. A track's notes are silenced as it's muted.
+    SetMute(usize, bool),
+    /// Solos or unsolos a track, like `SequencerOptions::soloed_tracks`.
+    SetSolo(usize, bool),
+}
+
+/// Which tracks are heard.
+struct TrackMutes {
+    muted: Vec<bool>,
+    soloed: Vec<bool>,
+}
+
+impl TrackMutes {
+    fn new(num_tracks: usize, options: &SequencerOptions) -> Self {
+        let mut mutes = TrackMutes {
+            muted: vec![false; num_tracks],
+            soloed: vec![false; num_tracks],
+        };
+        for &track in options.muted_tracks.iter() {
+            mutes.set(track, true, false);
+        }
+        for &track in options.soloed_tracks.iter() {
+            mutes.set(track, true, true);
+        }
+
+        mutes
+    }
+
+    fn is_audible(&self, track: usize) -> bool {
+        if self.soloed.contains(&true) {
+            self.soloed[track]
+        } else {
+            !self.muted[track]
+        }
+    }
+
+    /// Sets the track's mute, or solo, and returns whether it worked.
+    fn set(&mut self, track: usize, on: bool, solo: bool) -> bool {
+        let flags = if solo {
+            &mut self.soloed
+        } else {
+            &mut self.muted
+        };
+        match flags.get_mut(track) {
+            Some(flag) => {
+                *flag = on;
+                true
+            }
+            None => {
+                warn!("There is no track {}", track);
+                false
+            }
+        }
+    }
+}
+
+/// Sequences, in real time, every MIDI event for every track in the SMF. The file plays at `bpm`
+/// until its first Set Tempo event, and follows its tempo changes from then on.
 pub async fn quantize_midi_tracks(
     midi_bytes: MidiBytes,
     bpm: Bpm,
+    track_message_txs: Vec<mpsc::Sender<RawMidiMessage>>,
+    cancel: CancellationToken,
+) {
+    quantize_midi_tracks_with_clock(midi_bytes, bpm, track_message_txs, SystemClock, cancel).await
+}
+
+/// Like `quantize_midi_tracks`, but waits between events using `clock`.
+pub async fn quantize_midi_tracks_with_clock<C: Clock>(
+    midi_bytes: MidiBytes,
+    bpm: Bpm,
+    track_message_txs: Vec<mpsc::Sender<RawMidiMessage>>,
+    clock: C,
+    cancel: CancellationToken,
+) {
+    sequence_midi_tracks(
+        midi_bytes,
+        bpm,
+        SequencerOptions::default(),
+        track_message_txs,
+        clock,
+        None,
+        cancel,
+    )
+    .await
+}
+
+/// Like `quantize_midi_tracks`, timed by `options` and controlled by the commands on `transport`
+/// while it plays. Playback carries on as it is if the transport's senders are dropped.
+pub async fn quantize_midi_tracks_with_transport(
+    midi_bytes: MidiBytes,
+    bpm: Bpm,
+    options: SequencerOptions,
+    track_message_txs: Vec<mpsc::Sender<RawMidiMessage>>,
+    transport: mpsc::Receiver<TransportCommand>,
+    cancel: CancellationToken,
+) {
+    sequence_midi_tracks(
+        midi_bytes,
+        bpm,
+        options,
+        track_message_txs,
+        SystemClock,
+        Some(transport),
+        cancel,
+    )
+    .await
+}
+
+async fn sequence_midi_tracks<C: Clock>(
+    midi_bytes: MidiBytes,
+    bpm: Bpm,
+    options: SequencerOptions,
     mut track_message_txs: Vec<mpsc::Sender<RawMidiMessage>>,
+    clock: C,
+    mut transport: Option<mpsc::Receiver<TransportCommand>>,
+    cancel: CancellationToken,
 ) {
     let smf = midi_bytes.parse();
-
-    let ppqn = match smf.header.timing {
-        midly::Timing::Metrical(m) => m.as_int() as Ppqn,
-        midly::Timing::Timecode(_, _) => panic!("WTF is a timecode"),
+    let tempo_map = if options.fixed_tempo {
+        TempoMap::fixed(&smf, bpm)
+    } else {
+        TempoMap::new(&smf, bpm)
+    };
+    let tempo_map = match tempo_map {
+        Ok(tempo_map) => tempo_map,
+        Err(e) => {
+            warn!("Can't play the file: {}", e);
+            return;
+        }
     };
 
     // Collapse the events into one queue and sort them by absolute timestamp.
     let all_events = single_timeline_of_events(&smf);
-    if all_events.is_empty() {
+    let event_times: Vec<Duration> = all_events
+        .iter()
+        .map(|(t, _, _)| tempo_map.time_at(*t))
+        .collect();
+
+    let last_tick = all_events.last().map_or(0, |(t, _, _)| *t);
+    // Where each pass starts and ends, in the file's own time.
+    let loop_times = options.loop_region.and_then(|region| {
+        let (start, end) = region.ticks(&tempo_map, last_tick);
+        if end <= start {
+            warn!("Not looping the empty region {:?}", region);
+            return None;
+        }
+
+        Some((tempo_map.time_at(start), tempo_map.time_at(end)))
+    });
+    // Events from here on wait for the next pass.
+    let end_cursor = match loop_times {
+        Some((_, end)) => event_times.partition_point(|t| *t < end),
+        None => all_events.len(),
+    };
+
+    let mut mutes = TrackMutes::new(track_message_txs.len(), &options);
+    let mut speed = clamp_speed(options.speed);
+    let mut paused = false;
+    // How far into the file playback is, in the file's own time, as of `last_update`.
+    let mut position = Duration::ZERO;
+    let mut last_update = clock.now();
+    let mut cursor = 0;
+    if let Some((start, _)) = loop_times {
+        position = start;
+        cursor = event_times.partition_point(|t| *t < start);
+        replay_channel_setup(&all_events[..cursor], &mut track_message_txs).await;
+    }
+    loop {
+        if !paused {
+            // Send all of the events that happen at the same time.
+            while cursor < end_cursor && event_times[cursor] <= position {
+                let (t, track, event) = all_events[cursor];
+                // Everything but note ons still goes to a muted track, so it's ready to be heard.
+                if mutes.is_audible(track) || !is_note_on(event) {
+                    send_event_to_track(t as u64, event, &mut track_message_txs[track]).await;
+                }
+                cursor += 1;
+            }
+            if cursor >= end_cursor {
+                match loop_times {
+                    Some((start, end)) if position >= end => {
+                        all_notes_off(&mut track_message_txs).await;
+                        position = start;
+                        cursor = event_times.partition_point(|t| *t < start);
+                        // Each pass starts from the same settings, however the last one left them.
+                        replay_channel_setup(&all_events[..cursor], &mut track_message_txs).await;
+                        continue;
+                    }
+                    Some(_) => (),
+                    None => break,
+                }
+            }
+        }
+
+        // Sleep until next event, or until the transport changes how long that is.
+        let next_time = match event_times.get(cursor) {
+            Some(t) if cursor < end_cursor => *t,
+            _ => loop_times.map_or(position, |(_, end)| end),
+        };
+        let wait = next_time.saturating_sub(position).div_f64(speed);
+        select! {
+            _ = clock.delay_for(wait), if !paused => {
+                position = next_time;
+                last_update = clock.now();
+            }
+            command = next_transport_command(&mut transport) => {
+                let now = clock.now();
+                if !paused {
+                    position += now.saturating_duration_since(last_update).mul_f64(speed);
+                }
+                last_update = now;
+                let seek_tick = match command {
+                    Some(TransportCommand::SetSpeed(s)) => {
+                        speed = clamp_speed(s);
+                        info!("Playing at {}x speed", speed);
+                        None
+                    }
+                    Some(TransportCommand::Pause) => {
+                        if !paused {
+                            info!("Paused in bar {}", tempo_map.bar_at(tick_at(&all_events, cursor)));
+                            paused = true;
+                            all_notes_off(&mut track_message_txs).await;
+                        }
+                        None
+                    }
+                    Some(TransportCommand::Play) => {
+                        paused = false;
+                        None
+                    }
+                    Some(TransportCommand::SeekToTick(tick)) => Some(tick.max(0)),
+                    Some(TransportCommand::SeekToBar(bar)) => Some(tempo_map.tick_at_bar(bar)),
+                    Some(TransportCommand::SetMute(track, on)) => {
+                        set_track_mute(&mut mutes, track, on, false, &mut track_message_txs).await;
+                        None
+                    }
+                    Some(TransportCommand::SetSolo(track, on)) => {
+                        set_track_mute(&mut mutes, track, on, true, &mut track_message_txs).await;
+                        None
+                    }
+                    None => {
+                        transport = None;
+                        None
+                    }
+                };
+                if let Some(tick) = seek_tick {
+                    info!("Seeking to bar {}", tempo_map.bar_at(tick));
+                    all_notes_off(&mut track_message_txs).await;
+                    position = tempo_map.time_at(tick);
+                    cursor = event_times.partition_point(|t| *t < position);
+                    replay_channel_setup(&all_events[..cursor], &mut track_message_txs).await;
+                }
+            }
+            _ = cancel.cancelled() => {
+                info!("MIDI file playback cancelled");
+                return;
+            }
+        }
+    }
+
+    info!("Exiting MIDI file playback thread")
+}
+
+/// Changes a mute or solo, and silences every track that can't be heard anymore.
+async fn set_track_mute(
+    mutes: &mut TrackMutes,
+    track: usize,
+    on: bool,
+    solo: bool,
+    track_message_txs: &mut [mpsc::Sender<RawMidiMessage>],
+) {
+    let was_audible: Vec<bool> = (0..track_message_txs.len())
+        .map(|t| mutes.is_audible(t))
+        .collect();
+    if !mutes.set(track, on, solo) {
         return;
     }
+    info!(
+        "Track {} {}",
+        track,
+        match (solo, on) {
+            (false, true) => "muted",
+            (false, false) => "unmuted",
+            (true, true) => "soloed",
+            (true, false) => "unsoloed",
+        }
+    );
+    for (t, tx) in track_message_txs.iter_mut().enumerate() {
+        if was_audible[t] && !mutes.is_audible(t) {
+            track_notes_off(tx).await;
+        }
+    }
+}
+
+fn is_note_on(event: &midly::Event<'_>) -> bool {
+    matches!(
+        event.kind,
+        EventKind::Midi {
+            message: MidiMessage::NoteOn { .. },
+            ..
+        }
+    )
+}
+
+/// Program changes, controllers and pitch bends, which set up how a channel's notes sound.
+fn is_channel_setup(event: &midly::Event<'_>) -> bool {
+    matches!(
+        event.kind,
+        EventKind::Midi {
+            message: MidiMessage::ProgramChange { .. }
+                | MidiMessage::Controller { .. }
+                | MidiMessage::PitchBend { .. },
+            ..
+        }
+    )
+}
+
+/// Sends the channel setup among `events`, so playback from after them is on the instruments and
+/// settings the file had chosen by then. Every channel is reset first, so nothing set later in the
+/// file, like the end of a fade, carries over to before it.
+async fn replay_channel_setup(
+    events: &[(i64, usize, &midly::Event<'_>)],
+    track_message_txs: &mut [mpsc::Sender<RawMidiMessage>],
+) {
+    for tx in track_message_txs.iter_mut() {
+        reset_track_channels(tx).await;
+    }
+    for (t, track, event) in events {
+        if is_channel_setup(event) {
+            send_event_to_track(*t as u64, event, &mut track_message_txs[*track]).await;
+        }
+    }
+}
 
-    let num_events = all_events.len();
-    let mut i = 0;
-    while i < num_events - 1 {
-        // Send all of the events that happen at the same time.
-        let mut delta_t = 0;
-        while delta_t == 0 && i < num_events - 1 {
-            let (this_t, this_track, this_event) = all_events[i];
-            let (next_t, _, _) = all_events[i + 1];
+/// The tick of the next event, or of the end of the file.
+fn tick_at(all_events: &[(i64, usize, &midly::Event<'_>)], cursor: usize) -> i64 {
+    match all_events.get(cursor) {
+        Some((t, _, _)) => *t,
+        None => all_events.last().map_or(0, |(t, _, _)| *t),
+    }
+}
 
-            send_event_to_track(
-                this_t as u64,
-                &this_event,
-                &mut track_message_txs[this_track],
-            )
-            .await;
+fn clamp_speed(speed: f64) -> f64 {
+    if speed.is_nan() {
+        return 1.0;
+    }
 
-            // We'll end iteration if the next event does not start at the same time.
-            delta_t = next_t - this_t;
+    speed.clamp(MIN_SPEED, MAX_SPEED)
+}
 
-            // Move to the next pair of adjacent events.
-            i += 1;
+/// Never finishes without a transport, so a `select!` waits on everything else.
+async fn next_transport_command(
+    transport: &mut Option<mpsc::Receiver<TransportCommand>>,
+) -> Option<TransportCommand> {
+    match transport {
+        Some(rx) => rx.recv().await,
+        None => future::pending().await,
+    }
+}
+
+/// If no quarter frames arrive for this long, the external transport has stopped.
+const MTC_STOP_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// A jump in MTC position bigger than this is a relocation rather than jitter.
+const MTC_RELOCATE_THRESHOLD: Duration = Duration::from_secs(1);
+
+const CC_BANK_SELECT: u8 = 0;
+const CC_RESET_ALL_CONTROLLERS: u8 = 121;
+const CC_ALL_NOTES_OFF: u8 = 123;
+
+/// Sequences every MIDI event for every track in the SMF, following the position of an external
+/// transport that sends MIDI Time Code quarter frames on `mtc_stream`. Playback starts, stops and
+/// relocates along with the transport, and ends when `mtc_stream` does.
+pub async fn chase_mtc_midi_tracks<S>(
+    midi_bytes: MidiBytes,
+    bpm: Bpm,
+    mut mtc_stream: S,
+    mut track_message_txs: Vec<mpsc::Sender<RawMidiMessage>>,
+    cancel: CancellationToken,
+) where
+    S: Stream<Item = RawMidiMessage> + Unpin,
+{
+    let smf = midi_bytes.parse();
+    let tempo_map = match TempoMap::new(&smf, bpm) {
+        Ok(tempo_map) => tempo_map,
+        Err(e) => {
+            warn!("Can't chase the file: {}", e);
+            return;
         }
+    };
 
-        // Sleep until next event.
-        delay_for(ticks_to_duration(bpm, ppqn, delta_t)).await;
+    let all_events = single_timeline_of_events(&smf);
+    let event_times: Vec<Duration> = all_events
+        .iter()
+        .map(|(t, _, _)| tempo_map.time_at(*t))
+        .collect();
+
+    let mut decoder = MtcDecoder::new();
+    // Index of the next event to send.
+    let mut cursor = 0;
+    let mut last_position: Option<Duration> = None;
+    loop {
+        let next_message = select! {
+            m = timeout(MTC_STOP_TIMEOUT, mtc_stream.next()) => m,
+            _ = cancel.cancelled() => break,
+        };
+        let message = match next_message {
+            Ok(Some((_, message))) => message,
+            Ok(None) => break,
+            Err(_) => {
+                if last_position.is_some() {
+                    info!("MTC stopped");
+                    all_notes_off(&mut track_message_txs).await;
+                    decoder.reset();
+                    last_position = None;
+                }
+                continue;
+            }
+        };
+        if message[0] != 0xF1 {
+            continue;
+        }
+        let position = match decoder.handle_quarter_frame(message[1]) {
+            Some(p) => p,
+            None => continue,
+        };
+
+        let relocated = match last_position {
+            None => true,
+            Some(last) => position < last || position - last > MTC_RELOCATE_THRESHOLD,
+        };
+        if relocated {
+            info!("MTC located to {:?}", position);
+            if last_position.is_some() {
+                all_notes_off(&mut track_message_txs).await;
+            }
+            cursor = event_times.partition_point(|t| *t < position);
+            replay_channel_setup(&all_events[..cursor], &mut track_message_txs).await;
+        }
+        last_position = Some(position);
+
+        while cursor < all_events.len() && event_times[cursor] <= position {
+            let (t, track, event) = all_events[cursor];
+            send_event_to_track(t as u64, event, &mut track_message_txs[track]).await;
+            cursor += 1;
+        }
     }
 
-    // Send the last event.
-    let (this_t, this_track, this_event) = all_events[i];
-    send_event_to_track(
-        this_t as u64,
-        this_event,
-        &mut track_message_txs[this_track],
-    )
-    .await;
+    info!("Exiting MTC chase thread")
+}
 
-    info!("Exiting MIDI file playback thread")
+/// Sequences every MIDI event for every track in the SMF, keeping time with the MIDI clock on
+/// `clock_stream` instead of the file's tempo. Each Timing Clock moves playback on by a 24th of a
+/// beat, and the events in between are spread out at the clock's measured tempo. Start plays from
+/// the beginning of the file, Continue from where it stopped, Stop silences every track, and Song
+/// Position Pointer relocates. The clock is passed on to every track, so their effects can keep
+/// time with it too. Ends when `clock_stream` does.
+pub async fn follow_midi_clock_midi_tracks<S>(
+    midi_bytes: MidiBytes,
+    mut clock_stream: S,
+    mut track_message_txs: Vec<mpsc::Sender<RawMidiMessage>>,
+    cancel: CancellationToken,
+) where
+    S: Stream<Item = RawMidiMessage> + Unpin,
+{
+    let smf = midi_bytes.parse();
+    // Only for the bars, since the clock has the tempo.
+    let tempo_map = match TempoMap::fixed(&smf, 120.0) {
+        Ok(tempo_map) => tempo_map,
+        Err(e) => {
+            warn!("Can't follow the file: {}", e);
+            return;
+        }
+    };
+    let ticks_per_pulse = tempo_map.ppqn() as f64 / MIDI_CLOCK_PULSES_PER_BEAT as f64;
+    let tick_at_pulse = |pulse: u64| (pulse as f64 * ticks_per_pulse) as i64;
+    let all_events = single_timeline_of_events(&smf);
+
+    let mut tempo = MidiClockTempo::new();
+    let mut playing = false;
+    // The pulse that the next Timing Clock marks, counting from the start of the file.
+    let mut next_pulse = 0;
+    // The last pulse played and when it arrived, for timing the events before the next one.
+    let mut last_pulse: Option<(u64, Instant)> = None;
+    // Index of the next event to send.
+    let mut cursor = 0;
+    loop {
+        let due = match (last_pulse, tempo.pulse_duration(), all_events.get(cursor)) {
+            (Some((pulse, arrived)), Some(period), Some((t, _, _)))
+                if playing && *t < tick_at_pulse(next_pulse) =>
+            {
+                let pulses = (*t as f64 / ticks_per_pulse - pulse as f64).max(0.0);
+                Some(arrived + period.mul_f64(pulses))
+            }
+            _ => None,
+        };
+        let wait = due.map(|due| due.saturating_duration_since(Instant::now()));
+        let (timestamp, message) = select! {
+            _ = delay_for(wait.unwrap_or_default()), if wait.is_some() => {
+                let tick = all_events[cursor].0;
+                cursor = send_events_through(&all_events, cursor, tick, &mut track_message_txs).await;
+                continue;
+            }
+            m = clock_stream.next() => match m {
+                Some(m) => m,
+                None => break,
+            },
+            _ = cancel.cancelled() => break,
+        };
+        match message.first() {
+            // Timing Clock
+            Some(0xF8) => {
+                tempo.pulse(Duration::from_micros(timestamp));
+                for tx in track_message_txs.iter_mut() {
+                    let _ = tx.send((timestamp, message.clone())).await;
+                }
+                if playing {
+                    let pulse = next_pulse;
+                    next_pulse += 1;
+                    let tick = tick_at_pulse(pulse);
+                    cursor = send_events_through(&all_events, cursor, tick, &mut track_message_txs)
+                        .await;
+                    last_pulse = Some((pulse, Instant::now()));
+                }
+            }
+            // Start
+            Some(0xFA) => {
+                info!("MIDI clock started");
+                if playing {
+                    all_notes_off(&mut track_message_txs).await;
+                }
+                playing = true;
+                next_pulse = 0;
+                last_pulse = None;
+                cursor = 0;
+            }
+            // Continue
+            Some(0xFB) => {
+                info!(
+                    "MIDI clock continued in bar {}",
+                    tempo_map.bar_at(tick_at_pulse(next_pulse))
+                );
+                playing = true;
+            }
+            // Stop
+            Some(0xFC) => {
+                if playing {
+                    info!("MIDI clock stopped");
+                    all_notes_off(&mut track_message_txs).await;
+                }
+                playing = false;
+                last_pulse = None;
+                tempo.pause();
+            }
+            // Song Position Pointer, in sixteenth notes of 6 pulses each.
+            Some(0xF2) if message.len() == 3 => {
+                let sixteenths = message[1] as u64 | (message[2] as u64) << 7;
+                next_pulse = sixteenths * 6;
+                let tick = tick_at_pulse(next_pulse);
+                info!("MIDI clock located to bar {}", tempo_map.bar_at(tick));
+                all_notes_off(&mut track_message_txs).await;
+                last_pulse = None;
+                cursor = all_events.partition_point(|(t, _, _)| *t < tick);
+                replay_channel_setup(&all_events[..cursor], &mut track_message_txs).await;
+            }
+            _ => (),
+        }
+    }
+
+    info!("Exiting MIDI clock follower thread")
+}
+
+/// Sends the events from `cursor` up to and including `tick`, and returns the index of the next.
+async fn send_events_through(
+    all_events: &[(i64, usize, &midly::Event<'_>)],
+    mut cursor: usize,
+    tick: i64,
+    track_message_txs: &mut [mpsc::Sender<RawMidiMessage>],
+) -> usize {
+    while cursor < all_events.len() && all_events[cursor].0 <= tick {
+        let (t, track, event) = all_events[cursor];
+        send_event_to_track(t as u64, event, &mut track_message_txs[track]).await;
+        cursor += 1;
+    }
+
+    cursor
+}
+
+/// Sends All Notes Off on every channel of every track.
+async fn all_notes_off(track_message_txs: &mut [mpsc::Sender<RawMidiMessage>]) {
+    for tx in track_message_txs.iter_mut() {
+        track_notes_off(tx).await;
+    }
+}
+
+/// Sends All Notes Off on every channel of one track.
+async fn track_notes_off(tx: &mut mpsc::Sender<RawMidiMessage>) {
+    for channel in 0..16 {
+        let message = [0xB0 | channel, CC_ALL_NOTES_OFF, 0];
+        // Nothing to silence once the track's instrument has stopped.
+        let _ = tx.send((0, message.into())).await;
+    }
+}
+
+/// Sends Reset All Controllers, bank 0 and program 0, and a centred pitch bend on every channel of
+/// one track.
+async fn reset_track_channels(tx: &mut mpsc::Sender<RawMidiMessage>) {
+    for channel in 0..16 {
+        let messages: [&[u8]; 4] = [
+            &[0xB0 | channel, CC_RESET_ALL_CONTROLLERS, 0],
+            &[0xB0 | channel, CC_BANK_SELECT, 0],
+            &[0xC0 | channel, 0],
+            &[0xE0 | channel, 0x00, 0x40],
+        ];
+        for message in messages.iter() {
+            // Nothing to reset once the track's instrument has stopped.
+            let _ = tx.send((0, MidiMessageBytes::new(message))).await;
+        }
+    }
+}
+
+/// Saved files have ticks no longer than this, so messages land within a fraction of a millisecond
+/// of when they were played.
+const SAVED_MAX_TICK_MICROS: f64 = 100.0;
+/// Saved resolutions are multiples of this, so triplets and common subdivisions stay whole ticks.
+const SAVED_PPQN_STEP: u16 = 96;
+
+/// The resolution for a saved file at the given tempo: the coarsest whose ticks are no longer than
+/// `SAVED_MAX_TICK_MICROS`, as far as SMF allows.
+fn saved_ppqn(micros_per_beat: u32) -> u16 {
+    let ticks = (micros_per_beat as f64 / SAVED_MAX_TICK_MICROS).ceil() as u32;
+    let steps = ticks.div_ceil(SAVED_PPQN_STEP as u32).max(1);
+    let max_steps = i16::MAX as u32 / SAVED_PPQN_STEP as u32;
+
+    (steps.min(max_steps) * SAVED_PPQN_STEP as u32) as u16
+}
+
+/// Writes channel messages, SysEx and meta events, timed from the start of the file, as a
+/// single-track SMF at 120 BPM. Other messages (system real time and common) are left out, as are
+/// tempo changes, since the file has a tempo of its own.
+pub fn save_timed_messages(
+    messages: &[(Duration, MidiMessageBytes)],
+    path: &Path,
+) -> io::Result<()> {
+    save_timed_messages_at_tempo(messages, 120.0, path)
+}
+
+/// Like `save_timed_messages`, with the file's tempo set to `bpm`, so its beats line up with the
+/// session's. Every channel message is kept, including pitch bend, controllers and aftertouch,
+/// with ticks short enough that playing the file back at `bpm` times them to within 0.1 ms.
+pub fn save_timed_messages_at_tempo(
+    messages: &[(Duration, MidiMessageBytes)],
+    bpm: Bpm,
+    path: &Path,
+) -> io::Result<()> {
+    use midly::number::{u15, u24, u28};
+
+    // The tempo event has 24 bits.
+    let micros_per_beat = (60_000_000.0 / bpm).round().clamp(1.0, 16_777_215.0) as u32;
+    let ppqn = saved_ppqn(micros_per_beat);
+    let micros_per_tick = micros_per_beat as f64 / ppqn as f64;
+    let mut track = vec![midly::Event {
+        delta: u28::from(0),
+        kind: EventKind::Meta(MetaMessage::Tempo(u24::from(micros_per_beat))),
+    }];
+    let mut last_tick = 0;
+    for (time, message) in messages {
+        let kind = match message.to_event() {
+            Some(EventKind::Meta(MetaMessage::Tempo(_)))
+            | Some(EventKind::Meta(MetaMessage::EndOfTrack)) => continue,
+            Some(kind) => kind,
+            None => {
+                if matches!(message.first(), Some(0x80..=0xEF) | Some(0xFF)) && message.len() > 1 {
+                    warn!("Skipping malformed MIDI message {:?}", message);
+                }
+                continue;
+            }
+        };
+        let tick = (time.as_micros() as f64 / micros_per_tick).round() as u32;
+        // Messages can arrive slightly out of order.
+        let tick = tick.max(last_tick);
+        track.push(midly::Event {
+            delta: u28::from(tick - last_tick),
+            kind,
+        });
+        last_tick = tick;
+    }
+    track.push(midly::Event {
+        delta: u28::from(0),
+        kind: EventKind::Meta(MetaMessage::EndOfTrack),
+    });
+
+    let header = midly::Header::new(
+        midly::Format::SingleTrack,
+        midly::Timing::Metrical(u15::from(ppqn)),
+    );
+    let smf = Smf::new(header, vec![track])
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    smf.save(path)
 }
 
 pub fn ticks_to_duration(bpm: Bpm, ppqn: Ppqn, delta_t: i64) -> Duration {
     let delta_ticks = Ticks(delta_t);
     let millis = delta_ticks.ms(bpm, ppqn);
-    let mut nanos = (millis * 1_000_000.0).floor() as u64;
-    let seconds = nanos / 1_000_000_000;
-    nanos -= seconds * 1_000_000;
+    let nanos = (millis * 1_000_000.0).floor() as u64;
+
+    Duration::from_nanos(nanos)
+}
+
+/// Where the tempo and time signature change in a file, for converting its ticks to time and
+/// bars. Until the file's first Set Tempo event, it plays at the tempo it was given, and until its
+/// first Time Signature event, it is in 4/4.
+#[derive(Clone, Debug)]
+pub struct TempoMap {
+    ppqn: Ppqn,
+    /// Ordered by tick, starting from tick 0.
+    changes: Vec<TempoChange>,
+    /// Ordered by tick, starting from tick 0.
+    meters: Vec<Meter>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TempoChange {
+    tick: i64,
+    time: Duration,
+    bpm: Bpm,
+}
 
-    Duration::new(seconds as u64, nanos as u32)
+#[derive(Clone, Copy, Debug)]
+struct Meter {
+    tick: i64,
+    /// The bar that starts at `tick`, counting from 0.
+    bar: i64,
+    ticks_per_bar: i64,
+}
+
+impl TempoMap {
+    /// Reads the Set Tempo events from every track, since type-1 files usually keep them all in
+    /// the first.
+    pub fn new(smf: &Smf<'_>, bpm: Bpm) -> Result<Self> {
+        let mut map = Self::fixed(smf, bpm)?;
+        for (tick, _, event) in single_timeline_of_events(smf) {
+            if let EventKind::Meta(MetaMessage::Tempo(micros_per_beat)) = event.kind {
+                let micros_per_beat = micros_per_beat.as_int().max(1);
+                map.push(tick, 60_000_000.0 / micros_per_beat as f64);
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Ignores the file's tempo changes, playing it all at `bpm`. Its bars still follow its time
+    /// signatures. Fails for files timed in SMPTE frames rather than beats.
+    pub fn fixed(smf: &Smf<'_>, bpm: Bpm) -> Result<Self> {
+        let ppqn = match smf.header.timing {
+            midly::Timing::Metrical(m) => m.as_int() as Ppqn,
+            midly::Timing::Timecode(_, _) => return Err(NocturneError::UnsupportedMidiTiming),
+        };
+        let mut map = TempoMap {
+            ppqn,
+            changes: vec![TempoChange {
+                tick: 0,
+                time: Duration::ZERO,
+                bpm,
+            }],
+            meters: vec![Meter {
+                tick: 0,
+                bar: 0,
+                ticks_per_bar: 4 * ppqn as i64,
+            }],
+        };
+        for (tick, _, event) in single_timeline_of_events(smf) {
+            if let EventKind::Meta(MetaMessage::TimeSignature(numerator, denominator_pow, _, _)) =
+                event.kind
+            {
+                // The denominator is a power of two: 2 is a quarter note, 3 an eighth note.
+                let beat_ticks = (4 * ppqn as i64) >> denominator_pow.min(6);
+                map.push_meter(tick, (numerator as i64 * beat_ticks).max(1));
+            }
+        }
+
+        Ok(map)
+    }
+
+    fn push_meter(&mut self, tick: i64, ticks_per_bar: i64) {
+        let last = self
+            .meters
+            .last_mut()
+            .expect("There is always a first meter");
+        if last.tick == tick {
+            last.ticks_per_bar = ticks_per_bar;
+            return;
+        }
+        // A change in the middle of a bar starts a new one.
+        let bars = (tick - last.tick + last.ticks_per_bar - 1) / last.ticks_per_bar;
+        let bar = last.bar + bars;
+        self.meters.push(Meter {
+            tick,
+            bar,
+            ticks_per_bar,
+        });
+    }
+
+    fn push(&mut self, tick: i64, bpm: Bpm) {
+        let time = self.time_at(tick);
+        let last = self
+            .changes
+            .last_mut()
+            .expect("There is always a first tempo");
+        // Several changes at once, like a file's initial tempo on tick 0, leave only the last.
+        if last.tick == tick {
+            last.bpm = bpm;
+        } else {
+            self.changes.push(TempoChange { tick, time, bpm });
+        }
+    }
+
+    /// How long after the start of the file `tick` comes.
+    pub fn time_at(&self, tick: i64) -> Duration {
+        let change = self.change_at(tick);
+
+        change.time + ticks_to_duration(change.bpm, self.ppqn, tick - change.tick)
+    }
+
+    /// Ticks per quarter note.
+    pub fn ppqn(&self) -> Ppqn {
+        self.ppqn
+    }
+
+    /// The tempo from `tick` until the next change.
+    pub fn bpm_at(&self, tick: i64) -> Bpm {
+        self.change_at(tick).bpm
+    }
+
+    fn change_at(&self, tick: i64) -> &TempoChange {
+        let i = self.changes.partition_point(|c| c.tick <= tick).max(1);
+
+        &self.changes[i - 1]
+    }
+
+    /// Where bar `bar` starts, counting bars from 1 like a score does.
+    pub fn tick_at_bar(&self, bar: u32) -> i64 {
+        let bar = bar.max(1) as i64 - 1;
+        let i = self.meters.partition_point(|m| m.bar <= bar).max(1);
+        let meter = &self.meters[i - 1];
+
+        meter.tick + (bar - meter.bar) * meter.ticks_per_bar
+    }
+
+    /// The bar that `tick` is in, counting from 1.
+    pub fn bar_at(&self, tick: i64) -> u32 {
+        let i = self.meters.partition_point(|m| m.tick <= tick).max(1);
+        let meter = &self.meters[i - 1];
+
+        (meter.bar + (tick - meter.tick).max(0) / meter.ticks_per_bar + 1) as u32
+    }
 }
 
 pub fn single_timeline_of_events<'a>(smf: &'a Smf<'a>) -> Vec<(i64, usize, &'a midly::Event<'a>)> {
@@ -162,35 +1318,91 @@ pub fn single_timeline_of_events<'a>(smf: &'a Smf<'a>) -> Vec<(i64, usize, &'a m
     for (track_num, track) in smf.tracks.iter().enumerate() {
         let mut abs_t: i64 = 0;
         for event in track.iter() {
-            let delta_t = event.delta;
+            // The delta is the time since the previous event on this track.
+            abs_t += event.delta.as_int() as i64;
             all_events.push((abs_t, track_num, event));
-            abs_t += delta_t.as_int() as i64;
         }
     }
-    all_events.sort_by(|(t1, _, _), (t2, _, _)| t1.cmp(&t2));
+    all_events.sort_by_key(|(t, _, _)| *t);
 
     all_events
 }
 
-fn convert_event_to_raw_message(event: &midly::Event<'_>) -> Option<[u8; 3]> {
-    let mut raw_message = Vec::with_capacity(3);
-    event
-        .kind
-        .write(&mut None, &mut raw_message)
-        .expect("Failed to serialize MIDI message");
-    let message_len = raw_message.len();
+/// Peak numbers of simultaneous notes, for choosing voice limits and finding the passages that
+/// load the CPU the most.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PolyphonyStats {
+    /// Peak simultaneous notes on each MIDI channel, over all tracks.
+    pub per_channel: [usize; 16],
+    /// Peak simultaneous notes on each track.
+    pub per_track: Vec<usize>,
+    /// Peak simultaneous notes over the whole file.
+    pub total: usize,
+}
 
-    if message_len <= 3 {
-        let mut raw_message_buf = [0u8; 3];
-        raw_message_buf[..message_len].copy_from_slice(&raw_message[..]);
+/// Counts held notes through the whole file. A note that is struck again before being released
+/// still counts once, like it does in the synthesizer.
+pub fn polyphony_stats(midi_bytes: &MidiBytes) -> PolyphonyStats {
+    let smf = midi_bytes.parse();
+    let mut stats = PolyphonyStats {
+        per_track: vec![0; smf.tracks.len()],
+        ..Default::default()
+    };
+    let mut channel_counts = [0; 16];
+    let mut track_counts = vec![0; smf.tracks.len()];
+    let mut held = HashSet::new();
 
-        Some(raw_message_buf)
-    } else {
-        // HACK: ignore certain events
-        trace!("Ignoring {}-byte event {:?}", message_len, event.kind);
+    let all_events = single_timeline_of_events(&smf);
+    let mut start = 0;
+    while start < all_events.len() {
+        // The events at the same tick.
+        let tick = all_events[start].0;
+        let end = start
+            + all_events[start..]
+                .iter()
+                .take_while(|(t, _, _)| *t == tick)
+                .count();
+        let group = &all_events[start..end];
+        start = end;
+        // Releases at the same tick as a strike free the voice first.
+        let changes = group
+            .iter()
+            .filter_map(|(_, track, event)| match event.kind {
+                EventKind::Midi { channel, message } => match message {
+                    MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                        Some((true, *track, channel.as_int(), key.as_int()))
+                    }
+                    MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                        Some((false, *track, channel.as_int(), key.as_int()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            });
+        let (strikes, releases): (Vec<_>, Vec<_>) = changes.partition(|(on, _, _, _)| *on);
+        for (_, track, channel, key) in releases {
+            if held.remove(&(track, channel, key)) {
+                channel_counts[channel as usize] -= 1;
+                track_counts[track] -= 1;
+            }
+        }
+        for (_, track, channel, key) in strikes {
+            if held.insert((track, channel, key)) {
+                channel_counts[channel as usize] += 1;
+                track_counts[track] += 1;
+            }
+        }
 
-        None
+        for (peak, count) in stats.per_channel.iter_mut().zip(channel_counts.iter()) {
+            *peak = (*peak).max(*count);
+        }
+        for (peak, count) in stats.per_track.iter_mut().zip(track_counts.iter()) {
+            *peak = (*peak).max(*count);
+        }
+        stats.total = stats.total.max(held.len());
     }
+
+    stats
 }
 
 async fn send_event_to_track(
@@ -198,11 +1410,167 @@ async fn send_event_to_track(
     event: &midly::Event<'_>,
     message_tx: &mut mpsc::Sender<RawMidiMessage>,
 ) {
-    // Send one event to the corresponding track receiver.
-    if let Some(raw_message) = convert_event_to_raw_message(event) {
-        message_tx
-            .send((timestamp, raw_message))
-            .await
-            .expect("Failed to send MIDI message");
+    // The track's instrument may have stopped, like when the output fails. The sequencer carries
+    // on until it is cancelled.
+    let message = MidiMessageBytes::from_event(&event.kind);
+    let _ = message_tx.send((timestamp, message)).await;
+}
+
+/// A file of one track at 96 ticks per quarter note, for tests. `events` are the track's events,
+/// each with its delta time, and End of Track is added after them.
+#[cfg(test)]
+pub(crate) fn single_track_file(events: &[u8]) -> MidiBytes {
+    let end_of_track = [0x00, 0xFF, 0x2F, 0x00];
+    let mut bytes = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60MTrk".to_vec();
+    bytes.extend_from_slice(&((events.len() + end_of_track.len()) as u32).to_be_bytes());
+    bytes.extend_from_slice(events);
+    bytes.extend_from_slice(&end_of_track);
+
+    MidiBytes::new(bytes).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::{settle, ManualClock};
+
+    /// Middle C for a quarter note, and then E another quarter note later.
+    fn two_notes() -> MidiBytes {
+        single_track_file(&[
+            0x00, 0x90, 60, 100, // Note on, at 0
+            0x60, 0x80, 60, 0, // Note off, at 96
+            0x60, 0x90, 64, 100, // Note on, at 192
+        ])
+    }
+
+    #[tokio::test]
+    async fn events_wait_for_the_clock() {
+        let clock = ManualClock::new();
+        let (tx, mut rx) = mpsc::channel(16);
+        let sequencer = tokio::spawn(quantize_midi_tracks_with_clock(
+            two_notes(),
+            120.0,
+            vec![tx],
+            clock.clone(),
+            CancellationToken::new(),
+        ));
+
+        settle().await;
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            (0, MidiMessageBytes::new(&[0x90, 60, 100]))
+        );
+        assert!(rx.try_recv().is_err());
+
+        // A quarter note at 120 BPM is half a second.
+        clock.advance(Duration::from_millis(499));
+        settle().await;
+        assert!(rx.try_recv().is_err());
+
+        clock.advance(Duration::from_millis(1));
+        settle().await;
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            (96, MidiMessageBytes::new(&[0x80, 60, 0]))
+        );
+        assert!(rx.try_recv().is_err());
+
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            (192, MidiMessageBytes::new(&[0x90, 64, 100]))
+        );
+        sequencer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pausing_stops_the_clock() {
+        let clock = ManualClock::new();
+        let (tx, mut rx) = mpsc::channel(32);
+        let (mut transport_tx, transport_rx) = mpsc::channel(4);
+        let sequencer = tokio::spawn(sequence_midi_tracks(
+            two_notes(),
+            120.0,
+            SequencerOptions::default(),
+            vec![tx],
+            clock.clone(),
+            Some(transport_rx),
+            CancellationToken::new(),
+        ));
+
+        settle().await;
+        assert_eq!(rx.try_recv().unwrap().0, 0);
+        clock.advance(Duration::from_millis(200));
+        transport_tx.send(TransportCommand::Pause).await.unwrap();
+        settle().await;
+        // Pausing silences the track, on every channel.
+        for channel in 0..16 {
+            assert_eq!(
+                rx.try_recv().unwrap(),
+                (0, MidiMessageBytes::new(&[0xB0 | channel, 123, 0]))
+            );
+        }
+        assert!(rx.try_recv().is_err());
+
+        // Time spent paused doesn't count.
+        clock.advance(Duration::from_secs(5));
+        settle().await;
+        assert!(rx.try_recv().is_err());
+
+        transport_tx.send(TransportCommand::Play).await.unwrap();
+        settle().await;
+        clock.advance(Duration::from_millis(299));
+        settle().await;
+        assert!(rx.try_recv().is_err());
+
+        clock.advance(Duration::from_millis(1));
+        settle().await;
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            (96, MidiMessageBytes::new(&[0x80, 60, 0]))
+        );
+        clock.advance(Duration::from_millis(500));
+        settle().await;
+        assert_eq!(rx.try_recv().unwrap().0, 192);
+        sequencer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn seeking_resets_channels_before_replaying_their_setup() {
+        let volume = |value: u8| midly::Event {
+            delta: 0.into(),
+            kind: EventKind::Midi {
+                channel: 2.into(),
+                message: MidiMessage::Controller {
+                    controller: 7.into(),
+                    value: value.into(),
+                },
+            },
+        };
+        let (early, late) = (volume(20), volume(0));
+        let events = [(0, 0, &early), (1920, 0, &late)];
+        let (tx, mut rx) = mpsc::channel(128);
+
+        // Seeking to before the fade.
+        replay_channel_setup(&events[..1], &mut [tx]).await;
+
+        for channel in 0..16 {
+            let expected: [&[u8]; 4] = [
+                &[0xB0 | channel, 121, 0],
+                &[0xB0 | channel, 0, 0],
+                &[0xC0 | channel, 0],
+                &[0xE0 | channel, 0x00, 0x40],
+            ];
+            for message in expected.iter() {
+                assert_eq!(rx.try_recv().unwrap().1, MidiMessageBytes::new(message));
+            }
+        }
+        assert_eq!(
+            rx.try_recv().unwrap().1,
+            MidiMessageBytes::new(&[0xB2, 7, 20])
+        );
+        assert!(rx.try_recv().is_err());
     }
 }
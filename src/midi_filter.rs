@@ -0,0 +1,653 @@
+//! Filters and transforms on MIDI messages on their way from a source to a synth, like keeping to
+//! a few channels or moving every note down an octave. They chain like effects do, and live input
+//! can be filtered from the config file:
+//!
+//! ```toml
+//! [[midi_filters]]
+//! type = "channels"
+//! channels = [0, 1]
+//!
+//! [[midi_filters]]
+//! type = "transpose"
+//! semitones = -12
+//! # Octaves up or down from CC 21, as it moves.
+//! octave_controller = 21
+//!
+//! [[midi_filters]]
+//! type = "scale"
+//! key = "D"
+//! scale = "dorian"
+//! ```
+
+use crate::{
+    midi::{MidiMessageBytes, RawMidiMessage},
+    routing::parse_channels,
+};
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI8, Ordering};
+use std::sync::Arc;
+use tokio::stream::{Stream, StreamExt};
+
+/// Passes, changes or drops each MIDI message in turn.
+pub trait MidiFilter: Send {
+    /// The message to pass on in place of `message`, or `None` to drop it.
+    fn filter(&mut self, message: RawMidiMessage) -> Option<RawMidiMessage>;
+}
+
+/// Filters applied one after the other, in the order they were added.
+#[derive(Default)]
+pub struct MidiFilterChain {
+    filters: Vec<Box<dyn MidiFilter>>,
+}
+
+impl MidiFilterChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push<F: MidiFilter + 'static>(&mut self, filter: F) {
+        self.filters.push(Box::new(filter));
+    }
+
+    /// Builder-style `push`.
+    pub fn with<F: MidiFilter + 'static>(mut self, filter: F) -> Self {
+        self.push(filter);
+
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
+
+impl MidiFilter for MidiFilterChain {
+    fn filter(&mut self, message: RawMidiMessage) -> Option<RawMidiMessage> {
+        self.filters
+            .iter_mut()
+            .try_fold(message, |message, filter| filter.filter(message))
+    }
+}
+
+/// Passes `input` through `filter`.
+pub fn filter_midi<S, F>(input: S, mut filter: F) -> impl Stream<Item = RawMidiMessage>
+where
+    S: Stream<Item = RawMidiMessage>,
+    F: MidiFilter,
+{
+    input.filter_map(move |message| filter.filter(message))
+}
+
+/// Note on or off, with the channel, key and velocity. A note on with velocity 0 counts as a note
+/// off, as in MIDI.
+fn note_of(message: &MidiMessageBytes) -> Option<(bool, u8, u8, u8)> {
+    match *message.as_ref() {
+        [status @ 0x90..=0x9F, key, velocity] if velocity > 0 => {
+            Some((true, status & 0x0F, key, velocity))
+        }
+        [status @ 0x80..=0x9F, key, velocity] => Some((false, status & 0x0F, key, velocity)),
+        _ => None,
+    }
+}
+
+/// Only passes channel messages on these channels, counting from 0. System messages always pass.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChannelFilter {
+    pub channels: Vec<u8>,
+}
+
+impl MidiFilter for ChannelFilter {
+    fn filter(&mut self, message: RawMidiMessage) -> Option<RawMidiMessage> {
+        match message.1.first() {
+            Some(status @ 0x80..=0xEF) if !self.channels.contains(&(status & 0x0F)) => None,
+            _ => Some(message),
+        }
+    }
+}
+
+/// Drops the notes, and their key pressure, outside of `lowest..=highest`, like a keyboard split.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NoteRange {
+    pub lowest: u8,
+    pub highest: u8,
+}
+
+impl MidiFilter for NoteRange {
+    fn filter(&mut self, message: RawMidiMessage) -> Option<RawMidiMessage> {
+        match *message.1.as_ref() {
+            [0x80..=0xAF, key, _] if !(self.lowest..=self.highest).contains(&key) => None,
+            _ => Some(message),
+        }
+    }
+}
+
+/// Scales the velocity of every note on, for a keyboard that plays too loud or too soft. Notes stay
+/// at a velocity of at least 1, so none of them turn into note offs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VelocityScale {
+    pub scale: f32,
+}
+
+impl MidiFilter for VelocityScale {
+    fn filter(&mut self, (timestamp, message): RawMidiMessage) -> Option<RawMidiMessage> {
+        match note_of(&message) {
+            Some((true, channel, key, velocity)) => {
+                let velocity = (velocity as f32 * self.scale).round().clamp(1.0, 127.0) as u8;
+                Some((timestamp, [0x90 | channel, key, velocity].into()))
+            }
+            _ => Some((timestamp, message)),
+        }
+    }
+}
+
+/// Where the note on each channel's keys went, while they're held, for filters that move notes to
+/// other keys. Each note's off and key pressure go to the key its note on was moved to, even if the
+/// filter changes while it's held.
+#[derive(Clone, Debug)]
+struct HeldKeys {
+    keys: Box<[[Option<u8>; 128]; 16]>,
+}
+
+impl HeldKeys {
+    fn new() -> Self {
+        HeldKeys {
+            keys: Box::new([[None; 128]; 16]),
+        }
+    }
+
+    /// Moves a note or key pressure message with `moved`, which gives the key a new note goes to,
+    /// if any.
+    fn move_key(
+        &mut self,
+        (timestamp, message): RawMidiMessage,
+        moved: impl FnOnce(u8) -> Option<u8>,
+    ) -> Option<RawMidiMessage> {
+        let (status, key, value) = match *message.as_ref() {
+            [status @ 0x80..=0xAF, key, value] => (status, key, value),
+            _ => return Some((timestamp, message)),
+        };
+        let moved_now = moved(key);
+        let held = &mut self.keys[(status & 0x0F) as usize][(key & 0x7F) as usize];
+        let moved = match note_of(&message) {
+            Some((true, _, _, _)) => {
+                *held = moved_now;
+                moved_now
+            }
+            Some((false, _, _, _)) => held.take(),
+            // Key pressure on a key that isn't held is moved like a new note would be.
+            None => held.or(moved_now),
+        }?;
+
+        Some((timestamp, [status, moved, value].into()))
+    }
+}
+
+/// The most octaves a controller moves notes up or down.
+const MAX_CONTROLLED_OCTAVES: i8 = 4;
+
+/// Moves every note up or down by some semitones and octaves, which can change while it plays,
+/// from its `TransposeControl` or from controllers. Notes moved off the ends of the keyboard are
+/// dropped. Held notes end on the key they started on, even if the transposition changes.
+#[derive(Clone, Debug)]
+pub struct Transpose {
+    control: TransposeControl,
+    /// Takes the semitones from this controller, from -12 at 0 to 12 at 127.
+    semitone_controller: Option<u8>,
+    /// Takes the octaves from this controller, from -4 at 0 to 4 at 127.
+    octave_controller: Option<u8>,
+    held: HeldKeys,
+}
+
+impl Transpose {
+    pub fn new(semitones: i8) -> Self {
+        let control = TransposeControl::default();
+        control.set_semitones(semitones);
+
+        Transpose {
+            control,
+            semitone_controller: None,
+            octave_controller: None,
+            held: HeldKeys::new(),
+        }
+    }
+
+    /// Follow these controllers, on any channel, for the semitones and octaves. They go no further
+    /// than the transposition.
+    pub fn with_controllers(
+        mut self,
+        semitone_controller: Option<u8>,
+        octave_controller: Option<u8>,
+    ) -> Self {
+        self.semitone_controller = semitone_controller;
+        self.octave_controller = octave_controller;
+
+        self
+    }
+
+    /// For changing the transposition while the filter is in use, like in an `Engine`.
+    pub fn control(&self) -> TransposeControl {
+        self.control.clone()
+    }
+
+    pub fn semitones(&self) -> i8 {
+        self.control.semitones()
+    }
+
+    /// Takes effect from the next note on.
+    pub fn set_semitones(&self, semitones: i8) {
+        self.control.set_semitones(semitones);
+    }
+
+    /// Returns whether the controller message moved the transposition.
+    fn follow_controller(&self, message: &MidiMessageBytes) -> bool {
+        let (controller, value) = match *message.as_ref() {
+            [0xB0..=0xBF, controller, value] => (controller, value as f32 / 127.0),
+            _ => return false,
+        };
+        if Some(controller) == self.semitone_controller {
+            self.control
+                .set_semitones((value * 24.0).round() as i8 - 12);
+            true
+        } else if Some(controller) == self.octave_controller {
+            let octaves = (value * 2.0 * MAX_CONTROLLED_OCTAVES as f32).round() as i8;
+            self.control.set_octaves(octaves - MAX_CONTROLLED_OCTAVES);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl MidiFilter for Transpose {
+    fn filter(&mut self, message: RawMidiMessage) -> Option<RawMidiMessage> {
+        if self.follow_controller(&message.1) {
+            return None;
+        }
+        let semitones = self.control.total_semitones();
+
+        self.held.move_key(message, |key| {
+            let key = key as i16 + semitones;
+
+            (0..128).contains(&key).then_some(key as u8)
+        })
+    }
+}
+
+/// Changes a `Transpose` while it plays. Clones change the same one.
+#[derive(Clone, Debug, Default)]
+pub struct TransposeControl {
+    semitones: Arc<AtomicI8>,
+    octaves: Arc<AtomicI8>,
+}
+
+impl TransposeControl {
+    pub fn semitones(&self) -> i8 {
+        self.semitones.load(Ordering::Relaxed)
+    }
+
+    /// Takes effect from the next note on.
+    pub fn set_semitones(&self, semitones: i8) {
+        self.semitones.store(semitones, Ordering::Relaxed);
+    }
+
+    pub fn octaves(&self) -> i8 {
+        self.octaves.load(Ordering::Relaxed)
+    }
+
+    /// Takes effect from the next note on.
+    pub fn set_octaves(&self, octaves: i8) {
+        self.octaves.store(octaves, Ordering::Relaxed);
+    }
+
+    /// Up or down an octave from where it is, like an octave button on a keyboard.
+    pub fn shift_octaves(&self, octaves: i8) {
+        self.set_octaves(self.octaves().saturating_add(octaves));
+    }
+
+    /// The semitones and octaves together.
+    pub fn total_semitones(&self) -> i16 {
+        self.semitones() as i16 + 12 * self.octaves() as i16
+    }
+}
+
+/// A note name without an octave, like `C`, `F#` or `Bb`, as a number of semitones above C.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PitchClass(u8);
+
+impl PitchClass {
+    const NAMES: [&'static str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+
+    /// `semitones` above C, wrapping around at the octave.
+    pub fn new(semitones: u8) -> Self {
+        PitchClass(semitones % 12)
+    }
+
+    pub fn semitones(self) -> u8 {
+        self.0
+    }
+}
+
+impl FromStr for PitchClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{:?} is not a note name, like C, F# or Bb", s);
+        let mut chars = s.trim().chars();
+        let natural = match chars.next().map(|c| c.to_ascii_uppercase()) {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => return Err(invalid()),
+        };
+        let semitones = match chars.as_str() {
+            "" => natural,
+            "#" | "♯" => natural + 1,
+            "b" | "♭" => natural + 11,
+            _ => return Err(invalid()),
+        };
+
+        Ok(PitchClass::new(semitones))
+    }
+}
+
+impl TryFrom<String> for PitchClass {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PitchClass> for String {
+    fn from(pitch_class: PitchClass) -> Self {
+        pitch_class.to_string()
+    }
+}
+
+impl fmt::Display for PitchClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(Self::NAMES[self.0 as usize])
+    }
+}
+
+/// The scales that `ScaleQuantize` snaps notes to.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scale {
+    Major,
+    Minor,
+    HarmonicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+}
+
+impl Scale {
+    pub const NAMES: [&'static str; 11] = [
+        "major",
+        "minor",
+        "harmonic-minor",
+        "dorian",
+        "phrygian",
+        "lydian",
+        "mixolydian",
+        "locrian",
+        "major-pentatonic",
+        "minor-pentatonic",
+        "blues",
+    ];
+
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "major" | "ionian" => Some(Scale::Major),
+            "minor" | "aeolian" => Some(Scale::Minor),
+            "harmonic-minor" => Some(Scale::HarmonicMinor),
+            "dorian" => Some(Scale::Dorian),
+            "phrygian" => Some(Scale::Phrygian),
+            "lydian" => Some(Scale::Lydian),
+            "mixolydian" => Some(Scale::Mixolydian),
+            "locrian" => Some(Scale::Locrian),
+            "major-pentatonic" => Some(Scale::MajorPentatonic),
+            "minor-pentatonic" => Some(Scale::MinorPentatonic),
+            "blues" => Some(Scale::Blues),
+            _ => None,
+        }
+    }
+
+    /// The notes of the scale, in semitones above its key.
+    pub fn semitones(self) -> &'static [u8] {
+        match self {
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Scale::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Scale::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Scale::Blues => &[0, 3, 5, 6, 7, 10],
+        }
+    }
+}
+
+/// Snaps every note to the nearest note of a scale, like D dorian, so pads and keys can't play a
+/// wrong note. A note halfway between two notes of the scale goes down. Held notes end on the key
+/// they started on, even if the scale changes.
+#[derive(Clone, Debug)]
+pub struct ScaleQuantize {
+    key: PitchClass,
+    scale: Scale,
+    held: HeldKeys,
+}
+
+impl ScaleQuantize {
+    pub fn new(key: PitchClass, scale: Scale) -> Self {
+        ScaleQuantize {
+            key,
+            scale,
+            held: HeldKeys::new(),
+        }
+    }
+
+    pub fn key(&self) -> PitchClass {
+        self.key
+    }
+
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    /// Takes effect from the next note on.
+    pub fn set_scale(&mut self, key: PitchClass, scale: Scale) {
+        self.key = key;
+        self.scale = scale;
+    }
+
+    /// The nearest key in the scale to `key`.
+    pub fn snap(&self, key: u8) -> u8 {
+        snap_to_scale(key, self.key, self.scale)
+    }
+}
+
+impl MidiFilter for ScaleQuantize {
+    fn filter(&mut self, message: RawMidiMessage) -> Option<RawMidiMessage> {
+        let (scale_key, scale) = (self.key, self.scale);
+
+        self.held
+            .move_key(message, |key| Some(snap_to_scale(key, scale_key, scale)))
+    }
+}
+
+fn snap_to_scale(key: u8, scale_key: PitchClass, scale: Scale) -> u8 {
+    let degree = (key as i16 - scale_key.semitones() as i16).rem_euclid(12);
+    let offset = scale
+        .semitones()
+        .iter()
+        .chain(Some(&12))
+        .map(|&note| note as i16 - degree)
+        .min_by_key(|offset| (offset.abs(), *offset > 0))
+        .unwrap_or(0);
+
+    // Snapping off either end of the keyboard goes an octave back in.
+    match key as i16 + offset {
+        snapped if snapped < 0 => (snapped + 12) as u8,
+        snapped if snapped > 127 => (snapped - 12) as u8,
+        snapped => snapped as u8,
+    }
+}
+
+/// The settings of one filter, for the config file and the command line.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum MidiFilterConfig {
+    Channels {
+        channels: Vec<u8>,
+    },
+    NoteRange {
+        lowest: u8,
+        highest: u8,
+    },
+    Velocity {
+        scale: f32,
+    },
+    Transpose {
+        #[serde(default)]
+        semitones: i8,
+        #[serde(default)]
+        octaves: i8,
+        #[serde(default)]
+        semitone_controller: Option<u8>,
+        #[serde(default)]
+        octave_controller: Option<u8>,
+    },
+    Scale {
+        key: PitchClass,
+        scale: Scale,
+    },
+}
+
+impl MidiFilterConfig {
+    pub fn push_onto(&self, chain: &mut MidiFilterChain) {
+        match *self {
+            MidiFilterConfig::Channels { ref channels } => chain.push(ChannelFilter {
+                channels: channels.clone(),
+            }),
+            MidiFilterConfig::NoteRange { lowest, highest } => {
+                chain.push(NoteRange { lowest, highest })
+            }
+            MidiFilterConfig::Velocity { scale } => chain.push(VelocityScale { scale }),
+            MidiFilterConfig::Transpose {
+                semitones,
+                octaves,
+                semitone_controller,
+                octave_controller,
+            } => {
+                let transpose = Transpose::new(semitones)
+                    .with_controllers(semitone_controller, octave_controller);
+                transpose.control().set_octaves(octaves);
+                chain.push(transpose);
+            }
+            MidiFilterConfig::Scale { key, scale } => chain.push(ScaleQuantize::new(key, scale)),
+        }
+    }
+
+    /// A chain of every filter in `configs`, in order.
+    pub fn chain(configs: &[Self]) -> MidiFilterChain {
+        let mut chain = MidiFilterChain::new();
+        for config in configs {
+            config.push_onto(&mut chain);
+        }
+
+        chain
+    }
+}
+
+impl FromStr for MidiFilterConfig {
+    type Err = String;
+
+    /// `channels=0-3,9`, `notes=36-59`, `velocity=0.8`, `transpose=-12`, `octave=-1` or
+    /// `scale=D-dorian`, with channels counting from 0 and notes as MIDI key numbers. Transposing
+    /// by `cc20` instead of a number follows controller 20.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "{:?} is not a MIDI filter, like channels=0-3,9, notes=36-59, velocity=0.8, \
+                 transpose=-12, octave=cc21 or scale=D-dorian",
+                s
+            )
+        };
+        let (kind, value) = s.split_once('=').ok_or_else(invalid)?;
+        let value = value.trim();
+        match kind.trim() {
+            "channels" => Ok(MidiFilterConfig::Channels {
+                channels: parse_channels(value).ok_or_else(invalid)?,
+            }),
+            "notes" => {
+                let (lowest, highest) = value.split_once('-').ok_or_else(invalid)?;
+                let lowest = lowest.trim().parse().map_err(|_| invalid())?;
+                let highest = highest.trim().parse().map_err(|_| invalid())?;
+                if lowest > highest || highest > 127 {
+                    return Err(invalid());
+                }
+                Ok(MidiFilterConfig::NoteRange { lowest, highest })
+            }
+            "velocity" => Ok(MidiFilterConfig::Velocity {
+                scale: value.parse().map_err(|_| invalid())?,
+            }),
+            kind @ ("transpose" | "octave") => {
+                // A number, or `ccN` to follow a controller.
+                let (amount, controller) = match value.strip_prefix("cc") {
+                    Some(controller) => (0, Some(controller.parse().map_err(|_| invalid())?)),
+                    None => (value.parse().map_err(|_| invalid())?, None),
+                };
+                Ok(if kind == "transpose" {
+                    MidiFilterConfig::Transpose {
+                        semitones: amount,
+                        octaves: 0,
+                        semitone_controller: controller,
+                        octave_controller: None,
+                    }
+                } else {
+                    MidiFilterConfig::Transpose {
+                        semitones: 0,
+                        octaves: amount,
+                        semitone_controller: None,
+                        octave_controller: controller,
+                    }
+                })
+            }
+            "scale" => {
+                let (key, scale) = value.split_once(['-', ' ']).ok_or_else(invalid)?;
+                Ok(MidiFilterConfig::Scale {
+                    key: key.parse()?,
+                    scale: Scale::by_name(&scale.trim().to_lowercase()).ok_or_else(|| {
+                        format!(
+                            "{:?} is not a scale, try one of {}",
+                            scale,
+                            Scale::NAMES.join(", ")
+                        )
+                    })?,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
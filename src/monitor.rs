@@ -0,0 +1,330 @@
+//! Live input monitoring: the audio input goes through an effects chain and out to the audio
+//! output, which makes nocturne a simple effects processor for a guitar or a microphone.
+
+use crate::{
+    audio_device::{AudioOutputDeviceStream, FramePool, OutputDevice, RENDER_SAMPLE_HZ},
+    audio_input::{AudioInputDeviceStream, InputDevice},
+    cancel::CancellationToken,
+    config::Config,
+    effects::{Effect, EffectsChain},
+    error::Result,
+    instrument::next_stream_error,
+    recording::{RecorderSet, RecordingTarget},
+    AudioFrame, TimedFrame, CHANNEL_MAX_BUFFER,
+};
+
+use log::warn;
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+};
+
+/// Frames the monitor keeps queued for the output on average: enough to ride out the input and
+/// output callbacks not lining up, and little enough to keep the latency down.
+const TARGET_QUEUED_FRAMES: f64 = 3.0;
+
+/// How far the input's conversion ratio may be pulled from nominal. Sound cards' clocks are
+/// usually within a few hundred ppm of each other, and this is well short of an audible change in
+/// pitch.
+const MAX_DRIFT_CORRECTION: f64 = 0.002;
+
+/// Correction per frame of difference between the queue's fill level and the target.
+const DRIFT_GAIN: f64 = 0.001;
+
+/// How quickly the steady correction builds up, per frame of difference each input frame.
+const DRIFT_INTEGRAL_GAIN: f64 = DRIFT_GAIN * DRIFT_GAIN / 4.0;
+
+/// How much of each new reading of the queue goes into its smoothed fill level.
+const FILL_SMOOTHING: f64 = 0.01;
+
+/// Both devices. Like the synth's output stream, they're !Send, but are only ever touched from
+/// one task at a time.
+struct MonitorStreams {
+    input: AudioInputDeviceStream,
+    output: AudioOutputDeviceStream,
+}
+
+unsafe impl Send for MonitorStreams {}
+
+/// Plays `input` through `effects` on `output` until cancelled, recording the processed sound to
+/// `recordings`. The input sets the pace: each frame is played as soon as it's captured, so the
+/// latency is one input frame plus the output's buffering. Input that isn't at
+/// `RENDER_SAMPLE_HZ` is resampled, and its channels are spread over the output's. The two
+/// devices run on their own clocks, so the resampling is steered to keep the output's queue at
+/// `TARGET_QUEUED_FRAMES`.
+///
+/// Fails if either device fails, since there's nothing to monitor without both.
+pub async fn monitor_audio_input(
+    input: InputDevice,
+    mut effects: EffectsChain,
+    output: OutputDevice,
+    recordings: Vec<RecordingTarget>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let (frame_tx, device_frame_rx) = broadcast::channel(CHANNEL_MAX_BUFFER);
+    // The output asks for frames, but it gets them when the input has them.
+    let (buffer_request_tx, mut buffer_request_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
+
+    let (mut streams, mut input_rx, mut converter, recorders) = {
+        // Unsafe streams need to stay in this scope to keep this async function Send.
+        let input = AudioInputDeviceStream::connect(&input)?;
+        let output =
+            AudioOutputDeviceStream::connect_to(&output, device_frame_rx, buffer_request_tx)?;
+        let input_config = input.get_config();
+        let num_channels = output.get_config().channels;
+        let converter = InputConverter::new(
+            input_config.channels as usize,
+            input_config.sample_rate.0,
+            num_channels as usize,
+            Config::load_default().frame_size(),
+        );
+        let recorders =
+            RecorderSet::connect(recordings, num_channels, RENDER_SAMPLE_HZ, &frame_tx)?;
+        effects.prepare(RENDER_SAMPLE_HZ as f32, num_channels as usize);
+        let input_rx = input.subscribe();
+
+        (
+            MonitorStreams { input, output },
+            input_rx,
+            converter,
+            recorders,
+        )
+    };
+    let mut frame_pool = FramePool::new();
+    let mut drift = DriftControl::new();
+    let mut frames_sent: usize = 0;
+    let mut input_errors = streams.input.take_error_receiver();
+    let mut output_errors = streams.output.take_error_receiver();
+
+    let mut result = streams.output.play().and_then(|()| streams.input.play());
+    while result.is_ok() {
+        select! {
+            frame = input_rx.recv() => match frame {
+                Ok(frame) => {
+                    converter.push(&frame.samples);
+                    while let Some(mut samples) = converter.next_frame() {
+                        effects.process(&mut samples);
+                        let frame = TimedFrame {
+                            samples: frame_pool.frame(&samples),
+                            position: converter.position(),
+                            rendered_at: frame.rendered_at,
+                        };
+                        // The output always listens, so this can't fail.
+                        let _ = frame_tx.send(frame);
+                        frames_sent += 1;
+                    }
+                    let queued = frames_sent.saturating_sub(streams.output.frames_taken());
+                    converter.steer(drift.correction(queued));
+                }
+                Err(broadcast::RecvError::Lagged(n)) => {
+                    warn!("Monitoring fell behind and skipped {} input frames", n);
+                }
+                Err(broadcast::RecvError::Closed) => break,
+            },
+            Some(()) = buffer_request_rx.recv() => (),
+            Some(e) = next_stream_error(&mut input_errors) => result = Err(e.into()),
+            Some(e) = next_stream_error(&mut output_errors) => result = Err(e.into()),
+            _ = cancel.cancelled() => break,
+        }
+    }
+    let paused = streams.input.pause().and_then(|()| streams.output.pause());
+    streams.output.log_callback_problems();
+    drop(streams);
+    let recorded = recorders.close().await;
+
+    result.and(paused).and(recorded)
+}
+
+/// Steers the input's conversion so the output's queue holds steady. No two clocks agree exactly,
+/// so left alone the queue would slowly fill until the output drops frames, or empty until it
+/// underruns. The fill level is read off the queue rather than the time, which is on a third
+/// clock.
+struct DriftControl {
+    /// The queue's fill level, smoothed over the bursts the devices' callbacks make.
+    fill: f64,
+    /// The correction the clocks' steady difference needs, built up over time.
+    integral: f64,
+}
+
+impl DriftControl {
+    fn new() -> Self {
+        DriftControl {
+            fill: TARGET_QUEUED_FRAMES,
+            integral: 0.0,
+        }
+    }
+
+    /// The correction to the input's conversion ratio, given the frames now queued for the
+    /// output. Positive while the input runs fast.
+    fn correction(&mut self, queued: usize) -> f64 {
+        self.fill += FILL_SMOOTHING * (queued as f64 - self.fill);
+        let error = self.fill - TARGET_QUEUED_FRAMES;
+        self.integral = (self.integral + DRIFT_INTEGRAL_GAIN * error)
+            .clamp(-MAX_DRIFT_CORRECTION, MAX_DRIFT_CORRECTION);
+
+        (DRIFT_GAIN * error + self.integral).clamp(-MAX_DRIFT_CORRECTION, MAX_DRIFT_CORRECTION)
+    }
+}
+
+/// Turns input frames into output frames: linearly resampled to `RENDER_SAMPLE_HZ`, with output
+/// channel `c` taking input channel `c % input_channels`, so a mono input plays in both ears.
+struct InputConverter {
+    input_channels: usize,
+    output_channels: usize,
+    /// Input sample frames per output sample frame, at the devices' nominal rates.
+    nominal_step: f64,
+    /// The same, steered for the drift between the devices.
+    step: f64,
+    /// Where the next output sample frame falls between `previous` (0) and the next input sample
+    /// frame (1).
+    phase: f64,
+    previous: Vec<f32>,
+    /// Converted samples, interleaved, that don't make up a whole frame yet.
+    pending: Vec<f32>,
+    /// Samples per output frame.
+    frame_len: usize,
+    /// The first sample frame of the next frame.
+    position: u64,
+    /// Sample frames in the last frame handed out.
+    last_frame_len: u64,
+}
+
+impl InputConverter {
+    fn new(
+        input_channels: usize,
+        input_hz: u32,
+        output_channels: usize,
+        frame_size: usize,
+    ) -> Self {
+        if input_hz != RENDER_SAMPLE_HZ {
+            warn!(
+                "Resampling the audio input from {} Hz to {} Hz",
+                input_hz, RENDER_SAMPLE_HZ
+            );
+        }
+
+        InputConverter {
+            input_channels,
+            output_channels,
+            nominal_step: input_hz as f64 / RENDER_SAMPLE_HZ as f64,
+            step: input_hz as f64 / RENDER_SAMPLE_HZ as f64,
+            phase: 1.0,
+            previous: vec![0.0; input_channels],
+            pending: Vec::with_capacity(2 * frame_size),
+            frame_len: frame_size / output_channels * output_channels,
+            position: 0,
+            last_frame_len: 0,
+        }
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        for current in samples.chunks_exact(self.input_channels) {
+            while self.phase <= 1.0 {
+                let t = self.phase as f32;
+                for c in 0..self.output_channels {
+                    let c = c % self.input_channels;
+                    self.pending
+                        .push(self.previous[c] + (current[c] - self.previous[c]) * t);
+                }
+                self.phase += self.step;
+            }
+            self.phase -= 1.0;
+            self.previous.copy_from_slice(current);
+        }
+    }
+
+    /// Makes fewer output sample frames of each input sample frame by `correction`, or more if
+    /// it's negative.
+    fn steer(&mut self, correction: f64) {
+        self.step = self.nominal_step * (1.0 + correction);
+    }
+
+    /// The next whole frame, if enough has been pushed.
+    fn next_frame(&mut self) -> Option<AudioFrame> {
+        if self.pending.len() < self.frame_len {
+            return None;
+        }
+        let frame: AudioFrame = self.pending.drain(..self.frame_len).collect();
+        self.position += self.last_frame_len;
+        self.last_frame_len = (self.frame_len / self.output_channels) as u64;
+
+        Some(frame)
+    }
+
+    /// The position of the frame `next_frame` last handed out.
+    fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::FRAME_SIZE;
+
+    /// Simulated minutes of monitoring in the drift test.
+    const DRIFT_MINUTES: u32 = 5;
+    /// Sample frames the fake input device delivers per callback.
+    const INPUT_CALLBACK_FRAMES: usize = 300;
+
+    /// Plays a mono input whose clock runs `drift` fast against a stereo output, steering the
+    /// conversion the way `monitor_audio_input` does. Returns the most frames ever queued and how
+    /// often the output found none, after the first minute.
+    fn simulate(drift: f64) -> (usize, usize) {
+        let output_channels = 2;
+        let mut converter = InputConverter::new(1, RENDER_SAMPLE_HZ, output_channels, FRAME_SIZE);
+        let mut control = DriftControl::new();
+        let input_period = INPUT_CALLBACK_FRAMES as f64 / (RENDER_SAMPLE_HZ as f64 * (1.0 + drift));
+        let output_period = (FRAME_SIZE / output_channels) as f64 / RENDER_SAMPLE_HZ as f64;
+        let input = vec![0.0; INPUT_CALLBACK_FRAMES];
+        let (mut sent, mut taken) = (0, 0);
+        let (mut most_queued, mut underruns) = (0, 0);
+        let (mut inputs, mut outputs) = (0, 0);
+        loop {
+            let input_at = inputs as f64 * input_period;
+            let output_at = outputs as f64 * output_period;
+            if input_at.min(output_at) > DRIFT_MINUTES as f64 * 60.0 {
+                break;
+            }
+            let settled = input_at.min(output_at) > 60.0;
+            if input_at < output_at {
+                converter.push(&input);
+                while converter.next_frame().is_some() {
+                    sent += 1;
+                }
+                converter.steer(control.correction(sent - taken));
+                inputs += 1;
+            } else {
+                if sent > taken {
+                    taken += 1;
+                } else if settled {
+                    underruns += 1;
+                }
+                outputs += 1;
+            }
+            if settled {
+                most_queued = most_queued.max(sent - taken);
+            }
+        }
+
+        (most_queued, underruns)
+    }
+
+    #[test]
+    fn keeps_the_queue_steady_while_the_clocks_drift() {
+        // A few times what sound cards are usually off by, either way. Uncorrected, the queue would
+        // be off by more than 20 frames by the end.
+        for drift in [-0.0005, 0.0, 0.0005] {
+            let (most_queued, underruns) = simulate(drift);
+
+            assert!(
+                most_queued <= 2 * TARGET_QUEUED_FRAMES as usize,
+                "{} frames queued at {} drift",
+                most_queued,
+                drift
+            );
+            assert_eq!(underruns, 0, "Underruns at {} drift", drift);
+        }
+    }
+}
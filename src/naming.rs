@@ -0,0 +1,101 @@
+//! Names for recordings made into a directory, like `nocturne-2024-05-01T12-30-00.wav`. They sort
+//! in the order the recordings started, and never replace an earlier recording.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const NAME_PREFIX: &str = "nocturne";
+const DEFAULT_EXTENSION: &str = "wav";
+
+/// Where to record for `path`: the path itself for a file, or a new timestamped WAV file in it for
+/// a directory. The new file is created empty straight away, so recordings started in the same
+/// second get different names.
+pub(crate) fn recording_file_path(path: &Path) -> io::Result<PathBuf> {
+    if !path.is_dir() {
+        return Ok(path.to_owned());
+    }
+    for candidate in timestamped_candidates(path, SystemTime::now()) {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    unreachable!("Ran out of recording file names")
+}
+
+/// Like `recording_file_path`, without creating the file, for a name that other names are made
+/// from, like stems.
+pub(crate) fn recording_base_path(path: &Path) -> PathBuf {
+    if !path.is_dir() {
+        return path.to_owned();
+    }
+
+    timestamped_candidates(path, SystemTime::now())
+        .find(|candidate| !candidate.exists())
+        .expect("Ran out of recording file names")
+}
+
+/// `nocturne-2024-05-01T12-30-00.wav`, then `nocturne-2024-05-01T12-30-00-2.wav` and so on.
+fn timestamped_candidates(dir: &Path, time: SystemTime) -> impl Iterator<Item = PathBuf> + '_ {
+    let stamp = format_timestamp(time);
+
+    (1..).map(move |n| {
+        let name = if n == 1 {
+            format!("{}-{}.{}", NAME_PREFIX, stamp, DEFAULT_EXTENSION)
+        } else {
+            format!("{}-{}-{}.{}", NAME_PREFIX, stamp, n, DEFAULT_EXTENSION)
+        };
+
+        dir.join(name)
+    })
+}
+
+/// ISO 8601 in UTC, with dashes instead of colons so it's a valid file name everywhere.
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs_of_day = secs % 86_400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}-{:02}-{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// The proleptic Gregorian date of a day counted from 1970-01-01, from Howard Hinnant's
+/// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Counting months from March, so the leap day comes last.
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
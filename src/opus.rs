@@ -0,0 +1,292 @@
+//! An Ogg Opus writer, for recordings a tenth the size of a WAV file or less, at a chosen bitrate.
+//!
+//! Packets are 20 ms each. One is always held back, so that `flush` can end the Ogg page with it
+//! and `finalize` can end the stream. The file is playable up to the last page even if the process
+//! dies, since Ogg has no header to fix up afterwards.
+//!
+//! Only mono and stereo are supported, at the sample rates Opus takes: 8, 12, 16, 24 or 48 kHz.
+
+use audiopus::{coder::Encoder, Application, Bitrate, Channels, Error as OpusError, SampleRate};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+/// Opus granule positions and pre-skip count samples at 48 kHz, whatever the input rate.
+const GRANULE_HZ: u64 = 48_000;
+
+/// Packets per second, for 20 ms each.
+const PACKETS_PER_SECOND: u32 = 50;
+
+/// Big enough for any packet at the highest bitrate, as libopus recommends.
+const MAX_PACKET_BYTES: usize = 4000;
+
+/// Used when a recording doesn't ask for a bitrate. Plenty for music, and still a fraction of the
+/// size of 16-bit PCM.
+const DEFAULT_BITRATE_PER_CHANNEL: u32 = 64_000;
+
+/// One logical stream per file, so any serial number will do. A fixed one keeps renders
+/// reproducible.
+const STREAM_SERIAL: u32 = 0x6e6f_6374;
+
+pub struct OpusFileWriter {
+    packets: PacketWriter<BufWriter<File>>,
+    encoder: Encoder,
+    channels: usize,
+    sample_hz: u32,
+    /// The encoder's lookahead, at 48 kHz. Players skip this much from the start.
+    pre_skip: u64,
+    /// Samples for the next packet, interleaved.
+    frame: Vec<f32>,
+    /// Samples per packet, across all channels.
+    frame_len: usize,
+    /// The last packet encoded, with its granule position, until the next one comes along.
+    held: Option<(Box<[u8]>, u64)>,
+    /// Sample frames that went into packets, at the input rate, padding included.
+    frames_encoded: u64,
+    /// Sample frames written by the caller, at the input rate.
+    frames_written: u64,
+}
+
+impl OpusFileWriter {
+    /// `bitrate` is in bits per second, and defaults to 64 kb/s per channel. libopus clamps it to
+    /// what it supports.
+    pub fn create(
+        path: &Path,
+        channels: u16,
+        sample_hz: u32,
+        bitrate: Option<u32>,
+    ) -> io::Result<Self> {
+        let opus_channels = match channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Opus recordings can be mono or stereo, not {} channels",
+                        channels
+                    ),
+                ))
+            }
+        };
+        let opus_hz = SampleRate::try_from(sample_hz as i32).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Opus can't record at {} Hz", sample_hz),
+            )
+        })?;
+        let mut encoder =
+            Encoder::new(opus_hz, opus_channels, Application::Audio).map_err(opus_error)?;
+        let bitrate = bitrate.unwrap_or(DEFAULT_BITRATE_PER_CHANNEL * channels as u32);
+        encoder
+            .set_bitrate(Bitrate::BitsPerSecond(bitrate.min(i32::MAX as u32) as i32))
+            .map_err(opus_error)?;
+        let lookahead = encoder.lookahead().map_err(opus_error)? as u64;
+
+        let frame_len = (sample_hz / PACKETS_PER_SECOND) as usize * channels as usize;
+        let mut writer = OpusFileWriter {
+            packets: PacketWriter::new(BufWriter::new(File::create(path)?)),
+            encoder,
+            channels: channels as usize,
+            sample_hz,
+            pre_skip: lookahead * GRANULE_HZ / sample_hz as u64,
+            frame: Vec::with_capacity(frame_len),
+            frame_len,
+            held: None,
+            frames_encoded: 0,
+            frames_written: 0,
+        };
+        writer.write_headers()?;
+
+        Ok(writer)
+    }
+
+    /// Writes one sample in [-1.0, 1.0], clipping anything outside that range.
+    pub fn write_sample(&mut self, sample: f32) -> io::Result<()> {
+        self.frame.push(sample.clamp(-1.0, 1.0));
+        if self.frame.len() == self.frame_len {
+            self.frames_written += (self.frame_len / self.channels) as u64;
+            self.encode_frame()?;
+        }
+
+        Ok(())
+    }
+
+    /// Ends the current page with the packets so far, so they're on disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some((packet, granule)) = self.held.take() {
+            self.packets.write_packet(
+                packet,
+                STREAM_SERIAL,
+                PacketWriteEndInfo::EndPage,
+                granule,
+            )?;
+        }
+
+        io::Write::flush(self.packets.inner_mut())
+    }
+
+    /// Pads the last packet, and the encoder's lookahead, with silence and ends the stream. The
+    /// last page's granule position tells players where the real samples stop.
+    pub fn finalize(mut self) -> io::Result<()> {
+        let partial = self.frame.len() / self.channels;
+        self.frames_written += partial as u64;
+        let lookahead = self.pre_skip * self.sample_hz as u64 / GRANULE_HZ;
+        while !self.frame.is_empty() || self.frames_encoded < self.frames_written + lookahead {
+            self.frame.resize(self.frame_len, 0.0);
+            self.encode_frame()?;
+        }
+        let end = self.pre_skip + self.frames_written * GRANULE_HZ / self.sample_hz as u64;
+        // Even an empty recording has a packet to end the stream on.
+        let packet = match self.held.take() {
+            Some((packet, _)) => packet,
+            None => Box::new([]),
+        };
+        self.packets
+            .write_packet(packet, STREAM_SERIAL, PacketWriteEndInfo::EndStream, end)?;
+
+        io::Write::flush(self.packets.inner_mut())
+    }
+
+    /// The identification header and an empty comment header, each on a page of its own.
+    fn write_headers(&mut self) -> io::Result<()> {
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(self.channels as u8);
+        head.extend_from_slice(&(self.pre_skip as u16).to_le_bytes());
+        head.extend_from_slice(&self.sample_hz.to_le_bytes());
+        // No output gain, and channel mapping family 0, for mono or stereo.
+        head.extend_from_slice(&0i16.to_le_bytes());
+        head.push(0);
+        self.packets.write_packet(
+            head.into_boxed_slice(),
+            STREAM_SERIAL,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+
+        let vendor = concat!("nocturne ", env!("CARGO_PKG_VERSION"));
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&0u32.to_le_bytes());
+
+        self.packets.write_packet(
+            tags.into_boxed_slice(),
+            STREAM_SERIAL,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )
+    }
+
+    fn encode_frame(&mut self) -> io::Result<()> {
+        let mut packet = [0; MAX_PACKET_BYTES];
+        let len = self
+            .encoder
+            .encode_float(&self.frame, &mut packet)
+            .map_err(opus_error)?;
+        self.frame.clear();
+        self.frames_encoded += (self.frame_len / self.channels) as u64;
+        let granule = self.frames_encoded * GRANULE_HZ / self.sample_hz as u64;
+
+        if let Some((held, held_granule)) = self.held.replace((packet[..len].into(), granule)) {
+            self.packets.write_packet(
+                held,
+                STREAM_SERIAL,
+                PacketWriteEndInfo::NormalPacket,
+                held_granule,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn opus_error(e: OpusError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use audiopus::{coder::Decoder, packet::Packet, MutSignals};
+    use ogg::reading::PacketReader;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nocturne-{}-{}.opus", name, std::process::id()))
+    }
+
+    /// A second and a bit of a 440 Hz tone, with the right channel at half the level.
+    fn test_signal(sample_hz: u32) -> Vec<f32> {
+        let frames = sample_hz as usize + 123;
+        (0..frames)
+            .flat_map(|i| {
+                let s = 0.5 * (i as f32 * 440.0 * std::f32::consts::TAU / sample_hz as f32).sin();
+                vec![s, 0.5 * s]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_through_a_decoder() {
+        let path = temp_path("round-trip");
+        let input = test_signal(48_000);
+        let mut writer = OpusFileWriter::create(&path, 2, 48_000, Some(96_000)).unwrap();
+        for &s in input.iter() {
+            writer.write_sample(s).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut reader = PacketReader::new(File::open(&path).unwrap());
+        let head = reader.read_packet_expected().unwrap();
+        assert_eq!(&head.data[..8], b"OpusHead");
+        assert_eq!(head.data[9], 2);
+        let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as usize;
+        let tags = reader.read_packet_expected().unwrap();
+        assert_eq!(&tags.data[..8], b"OpusTags");
+
+        let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+        let mut output = Vec::new();
+        let mut end = 0;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            let mut decoded = [0.0; MAX_PACKET_BYTES * 2];
+            let packet_data = Packet::try_from(&packet.data[..]).unwrap();
+            let frames = decoder
+                .decode_float(
+                    Some(packet_data),
+                    MutSignals::try_from(&mut decoded[..]).unwrap(),
+                    false,
+                )
+                .unwrap();
+            output.extend_from_slice(&decoded[..frames * 2]);
+            if packet.last_in_stream() {
+                end = packet.absgp_page() as usize;
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        // The last granule position trims the padding back off.
+        assert_eq!(end - pre_skip, input.len() / 2);
+        let output = &output[2 * pre_skip..2 * end];
+        let error = input
+            .iter()
+            .zip(output.iter())
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum::<f32>()
+            / input.iter().map(|a| a * a).sum::<f32>();
+        assert!(error < 0.01, "Relative error {}", error);
+    }
+
+    #[test]
+    fn rejects_more_than_two_channels() {
+        let error = OpusFileWriter::create(&temp_path("surround"), 4, 48_000, None)
+            .err()
+            .unwrap();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,415 @@
+//! Remote control over OSC (Open Sound Control) on UDP, for control surfaces like TouchOSC and
+//! patchers like Max/MSP. Synths are numbered as in routes, with the instrument as 0:
+//!
+//! - `/nocturne/synth/0/cutoff 0.75` moves a parameter, from 0.0 to 1.0, by any of the names in
+//!   `SynthParameter::NAMES`.
+//! - `/nocturne/synth/0/note-on 60 100` and `/nocturne/synth/0/note-off 60` play notes, with an
+//!   optional MIDI channel after them, counting from 0. A note on with velocity 0 is a note off.
+//! - `/nocturne/transport/play`, `/pause`, `/speed 0.5`, `/bar 9`, `/tick 1920`, `/mute 2`,
+//!   `/solo 2` control file playback, like the commands `play-file` takes on the terminal. Mute and
+//!   solo take an optional second argument, which turns them off when it is 0 or false.
+//!
+//! Numbers can be sent as ints, floats or doubles. Bundles are taken apart and played straight
+//! away, whatever their time tags say.
+
+use crate::{
+    cancel::CancellationToken, controls::SynthParameter, engine::Engine, midi::MidiMessageBytes,
+    midi::TransportCommand,
+};
+
+use log::{trace, warn};
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use tokio::{net::UdpSocket, select, sync::mpsc};
+
+/// Big enough for any packet that fits in one UDP datagram.
+const MAX_PACKET_SIZE: usize = 65536;
+
+const SYNTH_PREFIX: &str = "/nocturne/synth/";
+const TRANSPORT_PREFIX: &str = "/nocturne/transport/";
+
+/// Something an OSC message asks for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscCommand {
+    /// Control file playback.
+    Transport(TransportCommand),
+    /// Move a synth's parameter to a value from 0.0 to 1.0.
+    SetParameter {
+        synth: usize,
+        parameter: SynthParameter,
+        value: f32,
+    },
+    /// Play a MIDI message, a note on or off, on a synth.
+    Midi {
+        synth: usize,
+        message: MidiMessageBytes,
+    },
+}
+
+/// Listens for OSC messages on a UDP socket.
+pub struct OscServer {
+    socket: UdpSocket,
+    buffer: Box<[u8]>,
+    /// The rest of the last bundle.
+    pending: VecDeque<OscCommand>,
+}
+
+impl OscServer {
+    /// Listens on `addr`, like `0.0.0.0:9000` for every network interface or `127.0.0.1:9000` for
+    /// this computer alone.
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Ok(OscServer {
+            socket: UdpSocket::bind(addr).await?,
+            buffer: vec![0; MAX_PACKET_SIZE].into_boxed_slice(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Waits for the next command. Packets that can't be read, and messages to addresses that
+    /// don't mean anything, are logged and skipped.
+    pub async fn recv(&mut self) -> io::Result<OscCommand> {
+        loop {
+            if let Some(command) = self.pending.pop_front() {
+                return Ok(command);
+            }
+            let (len, from) = self.socket.recv_from(&mut self.buffer).await?;
+            let mut messages = Vec::new();
+            if let Err(e) = read_packet(&self.buffer[..len], &mut messages) {
+                warn!("Skipping an OSC packet from {}: {}", from, e);
+                continue;
+            }
+            for message in messages {
+                match command_of(&message) {
+                    Ok(command) => self.pending.push_back(command),
+                    Err(e) => warn!(
+                        "Skipping OSC message {} from {}: {}",
+                        message.address, from, e
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Carries out commands on `engine` until it stops or `cancel` is cancelled. Transport commands
+    /// go to `transport_tx`, for whatever is playing a file with it, or are dropped without one.
+    pub async fn control_engine(
+        mut self,
+        engine: &Engine,
+        mut transport_tx: Option<mpsc::Sender<TransportCommand>>,
+        cancel: CancellationToken,
+    ) -> io::Result<()> {
+        while engine.is_running() {
+            let command = select! {
+                command = self.recv() => command?,
+                _ = cancel.cancelled() => break,
+            };
+            trace!("OSC: {:?}", command);
+            match command {
+                OscCommand::Transport(command) => match transport_tx.as_mut() {
+                    Some(tx) => {
+                        if !send_transport(tx, command) {
+                            transport_tx = None;
+                        }
+                    }
+                    None => warn!("Nothing is playing a file, so {:?} does nothing", command),
+                },
+                OscCommand::SetParameter {
+                    synth,
+                    parameter,
+                    value,
+                } => match engine.synth_controls(synth) {
+                    Some(controls) => controls.set(parameter, value),
+                    None => warn!(
+                        "There is no synth {} to move {} on",
+                        synth,
+                        parameter.name()
+                    ),
+                },
+                OscCommand::Midi { synth, message } => {
+                    if synth < engine.num_synths() {
+                        engine.send_to(synth, (0, message)).await;
+                    } else {
+                        warn!("There is no synth {} to play on", synth);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Passes transport commands to `transport_tx` until whatever is playing hangs up or `cancel`
+    /// is cancelled. Other commands are dropped, since there is no engine to carry them out.
+    pub async fn control_transport(
+        mut self,
+        mut transport_tx: mpsc::Sender<TransportCommand>,
+        cancel: CancellationToken,
+    ) -> io::Result<()> {
+        loop {
+            let command = select! {
+                command = self.recv() => command?,
+                _ = cancel.cancelled() => return Ok(()),
+            };
+            match command {
+                OscCommand::Transport(command) => {
+                    if !send_transport(&mut transport_tx, command) {
+                        return Ok(());
+                    }
+                }
+                command => warn!("Only transport commands work here, not {:?}", command),
+            }
+        }
+    }
+}
+
+/// Returns false once nothing is listening.
+fn send_transport(
+    transport_tx: &mut mpsc::Sender<TransportCommand>,
+    command: TransportCommand,
+) -> bool {
+    match transport_tx.try_send(command) {
+        Ok(()) => true,
+        Err(mpsc::error::TrySendError::Full(_)) => {
+            warn!(
+                "Dropping transport command {:?}, too many are waiting",
+                command
+            );
+            true
+        }
+        Err(mpsc::error::TrySendError::Closed(_)) => false,
+    }
+}
+
+/// An OSC message, with the arguments that matter here.
+#[derive(Clone, Debug)]
+struct OscMessage {
+    address: String,
+    args: Vec<OscArg>,
+}
+
+#[derive(Clone, Debug)]
+enum OscArg {
+    Number(f64),
+    /// Strings, blobs and the rarer types, which nothing here takes.
+    Other,
+}
+
+impl OscArg {
+    /// NaN and the infinities aren't taken, since a single one would stick in whatever it's
+    /// smoothed into.
+    fn number(&self) -> Option<f64> {
+        match self {
+            OscArg::Number(n) if n.is_finite() => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the messages in a packet, taking bundles apart.
+fn read_packet(packet: &[u8], messages: &mut Vec<OscMessage>) -> Result<(), String> {
+    if packet.starts_with(b"#bundle\0") {
+        // The time tag is ignored.
+        let mut rest = packet.get(16..).ok_or("The bundle is cut short")?;
+        while !rest.is_empty() {
+            let size = read_i32(&mut rest)?;
+            if size < 0 || size as usize > rest.len() {
+                return Err("A bundle element is cut short".to_string());
+            }
+            let (element, after) = rest.split_at(size as usize);
+            read_packet(element, messages)?;
+            rest = after;
+        }
+
+        return Ok(());
+    }
+
+    let mut rest = packet;
+    let address = read_string(&mut rest)?;
+    if !address.starts_with('/') {
+        return Err(format!("{:?} is not an OSC address", address));
+    }
+    // Very old senders leave out the type tags when there are no arguments.
+    let type_tags = if rest.is_empty() {
+        String::new()
+    } else {
+        read_string(&mut rest)?
+    };
+    let type_tags = type_tags
+        .strip_prefix(',')
+        .ok_or("The type tags are missing")?;
+    let mut args = Vec::with_capacity(type_tags.len());
+    for tag in type_tags.chars() {
+        let arg = match tag {
+            'i' => OscArg::Number(read_i32(&mut rest)? as f64),
+            'f' => OscArg::Number(f32::from_bits(read_i32(&mut rest)? as u32) as f64),
+            'h' => OscArg::Number(read_i64(&mut rest)? as f64),
+            'd' => OscArg::Number(f64::from_bits(read_i64(&mut rest)? as u64)),
+            'T' => OscArg::Number(1.0),
+            'F' => OscArg::Number(0.0),
+            's' | 'S' => {
+                read_string(&mut rest)?;
+                OscArg::Other
+            }
+            'b' => {
+                let size = read_i32(&mut rest)?.max(0) as usize;
+                take(&mut rest, padded(size))?;
+                OscArg::Other
+            }
+            't' => {
+                read_i64(&mut rest)?;
+                OscArg::Other
+            }
+            'c' | 'r' | 'm' => {
+                read_i32(&mut rest)?;
+                OscArg::Other
+            }
+            'N' | 'I' => OscArg::Other,
+            _ => return Err(format!("Can't read arguments of type {:?}", tag)),
+        };
+        args.push(arg);
+    }
+    messages.push(OscMessage { address, args });
+
+    Ok(())
+}
+
+/// `len` rounded up to a multiple of 4, as everything in OSC is.
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if rest.len() < len {
+        return Err("The message is cut short".to_string());
+    }
+    let (taken, after) = rest.split_at(len);
+    *rest = after;
+
+    Ok(taken)
+}
+
+fn read_i32(rest: &mut &[u8]) -> Result<i32, String> {
+    let bytes = take(rest, 4)?;
+
+    Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_i64(rest: &mut &[u8]) -> Result<i64, String> {
+    let high = read_i32(rest)? as u32 as u64;
+    let low = read_i32(rest)? as u32 as u64;
+
+    Ok((high << 32 | low) as i64)
+}
+
+/// A string ends with at least one NUL, padded out to a multiple of 4.
+fn read_string(rest: &mut &[u8]) -> Result<String, String> {
+    let len = rest
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or("A string is cut short")?;
+    let bytes = take(rest, padded(len + 1))?;
+
+    String::from_utf8(bytes[..len].to_vec()).map_err(|_| "A string isn't UTF-8".to_string())
+}
+
+fn command_of(message: &OscMessage) -> Result<OscCommand, String> {
+    if let Some(rest) = message.address.strip_prefix(SYNTH_PREFIX) {
+        let (synth, action) = rest.split_once('/').ok_or("No parameter or note")?;
+        let synth = synth
+            .parse()
+            .map_err(|_| format!("{:?} is not a synth number", synth))?;
+        synth_command_of(synth, action, &message.args)
+    } else if let Some(action) = message.address.strip_prefix(TRANSPORT_PREFIX) {
+        transport_command_of(action, &message.args).map(OscCommand::Transport)
+    } else {
+        Err("Nothing listens there".to_string())
+    }
+}
+
+fn synth_command_of(synth: usize, action: &str, args: &[OscArg]) -> Result<OscCommand, String> {
+    let number = |i: usize| -> Result<f64, String> {
+        args.get(i)
+            .and_then(OscArg::number)
+            .ok_or_else(|| format!("Argument {} should be a number", i + 1))
+    };
+    let byte = |i: usize, max: u8| -> Result<u8, String> {
+        let n = number(i)?.round();
+        if (0.0..=max as f64).contains(&n) {
+            Ok(n as u8)
+        } else {
+            Err(format!("{} is out of range, from 0 to {}", n, max))
+        }
+    };
+    // The channel is optional, after the other arguments.
+    let channel = |i: usize| if args.len() > i { byte(i, 15) } else { Ok(0) };
+    let message: MidiMessageBytes = match action {
+        "note-on" => [0x90 | channel(2)?, byte(0, 127)?, byte(1, 127)?].into(),
+        "note-off" => [0x80 | channel(1)?, byte(0, 127)?, 0].into(),
+        parameter => {
+            let parameter = SynthParameter::by_name(parameter).ok_or_else(|| {
+                format!(
+                    "{:?} is not a parameter, try one of {}",
+                    parameter,
+                    SynthParameter::NAMES.join(", ")
+                )
+            })?;
+            return Ok(OscCommand::SetParameter {
+                synth,
+                parameter,
+                value: number(0)?.clamp(0.0, 1.0) as f32,
+            });
+        }
+    };
+
+    Ok(OscCommand::Midi { synth, message })
+}
+
+fn transport_command_of(action: &str, args: &[OscArg]) -> Result<TransportCommand, String> {
+    let number = |i: usize| -> Result<f64, String> {
+        args.get(i)
+            .and_then(OscArg::number)
+            .ok_or_else(|| format!("Argument {} should be a number", i + 1))
+    };
+    // Mute and solo turn on without a second argument.
+    let on = || !matches!(number(1), Ok(n) if n == 0.0);
+
+    Ok(match action {
+        "play" => TransportCommand::Play,
+        "pause" => TransportCommand::Pause,
+        "speed" => match number(0)? {
+            speed if speed > 0.0 => TransportCommand::SetSpeed(speed),
+            speed => return Err(format!("{} is not a speed", speed)),
+        },
+        "bar" => TransportCommand::SeekToBar(number(0)?.max(1.0) as u32),
+        "tick" => TransportCommand::SeekToTick(number(0)? as i64),
+        "mute" => TransportCommand::SetMute(number(0)?.max(0.0) as usize, on()),
+        "solo" => TransportCommand::SetSolo(number(0)?.max(0.0) as usize, on()),
+        _ => return Err(format!("{:?} is not a transport command", action)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(address: &str, value: f64) -> Result<OscCommand, String> {
+        command_of(&OscMessage {
+            address: address.to_string(),
+            args: vec![OscArg::Number(value)],
+        })
+    }
+
+    #[test]
+    fn rejects_numbers_that_arent_finite() {
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(command("/nocturne/synth/0/gain", value).is_err());
+            assert!(command("/nocturne/transport/speed", value).is_err());
+        }
+        assert!(command("/nocturne/synth/0/gain", 0.5).is_ok());
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{
+    patch::{LoadedPatch, PatchBank},
+    sampler::KeyMap,
+    soundfont::SoundFont,
+    wave_table::{load_wave_from_wav, Wave, WaveTableIndex},
+};
+
+use std::fmt;
+use std::path::Path;
+
+/// What a voice plays: a pitched wave table, unpitched noise, the samples of a soundfont, or any of
+/// those as set up by a synth patch.
+#[derive(Clone, Copy)]
+pub enum Source {
+    Wave(Wave),
+    WhiteNoise,
+    PinkNoise,
+    /// Each channel plays the preset picked by its bank select and program change messages. A
+    /// sampler `KeyMap` builds a font with one preset, which every channel plays.
+    SoundFont(&'static SoundFont),
+    /// Made by `SynthPatch::instrument`.
+    Patch(&'static LoadedPatch),
+    /// Each channel plays the patch picked by its program change messages.
+    PatchBank(&'static PatchBank),
+}
+
+impl From<Wave> for Source {
+    fn from(wave: Wave) -> Self {
+        Source::Wave(wave)
+    }
+}
+
+impl fmt::Debug for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't dump 64K table entries.
+        match self {
+            Source::Wave(w) => write!(f, "Wave({:p})", w.as_ptr()),
+            Source::WhiteNoise => write!(f, "WhiteNoise"),
+            Source::PinkNoise => write!(f, "PinkNoise"),
+            Source::SoundFont(s) => write!(f, "SoundFont({:p})", *s),
+            Source::Patch(p) => write!(f, "Patch({:?})", p.patch.name),
+            Source::PatchBank(b) => write!(f, "PatchBank({} patches)", b.patches().len()),
+        }
+    }
+}
+
+impl Source {
+    /// Looks up a built-in wave or noise source by name.
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "white-noise" | "noise" => Some(Source::WhiteNoise),
+            "pink-noise" => Some(Source::PinkNoise),
+            other => crate::wave_table::wave_by_name(other).map(Source::Wave),
+        }
+    }
+
+    /// A built-in source by name, or one loaded from a single-cycle WAV file, an SF2 soundfont or
+    /// a sampler key map (`.toml`). The error says what was wrong, for showing to the user.
+    pub fn by_name_or_path(s: &str) -> Result<Self, String> {
+        if let Some(source) = Source::by_name(s) {
+            return Ok(source);
+        }
+        let path = Path::new(s);
+        if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("sf2"))
+        {
+            return SoundFont::load(path)
+                .map(Source::SoundFont)
+                .map_err(|e| format!("{:?} is not a readable soundfont: {}", s, e));
+        }
+        if path.extension().is_some_and(|e| e == "toml") {
+            return KeyMap::load(path)
+                .and_then(|map| map.build())
+                .map(Source::SoundFont)
+                .map_err(|e| format!("{:?} is not a usable sampler key map: {}", s, e));
+        }
+
+        load_wave_from_wav(path).map(Source::Wave).map_err(|e| {
+            format!(
+                "{:?} is not a built-in wave or a readable WAV file: {}",
+                s, e
+            )
+        })
+    }
+}
+
+/// The per-voice state for a `Source`.
+pub enum Oscillator {
+    WaveTable(Wave, WaveTableIndex),
+    WhiteNoise(WhiteNoise),
+    PinkNoise(PinkNoise),
+}
+
+impl Oscillator {
+    /// `phase` is where in the cycle a wave starts, in [0.0, 1.0). `seed` only matters for noise.
+    /// Give each voice a different one so simultaneous noise voices aren't correlated.
+    ///
+    /// Panics for `Source::SoundFont`, whose notes are played from samples instead, and for
+    /// `Source::Patch` and `Source::PatchBank`, which the synthesizer unwraps first.
+    pub fn new(source: Source, sample_hz: f32, hz: f32, phase: f32, seed: u32) -> Self {
+        match source {
+            Source::Wave(wave) => Oscillator::WaveTable(
+                wave,
+                WaveTableIndex::from_hz_with_phase(sample_hz, hz, phase),
+            ),
+            Source::WhiteNoise => Oscillator::WhiteNoise(WhiteNoise::new(seed)),
+            Source::PinkNoise => Oscillator::PinkNoise(PinkNoise::new(seed)),
+            Source::SoundFont(_) => panic!("A soundfont has no oscillator"),
+            Source::Patch(_) | Source::PatchBank(_) => {
+                panic!("A patch has no oscillator of its own")
+            }
+        }
+    }
+
+    /// Retunes a wave without resetting its phase. Noise has no pitch, so it is unaffected.
+    pub fn set_frequency(&mut self, sample_hz: f32, hz: f32) {
+        if let Oscillator::WaveTable(_, index) = self {
+            index.set_frequency(sample_hz, hz);
+        }
+    }
+
+    pub fn sample(&mut self) -> f32 {
+        match self {
+            Oscillator::WaveTable(wave, index) => index.sample_table(wave),
+            Oscillator::WhiteNoise(n) => n.sample(),
+            Oscillator::PinkNoise(n) => n.sample(),
+        }
+    }
+
+    /// Fills `out` with the next samples, the same as calling `sample` for each.
+    pub fn sample_block(&mut self, out: &mut [f32]) {
+        match self {
+            Oscillator::WaveTable(wave, index) => index.sample_table_block(wave, out),
+            Oscillator::WhiteNoise(n) => out.iter_mut().for_each(|o| *o = n.sample()),
+            Oscillator::PinkNoise(n) => out.iter_mut().for_each(|o| *o = n.sample()),
+        }
+    }
+}
+
+/// Uniform noise in [-1.0, 1.0] from a xorshift generator. Cheap enough to run per voice.
+pub struct WhiteNoise {
+    state: u32,
+}
+
+impl WhiteNoise {
+    pub fn new(seed: u32) -> Self {
+        // Xorshift gets stuck at zero.
+        WhiteNoise { state: seed.max(1) }
+    }
+
+    pub fn sample(&mut self) -> f32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+
+        (x as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+/// Noise with equal power per octave (-3 dB/octave), made by filtering white noise with Paul
+/// Kellet's economy filter.
+pub struct PinkNoise {
+    white: WhiteNoise,
+    b: [f32; 3],
+}
+
+impl PinkNoise {
+    pub fn new(seed: u32) -> Self {
+        PinkNoise {
+            white: WhiteNoise::new(seed),
+            b: [0.0; 3],
+        }
+    }
+
+    pub fn sample(&mut self) -> f32 {
+        let white = self.white.sample();
+        self.b[0] = 0.99765 * self.b[0] + white * 0.0990460;
+        self.b[1] = 0.96300 * self.b[1] + white * 0.2965164;
+        self.b[2] = 0.57000 * self.b[2] + white * 1.0526913;
+
+        // Scale back to roughly [-1.0, 1.0].
+        0.25 * (self.b[0] + self.b[1] + self.b[2] + white * 0.1848)
+    }
+}
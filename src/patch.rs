@@ -0,0 +1,491 @@
+//! Synth patches: the oscillator, envelope, filter and effect settings that make up a sound, saved
+//! as TOML or JSON so they can be shared and recalled. A TOML patch looks like
+//!
+//! ```toml
+//! name = "Warm pad"
+//!
+//! [oscillator]
+//! wave = "sawtooth"
+//! unison = { voices = 3, detune_cents = 12.0, stereo_spread = 0.6 }
+//!
+//! [envelope]
+//! attack_secs = 0.4
+//! release_secs = 1.2
+//!
+//! [filter]
+//! cutoff_hz = 1800.0
+//! envelope_octaves = 1.0
+//!
+//! [[effects]]
+//! type = "chorus"
+//! mix = 0.4
+//!
+//! [[controls]]
+//! parameter = "cutoff"
+//! controller = 21
+//! ```
+//!
+//! Anything left out has its default value.
+
+use crate::{
+    controls::{ControlBinding, ControlBindings},
+    effects::{Chorus, Compressor, EffectsChain, Gain, Limiter, ShaperCurve, Waveshaper},
+    envelope::Adsr,
+    oscillator::Source,
+    rng::Rng,
+    synthesizer::{Unison, VoiceFilter},
+};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct SynthPatch {
+    pub name: String,
+    pub oscillator: OscillatorPatch,
+    /// The amplitude envelope of each note. Without one, notes use the synthesizer's built-in
+    /// attack and decay.
+    pub envelope: Option<Adsr>,
+    pub filter: VoiceFilter,
+    /// Applied to the output in order.
+    pub effects: Vec<EffectPatch>,
+    /// Controllers that move the patch's parameters, from MIDI learn or written by hand.
+    pub controls: Vec<ControlBinding>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct OscillatorPatch {
+    /// A built-in wave or noise name, or the path of a single-cycle WAV file, an SF2 soundfont or a
+    /// sampler key map. Paths are relative to the patch file.
+    pub wave: String,
+    pub unison: Unison,
+}
+
+impl Default for OscillatorPatch {
+    fn default() -> Self {
+        OscillatorPatch {
+            wave: "triangle".to_string(),
+            unison: Unison::default(),
+        }
+    }
+}
+
+/// The settings of one effect. Fields that are left out keep the effect's defaults.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum EffectPatch {
+    Gain {
+        db: f32,
+    },
+    Chorus {
+        #[serde(default)]
+        rate_hz: Option<f32>,
+        /// Beats per LFO cycle, when following a MIDI clock.
+        #[serde(default)]
+        sync_beats: Option<f32>,
+        #[serde(default)]
+        delay_ms: Option<f32>,
+        #[serde(default)]
+        depth_ms: Option<f32>,
+        #[serde(default)]
+        mix: Option<f32>,
+        #[serde(default)]
+        voices: Option<usize>,
+    },
+    Distortion {
+        curve: ShaperCurve,
+        #[serde(default)]
+        drive: Option<f32>,
+        #[serde(default)]
+        output_gain: Option<f32>,
+    },
+    Compressor {
+        #[serde(default)]
+        threshold_db: Option<f32>,
+        #[serde(default)]
+        ratio: Option<f32>,
+        #[serde(default)]
+        attack_ms: Option<f32>,
+        #[serde(default)]
+        release_ms: Option<f32>,
+        #[serde(default)]
+        makeup_db: Option<f32>,
+    },
+    Limiter {
+        #[serde(default)]
+        ceiling_db: Option<f32>,
+        #[serde(default)]
+        lookahead_ms: Option<f32>,
+        #[serde(default)]
+        release_ms: Option<f32>,
+    },
+}
+
+impl EffectPatch {
+    fn push_onto(&self, chain: &mut EffectsChain) {
+        match *self {
+            EffectPatch::Gain { db } => chain.push(Gain::from_db(db)),
+            EffectPatch::Chorus {
+                rate_hz,
+                sync_beats,
+                delay_ms,
+                depth_ms,
+                mix,
+                voices,
+            } => {
+                let mut chorus = Chorus::new();
+                chorus.rate_hz = rate_hz.unwrap_or(chorus.rate_hz);
+                chorus.sync_beats = sync_beats;
+                chorus.delay_ms = delay_ms.unwrap_or(chorus.delay_ms);
+                chorus.depth_ms = depth_ms.unwrap_or(chorus.depth_ms);
+                chorus.mix = mix.unwrap_or(chorus.mix);
+                chorus.voices = voices.unwrap_or(chorus.voices);
+                chain.push(chorus);
+            }
+            EffectPatch::Distortion {
+                curve,
+                drive,
+                output_gain,
+            } => {
+                let mut shaper = Waveshaper::new(curve);
+                shaper.drive = drive.unwrap_or(shaper.drive);
+                shaper.output_gain = output_gain.unwrap_or(shaper.output_gain);
+                chain.push(shaper);
+            }
+            EffectPatch::Compressor {
+                threshold_db,
+                ratio,
+                attack_ms,
+                release_ms,
+                makeup_db,
+            } => {
+                let mut compressor = Compressor::new();
+                compressor.threshold_db = threshold_db.unwrap_or(compressor.threshold_db);
+                compressor.ratio = ratio.unwrap_or(compressor.ratio);
+                compressor.attack_ms = attack_ms.unwrap_or(compressor.attack_ms);
+                compressor.release_ms = release_ms.unwrap_or(compressor.release_ms);
+                compressor.makeup_db = makeup_db.unwrap_or(compressor.makeup_db);
+                chain.push(compressor);
+            }
+            EffectPatch::Limiter {
+                ceiling_db,
+                lookahead_ms,
+                release_ms,
+            } => {
+                let mut limiter = Limiter::new();
+                limiter.ceiling_db = ceiling_db.unwrap_or(limiter.ceiling_db);
+                limiter.lookahead_ms = lookahead_ms.unwrap_or(limiter.lookahead_ms);
+                limiter.release_ms = release_ms.unwrap_or(limiter.release_ms);
+                chain.push(limiter);
+            }
+        }
+    }
+}
+
+/// A patch with its wave loaded, ready to play. Notes take their unison, envelope and filter from
+/// the patch instead of the synthesizer.
+#[derive(Debug)]
+pub struct LoadedPatch {
+    pub patch: SynthPatch,
+    /// Never another `Source::Patch`.
+    pub source: Source,
+}
+
+/// Patches to switch between with program changes. Each channel plays the patch for its last
+/// program change, or the first patch before it gets one or if the bank has no patch for it.
+#[derive(Debug)]
+pub struct PatchBank {
+    patches: Vec<LoadedPatch>,
+}
+
+impl PatchBank {
+    /// Loads every `.toml` and `.json` patch in `dir`. They are numbered in file name order from
+    /// program 0, so names like `000 piano.toml` and `001 strings.toml` keep them in place. Like
+    /// `SoundFont::load`, the bank lives for the rest of the program.
+    pub fn load_dir(dir: &Path) -> io::Result<&'static PatchBank> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file()
+                && path.extension().is_some_and(|e| {
+                    e.eq_ignore_ascii_case("toml") || e.eq_ignore_ascii_case("json")
+                })
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        if paths.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No .toml or .json patches in the directory",
+            ));
+        }
+
+        let mut patches = Vec::with_capacity(paths.len());
+        for path in paths {
+            let loaded = SynthPatch::load(&path)
+                .and_then(|patch| patch.loaded())
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            patches.push(loaded);
+        }
+
+        Ok(Self::from_patches(patches))
+    }
+
+    /// Numbered from program 0, like `load_dir`. There must be at least one patch.
+    pub(crate) fn from_patches(patches: Vec<LoadedPatch>) -> &'static PatchBank {
+        assert!(!patches.is_empty(), "A patch bank needs a patch");
+
+        Box::leak(Box::new(PatchBank { patches }))
+    }
+
+    pub fn patches(&self) -> &[LoadedPatch] {
+        &self.patches
+    }
+
+    pub(crate) fn patch(&self, program: u8) -> &LoadedPatch {
+        self.patches
+            .get(program as usize)
+            .unwrap_or(&self.patches[0])
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+}
+
+/// TOML floats are 64-bit, so every `f32` setting would otherwise be written with the digits of
+/// its binary approximation, like 0.4000000059604645.
+fn shorten_floats(value: &mut toml::Value) {
+    match value {
+        toml::Value::Float(x) => {
+            *x = (*x as f32).to_string().parse().unwrap_or(*x);
+        }
+        toml::Value::Array(values) => values.iter_mut().for_each(shorten_floats),
+        toml::Value::Table(table) => table.iter_mut().for_each(|(_, v)| shorten_floats(v)),
+        _ => (),
+    }
+}
+
+impl SynthPatch {
+    /// Reads a patch from a `.json` file, or TOML otherwise. A wave path in the patch is resolved
+    /// against the file's directory.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut patch = Self::load_as_written(path)?;
+        if Source::by_name(&patch.oscillator.wave).is_none() {
+            let dir = path.parent().unwrap_or_else(|| Path::new(""));
+            patch.oscillator.wave = dir
+                .join(&patch.oscillator.wave)
+                .to_string_lossy()
+                .into_owned();
+        }
+
+        Ok(patch)
+    }
+
+    /// Like `load`, with the wave path left as it is in the file.
+    fn load_as_written(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        if is_json(path) {
+            serde_json::from_str(&text).map_err(invalid_data)
+        } else {
+            toml::from_str(&text).map_err(invalid_data)
+        }
+    }
+
+    /// Replaces the controller bindings of the patch file at `path`, as after MIDI learn, and
+    /// leaves the rest of the patch as it is.
+    pub fn save_controls(path: &Path, controls: &[ControlBinding]) -> io::Result<()> {
+        let mut patch = Self::load_as_written(path)?;
+        patch.controls = controls.to_vec();
+
+        patch.save(path)
+    }
+
+    /// The bindings for a synth playing this patch.
+    pub fn control_bindings(&self) -> ControlBindings {
+        ControlBindings::new(self.controls.clone())
+    }
+
+    /// Writes the patch as JSON if `path` ends in `.json`, or TOML otherwise.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let text = if is_json(path) {
+            serde_json::to_string_pretty(self).map_err(invalid_data)?
+        } else {
+            // Going through a `Value` puts nested tables after plain values, as TOML requires.
+            let mut value = toml::Value::try_from(self).map_err(invalid_data)?;
+            shorten_floats(&mut value);
+            toml::to_string(&value).map_err(invalid_data)?
+        };
+
+        fs::write(path, text)
+    }
+
+    /// Loads the wave and returns a source that plays the patch. Like `SoundFont::load`, the loaded
+    /// patch lives for the rest of the program.
+    pub fn instrument(&self) -> io::Result<Source> {
+        let source = self.load_source()?;
+
+        Ok(Source::Patch(Box::leak(Box::new(LoadedPatch {
+            patch: self.clone(),
+            source,
+        }))))
+    }
+
+    fn load_source(&self) -> io::Result<Source> {
+        Source::by_name_or_path(&self.oscillator.wave).map_err(invalid_data)
+    }
+
+    /// The patch with its wave loaded, for a `PatchBank`.
+    pub(crate) fn loaded(self) -> io::Result<LoadedPatch> {
+        let source = self.load_source()?;
+
+        Ok(LoadedPatch {
+            patch: self,
+            source,
+        })
+    }
+
+    /// The patch's effects, to go on the output of whatever plays it.
+    pub fn effects(&self) -> EffectsChain {
+        let mut chain = EffectsChain::new();
+        for effect in self.effects.iter() {
+            effect.push_onto(&mut chain);
+        }
+
+        chain
+    }
+}
+
+/// The limits a random patch stays within. The defaults give playable, mostly tonal sounds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatchConstraints {
+    /// Waves to pick from, by name or path.
+    pub waves: Vec<String>,
+    pub max_unison_voices: usize,
+    pub max_detune_cents: f32,
+    /// The lowest and highest filter cutoff.
+    pub cutoff_hz: (f32, f32),
+    pub max_resonance: f32,
+    pub max_attack_secs: f32,
+    pub max_release_secs: f32,
+    /// Up to this many of distortion, chorus and compression, in that order.
+    pub max_effects: usize,
+}
+
+impl Default for PatchConstraints {
+    fn default() -> Self {
+        PatchConstraints {
+            waves: ["sine", "triangle", "sawtooth", "square"]
+                .iter()
+                .map(|w| w.to_string())
+                .collect(),
+            max_unison_voices: 5,
+            max_detune_cents: 30.0,
+            cutoff_hz: (300.0, 8000.0),
+            max_resonance: 0.7,
+            max_attack_secs: 0.5,
+            max_release_secs: 1.5,
+            max_effects: 2,
+        }
+    }
+}
+
+/// Envelope times never go below this, so notes don't click.
+const MIN_ENVELOPE_SECS: f32 = 0.002;
+
+impl SynthPatch {
+    /// A random patch within `constraints`. The same seed always gives the same patch.
+    pub fn randomize(seed: u64, constraints: &PatchConstraints) -> Self {
+        let mut rng = Rng::new(seed);
+        let wave = constraints
+            .waves
+            .get(rng.index(constraints.waves.len()))
+            .cloned()
+            .unwrap_or_else(|| OscillatorPatch::default().wave);
+        let voices = 1 + rng.index(constraints.max_unison_voices.max(1));
+        let unison = Unison {
+            voices,
+            detune_cents: if voices > 1 {
+                rng.range(0.0, constraints.max_detune_cents)
+            } else {
+                0.0
+            },
+            stereo_spread: if voices > 1 { rng.next() } else { 0.0 },
+        };
+
+        let max_attack_secs = constraints.max_attack_secs.max(MIN_ENVELOPE_SECS);
+        let max_release_secs = constraints.max_release_secs.max(MIN_ENVELOPE_SECS);
+        let random_adsr = |rng: &mut Rng| Adsr {
+            attack_secs: rng.log_range(MIN_ENVELOPE_SECS, max_attack_secs),
+            decay_secs: rng.log_range(0.05, 2.0),
+            sustain: rng.next(),
+            release_secs: rng.log_range(MIN_ENVELOPE_SECS, max_release_secs),
+        };
+        let envelope = random_adsr(&mut rng);
+        let (low_hz, high_hz) = constraints.cutoff_hz;
+        let low_hz = low_hz.max(20.0);
+        let filter = VoiceFilter {
+            cutoff_hz: rng.log_range(low_hz, high_hz.max(low_hz)),
+            resonance: rng.range(0.0, constraints.max_resonance.clamp(0.0, 1.0)),
+            envelope: random_adsr(&mut rng),
+            envelope_octaves: rng.range(-1.0, 3.0),
+            key_tracking: rng.next(),
+        };
+
+        let mut effects = Vec::new();
+        if effects.len() < constraints.max_effects && rng.chance(0.3) {
+            effects.push(EffectPatch::Distortion {
+                curve: [
+                    ShaperCurve::Tanh,
+                    ShaperCurve::HardClip,
+                    ShaperCurve::Foldback,
+                ][rng.index(3)],
+                drive: Some(rng.log_range(1.0, 8.0)),
+                // Keep the level about the same however hard it's driven.
+                output_gain: Some(0.5),
+            });
+        }
+        if effects.len() < constraints.max_effects && rng.chance(0.5) {
+            effects.push(EffectPatch::Chorus {
+                rate_hz: Some(rng.log_range(0.1, 3.0)),
+                sync_beats: None,
+                delay_ms: Some(rng.range(8.0, 25.0)),
+                depth_ms: Some(rng.range(1.0, 5.0)),
+                mix: Some(rng.range(0.2, 0.6)),
+                voices: Some(1 + rng.index(3)),
+            });
+        }
+        if effects.len() < constraints.max_effects && rng.chance(0.3) {
+            effects.push(EffectPatch::Compressor {
+                threshold_db: Some(rng.range(-30.0, -10.0)),
+                ratio: Some(rng.range(2.0, 8.0)),
+                attack_ms: None,
+                release_ms: None,
+                makeup_db: None,
+            });
+        }
+
+        SynthPatch {
+            name: format!("Random {}", seed),
+            oscillator: OscillatorPatch { wave, unison },
+            envelope: Some(envelope),
+            filter,
+            effects,
+            controls: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Performances: named setups for playing a whole file, bundling the instrument for each track,
+//! their levels and their effects.
+
+use crate::{
+    effects::{Chorus, EffectsChain, Gain, ShaperCurve, Waveshaper},
+    oscillator::Source,
+    wave_table,
+};
+
+#[derive(Debug)]
+pub struct Performance {
+    pub name: &'static str,
+    /// Track `i` plays instrument `i % len`, like the instruments given to `play_all_midi_tracks`.
+    pub track_instruments: Vec<Source>,
+    /// Track `i` gets gain `i % len`, in dB.
+    pub track_gains_db: Vec<f32>,
+    /// Builds the effects that go after each track's gain.
+    pub effects: fn() -> EffectsChain,
+}
+
+impl Performance {
+    pub const NAMES: [&'static str; 4] = ["orchestral", "chiptune", "rock", "ambient"];
+
+    pub fn by_name(name: &str) -> Option<Self> {
+        let performance = match name {
+            "orchestral" => Performance {
+                name: "orchestral",
+                track_instruments: vec![
+                    wave_table::sawtooth_wave().into(),
+                    wave_table::triangle_wave().into(),
+                    wave_table::sine_wave().into(),
+                ],
+                track_gains_db: vec![-3.0, -1.0, 0.0],
+                effects: || EffectsChain::new().with(Chorus::new()),
+            },
+            "chiptune" => Performance {
+                name: "chiptune",
+                track_instruments: vec![
+                    wave_table::square_wave().into(),
+                    wave_table::square_wave().into(),
+                    wave_table::triangle_wave().into(),
+                    Source::WhiteNoise,
+                ],
+                track_gains_db: vec![-6.0, -6.0, 0.0, -9.0],
+                effects: EffectsChain::new,
+            },
+            "rock" => Performance {
+                name: "rock",
+                track_instruments: vec![
+                    wave_table::sawtooth_wave().into(),
+                    wave_table::square_wave().into(),
+                ],
+                track_gains_db: vec![-6.0],
+                effects: || {
+                    EffectsChain::new()
+                        .with(Waveshaper {
+                            drive: 6.0,
+                            output_gain: 0.5,
+                            ..Waveshaper::new(ShaperCurve::Tanh)
+                        })
+                        .with(Chorus::new())
+                },
+            },
+            "ambient" => Performance {
+                name: "ambient",
+                track_instruments: vec![
+                    wave_table::sine_wave().into(),
+                    wave_table::triangle_wave().into(),
+                    Source::PinkNoise,
+                ],
+                track_gains_db: vec![0.0, -3.0, -18.0],
+                effects: || {
+                    let mut chorus = Chorus::new();
+                    chorus.depth_ms = 6.0;
+                    chorus.mix = 0.7;
+                    chorus.voices = 3;
+
+                    EffectsChain::new().with(chorus)
+                },
+            },
+            _ => return None,
+        };
+
+        Some(performance)
+    }
+
+    /// The gain and effects for one track.
+    pub fn track_effects(&self, track: usize) -> EffectsChain {
+        let gain_db = self.track_gains_db[track % self.track_gains_db.len()];
+        let mut effects = EffectsChain::new().with(Gain::from_db(gain_db));
+        effects.push((self.effects)());
+
+        effects
+    }
+}
@@ -0,0 +1,430 @@
+//! Practice scoring: play along with a reference MIDI file and get a report of how closely the live
+//! performance followed it.
+
+use crate::{
+    cancel::CancellationToken,
+    clock::{Clock, SystemClock},
+    effects::EffectsChain,
+    ensemble::play_all_midi_tracks,
+    error::Result,
+    instrument::play_midi,
+    midi::{single_timeline_of_events, MidiBytes, RawMidiMessage, TempoMap},
+    oscillator::Source,
+    synthesizer::NoteEvent,
+    wave_table, CHANNEL_MAX_BUFFER,
+};
+
+use log::info;
+use midly::{EventKind, MidiMessage};
+use std::fmt;
+use std::time::Duration;
+use time_calc::Bpm;
+use tokio::{
+    select,
+    stream::Stream,
+    sync::{broadcast, mpsc},
+    time::delay_for,
+};
+
+/// How long to keep listening after the last expected note, so it can still be played late.
+const PRACTICE_TAIL: Duration = Duration::from_secs(2);
+
+/// Enough room for the live synth's note events to be collected without lagging.
+const NOTE_EVENT_BUFFER: usize = 1024;
+
+const CLICK_KEY: u8 = 84;
+const CLICK_DOWNBEAT_KEY: u8 = 96;
+const CLICK_LENGTH: Duration = Duration::from_millis(30);
+const BEATS_PER_BAR: u64 = 4;
+
+/// What plays alongside the live input.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Accompaniment {
+    /// Every track of the reference file.
+    File,
+    /// Only a metronome click, for practicing the whole part unaided.
+    Click,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PracticeOptions {
+    pub accompaniment: Accompaniment,
+    /// Only score against this track of the file, instead of all of them.
+    pub track: Option<usize>,
+    /// How far a played note can be from an expected one and still count as hitting it.
+    pub window: Duration,
+    /// Subtracted from the time of every played note. Set it to the output latency, since the
+    /// player hears the accompaniment that much late and plays along with what they hear.
+    pub latency_compensation: Duration,
+}
+
+impl Default for PracticeOptions {
+    fn default() -> Self {
+        PracticeOptions {
+            accompaniment: Accompaniment::File,
+            track: None,
+            window: Duration::from_millis(150),
+            latency_compensation: Duration::from_secs(0),
+        }
+    }
+}
+
+/// A note onset in the reference file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ExpectedNote {
+    pub key: u8,
+    pub track: usize,
+    pub time: Duration,
+}
+
+/// A note onset in the live performance.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PlayedNote {
+    pub key: u8,
+    pub time: Duration,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PracticeReport {
+    pub expected: usize,
+    pub hits: usize,
+    /// Expected notes that were never played.
+    pub missed: usize,
+    /// Played notes that didn't match any expected note.
+    pub extra: usize,
+    /// Signed timing error of each hit in seconds. Positive is late.
+    pub offsets: Vec<f64>,
+}
+
+impl PracticeReport {
+    /// Fraction of the expected notes that were hit.
+    pub fn accuracy(&self) -> f64 {
+        if self.expected == 0 {
+            return 1.0;
+        }
+
+        self.hits as f64 / self.expected as f64
+    }
+
+    /// Average timing error of the hits, which shows a tendency to rush or drag.
+    pub fn mean_offset(&self) -> f64 {
+        mean(self.offsets.iter().copied())
+    }
+
+    /// Average distance from the beat, regardless of direction.
+    pub fn mean_absolute_offset(&self) -> f64 {
+        mean(self.offsets.iter().map(|o| o.abs()))
+    }
+}
+
+fn mean(values: impl ExactSizeIterator<Item = f64>) -> f64 {
+    let n = values.len();
+    if n == 0 {
+        return 0.0;
+    }
+
+    values.sum::<f64>() / n as f64
+}
+
+impl fmt::Display for PracticeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Hit {} of {} notes ({:.1}%)",
+            self.hits,
+            self.expected,
+            100.0 * self.accuracy()
+        )?;
+        writeln!(f, "Missed: {}", self.missed)?;
+        writeln!(f, "Extra: {}", self.extra)?;
+        let mean_offset_ms = 1000.0 * self.mean_offset();
+        let tendency = if mean_offset_ms >= 0.0 {
+            "late"
+        } else {
+            "early"
+        };
+        writeln!(
+            f,
+            "Mean timing: {:.1} ms {}",
+            mean_offset_ms.abs(),
+            tendency
+        )?;
+        write!(
+            f,
+            "Mean absolute timing error: {:.1} ms",
+            1000.0 * self.mean_absolute_offset()
+        )
+    }
+}
+
+/// Note onsets in the file, in time order, following its tempo changes.
+pub fn expected_notes(
+    midi_bytes: &MidiBytes,
+    bpm: Bpm,
+    track: Option<usize>,
+) -> Result<Vec<ExpectedNote>> {
+    let smf = midi_bytes.parse();
+    let tempo_map = TempoMap::new(&smf, bpm)?;
+
+    Ok(single_timeline_of_events(&smf)
+        .into_iter()
+        .filter(|(_, t, _)| track.map_or(true, |track| track == *t))
+        .filter_map(|(ticks, t, event)| match event.kind {
+            EventKind::Midi {
+                message: MidiMessage::NoteOn { key, vel },
+                ..
+            } if vel.as_int() > 0 => Some(ExpectedNote {
+                key: key.as_int(),
+                track: t,
+                time: tempo_map.time_at(ticks),
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Matches each played note to the closest unmatched expected note of the same key within
+/// `window`, in the order they were played.
+pub fn score_performance(
+    expected: &[ExpectedNote],
+    played: &[PlayedNote],
+    window: Duration,
+) -> PracticeReport {
+    let window = window.as_secs_f64();
+    let mut matched = vec![false; expected.len()];
+    let mut report = PracticeReport {
+        expected: expected.len(),
+        ..Default::default()
+    };
+    for p in played {
+        let played_at = p.time.as_secs_f64();
+        let closest = expected
+            .iter()
+            .enumerate()
+            .filter(|(i, e)| !matched[*i] && e.key == p.key)
+            .map(|(i, e)| (i, played_at - e.time.as_secs_f64()))
+            .filter(|(_, offset)| offset.abs() <= window)
+            .min_by(|(_, a), (_, b)| a.abs().partial_cmp(&b.abs()).unwrap());
+        match closest {
+            Some((i, offset)) => {
+                matched[i] = true;
+                report.hits += 1;
+                report.offsets.push(offset);
+            }
+            None => report.extra += 1,
+        }
+    }
+    report.missed = report.expected - report.hits;
+
+    report
+}
+
+/// Plays the reference file (or a click at its tempo) while `live_input` plays a synth of its own,
+/// then scores the live notes against the file.
+///
+/// Fails like `play_midi`, if either the live synth or the accompaniment can't play. Both start
+/// together. Live notes are timed on the live synth's output, less
+/// `options.latency_compensation`.
+pub async fn practice_midi_file<S>(
+    midi_bytes: MidiBytes,
+    bpm: Bpm,
+    live_input: S,
+    source: Source,
+    options: PracticeOptions,
+    cancel: CancellationToken,
+) -> Result<PracticeReport>
+where
+    S: Stream<Item = RawMidiMessage> + Unpin,
+{
+    let expected = expected_notes(&midi_bytes, bpm, options.track)?;
+    let length = expected.last().map(|n| n.time).unwrap_or_default() + PRACTICE_TAIL;
+    info!(
+        "Practicing {} notes over {:.1} seconds",
+        expected.len(),
+        length.as_secs_f64()
+    );
+
+    // Stops everything at the end of the piece, or earlier if the caller cancels.
+    let session = CancellationToken::new();
+    let stop_session = {
+        let session = session.clone();
+        async move {
+            select! {
+                _ = delay_for(length) => (),
+                _ = cancel.cancelled() => (),
+                _ = session.cancelled() => (),
+            }
+            session.cancel();
+        }
+    };
+
+    let (note_event_tx, mut note_event_rx) = broadcast::channel(NOTE_EVENT_BUFFER);
+    let live = {
+        let session = session.clone();
+        async move {
+            let result = play_midi(
+                live_input,
+                source,
+                EffectsChain::default(),
+                Vec::new(),
+                Some(note_event_tx),
+                session.clone(),
+            )
+            .await;
+            // There's nothing to practice without it.
+            if result.is_err() {
+                session.cancel();
+            }
+
+            result
+        }
+    };
+
+    let accompaniment = {
+        let session = session.clone();
+        async move {
+            let result = match options.accompaniment {
+                Accompaniment::File => {
+                    let instruments = [wave_table::sine_wave().into()];
+                    play_all_midi_tracks(midi_bytes, bpm, &instruments, session.clone()).await
+                }
+                Accompaniment::Click => play_click(bpm, length, session.clone()).await,
+            };
+            if result.is_err() {
+                session.cancel();
+            }
+
+            result
+        }
+    };
+
+    // The channel closes when the live synth is dropped.
+    let collect = async move {
+        let mut played = Vec::new();
+        loop {
+            match note_event_rx.recv().await {
+                Ok(NoteEvent::NoteStarted { key, time, .. }) => played.push(PlayedNote {
+                    key: u8::from(key),
+                    time: time
+                        .checked_sub(options.latency_compensation)
+                        .unwrap_or_default(),
+                }),
+                Ok(NoteEvent::NoteEnded { .. }) => (),
+                Err(broadcast::RecvError::Lagged(n)) => {
+                    log::warn!("Lost {} live note events", n);
+                }
+                Err(broadcast::RecvError::Closed) => break,
+            }
+        }
+
+        played
+    };
+
+    let (_, live, accompaniment, played) =
+        futures::join!(stop_session, live, accompaniment, collect);
+    live.and(accompaniment)?;
+
+    Ok(score_performance(&expected, &played, options.window))
+}
+
+/// Plays a metronome on a square wave, accenting the first beat of each bar.
+async fn play_click(bpm: Bpm, length: Duration, cancel: CancellationToken) -> Result<()> {
+    let (click_tx, click_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
+    let clicks = send_clicks(bpm, length, click_tx, SystemClock);
+    let click = play_midi(
+        click_rx,
+        wave_table::square_wave().into(),
+        EffectsChain::default(),
+        Vec::new(),
+        None,
+        cancel,
+    );
+
+    futures::join!(clicks, click).1
+}
+
+/// Sends the metronome's notes on `click_tx` for `length`, timed by `clock`.
+async fn send_clicks<C: Clock>(
+    bpm: Bpm,
+    length: Duration,
+    mut click_tx: mpsc::Sender<RawMidiMessage>,
+    clock: C,
+) {
+    let beat = Duration::from_secs_f64(60.0 / bpm);
+    let start = clock.now();
+    let mut beat_i: u64 = 0;
+    while beat * beat_i as u32 <= length {
+        // Schedule from the start instead of the previous click, so the click doesn't drift.
+        let click_at = start + beat * beat_i as u32;
+        clock
+            .delay_for(click_at.saturating_duration_since(clock.now()))
+            .await;
+        let key = if beat_i % BEATS_PER_BAR == 0 {
+            CLICK_DOWNBEAT_KEY
+        } else {
+            CLICK_KEY
+        };
+        // Stop once the synth has hung up.
+        if click_tx.send((0, [0x90, key, 100].into())).await.is_err() {
+            break;
+        }
+        clock.delay_for(CLICK_LENGTH).await;
+        if click_tx.send((0, [0x80, key, 0].into())).await.is_err() {
+            break;
+        }
+        beat_i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        clock::{settle, ManualClock},
+        midi::MidiMessageBytes,
+    };
+
+    #[tokio::test]
+    async fn clicks_each_beat_and_accents_each_bar() {
+        let clock = ManualClock::new();
+        let (tx, mut rx) = mpsc::channel(16);
+        // Five beats at 120 BPM, half a second apart.
+        let clicks = tokio::spawn(send_clicks(
+            120.0,
+            Duration::from_secs(2),
+            tx,
+            clock.clone(),
+        ));
+
+        for beat in 0..5 {
+            settle().await;
+            let key = if beat == 0 || beat == 4 {
+                CLICK_DOWNBEAT_KEY
+            } else {
+                CLICK_KEY
+            };
+            assert_eq!(
+                rx.try_recv().unwrap().1,
+                MidiMessageBytes::new(&[0x90, key, 100])
+            );
+            assert!(rx.try_recv().is_err());
+
+            clock.advance(CLICK_LENGTH);
+            settle().await;
+            assert_eq!(
+                rx.try_recv().unwrap().1,
+                MidiMessageBytes::new(&[0x80, key, 0])
+            );
+
+            // Nothing until the next beat, however long the click took.
+            clock.advance(Duration::from_millis(469));
+            settle().await;
+            assert!(rx.try_recv().is_err());
+            clock.advance(Duration::from_millis(1));
+        }
+        settle().await;
+        clicks.await.unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,285 @@
+//! Checks that the audio callback keeps to the realtime rules, with the `realtime-audit` feature.
+//!
+//! The callback must never wait on anything. A heap allocation can take a lock inside the
+//! allocator, and a lock or a blocking system call can hold the callback past the point where the
+//! device needs its samples, which is a dropout. While the feature is on, every allocation made on
+//! the audio thread during a callback is counted, as is every callback that takes longer than the
+//! audio it produces. On Linux, so is every time a callback blocks, whether on a lock, a sleep or
+//! I/O, even in dependencies: the kernel counts each time the thread gives up the CPU to wait. A
+//! lock that was free or a system call that returned straight away isn't seen.
+//!
+//! Allocations are only counted by `AuditedAllocator`, which the program has to install:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: nocturne::AuditedAllocator = nocturne::AuditedAllocator;
+//! ```
+//!
+//! The CLI does that when built with the feature. Tests can play something and then call
+//! `assert_realtime_safe`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Counts what the audited callbacks allocate, and leaves the allocating to the system.
+pub struct AuditedAllocator;
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: AuditedAllocator = AuditedAllocator;
+
+static CALLBACKS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static DEALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static OVERRUNS: AtomicUsize = AtomicUsize::new(0);
+static BLOCKING_WAITS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static IN_CALLBACK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// False on threads that are shutting down, whose locals are already gone.
+fn in_callback() -> bool {
+    IN_CALLBACK.try_with(|c| c.get()).unwrap_or(false)
+}
+
+/// How many times the current thread has given up the CPU to wait. `getrusage` itself doesn't
+/// block, allocate or lock.
+#[cfg(target_os = "linux")]
+fn voluntary_context_switches() -> usize {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: `getrusage` fills in `usage` when it succeeds.
+    unsafe {
+        if libc::getrusage(libc::RUSAGE_THREAD, usage.as_mut_ptr()) == 0 {
+            usage.assume_init().ru_nvcsw as usize
+        } else {
+            0
+        }
+    }
+}
+
+/// Other systems don't count them per thread.
+#[cfg(not(target_os = "linux"))]
+fn voluntary_context_switches() -> usize {
+    0
+}
+
+unsafe impl GlobalAlloc for AuditedAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if in_callback() {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if in_callback() {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if in_callback() {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if in_callback() {
+            DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Everything the audit has caught since the program started.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RealtimeViolations {
+    /// How many callbacks were audited, for telling a clean run from one that never played.
+    pub callbacks: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    /// Callbacks that took longer than the audio they produced.
+    pub overruns: usize,
+    /// Times a callback blocked on a lock, a sleep or I/O. Always 0 except on Linux.
+    pub blocking_waits: usize,
+}
+
+impl RealtimeViolations {
+    pub fn is_clean(&self) -> bool {
+        self.allocations == 0
+            && self.deallocations == 0
+            && self.overruns == 0
+            && self.blocking_waits == 0
+    }
+}
+
+pub fn realtime_violations() -> RealtimeViolations {
+    RealtimeViolations {
+        callbacks: CALLBACKS.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+        overruns: OVERRUNS.load(Ordering::Relaxed),
+        blocking_waits: BLOCKING_WAITS.load(Ordering::Relaxed),
+    }
+}
+
+/// Panics if any audited callback has broken the rules.
+pub fn assert_realtime_safe() {
+    let violations = realtime_violations();
+    assert!(
+        violations.is_clean(),
+        "The audio callback isn't realtime safe: {:?}",
+        violations
+    );
+}
+
+/// Logs the violations so far, for runs outside of tests.
+#[cfg(feature = "realtime-audit")]
+pub(crate) fn log_violations() {
+    let violations = realtime_violations();
+    if violations.is_clean() {
+        log::info!("Realtime audit: {} clean callbacks", violations.callbacks);
+    } else {
+        log::warn!("Realtime audit: {:?}", violations);
+    }
+}
+
+/// The counts are shared, so only one test audits at a time.
+#[cfg(test)]
+static AUDIT_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Counts from zero until the guard is dropped, for tests that audit.
+#[cfg(test)]
+pub(crate) fn start_over() -> std::sync::MutexGuard<'static, ()> {
+    // Tests that panic on purpose poison the lock.
+    let guard = AUDIT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    for count in [
+        &CALLBACKS,
+        &ALLOCATIONS,
+        &DEALLOCATIONS,
+        &OVERRUNS,
+        &BLOCKING_WAITS,
+    ] {
+        count.store(0, Ordering::Relaxed);
+    }
+
+    guard
+}
+
+/// Audits the current thread as the audio callback until it is dropped.
+pub(crate) struct CallbackAudit {
+    started: Instant,
+    /// How long the device takes to play what the callback is producing.
+    deadline: Duration,
+    context_switches: usize,
+}
+
+impl CallbackAudit {
+    pub(crate) fn start(sample_frames: usize, sample_hz: u32) -> Self {
+        IN_CALLBACK.with(|c| c.set(true));
+        CallbackAudit {
+            started: Instant::now(),
+            deadline: Duration::from_secs_f64(sample_frames as f64 / sample_hz.max(1) as f64),
+            context_switches: voluntary_context_switches(),
+        }
+    }
+}
+
+impl Drop for CallbackAudit {
+    fn drop(&mut self) {
+        let waits = voluntary_context_switches().saturating_sub(self.context_switches);
+        IN_CALLBACK.with(|c| c.set(false));
+        CALLBACKS.fetch_add(1, Ordering::Relaxed);
+        BLOCKING_WAITS.fetch_add(waits, Ordering::Relaxed);
+        if self.started.elapsed() > self.deadline {
+            OVERRUNS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::thread;
+
+    /// Runs `f` as a callback with a second of audio to produce, so it never overruns.
+    fn audited(f: impl FnOnce()) {
+        let _audit = CallbackAudit::start(44100, 44100);
+        f();
+    }
+
+    #[test]
+    fn passes_a_callback_that_only_computes() {
+        let _guard = start_over();
+        let mut buffer = vec![0.0f32; 512];
+        audited(|| {
+            for (i, sample) in buffer.iter_mut().enumerate() {
+                *sample = (i as f32 * 0.01).sin();
+            }
+        });
+
+        assert_realtime_safe();
+        assert_eq!(realtime_violations().callbacks, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "allocations: 1, deallocations: 1")]
+    fn catches_allocating() {
+        let _guard = start_over();
+        audited(|| drop(std::hint::black_box(Vec::<f32>::with_capacity(512))));
+
+        assert_realtime_safe();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[should_panic(expected = "isn't realtime safe")]
+    fn catches_waiting_on_a_lock() {
+        let _guard = start_over();
+        let lock = Arc::new(Mutex::new(()));
+        let held = Arc::new(Barrier::new(2));
+        let holder = {
+            let lock = lock.clone();
+            let held = held.clone();
+            thread::spawn(move || {
+                let _locked = lock.lock().unwrap();
+                held.wait();
+                thread::sleep(Duration::from_millis(20));
+            })
+        };
+        held.wait();
+        audited(|| drop(lock.lock().unwrap()));
+        holder.join().unwrap();
+        let violations = realtime_violations();
+        assert!(
+            violations.blocking_waits > 0 && violations.allocations == 0,
+            "Only the wait should count: {:?}",
+            violations
+        );
+
+        assert_realtime_safe();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[should_panic(expected = "isn't realtime safe")]
+    fn catches_a_blocking_system_call() {
+        let _guard = start_over();
+        audited(|| thread::sleep(Duration::from_millis(1)));
+        let violations = realtime_violations();
+        assert!(
+            violations.blocking_waits > 0 && violations.allocations == 0,
+            "Only the wait should count: {:?}",
+            violations
+        );
+
+        assert_realtime_safe();
+    }
+}
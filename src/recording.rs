@@ -1,7 +1,17 @@
-use crate::AudioFrame;
+use crate::{
+    error::Result,
+    naming::recording_file_path,
+    timecode::{LtcEncoder, TimecodeRate},
+    wav::{WavFileWriter, WavSampleFormat, WavSpec},
+    TimedFrame,
+};
 
-use log::info;
-use std::path::Path;
+use futures::future::join_all;
+use log::{info, warn};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, TrySendError};
+use std::time::Duration;
 use tokio::{
     select,
     sync::{
@@ -11,9 +21,209 @@ use tokio::{
     task,
 };
 
+/// How much audio may be lost if the process dies mid-recording. Every checkpoint rewrites the WAV
+/// header so the file is readable up to that point.
+const CHECKPOINT_SECONDS: u32 = 1;
+
+#[derive(Clone, Debug, Default)]
+pub struct RecordingOptions {
+    /// Also write SMPTE LTC, starting at 00:00:00:00 on the first recorded sample, to a mono
+    /// sidecar file next to the recording (`take.wav` gets `take.ltc.wav`).
+    pub timecode: Option<TimecodeRate>,
+    /// Stop or pause the recording once the output has been quiet for a while.
+    pub silence: Option<SilenceDetection>,
+    /// 16-bit unless set. Opus recordings have no sample format, and ignore this and `dither`.
+    pub sample_format: WavSampleFormat,
+    /// Add dither when quantizing to integer samples, which keeps quiet fades and reverb tails
+    /// from turning into distortion at 16 bits.
+    pub dither: bool,
+    /// Bits per second for Opus recordings, or 64 kb/s per channel if unset. WAV recordings
+    /// ignore it.
+    pub bitrate: Option<u32>,
+}
+
+/// What a recording does once it has been silent for long enough.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SilenceAction {
+    /// Finish the file. Nothing more is recorded, even if the sound comes back.
+    Stop,
+    /// Skip writing until the sound comes back. The timecode track, if any, keeps running without
+    /// being written, so it jumps across each pause.
+    Pause,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SilenceDetection {
+    /// Frames with no sample louder than this (in dBFS) count as silent.
+    pub threshold_db: f32,
+    /// How long the silence lasts before `action` is taken. This much silence is still recorded.
+    pub duration: Duration,
+    pub action: SilenceAction,
+}
+
+impl SilenceDetection {
+    pub fn new(duration: Duration, action: SilenceAction) -> Self {
+        SilenceDetection {
+            threshold_db: -60.0,
+            duration,
+            action,
+        }
+    }
+
+    fn is_silent(&self, frame: &[f32]) -> bool {
+        let threshold = 10.0f32.powf(self.threshold_db / 20.0);
+
+        frame.iter().all(|s| s.abs() < threshold)
+    }
+}
+
+/// A recording or render is Ogg Opus if its path ends in `.opus`, and WAV otherwise. Opus needs
+/// the `opus` feature.
+pub(crate) enum SampleFileWriter {
+    Wav(WavFileWriter),
+    #[cfg(feature = "opus")]
+    Opus(crate::opus::OpusFileWriter),
+}
+
+impl SampleFileWriter {
+    pub(crate) fn create(
+        path: &Path,
+        spec: WavSpec,
+        dither: bool,
+        bitrate: Option<u32>,
+    ) -> io::Result<Self> {
+        let is_opus = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("opus"));
+        if is_opus {
+            #[cfg(feature = "opus")]
+            return Ok(SampleFileWriter::Opus(crate::opus::OpusFileWriter::create(
+                path,
+                spec.channels,
+                spec.sample_hz,
+                bitrate,
+            )?));
+            #[cfg(not(feature = "opus"))]
+            {
+                let _ = bitrate;
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "Can't record {:?}: Opus needs nocturne built with the `opus` feature",
+                        path
+                    ),
+                ));
+            }
+        }
+        let mut writer = WavFileWriter::create(path, spec)?;
+        writer.set_dither(dither);
+
+        Ok(SampleFileWriter::Wav(writer))
+    }
+
+    pub(crate) fn write_sample(&mut self, sample: f32) -> io::Result<()> {
+        match self {
+            SampleFileWriter::Wav(w) => w.write_sample(sample),
+            #[cfg(feature = "opus")]
+            SampleFileWriter::Opus(w) => w.write_sample(sample),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SampleFileWriter::Wav(w) => w.flush(),
+            #[cfg(feature = "opus")]
+            SampleFileWriter::Opus(w) => w.flush(),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> io::Result<()> {
+        match self {
+            SampleFileWriter::Wav(w) => w.finalize(),
+            #[cfg(feature = "opus")]
+            SampleFileWriter::Opus(w) => w.finalize(),
+        }
+    }
+}
+
+/// `take.wav` -> `take.ltc.wav`
+fn ltc_sidecar_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    path.with_file_name(format!("{}.ltc.wav", stem))
+}
+
+/// A file to record to, and how to record it. Paths ending in `.opus` are recorded as Ogg Opus,
+/// and anything else as WAV. An existing directory gets a new WAV file named after the time the
+/// recording starts, like `nocturne-2024-05-01T12-30-00.wav` (UTC).
+#[derive(Clone, Debug)]
+pub struct RecordingTarget {
+    pub path: PathBuf,
+    pub options: RecordingOptions,
+}
+
+impl RecordingTarget {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        RecordingTarget {
+            path: path.into(),
+            options: RecordingOptions::default(),
+        }
+    }
+}
+
+/// Any number of recordings of the same output, each with its own file and options. They all
+/// subscribe to the same broadcast of frames.
+#[derive(Default)]
+pub struct RecorderSet {
+    recorders: Vec<RecordingOutputStream>,
+}
+
+impl RecorderSet {
+    /// Fails if any of the files can't be created, without leaving the others recording.
+    pub fn connect(
+        targets: Vec<RecordingTarget>,
+        num_channels: u16,
+        sample_hz: u32,
+        frame_tx: &broadcast::Sender<TimedFrame>,
+    ) -> Result<Self> {
+        let recorders = targets
+            .into_iter()
+            .map(|t| {
+                RecordingOutputStream::connect_with_options(
+                    &t.path,
+                    num_channels,
+                    sample_hz,
+                    frame_tx.subscribe(),
+                    t.options,
+                )
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(RecorderSet { recorders })
+    }
+
+    pub fn len(&self) -> usize {
+        self.recorders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.recorders.is_empty()
+    }
+
+    /// Finishes every recording. They drain concurrently, so a slow disk for one doesn't hold up
+    /// the others. Returns the first error, once they have all finished.
+    pub async fn close(self) -> Result<()> {
+        join_all(self.recorders.into_iter().map(|r| r.close()))
+            .await
+            .into_iter()
+            .collect()
+    }
+}
+
 pub struct RecordingOutputStream {
+    path: PathBuf,
     exit_tx: oneshot::Sender<()>,
-    join_handle: task::JoinHandle<()>,
+    join_handle: task::JoinHandle<io::Result<()>>,
 }
 
 impl RecordingOutputStream {
@@ -21,63 +231,218 @@ impl RecordingOutputStream {
         path: &Path,
         num_channels: u16,
         sample_hz: u32,
-        frame_rx: broadcast::Receiver<AudioFrame>,
-    ) -> Self {
-        let path_str = path
-            .to_str()
-            .expect("Invalid path for recording file.")
-            .to_string();
+        frame_rx: broadcast::Receiver<TimedFrame>,
+    ) -> Result<Self> {
+        Self::connect_with_options(
+            path,
+            num_channels,
+            sample_hz,
+            frame_rx,
+            RecordingOptions::default(),
+        )
+    }
+
+    pub fn connect_with_options(
+        path: &Path,
+        num_channels: u16,
+        sample_hz: u32,
+        frame_rx: broadcast::Receiver<TimedFrame>,
+        options: RecordingOptions,
+    ) -> Result<Self> {
+        let path = recording_file_path(path)?;
+        info!("Recording to {:?}", path);
+        let spec = WavSpec {
+            channels: num_channels,
+            sample_hz,
+            sample_format: options.sample_format,
+        };
+        // A WAV file switches to RF64 by itself if the recording grows past 4 GB.
+        let writer = SampleFileWriter::create(&path, spec, options.dither, options.bitrate)?;
+        // The timecode track advances one sample per recorded sample frame, so it stays frame
+        // accurate no matter how the recording is later trimmed.
+        let ltc_writer = match options.timecode {
+            Some(_) => {
+                // The code only has two levels, so it needs no more than 16 bits.
+                let ltc_spec = WavSpec {
+                    channels: 1,
+                    sample_hz,
+                    sample_format: WavSampleFormat::Int16,
+                };
+                Some(WavFileWriter::create(&ltc_sidecar_path(&path), ltc_spec)?)
+            }
+            None => None,
+        };
         let (exit_tx, exit_rx) = oneshot::channel();
-        let join_handle = task::spawn(async move {
-            buffered_file_writer_task(path_str, num_channels, sample_hz, frame_rx, exit_rx).await
-        });
+        let join_handle = task::spawn(buffered_file_writer_task(
+            writer,
+            ltc_writer,
+            num_channels,
+            sample_hz,
+            options,
+            frame_rx,
+            exit_rx,
+        ));
 
-        RecordingOutputStream {
+        Ok(RecordingOutputStream {
+            path,
             exit_tx,
             join_handle,
-        }
+        })
     }
 
-    pub async fn close(self) {
-        self.exit_tx
-            .send(())
-            .expect("Failed to send exit signal to WAV writer task");
+    /// The file being recorded, which was named by the recorder if it was given a directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Finishes the file. Fails if anything couldn't be written, in which case the file has
+    /// everything up to the last checkpoint before the failure.
+    pub async fn close(self) -> Result<()> {
+        // The writer may have already stopped by itself after a silence.
+        let _ = self.exit_tx.send(());
         self.join_handle
             .await
-            .expect("Failed to join on WAV writer task");
+            .expect("Failed to join on recording writer task")?;
+
+        Ok(())
     }
 }
 
+/// Samples for the writer thread, in the order they go in the file.
+#[derive(Default)]
+struct WriteBatch {
+    samples: Vec<f32>,
+    /// The timecode track's samples for the same stretch of the recording.
+    timecode: Vec<f32>,
+    /// Samples of silence to write before `samples`, for batches dropped while the writer thread
+    /// was behind.
+    lost_samples: usize,
+    /// The same for `timecode`.
+    lost_timecode: usize,
+    /// Checkpoint the files once this batch is written.
+    checkpoint: bool,
+}
+
+/// Samples are handed to the writer thread in batches about this big, so a slow disk only ever
+/// holds up that thread, never the runtime.
+const WRITE_BATCH_SAMPLES: usize = 16 * 1024;
+
+/// Batches that can wait for the writer thread, a few seconds of audio. Past that, batches are
+/// dropped and written as silence, rather than piling up in memory while the disk is stalled.
+const WRITE_BATCHES_MAX: usize = 32;
+
 /// Runs until being told to stop, at which point it flushes outstanding file writes.
 async fn buffered_file_writer_task(
-    path: String,
+    writer: SampleFileWriter,
+    ltc_writer: Option<WavFileWriter>,
     channels: u16,
     sample_hz: u32,
-    mut frame_rx: broadcast::Receiver<AudioFrame>,
+    options: RecordingOptions,
+    mut frame_rx: broadcast::Receiver<TimedFrame>,
     mut exit_rx: oneshot::Receiver<()>,
-) {
-    let spec = hound::WavSpec {
-        channels,
-        sample_rate: sample_hz,
-        bits_per_sample: 16,
-        sample_format: hound::SampleFormat::Int,
-    };
-    let mut writer = hound::WavWriter::create(path, spec).expect("Failed to create WAV file");
+) -> io::Result<()> {
+    let mut encoder = options
+        .timecode
+        .map(|rate| LtcEncoder::new(sample_hz, rate, 0));
+
+    let (batch_tx, batch_rx) = sync_channel(WRITE_BATCHES_MAX);
+    let writer_thread = task::spawn_blocking(move || write_batches(writer, ltc_writer, batch_rx));
+
+    let samples_per_checkpoint =
+        CHECKPOINT_SECONDS as usize * sample_hz as usize * channels as usize;
+    let mut samples_since_checkpoint = 0;
 
+    let silence_limit = options
+        .silence
+        .map(|s| (s.duration.as_secs_f64() * sample_hz as f64) as usize * channels as usize);
+    let mut silent_samples = 0;
+
+    // Where the next frame should start on the sample clock.
+    let mut next_position = None;
+
+    let mut batch = WriteBatch::default();
     loop {
         select! {
             _ = &mut exit_rx => {
-                info!("WAV file writing task interrupted");
+                info!("Recording task interrupted");
                 break;
             },
             frame = frame_rx.recv() => {
                 match frame {
-                    Ok(samples) => {
-                        let amplitude = i16::max_value() as f32;
-                        for s in samples.iter() {
-                            // TODO: make async?
-                            writer.write_sample((amplitude * s) as i16)
-                                .expect("WAV writer failed to write sample.");
+                    Ok(TimedFrame { samples, position, .. }) => {
+                        let frame_length = (samples.len() / channels as usize) as u64;
+                        // Frames dropped while this writer lagged are filled with silence, so the
+                        // recording stays on the same timeline as everything else.
+                        let missing = next_position.map_or(0, |next| position.saturating_sub(next));
+                        next_position = Some(position + frame_length);
+                        if missing > 0 {
+                            warn!("Recording lost {} samples, filling them with silence", missing);
+                            batch.samples.resize(
+                                batch.samples.len() + (missing * channels as u64) as usize,
+                                0.0,
+                            );
+                            if let Some(encoder) = encoder.as_mut() {
+                                batch.timecode.extend((0..missing).map(|_| encoder.next_sample()));
+                            }
+                        }
+
+                        if let (Some(silence), Some(limit)) = (options.silence, silence_limit) {
+                            if silence.is_silent(&samples) {
+                                silent_samples += samples.len();
+                            } else {
+                                silent_samples = 0;
+                            }
+                            if silent_samples > limit {
+                                match silence.action {
+                                    SilenceAction::Stop => {
+                                        info!("Stopping recording after {:?} of silence", silence.duration);
+                                        break;
+                                    }
+                                    SilenceAction::Pause => {
+                                        if silent_samples - samples.len() <= limit {
+                                            info!("Pausing recording during silence");
+                                        }
+                                        if let Some(encoder) = encoder.as_mut() {
+                                            for _ in 0..frame_length {
+                                                encoder.next_sample();
+                                            }
+                                        }
+                                        continue;
+                                    }
+                                }
+                            }
+                        }
+
+                        batch.samples.extend_from_slice(&samples);
+                        if let Some(encoder) = encoder.as_mut() {
+                            batch.timecode.extend((0..frame_length).map(|_| encoder.next_sample()));
+                        }
+
+                        samples_since_checkpoint += samples.len();
+                        if samples_since_checkpoint >= samples_per_checkpoint {
+                            batch.checkpoint = true;
+                            samples_since_checkpoint = 0;
+                        }
+                        if batch.checkpoint || batch.samples.len() >= WRITE_BATCH_SAMPLES {
+                            match batch_tx.try_send(std::mem::take(&mut batch)) {
+                                Ok(()) => (),
+                                Err(TrySendError::Full(mut dropped)) => {
+                                    warn!(
+                                        "Recording fell behind the disk, writing {} samples as silence",
+                                        dropped.samples.len()
+                                    );
+                                    // The next batch stands in for this one, reusing its buffers.
+                                    dropped.lost_samples += dropped.samples.len();
+                                    dropped.lost_timecode += dropped.timecode.len();
+                                    dropped.samples.clear();
+                                    dropped.timecode.clear();
+                                    dropped.checkpoint = false;
+                                    batch = dropped;
+                                }
+                                // The writer thread only stops early if it fails, which the join
+                                // below reports.
+                                Err(TrySendError::Disconnected(_)) => break,
+                            }
                         }
                     }
                     Err(RecvError::Closed) => break,
@@ -87,7 +452,50 @@ async fn buffered_file_writer_task(
         }
     }
 
-    // TODO: make async?
-    writer.finalize().expect("Failed to finalize sample file.");
-    info!("Flushed WAV file buffer.");
+    // Dropping the sender tells the writer thread to finish up once it has written everything.
+    // Waiting for room in the queue blocks, so that happens off the runtime too.
+    let _ = task::spawn_blocking(move || batch_tx.send(batch)).await;
+    writer_thread
+        .await
+        .expect("Recording writer thread failed")?;
+    if let Some(encoder) = encoder {
+        info!("Recorded timecode up to {}", encoder.current_timecode());
+    }
+    info!("Flushed recording file buffer.");
+
+    Ok(())
+}
+
+/// Writes batches to the files until the sender hangs up, then finalizes them. Runs on a blocking
+/// thread, since file writes can stall for as long as the disk likes.
+fn write_batches(
+    mut writer: SampleFileWriter,
+    mut ltc_writer: Option<WavFileWriter>,
+    batch_rx: Receiver<WriteBatch>,
+) -> io::Result<()> {
+    for batch in batch_rx {
+        let lost = std::iter::repeat(&0.0).take(batch.lost_samples);
+        for &s in lost.chain(batch.samples.iter()) {
+            writer.write_sample(s)?;
+        }
+        if let Some(ltc_writer) = ltc_writer.as_mut() {
+            let lost = std::iter::repeat(&0.0).take(batch.lost_timecode);
+            for &s in lost.chain(batch.timecode.iter()) {
+                ltc_writer.write_sample(s)?;
+            }
+        }
+        if batch.checkpoint {
+            writer.flush()?;
+            if let Some(ltc_writer) = ltc_writer.as_mut() {
+                ltc_writer.flush()?;
+            }
+        }
+    }
+
+    writer.finalize()?;
+    if let Some(ltc_writer) = ltc_writer {
+        ltc_writer.finalize()?;
+    }
+
+    Ok(())
 }
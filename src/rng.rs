@@ -0,0 +1,68 @@
+//! The random numbers behind everything that varies from note to note or run to run. Each
+//! generator starts from a seed, so a render with the same seed comes out the same, sample for
+//! sample.
+
+/// Xorshift, like the noise oscillators. Everything here only needs to be varied and repeatable.
+#[derive(Clone, Debug)]
+pub(crate) struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        // Scramble first, since xorshift takes a while to get going from small seeds like 1 and 2.
+        // Then fold the high bits in. Xorshift also gets stuck at zero.
+        let seed = seed.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        let state = (seed ^ (seed >> 32)) as u32;
+
+        Rng {
+            state: state.max(1),
+        }
+    }
+
+    /// Never 0, so it can seed another xorshift generator directly.
+    pub(crate) fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+
+        x
+    }
+
+    /// Uniform in [0.0, 1.0).
+    pub(crate) fn next(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+
+    pub(crate) fn range(&mut self, low: f32, high: f32) -> f32 {
+        low + (high - low) * self.next()
+    }
+
+    /// Uniform in octaves rather than hertz or seconds, so short times and low cutoffs are as
+    /// likely as long and high ones. `low` must be positive.
+    pub(crate) fn log_range(&mut self, low: f32, high: f32) -> f32 {
+        low * (high / low).powf(self.next())
+    }
+
+    /// In [0, n), or 0 if `n` is 0.
+    pub(crate) fn index(&mut self, n: usize) -> usize {
+        ((self.next() * n as f32) as usize).min(n.saturating_sub(1))
+    }
+
+    pub(crate) fn chance(&mut self, p: f32) -> bool {
+        self.next() < p
+    }
+}
+
+/// The seed for one of several independent streams that share a session seed, like the tracks of
+/// a file. Nearby streams get unrelated seeds, so they don't play the same noise.
+pub(crate) fn derive_seed(seed: u64, stream: u64) -> u64 {
+    // SplitMix64's finalizer.
+    let mut z = seed ^ stream.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+    z ^ (z >> 31)
+}
@@ -0,0 +1,325 @@
+//! Which MIDI inputs reach which synths and MIDI outputs. Each route takes the messages of one
+//! input to one synth or output, optionally only on some channels or only some kinds of message,
+//! so a keyboard can be split across synths, or its pedals and clock passed through to another
+//! instrument.
+
+use crate::{
+    error::{NocturneError, Result},
+    midi::{MidiMessageBytes, MidiOutputDevice, RawMidiMessage},
+};
+
+use log::warn;
+use std::fmt;
+use std::str::FromStr;
+use tokio::sync::mpsc;
+
+/// What a message does, for filtering routes.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MessageKind {
+    /// Note on and note off.
+    Note,
+    /// Polyphonic key pressure.
+    KeyPressure,
+    Controller,
+    ProgramChange,
+    ChannelPressure,
+    PitchBend,
+    SysEx,
+    /// Every other system message, like clock, start and stop, and timecode.
+    System,
+}
+
+impl MessageKind {
+    pub const NAMES: [&'static str; 8] = [
+        "note",
+        "key-pressure",
+        "controller",
+        "program-change",
+        "channel-pressure",
+        "pitch-bend",
+        "sysex",
+        "system",
+    ];
+
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "note" => Some(MessageKind::Note),
+            "key-pressure" => Some(MessageKind::KeyPressure),
+            "controller" => Some(MessageKind::Controller),
+            "program-change" => Some(MessageKind::ProgramChange),
+            "channel-pressure" => Some(MessageKind::ChannelPressure),
+            "pitch-bend" => Some(MessageKind::PitchBend),
+            "sysex" => Some(MessageKind::SysEx),
+            "system" => Some(MessageKind::System),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MessageKind::Note => "note",
+            MessageKind::KeyPressure => "key-pressure",
+            MessageKind::Controller => "controller",
+            MessageKind::ProgramChange => "program-change",
+            MessageKind::ChannelPressure => "channel-pressure",
+            MessageKind::PitchBend => "pitch-bend",
+            MessageKind::SysEx => "sysex",
+            MessageKind::System => "system",
+        }
+    }
+
+    pub fn of(message: &MidiMessageBytes) -> Option<Self> {
+        Some(match message.first()? & 0xF0 {
+            0x80 | 0x90 => MessageKind::Note,
+            0xA0 => MessageKind::KeyPressure,
+            0xB0 => MessageKind::Controller,
+            0xC0 => MessageKind::ProgramChange,
+            0xD0 => MessageKind::ChannelPressure,
+            0xE0 => MessageKind::PitchBend,
+            _ if message.is_sysex() => MessageKind::SysEx,
+            _ => MessageKind::System,
+        })
+    }
+}
+
+/// The channel of a channel message, from 0.
+fn channel_of(message: &MidiMessageBytes) -> Option<u8> {
+    match message.first()? {
+        status @ 0x80..=0xEF => Some(status & 0x0F),
+        _ => None,
+    }
+}
+
+/// Which messages a route passes. The default passes everything.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RouteFilter {
+    /// Channels, counting from 0, whose messages pass. System messages have no channel, and pass
+    /// whatever the channels are. Every channel passes if this is `None`.
+    pub channels: Option<Vec<u8>>,
+    /// Every kind passes if this is `None`.
+    pub kinds: Option<Vec<MessageKind>>,
+}
+
+impl RouteFilter {
+    pub fn passes(&self, message: &MidiMessageBytes) -> bool {
+        let channel_passes = match (&self.channels, channel_of(message)) {
+            (Some(channels), Some(channel)) => channels.contains(&channel),
+            _ => true,
+        };
+        let kind_passes = match (&self.kinds, MessageKind::of(message)) {
+            (Some(kinds), Some(kind)) => kinds.contains(&kind),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+
+        channel_passes && kind_passes
+    }
+}
+
+/// Where a route sends its messages.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RouteTarget {
+    /// A synth, in the order they were added to the engine, where the engine's own instrument is
+    /// synth 0.
+    Synth(usize),
+    /// A MIDI output, in the order they were added to the engine.
+    Output(usize),
+}
+
+/// Sends the messages from one MIDI input that pass `filter` to `target`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Route {
+    /// The input, in the order they were added to the engine.
+    pub input: usize,
+    pub target: RouteTarget,
+    pub filter: RouteFilter,
+}
+
+impl Route {
+    /// Every message from `input` to `target`.
+    pub fn new(input: usize, target: RouteTarget) -> Self {
+        Route {
+            input,
+            target,
+            filter: RouteFilter::default(),
+        }
+    }
+
+    /// Checks that the route's input and target are among those given.
+    pub(crate) fn check(
+        &self,
+        num_inputs: usize,
+        num_synths: usize,
+        num_outputs: usize,
+    ) -> Result<()> {
+        let (target_count, target_name) = match self.target {
+            RouteTarget::Synth(_) => (num_synths, "synths"),
+            RouteTarget::Output(_) => (num_outputs, "MIDI outputs"),
+        };
+        let target = match self.target {
+            RouteTarget::Synth(i) | RouteTarget::Output(i) => i,
+        };
+        if self.input >= num_inputs {
+            return Err(NocturneError::InvalidRoute(format!(
+                "{}: there are only {} MIDI inputs",
+                self, num_inputs
+            )));
+        }
+        if target >= target_count {
+            return Err(NocturneError::InvalidRoute(format!(
+                "{}: there are only {} {}",
+                self, target_count, target_name
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Route {
+    /// In the form `FromStr` takes.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}>", self.input)?;
+        match self.target {
+            RouteTarget::Synth(i) => write!(f, "synth{}", i)?,
+            RouteTarget::Output(i) => write!(f, "out{}", i)?,
+        }
+        if let Some(channels) = &self.filter.channels {
+            let channels: Vec<String> = channels.iter().map(u8::to_string).collect();
+            write!(f, "/channels={}", channels.join(","))?;
+        }
+        if let Some(kinds) = &self.filter.kinds {
+            let kinds: Vec<&str> = kinds.iter().map(|kind| kind.name()).collect();
+            write!(f, "/kinds={}", kinds.join(","))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    /// `INPUT>TARGET`, where the target is `synthN` or `outN`, optionally followed by
+    /// `/channels=...` with channels or ranges of them from 0, like `0-3,9`, and `/kinds=...` with
+    /// any of `MessageKind::NAMES`. For example `1>out0/channels=9/kinds=note`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "{:?} is not a route, like 0>synth1 or 1>out0/channels=0-3,9/kinds=note,controller",
+                s
+            )
+        };
+        let mut parts = s.split('/');
+        let (input, target) = parts
+            .next()
+            .and_then(|p| p.split_once('>'))
+            .ok_or_else(invalid)?;
+        let input = input.trim().parse().map_err(|_| invalid())?;
+        let target = target.trim();
+        let target = if let Some(i) = target.strip_prefix("synth") {
+            RouteTarget::Synth(i.parse().map_err(|_| invalid())?)
+        } else if let Some(i) = target.strip_prefix("out") {
+            RouteTarget::Output(i.parse().map_err(|_| invalid())?)
+        } else {
+            return Err(invalid());
+        };
+
+        let mut filter = RouteFilter::default();
+        for part in parts {
+            match part.split_once('=') {
+                Some(("channels", channels)) => {
+                    filter.channels = Some(parse_channels(channels).ok_or_else(invalid)?);
+                }
+                Some(("kinds", kinds)) => {
+                    let kinds = kinds
+                        .split(',')
+                        .map(|kind| {
+                            MessageKind::by_name(kind.trim()).ok_or_else(|| {
+                                format!(
+                                    "{:?} is not a kind of message, try one of {}",
+                                    kind,
+                                    MessageKind::NAMES.join(", ")
+                                )
+                            })
+                        })
+                        .collect::<Result<_, _>>()?;
+                    filter.kinds = Some(kinds);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(Route {
+            input,
+            target,
+            filter,
+        })
+    }
+}
+
+/// Channels and ranges of them, like `0-3,9`.
+pub(crate) fn parse_channels(s: &str) -> Option<Vec<u8>> {
+    let mut channels = Vec::new();
+    for part in s.split(',') {
+        let (first, last): (u8, u8) = match part.split_once('-') {
+            Some((first, last)) => (first.trim().parse().ok()?, last.trim().parse().ok()?),
+            None => {
+                let channel = part.trim().parse().ok()?;
+                (channel, channel)
+            }
+        };
+        if first > last || last > 15 {
+            return None;
+        }
+        channels.extend(first..=last);
+    }
+
+    Some(channels)
+}
+
+/// Delivers input messages along the routes.
+pub(crate) struct Router {
+    routes: Vec<Route>,
+    synth_txs: Vec<mpsc::Sender<RawMidiMessage>>,
+    outputs: Vec<MidiOutputDevice>,
+}
+
+impl Router {
+    pub(crate) fn new(
+        routes: Vec<Route>,
+        synth_txs: Vec<mpsc::Sender<RawMidiMessage>>,
+        outputs: Vec<MidiOutputDevice>,
+    ) -> Self {
+        Router {
+            routes,
+            synth_txs,
+            outputs,
+        }
+    }
+
+    pub(crate) async fn route(&mut self, input: usize, message: &RawMidiMessage) {
+        for route in self.routes.iter() {
+            if route.input != input || !route.filter.passes(&message.1) {
+                continue;
+            }
+            match route.target {
+                RouteTarget::Synth(i) => {
+                    // Nothing to play once the synth has stopped.
+                    let _ = self.synth_txs[i].send(message.clone()).await;
+                }
+                RouteTarget::Output(i) => {
+                    if let Err(e) = self.outputs[i].send(&message.1) {
+                        warn!("Failed to send MIDI to output {}: {}", i, e);
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn close(self) {
+        for output in self.outputs {
+            output.close();
+        }
+    }
+}
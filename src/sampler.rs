@@ -0,0 +1,249 @@
+//! A simple sampler: WAV files mapped to ranges of keys, each pitched up or down from the key it
+//! was recorded at. The mapping is a TOML file like
+//!
+//! ```toml
+//! [[zone]]
+//! file = "piano-c3.wav"
+//! root_key = 48
+//! keys = [0, 53]
+//!
+//! [[zone]]
+//! file = "piano-c4.wav"
+//! root_key = 60
+//! keys = [54, 127]
+//! loop_start = 12000
+//! loop_end = 30000
+//! loop_crossfade = 2000
+//! release_file = "piano-c4-release.wav"
+//! ```
+//!
+//! File paths are relative to the mapping file.
+
+use crate::{
+    envelope::Adsr,
+    soundfont::{LoopMode, SoundFont, Zone},
+};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The attack is only there to avoid a click when a sample doesn't start at zero.
+const SAMPLER_ATTACK_SECS: f32 = 0.002;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct KeyMap {
+    #[serde(rename = "zone")]
+    pub zones: Vec<KeyMapZone>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct KeyMapZone {
+    pub file: PathBuf,
+    /// The key the sample plays at its recorded pitch.
+    pub root_key: u8,
+    /// The lowest and highest keys it plays, inclusive.
+    #[serde(default = "full_range")]
+    pub keys: (u8, u8),
+    #[serde(default = "full_range")]
+    pub velocities: (u8, u8),
+    /// Sample frames to loop between while the note is held. Without both, the sample plays once.
+    #[serde(default)]
+    pub loop_start: Option<usize>,
+    #[serde(default)]
+    pub loop_end: Option<usize>,
+    /// How the sample plays. Defaults to `forward` with both loop points, and `off` without.
+    #[serde(default)]
+    pub loop_mode: Option<KeyMapLoopMode>,
+    /// Sample frames before the loop end that fade into the same number before the loop start, to
+    /// smooth over a loop whose ends don't quite match. Only for forward loops.
+    #[serde(default)]
+    pub loop_crossfade: usize,
+    /// A sample played once when the key is released, like a piano's dampers coming down. It must
+    /// have the same sample rate as `file`.
+    #[serde(default)]
+    pub release_file: Option<PathBuf>,
+    #[serde(default)]
+    pub gain_db: f32,
+    #[serde(default = "default_release_secs")]
+    pub release_secs: f32,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyMapLoopMode {
+    /// Plays once, stopping early if the key is released.
+    Off,
+    /// Plays once through to the end, ignoring the key's release, like a drum hit.
+    OneShot,
+    /// Loops from the loop end back to the loop start.
+    Forward,
+    /// Loops back and forth between the loop points.
+    PingPong,
+}
+
+fn full_range() -> (u8, u8) {
+    (0, 127)
+}
+
+fn default_release_secs() -> f32 {
+    0.3
+}
+
+impl KeyMap {
+    /// Reads a mapping file, resolving its sample paths against the file's directory.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut map: KeyMap =
+            toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for zone in map.zones.iter_mut() {
+            zone.file = dir.join(&zone.file);
+            if let Some(release_file) = zone.release_file.as_mut() {
+                *release_file = dir.join(&release_file);
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Loads every sample. Like `SoundFont::load`, the result lives for the rest of the program.
+    /// Stereo files become a left and a right voice, panned apart.
+    pub fn build(&self) -> io::Result<&'static SoundFont> {
+        let mut zones = Vec::new();
+        let mut sample_data = Vec::new();
+        for zone in self.zones.iter() {
+            let (channels, sample_hz) = read_wav_channels(&zone.file)?;
+            let release_channels = match &zone.release_file {
+                Some(path) => {
+                    let (channels, release_hz) = read_wav_channels(path)?;
+                    if release_hz != sample_hz {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "{:?} is at {} Hz, but {:?} is at {} Hz",
+                                path, release_hz, zone.file, sample_hz
+                            ),
+                        ));
+                    }
+
+                    channels
+                }
+                None => Vec::new(),
+            };
+            let num_channels = channels.len();
+            for (c, samples) in channels.into_iter().enumerate() {
+                let pan = match num_channels {
+                    1 => 0.0,
+                    _ => 2.0 * c as f32 / (num_channels - 1) as f32 - 1.0,
+                };
+                let start = sample_data.len();
+                let length = samples.len();
+                sample_data.extend(samples);
+                let loop_points = match (zone.loop_start, zone.loop_end) {
+                    (Some(loop_start), Some(loop_end)) if loop_start < loop_end.min(length) => {
+                        Some((start + loop_start, start + loop_end.min(length)))
+                    }
+                    _ => None,
+                };
+                let (loop_mode, (loop_start, loop_end)) = match (zone.loop_mode, loop_points) {
+                    (Some(KeyMapLoopMode::OneShot), _) => (LoopMode::OneShot, (start, start)),
+                    (Some(KeyMapLoopMode::Off), _) | (None, None) => {
+                        (LoopMode::NoLoop, (start, start))
+                    }
+                    (Some(KeyMapLoopMode::Forward), Some(points)) | (None, Some(points)) => {
+                        (LoopMode::Continuous, points)
+                    }
+                    (Some(KeyMapLoopMode::PingPong), Some(points)) => (LoopMode::PingPong, points),
+                    (Some(mode), None) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{:?} needs loop points for {:?}", zone.file, mode),
+                        ))
+                    }
+                };
+                // A stereo release sample follows the sample's channels, and a mono one is shared.
+                let release_start = sample_data.len();
+                if let Some(release) = release_channels.get(c).or_else(|| release_channels.last()) {
+                    sample_data.extend_from_slice(release);
+                }
+                let release_end = sample_data.len();
+
+                zones.push(Zone {
+                    keys: zone.keys,
+                    velocities: zone.velocities,
+                    start,
+                    end: start + length,
+                    loop_start,
+                    loop_end,
+                    loop_mode,
+                    loop_crossfade: zone.loop_crossfade,
+                    release_start,
+                    release_end,
+                    sample_hz: sample_hz as f32,
+                    root_key: zone.root_key,
+                    tune_cents: 0.0,
+                    scale_tuning: 100.0,
+                    attenuation_db: -zone.gain_db,
+                    pan,
+                    envelope: Adsr {
+                        attack_secs: SAMPLER_ATTACK_SECS,
+                        decay_secs: 0.0,
+                        sustain: 1.0,
+                        release_secs: zone.release_secs,
+                    },
+                });
+            }
+        }
+
+        Ok(Box::leak(Box::new(SoundFont::from_zones(
+            zones,
+            sample_data,
+        ))))
+    }
+}
+
+/// Each channel of a WAV file as 16-bit samples, and the file's sample rate.
+fn read_wav_channels(path: &Path) -> io::Result<(Vec<Vec<i16>>, u32)> {
+    let to_io = |e: hound::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut reader = hound::WavReader::open(path).map_err(to_io)?;
+    let spec = reader.spec();
+    let interleaved: Vec<i16> = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .map(|s| s.map(|s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16))
+            .collect::<Result<_, _>>()
+            .map_err(to_io)?,
+        hound::SampleFormat::Int => {
+            let shift = spec.bits_per_sample as i32 - 16;
+            reader
+                .samples::<i32>()
+                .map(|s| {
+                    s.map(|s| {
+                        if shift >= 0 {
+                            (s >> shift) as i16
+                        } else {
+                            (s << -shift) as i16
+                        }
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map_err(to_io)?
+        }
+    };
+
+    let num_channels = spec.channels.max(1) as usize;
+    let channels = (0..num_channels)
+        .map(|c| {
+            interleaved
+                .iter()
+                .skip(c)
+                .step_by(num_channels)
+                .copied()
+                .collect()
+        })
+        .collect();
+
+    Ok((channels, spec.sample_rate))
+}
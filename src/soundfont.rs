@@ -0,0 +1,738 @@
+//! SoundFont 2 instruments: a loader for `.sf2` files and the voices that play their samples.
+//!
+//! Only what General MIDI fonts lean on is supported: key and velocity splits, tuning, looping,
+//! pan, attenuation and the volume envelope (without its delay and hold stages, and with linear
+//! rather than logarithmic segments). Modulators, filters and LFOs are ignored.
+
+use crate::envelope::{Adsr, Envelope};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The bank General MIDI fonts keep their drum kits in.
+pub(crate) const DRUM_BANK: u16 = 128;
+
+const GEN_START_OFFSET: usize = 0;
+const GEN_END_OFFSET: usize = 1;
+const GEN_LOOP_START_OFFSET: usize = 2;
+const GEN_LOOP_END_OFFSET: usize = 3;
+const GEN_START_COARSE_OFFSET: usize = 4;
+const GEN_END_COARSE_OFFSET: usize = 12;
+const GEN_PAN: usize = 17;
+const GEN_ATTACK_VOL_ENV: usize = 34;
+const GEN_DECAY_VOL_ENV: usize = 36;
+const GEN_SUSTAIN_VOL_ENV: usize = 37;
+const GEN_RELEASE_VOL_ENV: usize = 38;
+const GEN_INSTRUMENT: usize = 41;
+const GEN_KEY_RANGE: usize = 43;
+const GEN_VELOCITY_RANGE: usize = 44;
+const GEN_LOOP_START_COARSE_OFFSET: usize = 45;
+const GEN_INITIAL_ATTENUATION: usize = 48;
+const GEN_LOOP_END_COARSE_OFFSET: usize = 50;
+const GEN_COARSE_TUNE: usize = 51;
+const GEN_FINE_TUNE: usize = 52;
+const GEN_SAMPLE_ID: usize = 53;
+const GEN_SAMPLE_MODES: usize = 54;
+const GEN_SCALE_TUNING: usize = 56;
+const GEN_OVERRIDING_ROOT_KEY: usize = 58;
+const GEN_COUNT: usize = 61;
+
+/// Envelope times are in timecents, and this one (about a millisecond) is the default.
+const DEFAULT_TIMECENTS: i16 = -12000;
+
+/// Coarse address offsets count in steps of this many samples.
+const COARSE_OFFSET_SAMPLES: i32 = 32768;
+
+/// One zone's generator amounts, indexed by generator number.
+type Generators = [Option<i16>; GEN_COUNT];
+
+pub struct SoundFont {
+    presets: Vec<Preset>,
+    /// Every sample in the font, back to back.
+    sample_data: Vec<i16>,
+}
+
+struct Preset {
+    bank: u16,
+    program: u16,
+    zones: Vec<Zone>,
+}
+
+/// A sample and how to play it, with the preset and instrument generators already combined.
+#[derive(Clone, Copy)]
+pub(crate) struct Zone {
+    pub(crate) keys: (u8, u8),
+    pub(crate) velocities: (u8, u8),
+    /// Offsets into the font's sample data.
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) loop_start: usize,
+    pub(crate) loop_end: usize,
+    pub(crate) loop_mode: LoopMode,
+    /// Sample frames at the end of a forward loop that fade into the frames before its start.
+    pub(crate) loop_crossfade: usize,
+    /// A sample to play once from the key's release. Empty if there isn't one.
+    pub(crate) release_start: usize,
+    pub(crate) release_end: usize,
+    pub(crate) sample_hz: f32,
+    pub(crate) root_key: u8,
+    pub(crate) tune_cents: f32,
+    /// Cents of pitch per key. 100 for ordinary instruments, 0 for samples that shouldn't follow
+    /// the key.
+    pub(crate) scale_tuning: f32,
+    pub(crate) attenuation_db: f32,
+    /// In [-1.0, 1.0].
+    pub(crate) pan: f32,
+    pub(crate) envelope: Adsr,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum LoopMode {
+    NoLoop,
+    Continuous,
+    /// Loops while the key is held, then plays on through the end of the sample.
+    UntilRelease,
+    /// Plays forwards and backwards between the loop points.
+    PingPong,
+    /// Plays once through to the end, whether or not the key is released.
+    OneShot,
+}
+
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_hz: u32,
+    original_key: u8,
+    pitch_correction: i8,
+}
+
+impl SoundFont {
+    /// Loads a `.sf2` file. Like `load_wave_from_wav`, the font lives for the rest of the program,
+    /// so it can be shared by every voice as a `Source`.
+    pub fn load(path: &Path) -> io::Result<&'static SoundFont> {
+        let bytes = fs::read(path)?;
+
+        Ok(Box::leak(Box::new(Self::parse(&bytes)?)))
+    }
+
+    /// A font with a single preset, which every program and bank falls back to.
+    pub(crate) fn from_zones(zones: Vec<Zone>, sample_data: Vec<i16>) -> Self {
+        SoundFont {
+            presets: vec![Preset {
+                bank: 0,
+                program: 0,
+                zones,
+            }],
+            sample_data,
+        }
+    }
+
+    /// A font with a preset in bank 0 for each program, counting from 0. Programs past the end fall
+    /// back to the first.
+    pub(crate) fn from_programs(programs: Vec<Vec<Zone>>, sample_data: Vec<i16>) -> Self {
+        SoundFont {
+            presets: programs
+                .into_iter()
+                .enumerate()
+                .map(|(program, zones)| Preset {
+                    bank: 0,
+                    program: program as u16,
+                    zones,
+                })
+                .collect(),
+            sample_data,
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"sfbk" {
+            return Err(invalid("Not a SoundFont 2 file"));
+        }
+        // The RIFF size counts the "sfbk" form type, so anything smaller can't hold it.
+        let riff_len = read_u32(bytes, 4) as usize;
+        if riff_len < 4 {
+            return Err(invalid("Truncated SoundFont"));
+        }
+        let body = &bytes[12..riff_len.saturating_add(8).min(bytes.len())];
+
+        let mut sample_data = None;
+        let mut pdta = None;
+        for (id, data) in chunks(body)? {
+            if id != b"LIST" || data.len() < 4 {
+                continue;
+            }
+            match &data[..4] {
+                b"sdta" => {
+                    sample_data = chunks(&data[4..])?
+                        .into_iter()
+                        .find(|(id, _)| id == b"smpl")
+                        .map(|(_, smpl)| {
+                            smpl.chunks_exact(2)
+                                .map(|s| i16::from_le_bytes([s[0], s[1]]))
+                                .collect::<Vec<i16>>()
+                        });
+                }
+                b"pdta" => pdta = Some(chunks(&data[4..])?),
+                _ => (),
+            }
+        }
+        let sample_data = sample_data.ok_or_else(|| invalid("SoundFont has no samples"))?;
+        let pdta = pdta.ok_or_else(|| invalid("SoundFont has no presets"))?;
+        let sub_chunk = |name: &[u8; 4]| {
+            pdta.iter()
+                .find(|(id, _)| id == name)
+                .map(|(_, data)| *data)
+                .ok_or_else(|| invalid("SoundFont is missing preset data"))
+        };
+
+        // Each header points at its first bag, and each bag at its first generator. The next
+        // header or bag marks where they end, so every list has a terminal record.
+        let preset_headers: Vec<(u16, u16, usize)> = sub_chunk(b"phdr")?
+            .chunks_exact(38)
+            .map(|r| (read_u16(r, 22), read_u16(r, 20), read_u16(r, 24) as usize))
+            .collect();
+        let preset_bags = bag_generator_indices(sub_chunk(b"pbag")?);
+        let preset_generators = generator_records(sub_chunk(b"pgen")?);
+        let instrument_bags: Vec<usize> = sub_chunk(b"inst")?
+            .chunks_exact(22)
+            .map(|r| read_u16(r, 20) as usize)
+            .collect();
+        let instrument_zone_bags = bag_generator_indices(sub_chunk(b"ibag")?);
+        let instrument_generators = generator_records(sub_chunk(b"igen")?);
+        let samples: Vec<SampleHeader> = sub_chunk(b"shdr")?
+            .chunks_exact(46)
+            .map(|r| SampleHeader {
+                start: read_u32(r, 20),
+                end: read_u32(r, 24),
+                loop_start: read_u32(r, 28),
+                loop_end: read_u32(r, 32),
+                sample_hz: read_u32(r, 36),
+                original_key: r[40],
+                pitch_correction: r[41] as i8,
+            })
+            .collect();
+
+        let instruments: Vec<Vec<Generators>> = (0..instrument_bags.len().saturating_sub(1))
+            .map(|i| {
+                zones(
+                    &instrument_bags,
+                    i,
+                    &instrument_zone_bags,
+                    &instrument_generators,
+                    GEN_SAMPLE_ID,
+                )
+            })
+            .collect();
+
+        let preset_header_bags: Vec<usize> =
+            preset_headers.iter().map(|(_, _, bag)| *bag).collect();
+        let mut presets = Vec::new();
+        for (i, &(bank, program, _)) in preset_headers
+            .iter()
+            .enumerate()
+            .take(preset_headers.len().saturating_sub(1))
+        {
+            let mut preset_zones = Vec::new();
+            for preset_zone in zones(
+                &preset_header_bags,
+                i,
+                &preset_bags,
+                &preset_generators,
+                GEN_INSTRUMENT,
+            ) {
+                let instrument =
+                    preset_zone[GEN_INSTRUMENT].and_then(|i| instruments.get(i as u16 as usize));
+                for instrument_zone in instrument.into_iter().flatten() {
+                    if let Some(zone) =
+                        combine_zones(&preset_zone, instrument_zone, &samples, sample_data.len())
+                    {
+                        preset_zones.push(zone);
+                    }
+                }
+            }
+            presets.push(Preset {
+                bank,
+                program,
+                zones: preset_zones,
+            });
+        }
+        if presets.is_empty() {
+            return Err(invalid("SoundFont has no presets"));
+        }
+
+        Ok(SoundFont {
+            presets,
+            sample_data,
+        })
+    }
+
+    /// Falls back to the same program in bank 0 (or the standard kit for drums), then to the first
+    /// preset, so every program plays something.
+    fn preset(&self, bank: u16, program: u8) -> &Preset {
+        let program = program as u16;
+        let fallback_program = if bank == DRUM_BANK { 0 } else { program };
+        let fallback_bank = if bank == DRUM_BANK { DRUM_BANK } else { 0 };
+
+        self.presets
+            .iter()
+            .find(|p| p.bank == bank && p.program == program)
+            .or_else(|| {
+                self.presets
+                    .iter()
+                    .find(|p| p.bank == fallback_bank && p.program == fallback_program)
+            })
+            .unwrap_or(&self.presets[0])
+    }
+
+    /// A voice for every zone of the preset that covers `key` and `velocity`. Stereo samples come
+    /// as a left and a right zone, panned apart.
+    pub(crate) fn voices(
+        &'static self,
+        bank: u16,
+        program: u8,
+        key: u8,
+        velocity: u8,
+        sample_hz: f32,
+    ) -> Vec<SampleVoice> {
+        self.preset(bank, program)
+            .zones
+            .iter()
+            .filter(|z| z.keys.0 <= key && key <= z.keys.1)
+            .filter(|z| z.velocities.0 <= velocity && velocity <= z.velocities.1)
+            .map(|z| SampleVoice::new(&self.sample_data, z, key, sample_hz))
+            .collect()
+    }
+}
+
+/// Plays one zone's sample at a key's pitch, through the zone's volume envelope.
+pub(crate) struct SampleVoice {
+    /// Just this zone's sample.
+    data: &'static [i16],
+    /// Where the next sample is read from, between entries of `data`.
+    position: f64,
+    /// How far `position` moves per output sample.
+    step: f64,
+    /// From pitch bend, already included in `step`.
+    pitch_ratio: f64,
+    /// -1.0 while a ping-pong loop is playing backwards.
+    direction: f64,
+    sample_hz: f32,
+    loop_start: usize,
+    loop_end: usize,
+    loop_mode: LoopMode,
+    loop_crossfade: usize,
+    /// Played once from the key's release, outside of the envelope.
+    release_data: &'static [i16],
+    release_position: f64,
+    released: bool,
+    /// Set once a sample that doesn't loop has played to its end.
+    finished: bool,
+    gain: f32,
+    /// In [-1.0, 1.0].
+    pub(crate) pan: f32,
+    envelope: Envelope,
+}
+
+impl SampleVoice {
+    fn new(sample_data: &'static [i16], zone: &Zone, key: u8, sample_hz: f32) -> Self {
+        let cents = (key as f32 - zone.root_key as f32) * zone.scale_tuning + zone.tune_cents;
+        let data = &sample_data[zone.start..zone.end];
+        let loop_start = zone.loop_start.saturating_sub(zone.start);
+        let loop_end = zone.loop_end.saturating_sub(zone.start).min(data.len());
+        // A broken loop would read outside the sample, and a ping-pong loop needs two frames to
+        // turn around between.
+        let loop_mode = match zone.loop_mode {
+            LoopMode::NoLoop | LoopMode::OneShot => zone.loop_mode,
+            LoopMode::PingPong if loop_start + 1 < loop_end => LoopMode::PingPong,
+            LoopMode::PingPong if loop_start < loop_end => LoopMode::Continuous,
+            _ if loop_start < loop_end => zone.loop_mode,
+            _ => LoopMode::NoLoop,
+        };
+        // The crossfade reads as far before the loop start as it starts before the loop end.
+        let loop_crossfade = zone
+            .loop_crossfade
+            .min(loop_start)
+            .min(loop_end.saturating_sub(loop_start));
+        let release_data = sample_data
+            .get(zone.release_start..zone.release_end)
+            .unwrap_or(&[]);
+
+        SampleVoice {
+            data,
+            position: 0.0,
+            step: (zone.sample_hz / sample_hz * (cents / 1200.0).exp2()) as f64,
+            pitch_ratio: 1.0,
+            direction: 1.0,
+            sample_hz,
+            loop_start,
+            loop_end,
+            loop_mode,
+            loop_crossfade,
+            release_data,
+            release_position: 0.0,
+            released: false,
+            finished: false,
+            gain: 10f32.powf(-zone.attenuation_db / 20.0),
+            pan: zone.pan,
+            envelope: Envelope::new(zone.envelope, sample_hz),
+        }
+    }
+
+    fn is_looping(&self) -> bool {
+        match self.loop_mode {
+            LoopMode::NoLoop | LoopMode::OneShot => false,
+            LoopMode::Continuous | LoopMode::PingPong => true,
+            LoopMode::UntilRelease => !self.released,
+        }
+    }
+
+    /// Plays the sample `ratio` times faster than its unbent pitch from now on.
+    pub(crate) fn set_pitch_ratio(&mut self, ratio: f32) {
+        self.step *= ratio as f64 / self.pitch_ratio;
+        self.pitch_ratio = ratio as f64;
+    }
+
+    /// Keeps the pitch and envelope when the output rate changes.
+    pub(crate) fn set_sample_hz(&mut self, sample_hz: f32) {
+        self.step *= (self.sample_hz / sample_hz) as f64;
+        self.sample_hz = sample_hz;
+        self.envelope.set_sample_hz(sample_hz);
+    }
+
+    pub(crate) fn release(&mut self) {
+        self.released = true;
+        if self.loop_mode != LoopMode::OneShot {
+            self.envelope.release();
+        }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        let release_done =
+            !self.released || self.release_position as usize + 1 >= self.release_data.len();
+
+        (self.finished || self.envelope.is_done()) && release_done
+    }
+
+    pub(crate) fn sample(&mut self) -> f32 {
+        let note = if self.finished || self.envelope.is_done() {
+            0.0
+        } else {
+            let sample = self.sample_note();
+            self.envelope.next_level() * sample
+        };
+        let release = if self.released {
+            let sample = interpolate(self.release_data, self.release_position, None);
+            self.release_position += self.step;
+            sample
+        } else {
+            0.0
+        };
+
+        self.gain * (note + release)
+    }
+
+    fn sample_note(&mut self) -> f32 {
+        let looping = self.is_looping();
+        let i = self.position as usize;
+        if !looping && i + 1 >= self.data.len() {
+            self.finished = true;
+            return 0.0;
+        }
+        let sample = match self.loop_mode {
+            LoopMode::PingPong if looping => interpolate(self.data, self.position, None),
+            _ if looping => {
+                let sample = interpolate(
+                    self.data,
+                    self.position,
+                    Some(self.loop_start..self.loop_end),
+                );
+                let fade_start = self.loop_end - self.loop_crossfade;
+                if self.position >= fade_start as f64 {
+                    let t = (self.position - fade_start as f64) as f32 / self.loop_crossfade as f32;
+                    let loop_length = (self.loop_end - self.loop_start) as f64;
+                    let before_loop = interpolate(self.data, self.position - loop_length, None);
+                    (1.0 - t) * sample + t * before_loop
+                } else {
+                    sample
+                }
+            }
+            _ => interpolate(self.data, self.position, None),
+        };
+
+        self.position += self.direction * self.step;
+        if looping {
+            match self.loop_mode {
+                LoopMode::PingPong => {
+                    // Turn around on the last and first frames of the loop. It is only entered
+                    // going forwards, from before its start.
+                    let (first, last) = (self.loop_start as f64, (self.loop_end - 1) as f64);
+                    loop {
+                        if self.direction > 0.0 && self.position > last {
+                            self.position = 2.0 * last - self.position;
+                            self.direction = -1.0;
+                        } else if self.direction < 0.0 && self.position < first {
+                            self.position = 2.0 * first - self.position;
+                            self.direction = 1.0;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                _ => {
+                    let loop_length = (self.loop_end - self.loop_start) as f64;
+                    while self.position >= self.loop_end as f64 {
+                        self.position -= loop_length;
+                    }
+                }
+            }
+        }
+
+        sample
+    }
+}
+
+/// Linearly interpolates between the entries of `data` around `position`, or returns silence past
+/// the end. Within a forward `looped` range, the entry after the last one is the first.
+fn interpolate(data: &[i16], position: f64, looped: Option<std::ops::Range<usize>>) -> f32 {
+    let i = position as usize;
+    let current = match data.get(i) {
+        Some(&s) => s as f32,
+        None => return 0.0,
+    };
+    let next = match looped {
+        Some(range) if i + 1 >= range.end => range.start,
+        _ => i + 1,
+    };
+    let next = data.get(next).map_or(current, |&s| s as f32);
+    let fraction = (position - i as f64) as f32;
+
+    (current + fraction * (next - current)) / 32768.0
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// Splits the body of a RIFF list into its `(id, data)` chunks.
+fn chunks(mut bytes: &[u8]) -> io::Result<Vec<(&[u8], &[u8])>> {
+    let mut chunks = Vec::new();
+    while bytes.len() >= 8 {
+        let len = read_u32(bytes, 4) as usize;
+        let data = bytes
+            .get(8..8 + len)
+            .ok_or_else(|| invalid("Truncated SoundFont chunk"))?;
+        chunks.push((&bytes[..4], data));
+        // Chunks are padded to an even length.
+        let next = (8 + len + 1) & !1;
+        bytes = &bytes[next.min(bytes.len())..];
+    }
+
+    Ok(chunks)
+}
+
+fn bag_generator_indices(bags: &[u8]) -> Vec<usize> {
+    bags.chunks_exact(4)
+        .map(|r| read_u16(r, 0) as usize)
+        .collect()
+}
+
+fn generator_records(generators: &[u8]) -> Vec<(u16, i16)> {
+    generators
+        .chunks_exact(4)
+        .map(|r| (read_u16(r, 0), read_u16(r, 2) as i16))
+        .collect()
+}
+
+/// The zones of header `header`, with its global zone (the first one, if it lacks the `terminal`
+/// generator) folded into the others.
+fn zones(
+    header_bags: &[usize],
+    header: usize,
+    bags: &[usize],
+    generators: &[(u16, i16)],
+    terminal: usize,
+) -> Vec<Generators> {
+    let first_bag = header_bags[header];
+    let end_bag = header_bags[header + 1].max(first_bag);
+    let mut zones: Vec<Generators> = (first_bag..end_bag)
+        .map(|bag| {
+            let mut amounts = [None; GEN_COUNT];
+            let first = bags.get(bag).copied().unwrap_or(0);
+            let end = bags.get(bag + 1).copied().unwrap_or(first).max(first);
+            for &(op, amount) in generators.get(first..end).unwrap_or(&[]) {
+                if let Some(slot) = amounts.get_mut(op as usize) {
+                    *slot = Some(amount);
+                }
+            }
+
+            amounts
+        })
+        .collect();
+
+    let global = match zones.first() {
+        Some(zone) if zone[terminal].is_none() => zones.remove(0),
+        _ => [None; GEN_COUNT],
+    };
+    zones.retain(|zone| zone[terminal].is_some());
+    for zone in zones.iter_mut() {
+        for (amount, global) in zone.iter_mut().zip(global.iter()) {
+            if amount.is_none() {
+                *amount = *global;
+            }
+        }
+    }
+
+    zones
+}
+
+fn amount(generators: &Generators, op: usize, default: i16) -> i32 {
+    generators[op].unwrap_or(default) as i32
+}
+
+/// Preset generators offset the instrument's, except for ranges which narrow them.
+fn combined(preset: &Generators, instrument: &Generators, op: usize, default: i16) -> i32 {
+    amount(instrument, op, default) + amount(preset, op, 0)
+}
+
+fn range(generators: &Generators, op: usize) -> (u8, u8) {
+    generators[op].map_or((0, 127), |r| {
+        let r = r as u16;
+
+        ((r & 0xff) as u8, (r >> 8) as u8)
+    })
+}
+
+fn intersect((a_low, a_high): (u8, u8), (b_low, b_high): (u8, u8)) -> Option<(u8, u8)> {
+    let low = a_low.max(b_low);
+    let high = a_high.min(b_high);
+
+    (low <= high).then_some((low, high))
+}
+
+fn timecents_to_secs(timecents: i32) -> f32 {
+    (timecents as f32 / 1200.0).exp2()
+}
+
+fn combine_zones(
+    preset: &Generators,
+    instrument: &Generators,
+    samples: &[SampleHeader],
+    sample_data_len: usize,
+) -> Option<Zone> {
+    let keys = intersect(
+        range(preset, GEN_KEY_RANGE),
+        range(instrument, GEN_KEY_RANGE),
+    )?;
+    let velocities = intersect(
+        range(preset, GEN_VELOCITY_RANGE),
+        range(instrument, GEN_VELOCITY_RANGE),
+    )?;
+    let sample = samples.get(instrument[GEN_SAMPLE_ID]? as u16 as usize)?;
+
+    // Address offsets only appear in instrument zones.
+    let address = |base: u32, fine: usize, coarse: usize| {
+        let offset =
+            amount(instrument, fine, 0) + COARSE_OFFSET_SAMPLES * amount(instrument, coarse, 0);
+
+        (base as i64 + offset as i64).clamp(0, sample_data_len as i64) as usize
+    };
+    let start = address(sample.start, GEN_START_OFFSET, GEN_START_COARSE_OFFSET);
+    let end = address(sample.end, GEN_END_OFFSET, GEN_END_COARSE_OFFSET);
+    if start + 1 >= end {
+        return None;
+    }
+    let loop_start = address(
+        sample.loop_start,
+        GEN_LOOP_START_OFFSET,
+        GEN_LOOP_START_COARSE_OFFSET,
+    );
+    let loop_end = address(
+        sample.loop_end,
+        GEN_LOOP_END_OFFSET,
+        GEN_LOOP_END_COARSE_OFFSET,
+    );
+    let loop_mode = match amount(instrument, GEN_SAMPLE_MODES, 0) & 3 {
+        1 => LoopMode::Continuous,
+        3 => LoopMode::UntilRelease,
+        _ => LoopMode::NoLoop,
+    };
+    let root_key = match instrument[GEN_OVERRIDING_ROOT_KEY] {
+        Some(key @ 0..=127) => key as u8,
+        // 255 means the sample is unpitched.
+        _ if sample.original_key <= 127 => sample.original_key,
+        _ => 60,
+    };
+    let tune_cents = 100 * combined(preset, instrument, GEN_COARSE_TUNE, 0)
+        + combined(preset, instrument, GEN_FINE_TUNE, 0)
+        + sample.pitch_correction as i32;
+    let sustain_cb = combined(preset, instrument, GEN_SUSTAIN_VOL_ENV, 0).clamp(0, 1440);
+
+    Some(Zone {
+        keys,
+        velocities,
+        start,
+        end,
+        loop_start,
+        loop_end,
+        loop_mode,
+        loop_crossfade: 0,
+        release_start: 0,
+        release_end: 0,
+        sample_hz: sample.sample_hz.max(1) as f32,
+        root_key,
+        tune_cents: tune_cents as f32,
+        scale_tuning: combined(preset, instrument, GEN_SCALE_TUNING, 100) as f32,
+        attenuation_db: combined(preset, instrument, GEN_INITIAL_ATTENUATION, 0).max(0) as f32
+            / 10.0,
+        pan: (combined(preset, instrument, GEN_PAN, 0) as f32 / 500.0).clamp(-1.0, 1.0),
+        envelope: Adsr {
+            attack_secs: timecents_to_secs(combined(
+                preset,
+                instrument,
+                GEN_ATTACK_VOL_ENV,
+                DEFAULT_TIMECENTS,
+            )),
+            decay_secs: timecents_to_secs(combined(
+                preset,
+                instrument,
+                GEN_DECAY_VOL_ENV,
+                DEFAULT_TIMECENTS,
+            )),
+            // Sustain is given as an attenuation in centibels.
+            sustain: 10f32.powf(-sustain_cb as f32 / 200.0),
+            release_secs: timecents_to_secs(combined(
+                preset,
+                instrument,
+                GEN_RELEASE_VOL_ENV,
+                DEFAULT_TIMECENTS,
+            )),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_a_riff_size_too_small_for_its_form_type() {
+        for riff_len in 0..4u32 {
+            let mut bytes = b"RIFF".to_vec();
+            bytes.extend_from_slice(&riff_len.to_le_bytes());
+            bytes.extend_from_slice(b"sfbk");
+            let e = SoundFont::parse(&bytes).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
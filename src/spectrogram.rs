@@ -0,0 +1,139 @@
+//! Spectrogram images of offline renders, for checking timbre and spotting aliasing by eye.
+
+use crate::{
+    midi::MidiBytes,
+    oscillator::Source,
+    render::{render_midi_tracks, RenderOptions},
+};
+
+use rustfft::{num_complex::Complex, FftPlanner};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use time_calc::Bpm;
+
+const SPECTROGRAM_SAMPLE_HZ: u32 = 44100;
+
+/// Magnitudes this far below full scale are drawn black.
+const SPECTROGRAM_FLOOR_DB: f32 = -100.0;
+
+/// Dark to bright, roughly following the "inferno" colormap.
+const SPECTROGRAM_COLORS: [[f32; 3]; 5] = [
+    [0.0, 0.0, 4.0],
+    [87.0, 16.0, 110.0],
+    [188.0, 55.0, 84.0],
+    [249.0, 142.0, 9.0],
+    [252.0, 255.0, 164.0],
+];
+
+#[derive(Clone, Copy, Debug)]
+pub struct SpectrogramOptions {
+    /// FFT size in samples. The image is half this many pixels tall.
+    pub window_size: usize,
+    /// Samples between columns.
+    pub hop_size: usize,
+}
+
+impl Default for SpectrogramOptions {
+    fn default() -> Self {
+        SpectrogramOptions {
+            window_size: 2048,
+            hop_size: 512,
+        }
+    }
+}
+
+/// Renders the file offline and writes a spectrogram of the mono mix as a PNG, with time running
+/// left to right and frequency increasing upwards.
+pub fn write_midi_spectrogram(
+    midi_bytes: &MidiBytes,
+    bpm: Bpm,
+    track_instruments: &[Source],
+    output_path: &Path,
+    options: SpectrogramOptions,
+) -> io::Result<()> {
+    let mono = render_midi_tracks(
+        midi_bytes,
+        bpm,
+        track_instruments,
+        RenderOptions::default(),
+        SPECTROGRAM_SAMPLE_HZ,
+        1,
+    )?;
+    let columns = stft_magnitudes_db(&mono, options);
+
+    let width = columns.len().max(1);
+    let height = options.window_size / 2;
+    let mut pixels = vec![0u8; width * height * 3];
+    for (x, column) in columns.iter().enumerate() {
+        for (bin, db) in column.iter().enumerate() {
+            let y = height - 1 - bin;
+            let i = 3 * (y * width + x);
+            pixels[i..i + 3].copy_from_slice(&colormap(db / SPECTROGRAM_FLOOR_DB));
+        }
+    }
+
+    let file = BufWriter::new(File::create(output_path)?);
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(&pixels).map_err(png_error)?;
+    writer.finish().map_err(png_error)
+}
+
+fn png_error(e: png::EncodingError) -> io::Error {
+    match e {
+        png::EncodingError::IoError(e) => e,
+        other => io::Error::new(io::ErrorKind::Other, other),
+    }
+}
+
+/// Hann-windowed short-time Fourier transform. Each column has `window_size / 2` bins in dBFS,
+/// clamped to the floor, with DC first.
+fn stft_magnitudes_db(samples: &[f32], options: SpectrogramOptions) -> Vec<Vec<f32>> {
+    let n = options.window_size;
+    let fft = FftPlanner::new().plan_fft_forward(n);
+    let window: Vec<f32> = (0..n)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / n as f32).cos())
+        .collect();
+    // A full-scale sine peaks at n/4 after the Hann window.
+    let full_scale = n as f32 / 4.0;
+
+    let mut columns = Vec::new();
+    let mut buffer = vec![Complex::new(0.0, 0.0); n];
+    let mut start = 0;
+    while start + n <= samples.len() {
+        for ((b, s), w) in buffer
+            .iter_mut()
+            .zip(samples[start..start + n].iter())
+            .zip(window.iter())
+        {
+            *b = Complex::new(s * w, 0.0);
+        }
+        fft.process(&mut buffer);
+        columns.push(
+            buffer[..n / 2]
+                .iter()
+                .map(|c| (20.0 * (c.norm() / full_scale).log10()).max(SPECTROGRAM_FLOOR_DB))
+                .collect(),
+        );
+        start += options.hop_size;
+    }
+
+    columns
+}
+
+/// Maps 0.0 (loudest) through 1.0 (floor) to a color.
+fn colormap(quietness: f32) -> [u8; 3] {
+    let x = (1.0 - quietness).clamp(0.0, 1.0) * (SPECTROGRAM_COLORS.len() - 1) as f32;
+    let i = (x.floor() as usize).min(SPECTROGRAM_COLORS.len() - 2);
+    let t = x - i as f32;
+    let (a, b) = (SPECTROGRAM_COLORS[i], SPECTROGRAM_COLORS[i + 1]);
+
+    [
+        (a[0] + t * (b[0] - a[0])) as u8,
+        (a[1] + t * (b[1] - a[1])) as u8,
+        (a[2] + t * (b[2] - a[2])) as u8,
+    ]
+}
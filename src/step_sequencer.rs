@@ -0,0 +1,252 @@
+//! A 16-step sequencer, like on a drum machine or an analog sequencer, which plays a pattern over
+//! and over as MIDI messages for any instrument. Patterns are TOML files:
+//!
+//! ```toml
+//! bpm = 120.0
+//!
+//! [[steps]]
+//! key = 36
+//! velocity = 110
+//!
+//! # A rest.
+//! [[steps]]
+//!
+//! [[steps]]
+//! key = 48
+//! gate = 0.9
+//! ```
+//!
+//! Steps left out at the end are rests, and anything else left out has its default value.
+
+use crate::{
+    cancel::CancellationToken,
+    midi::{MidiMessageBytes, RawMidiMessage},
+    CHANNEL_MAX_BUFFER,
+};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::{select, sync::mpsc, task, time::delay_until};
+
+/// The number of steps in a pattern.
+pub const STEPS: usize = 16;
+
+/// One step of a pattern, which plays a note or rests.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Step {
+    /// The MIDI key to play, or `None` to rest.
+    pub key: Option<u8>,
+    /// From 1 to 127.
+    pub velocity: u8,
+    /// How much of the step the note is held for, from 0.0 to 1.0. A gate of 1.0 holds it until
+    /// the next step starts.
+    pub gate: f32,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Step {
+            key: None,
+            velocity: 100,
+            gate: 0.5,
+        }
+    }
+}
+
+impl Step {
+    pub fn note(key: u8, velocity: u8) -> Self {
+        Step {
+            key: Some(key),
+            velocity,
+            ..Self::default()
+        }
+    }
+}
+
+/// The steps to play, and how fast.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct StepPattern {
+    pub bpm: f64,
+    /// 4 makes each step a sixteenth note, so the pattern is a bar of 4/4.
+    pub steps_per_beat: u32,
+    /// The MIDI channel the notes are played on, counting from 0.
+    pub channel: u8,
+    /// At most `STEPS`, and the pattern rests for any after the last.
+    pub steps: Vec<Step>,
+}
+
+impl Default for StepPattern {
+    fn default() -> Self {
+        StepPattern {
+            bpm: 120.0,
+            steps_per_beat: 4,
+            channel: 0,
+            steps: Vec::new(),
+        }
+    }
+}
+
+impl StepPattern {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let pattern: StepPattern = toml::from_str(&text).map_err(invalid_data)?;
+        pattern.check().map_err(invalid_data)?;
+
+        Ok(pattern)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let text = toml::to_string(self).map_err(invalid_data)?;
+
+        fs::write(path, text)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.steps.len() > STEPS {
+            return Err(format!(
+                "A pattern has at most {} steps, not {}",
+                STEPS,
+                self.steps.len()
+            ));
+        }
+        if self.bpm <= 0.0 || self.steps_per_beat == 0 {
+            return Err("A pattern needs a tempo and steps per beat above 0".to_string());
+        }
+        if self.channel > 15 {
+            return Err(format!("{} is not a MIDI channel", self.channel));
+        }
+        if let Some(step) = self
+            .steps
+            .iter()
+            .find(|step| step.key.is_some_and(|key| key > 127))
+        {
+            return Err(format!("{:?} is not a MIDI key", step.key));
+        }
+
+        Ok(())
+    }
+
+    /// Step `i`, which is a rest past the end of `steps`.
+    pub fn step(&self, i: usize) -> Step {
+        self.steps.get(i).copied().unwrap_or_default()
+    }
+
+    pub fn step_duration(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / (self.bpm * self.steps_per_beat as f64))
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Plays a pattern, which can be changed while it plays. Clones share the same pattern.
+#[derive(Clone, Default)]
+pub struct StepSequencer {
+    pattern: Arc<Mutex<StepPattern>>,
+}
+
+impl StepSequencer {
+    pub fn new(pattern: StepPattern) -> Self {
+        StepSequencer {
+            pattern: Arc::new(Mutex::new(pattern)),
+        }
+    }
+
+    pub fn pattern(&self) -> StepPattern {
+        self.pattern.lock().unwrap().clone()
+    }
+
+    /// Takes effect from the next step.
+    pub fn set_pattern(&self, pattern: StepPattern) {
+        *self.pattern.lock().unwrap() = pattern;
+    }
+
+    /// Takes effect the next time the pattern gets to step `i`. Steps past `STEPS` are ignored.
+    pub fn set_step(&self, i: usize, step: Step) {
+        if i >= STEPS {
+            return;
+        }
+        let mut pattern = self.pattern.lock().unwrap();
+        if pattern.steps.len() <= i {
+            pattern.steps.resize(i + 1, Step::default());
+        }
+        pattern.steps[i] = step;
+    }
+
+    /// Takes effect from the next step.
+    pub fn set_bpm(&self, bpm: f64) {
+        self.pattern.lock().unwrap().bpm = bpm;
+    }
+
+    /// Plays the pattern over and over, from step 0, until `cancel` is cancelled or the messages
+    /// aren't wanted anymore. Timestamps are in microseconds from the first step. Must be called
+    /// from within a tokio runtime.
+    pub fn start(&self, cancel: CancellationToken) -> mpsc::Receiver<RawMidiMessage> {
+        let (message_tx, message_rx) = mpsc::channel(CHANNEL_MAX_BUFFER);
+        task::spawn(play_steps(self.pattern.clone(), message_tx, cancel));
+
+        message_rx
+    }
+}
+
+async fn play_steps(
+    pattern: Arc<Mutex<StepPattern>>,
+    mut message_tx: mpsc::Sender<RawMidiMessage>,
+    cancel: CancellationToken,
+) {
+    let start = Instant::now();
+    let mut step_start = Duration::from_secs(0);
+    for i in (0..STEPS).cycle() {
+        let (step, step_duration, channel) = {
+            let pattern = pattern.lock().unwrap();
+            (
+                pattern.step(i),
+                pattern.step_duration(),
+                pattern.channel & 0x0F,
+            )
+        };
+        // Rests wait too, so the next note comes on time.
+        if !wait_until(start + step_start, &cancel).await {
+            return;
+        }
+        if let Some(key) = step.key {
+            let key = key & 0x7F;
+            let velocity = step.velocity.clamp(1, 127);
+            let note_on: MidiMessageBytes = [0x90 | channel, key, velocity].into();
+            let note_off: MidiMessageBytes = [0x80 | channel, key, 0].into();
+            let gate_end = step_start + step_duration.mul_f32(step.gate.clamp(0.0, 1.0));
+            // Stop once nothing is listening.
+            if message_tx.send(timed(step_start, note_on)).await.is_err() {
+                return;
+            }
+            let played = wait_until(start + gate_end, &cancel).await;
+            // Sent even if cancelled, so the note isn't left hanging.
+            if message_tx.send(timed(gate_end, note_off)).await.is_err() || !played {
+                return;
+            }
+        }
+        step_start += step_duration;
+    }
+}
+
+fn timed(time: Duration, message: MidiMessageBytes) -> RawMidiMessage {
+    (time.as_micros() as u64, message)
+}
+
+/// Returns false if cancelled first.
+async fn wait_until(deadline: Instant, cancel: &CancellationToken) -> bool {
+    select! {
+        _ = delay_until(deadline.into()) => true,
+        _ = cancel.cancelled() => false,
+    }
+}
@@ -1,135 +1,1318 @@
 use crate::{
-    filters::ExponentialSmoothing,
+    clock::SampleClock,
+    controls::SynthParameter,
+    drums::DrumVoice,
+    effects::{Effect, Limiter},
+    envelope::{Adsr, Envelope},
+    filters::{DcBlocker, ExponentialSmoothing, ResonantLowPass, SvfCoefficients, SVF_BLOCK},
     midi::{get_midi_key_hz, RawMidiMessage},
-    wave_table::{Wave, WaveTableIndex},
-    AudioFrame, FRAME_SIZE,
+    oscillator::{Oscillator, Source},
+    patch::LoadedPatch,
+    rng::Rng,
+    soundfont::{SampleVoice, DRUM_BANK},
+    voice_pool::{VoiceKey, VoicePool},
+    AudioFrame, FRAME_SIZE, MAX_FRAME_SIZE, MIN_FRAME_SIZE,
 };
 
-use log::{info, trace};
-use std::collections::HashMap;
+use log::{info, trace, warn};
+use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
+use std::f32::consts::{FRAC_1_SQRT_2, FRAC_PI_4};
+use std::time::Duration;
+use tokio::sync::broadcast;
 use wmidi::MidiMessage;
 
+const NUM_MIDI_CHANNELS: usize = 16;
+/// Voices are rendered this many samples at a time, each voice into its channel's mix, so a voice
+/// stays in cache while it runs and its oscillators' table reads can be vectorized.
+const MIX_BLOCK: usize = 64;
+// A voice's filter coefficients for a whole block are worked out together.
+const _: () = assert!(MIX_BLOCK <= SVF_BLOCK);
+/// Channel 10, which General MIDI reserves for drums.
+const DRUM_CHANNEL: usize = 9;
+const CC_BANK_SELECT: u8 = 0;
+const CC_VOLUME: u8 = 7;
+const CC_PAN: u8 = 10;
+const CC_EXPRESSION: u8 = 11;
+const CC_RESONANCE: u8 = 71;
+const CC_BRIGHTNESS: u8 = 74;
+const CC_RESET_ALL_CONTROLLERS: u8 = 121;
+const CC_ALL_NOTES_OFF: u8 = 123;
+
+/// How far a full pitch bend either way moves a note, the General MIDI default.
+const PITCH_BEND_RANGE_SEMITONES: f32 = 2.0;
+
+/// Per-sample smoothing factor for pressure changes, which arrive in coarse 7-bit steps.
+const PRESSURE_SMOOTHING: f32 = 0.002;
+
+/// How much full pressure boosts a note's amplitude.
+const PRESSURE_AMPLITUDE_DEPTH: f32 = 0.5;
+
+/// How many octaves full pressure raises the voice filter's cutoff.
+const PRESSURE_CUTOFF_OCTAVES: f32 = 4.0;
+
+/// How many octaves CC74 (brightness) moves the voice filter cutoff at either extreme.
+const BRIGHTNESS_OCTAVES: f32 = 4.0;
+
+/// Per-sample smoothing factor for brightness and resonance changes, so filter sweeps from a knob
+/// don't zipper.
+const CHANNEL_FILTER_SMOOTHING: f32 = 0.002;
+
+/// Per-sample smoothing factor for channel gain changes, so volume and expression sweeps don't
+/// click. At 44.1 kHz this settles in roughly 20 milliseconds.
+const CHANNEL_GAIN_SMOOTHING: f32 = 0.001;
+
+/// Per-sample smoothing factor for the polyphony gain. At 44.1 kHz it settles in about 10
+/// milliseconds, in step with the default attack, so a chord's voices fade in under the reduced
+/// gain instead of spiking over it.
+const POLYPHONY_GAIN_SMOOTHING: f32 = 0.002;
+
+/// Soundfont samples are recorded near full scale, so they are brought down to sit with the
+/// oscillator voices.
+const SAMPLED_NOTE_GAIN: f32 = 0.3;
+
+/// The synthesized drums are about as loud as the oscillator voices at this gain.
+const DRUM_NOTE_GAIN: f32 = 0.3;
+
 // TODO: replace attack/decay with envelopes
 // TODO: legato polyphony
 
+/// Stacks several detuned oscillators in each voice, supersaw style.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Unison {
+    /// Oscillators per voice.
+    pub voices: usize,
+    /// Pitch distance in cents between the lowest and highest oscillator.
+    pub detune_cents: f32,
+    /// How far apart the oscillators are panned around the note's pan position, from 0.0 (all on
+    /// the same spot) to 1.0 (spread from hard left to hard right).
+    pub stereo_spread: f32,
+}
+
+impl Default for Unison {
+    fn default() -> Self {
+        Unison {
+            voices: 1,
+            detune_cents: 0.0,
+            stereo_spread: 0.0,
+        }
+    }
+}
+
+/// Key tracking is relative to middle C, so its cutoff is the same with or without tracking.
+const KEY_TRACKING_CENTER: u8 = 60;
+
+/// The low-pass filter that every voice runs through.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct VoiceFilter {
+    pub cutoff_hz: f32,
+    /// From 0.0 (no peak at the cutoff) to 1.0 (a sharp, ringing peak).
+    pub resonance: f32,
+    /// Each note's own envelope, which sweeps its cutoff. Takes effect on notes started after it is
+    /// set.
+    pub envelope: Adsr,
+    /// How many octaves the envelope raises the cutoff at its peak. Negative values sweep it down.
+    pub envelope_octaves: f32,
+    /// How much the cutoff follows the key, as octaves of cutoff per octave of pitch. At 1.0 every
+    /// note has the same brightness relative to its pitch.
+    pub key_tracking: f32,
+}
+
+impl Default for VoiceFilter {
+    fn default() -> Self {
+        VoiceFilter {
+            cutoff_hz: 1000.0,
+            resonance: 0.0,
+            envelope: Adsr::default(),
+            envelope_octaves: 0.0,
+            key_tracking: 0.0,
+        }
+    }
+}
+
+/// Caps on how many notes sound at once, to bound the work of rendering. When a new note would go
+/// over a cap, the oldest note in its way is cut off to make room, preferring notes that have
+/// already been released.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct VoiceLimits {
+    /// Across every channel.
+    pub total: Option<usize>,
+    /// For each MIDI channel on its own, counting from 0.
+    pub per_channel: [Option<usize>; NUM_MIDI_CHANNELS],
+}
+
+/// Where channel pressure (aftertouch) and polyphonic key pressure are routed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PressureDestination {
+    /// Pressure makes the note louder.
+    Amplitude,
+    /// Pressure raises the cutoff of the voice filter, making the note brighter.
+    FilterCutoff,
+}
+
+/// When notes start and end, for visualizers and practice tools that want to follow along without
+/// parsing MIDI themselves. Times are measured on the synthesizer's output, from the first rendered
+/// sample, so they line up with what was heard (to within one frame).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteEvent {
+    NoteStarted {
+        key: wmidi::Note,
+        velocity: u8,
+        channel: wmidi::Channel,
+        time: Duration,
+    },
+    /// The key was released, or the note decayed to silence while still held.
+    NoteEnded {
+        key: wmidi::Note,
+        channel: wmidi::Channel,
+        time: Duration,
+    },
+}
+
 pub struct Synthesizer {
     sample_hz: f32,
-    notes_playing: HashMap<wmidi::Note, SynthNote>,
-    filter: ExponentialSmoothing,
+    /// Samples per output channel rendered so far.
+    clock: SampleClock,
+    note_event_tx: Option<broadcast::Sender<NoteEvent>>,
+    pressure_destination: PressureDestination,
+    /// One voice per key, mixed in key order.
+    notes_playing: VoicePool<PlayingNote>,
+    voice_limits: VoiceLimits,
+    voice_filter: VoiceFilter,
+
+    channels: [ChannelState; NUM_MIDI_CHANNELS],
+    /// Set with `set_parameter`, and smoothed like the channels' controllers.
+    parameters: ParameterState,
+    /// Peak number of voices sounding at once on each channel, including released notes that are
+    /// still fading out.
+    peak_polyphony: [usize; NUM_MIDI_CHANNELS],
 
-    /// TODO: support multiple wave forms
-    wave: Wave,
+    /// TODO: support multiple sources
+    source: Source,
+    unison: Unison,
+    /// Seeds each new voice's noise generator.
+    rng: Rng,
+    /// Scales the mix down as more voices sound at once.
+    polyphony_gain: ExponentialSmoothing,
+    /// Left and right. Offsets from asymmetric waves and filters would otherwise end up in
+    /// recordings.
+    dc_blockers: [DcBlocker; 2],
+    /// Catches the peaks of loud chords, so the output never exceeds full scale.
+    limiter: Limiter,
+    /// Samples per frame, across all channels.
+    frame_size: usize,
+    /// Sample frames of the current frame that `render` has already rendered, and for how many
+    /// output channels.
+    frame_progress: usize,
+    render_channels: usize,
+    /// Sample frames per frame at the last rendered channel count.
+    frame_samples: usize,
+    /// Where each channel's voices are mixed, a block at a time. Boxed, since it's reused from
+    /// block to block rather than built on the stack each time.
+    channel_blocks: Box<[ChannelBlock; NUM_MIDI_CHANNELS]>,
+    /// Each voice's filter coefficients for the block, boxed for the same reason.
+    filter_coefficients: Box<SvfCoefficients>,
 }
 
 impl Synthesizer {
-    pub fn new(sample_hz: f32, wave: Wave) -> Self {
+    pub fn new(sample_hz: f32, source: Source) -> Self {
         Self {
             sample_hz,
-            notes_playing: HashMap::new(),
-            filter: ExponentialSmoothing::new(0.05),
-            wave,
+            clock: SampleClock::new(sample_hz.round() as u32),
+            note_event_tx: None,
+            pressure_destination: PressureDestination::Amplitude,
+            notes_playing: VoicePool::new(),
+            voice_limits: VoiceLimits::default(),
+            voice_filter: VoiceFilter::default(),
+            channels: [ChannelState::default(); NUM_MIDI_CHANNELS],
+            parameters: ParameterState::default(),
+            peak_polyphony: [0; NUM_MIDI_CHANNELS],
+            source,
+            unison: Unison::default(),
+            rng: Rng::new(0),
+            polyphony_gain: ExponentialSmoothing::with_initial_value(1.0, POLYPHONY_GAIN_SMOOTHING),
+            dc_blockers: [DcBlocker::new(sample_hz); 2],
+            limiter: Limiter::new(),
+            frame_size: FRAME_SIZE,
+            frame_progress: 0,
+            render_channels: 0,
+            frame_samples: FRAME_SIZE / 2,
+            channel_blocks: Box::new([ChannelBlock::default(); NUM_MIDI_CHANNELS]),
+            filter_coefficients: Box::default(),
         }
     }
 
     pub fn handle_midi_message(&mut self, (_timestamp, message): RawMidiMessage) {
-        // TODO: replace with midly::Event::read
-        let message = MidiMessage::try_from(&message[..]).expect("Failed to parse MIDI message.");
+        // Meta events are for the sequencer.
+        if message.is_meta() {
+            return;
+        }
+        let message = match MidiMessage::try_from(&message[..]) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Ignoring malformed MIDI message {:?}: {:?}", message, e);
+                return;
+            }
+        };
         match message {
-            MidiMessage::NoteOn(_, key, velocity) => {
+            MidiMessage::NoteOn(channel, key, velocity) => {
                 info!("NoteOn key = {} vel = {:?}", key, velocity);
                 if u8::from(velocity) == 0 {
-                    self.stop_key(key);
+                    self.stop_key(channel, key);
                 } else {
-                    self.start_note(key, velocity, self.wave);
+                    self.start_note(channel, key, velocity, self.source);
                 }
             }
-            MidiMessage::NoteOff(_, key, _) => {
+            MidiMessage::NoteOff(channel, key, _) => {
                 info!("NoteOff key = {}", key);
-                self.stop_key(key);
+                self.stop_key(channel, key);
+            }
+            MidiMessage::ChannelPressure(channel, pressure) => {
+                let channel = channel.index() as usize;
+                let pressure = u8::from(pressure) as f32 / 127.0;
+                for note in self.notes_playing.values_mut() {
+                    if note.channel() == channel {
+                        note.set_pressure_target(pressure);
+                    }
+                }
+            }
+            MidiMessage::PolyphonicKeyPressure(channel, key, pressure) => {
+                let voice_key = (channel.index() as usize, key);
+                if let Some(note) = self.notes_playing.get_mut(voice_key) {
+                    note.set_pressure_target(u8::from(pressure) as f32 / 127.0);
+                }
+            }
+            MidiMessage::ControlChange(channel, control, value) => {
+                self.handle_control_change(channel, u8::from(control), u8::from(value));
             }
-            MidiMessage::TimingClock => (),
-            other => {
-                trace!("unsupported MIDI message = {:?}", other);
-                self.notes_playing = HashMap::new();
+            MidiMessage::PitchBendChange(channel, bend) => {
+                // 14 bits, centered on 8192.
+                let bend = (u16::from(bend) as f32 - 8192.0) / 8192.0;
+                self.set_channel_pitch_bend(channel, bend * PITCH_BEND_RANGE_SEMITONES);
             }
+            MidiMessage::ProgramChange(channel, program) => {
+                let state = &mut self.channels[channel.index() as usize];
+                state.bank = state.pending_bank;
+                state.program = u8::from(program);
+            }
+            // The clock is for the sequencer and effects.
+            MidiMessage::TimingClock
+            | MidiMessage::Start
+            | MidiMessage::Continue
+            | MidiMessage::SysEx(_) => (),
+            // Whatever was playing when the sequencer stopped fades out as if let go.
+            MidiMessage::Stop => self.release_all_notes(),
+            other => trace!("unsupported MIDI message = {:?}", other),
         }
     }
 
-    pub fn sample_notes(&mut self, num_channels: usize) -> AudioFrame {
-        let mut frame = [0.0; FRAME_SIZE];
-        let samples_per_frame = FRAME_SIZE / num_channels;
-        let mut i = 0;
-        for _ in 0..samples_per_frame {
-            let mut mixed_notes_sample = 0.0;
-            for (_, note) in self.notes_playing.iter_mut() {
-                // TODO: scale down note sample generator instead of clipping
-                mixed_notes_sample += note.sample_table().min(1.0);
+    /// Starts a note without building a MIDI message. `channel` counts from 0, and a velocity of 0
+    /// stops the note, as in MIDI.
+    pub fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        let message = [0x90 | (channel & 0x0f), key & 0x7f, velocity & 0x7f];
+        self.handle_midi_message((0, message.into()));
+    }
+
+    pub fn note_off(&mut self, channel: u8, key: u8) {
+        self.handle_midi_message((0, [0x80 | (channel & 0x0f), key & 0x7f, 0].into()));
+    }
+
+    /// Publishes a `NoteEvent` on `tx` whenever a note starts or ends. Nothing is sent if there are
+    /// no receivers.
+    pub fn set_note_event_sender(&mut self, tx: broadcast::Sender<NoteEvent>) {
+        self.note_event_tx = Some(tx);
+    }
+
+    /// The output time of the next sample to be rendered.
+    pub fn time(&self) -> Duration {
+        self.clock.time()
+    }
+
+    /// Where the next frame starts on the output timeline.
+    pub fn clock(&self) -> SampleClock {
+        self.clock
+    }
+
+    /// Carries on at a new output rate, as when the device is reconfigured mid-session. Playing
+    /// notes keep their pitch, and the output time stays continuous.
+    pub fn set_sample_hz(&mut self, sample_hz: f32) {
+        if sample_hz == self.sample_hz {
+            return;
+        }
+        info!(
+            "Synthesizer sample rate changed from {} to {}",
+            self.sample_hz, sample_hz
+        );
+        self.sample_hz = sample_hz;
+        self.clock.set_sample_hz(sample_hz.round() as u32);
+        self.dc_blockers = [DcBlocker::new(sample_hz); 2];
+        let num_channels = self.limiter.num_channels();
+        if num_channels > 0 {
+            self.limiter.prepare(sample_hz, num_channels);
+        }
+        for note in self.notes_playing.values_mut() {
+            note.set_sample_hz(sample_hz);
+        }
+    }
+
+    pub fn sample_hz(&self) -> f32 {
+        self.sample_hz
+    }
+
+    /// Peak number of voices sounding at once on each MIDI channel so far.
+    pub fn peak_polyphony(&self) -> [usize; NUM_MIDI_CHANNELS] {
+        self.peak_polyphony
+    }
+
+    /// How many samples, across all channels, `sample_notes` renders at a time. Messages take
+    /// effect between frames, so shorter frames are more responsive, and longer ones are cheaper.
+    pub fn set_frame_size(&mut self, frame_size: usize) {
+        self.frame_size = frame_size.clamp(MIN_FRAME_SIZE, MAX_FRAME_SIZE);
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    /// Applies to notes started from now on. Notes already over a new limit keep playing.
+    pub fn set_voice_limits(&mut self, limits: VoiceLimits) {
+        self.voice_limits = limits;
+    }
+
+    /// Restarts the noise and drum voices' random numbers from `seed`. Two synthesizers with the
+    /// same seed, source and messages produce the same samples.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Applies to every note, including those already playing.
+    pub fn set_voice_filter(&mut self, filter: VoiceFilter) {
+        self.voice_filter = filter;
+    }
+
+    pub fn set_pressure_destination(&mut self, destination: PressureDestination) {
+        self.pressure_destination = destination;
+    }
+
+    /// Applies to notes started from now on.
+    pub fn set_unison(&mut self, unison: Unison) {
+        self.unison = Unison {
+            voices: unison.voices.max(1),
+            ..unison
+        };
+    }
+
+    /// Sets the stereo position for notes started on `channel` from now on. `pan` ranges from -1.0
+    /// (hard left) to 1.0 (hard right).
+    pub fn set_channel_pan(&mut self, channel: wmidi::Channel, pan: f32) {
+        self.channels[channel.index() as usize].pan = pan.clamp(-1.0, 1.0);
+    }
+
+    /// Sets the channel volume (CC7) in [0.0, 1.0].
+    pub fn set_channel_volume(&mut self, channel: wmidi::Channel, volume: f32) {
+        self.channels[channel.index() as usize].volume = volume.clamp(0.0, 1.0);
+    }
+
+    /// Moves the voice filter cutoff for notes on `channel` (CC74). `brightness` ranges from -1.0 to
+    /// 1.0, and 0.0 leaves the cutoff alone.
+    pub fn set_channel_brightness(&mut self, channel: wmidi::Channel, brightness: f32) {
+        self.channels[channel.index() as usize].brightness = brightness.clamp(-1.0, 1.0);
+    }
+
+    /// Adds to the voice filter resonance for notes on `channel` (CC71). `resonance` ranges from
+    /// -1.0 to 1.0, and 0.0 leaves the resonance alone.
+    pub fn set_channel_resonance(&mut self, channel: wmidi::Channel, resonance: f32) {
+        self.channels[channel.index() as usize].resonance = resonance.clamp(-1.0, 1.0);
+    }
+
+    /// Sets one of the synth's own parameters, from 0.0 to 1.0, as a controller bound to it does.
+    /// Effect parameters are left for the effects.
+    pub fn set_parameter(&mut self, parameter: SynthParameter, value: f32) {
+        // NaN would stay in the smoothing for good.
+        if !value.is_finite() {
+            return;
+        }
+        let value = value.clamp(0.0, 1.0);
+        match parameter {
+            SynthParameter::Cutoff => self.parameters.brightness = 2.0 * value - 1.0,
+            SynthParameter::Resonance => self.parameters.resonance = 2.0 * value - 1.0,
+            // Squared, so the level follows the control more like loudness does.
+            SynthParameter::Gain => self.parameters.gain = value * value,
+            SynthParameter::ChorusRate | SynthParameter::ChorusMix => (),
+        }
+    }
+
+    /// Sets the channel expression (CC11) in [0.0, 1.0].
+    pub fn set_channel_expression(&mut self, channel: wmidi::Channel, expression: f32) {
+        self.channels[channel.index() as usize].expression = expression.clamp(0.0, 1.0);
+    }
+
+    /// Bends every note on the channel by `semitones`, including notes started later, until the
+    /// next bend.
+    fn set_channel_pitch_bend(&mut self, channel: wmidi::Channel, semitones: f32) {
+        let channel = channel.index() as usize;
+        self.channels[channel].pitch_bend = semitones;
+        let ratio = self.channels[channel].pitch_ratio();
+        for note in self.notes_playing.values_mut() {
+            if note.channel() == channel {
+                note.set_pitch_ratio(ratio, self.sample_hz);
             }
-            let filtered_sample = self.filter.apply(mixed_notes_sample);
+        }
+    }
+
+    /// Puts the channel's controllers and pitch bend back where a channel starts. Unlike General
+    /// MIDI's recommendation, volume and pan are reset too, so a sequencer that seeks back to
+    /// before a fade hears the file as it was there. The program and bank are left alone.
+    fn reset_channel_controllers(&mut self, channel: wmidi::Channel) {
+        let state = &mut self.channels[channel.index() as usize];
+        let default = ChannelState::default();
+        state.pan = default.pan;
+        state.volume = default.volume;
+        state.expression = default.expression;
+        state.brightness = default.brightness;
+        state.resonance = default.resonance;
+        self.set_channel_pitch_bend(channel, default.pitch_bend);
+    }
 
-            for _ in 0..num_channels {
-                frame[i] = filtered_sample;
-                i += 1;
+    fn handle_control_change(&mut self, channel: wmidi::Channel, control: u8, value: u8) {
+        match control {
+            // Takes effect at the next program change, as General MIDI asks.
+            CC_BANK_SELECT => self.channels[channel.index() as usize].pending_bank = value as u16,
+            CC_VOLUME => self.set_channel_volume(channel, value as f32 / 127.0),
+            CC_EXPRESSION => self.set_channel_expression(channel, value as f32 / 127.0),
+            // Both are centered on 64, like pan.
+            CC_BRIGHTNESS => self.set_channel_brightness(channel, (value as f32 - 64.0) / 63.0),
+            CC_RESONANCE => self.set_channel_resonance(channel, (value as f32 - 64.0) / 63.0),
+            CC_PAN => {
+                // 64 is center; 0 and 127 are hard left and right.
+                self.set_channel_pan(channel, (value as f32 - 64.0) / 63.0);
+            }
+            CC_RESET_ALL_CONTROLLERS => self.reset_channel_controllers(channel),
+            CC_ALL_NOTES_OFF => {
+                let time = self.clock.time();
+                for (voice_key, n) in self.notes_playing.iter_mut() {
+                    if voice_key.0 == channel.index() as usize && !n.stop_requested() {
+                        n.request_stop();
+                        Self::send_note_ended(&self.note_event_tx, time, voice_key, n);
+                    }
+                }
             }
+            other => trace!("unsupported MIDI controller = {}", other),
         }
+    }
 
-        let mut remove_keys = vec![];
-        for (key, note) in self.notes_playing.iter_mut() {
-            note.update_after_sample();
-            if note.is_done_playing() {
-                remove_keys.push(*key);
+    /// Fills `buffer` with interleaved samples for `num_channels` channels, for callers with their
+    /// own buffer sizes. Nothing is rendered ahead, so a MIDI message handled between two calls
+    /// takes effect on the first sample of the second. Voices still age a frame at a time, so the
+    /// output is the same however it is split up.
+    pub fn render(&mut self, buffer: &mut [f32], num_channels: usize) {
+        if num_channels != self.render_channels {
+            self.frame_progress = 0;
+            self.render_channels = num_channels;
+        }
+
+        let samples_per_frame = self.frame_size / num_channels;
+        let mut filled = 0;
+        while buffer.len() - filled >= num_channels {
+            let n = (samples_per_frame - self.frame_progress)
+                .min((buffer.len() - filled) / num_channels);
+            let end = filled + n * num_channels;
+            self.render_span(&mut buffer[filled..end], num_channels);
+            self.frame_progress += n;
+            if self.frame_progress >= samples_per_frame {
+                self.end_frame();
+                self.frame_progress = 0;
             }
+            filled = end;
         }
-        for key in remove_keys {
-            self.notes_playing.remove(&key);
+        // Less than a sample frame is left over.
+        buffer[filled..].fill(0.0);
+    }
+
+    /// Like `render`, with each of `messages` handled at its offset into `buffer`, counted in
+    /// sample frames, as a plugin host hands over a block's events. Messages must be in offset
+    /// order, and those at or past the end of `buffer` are handled after it is filled.
+    pub fn render_with_messages(
+        &mut self,
+        buffer: &mut [f32],
+        num_channels: usize,
+        messages: &[(usize, RawMidiMessage)],
+    ) {
+        let mut filled = 0;
+        for (offset, message) in messages {
+            let at = (offset * num_channels).clamp(filled, buffer.len());
+            self.render(&mut buffer[filled..at], num_channels);
+            filled = at;
+            self.handle_midi_message(message.clone());
         }
+        self.render(&mut buffer[filled..], num_channels);
+    }
+
+    /// Renders one interleaved frame, of as many whole sample frames as fit in the frame size.
+    /// With two or more output channels, even channels carry the left mix and odd channels carry
+    /// the right mix. A single output channel gets the mono downmix.
+    pub fn sample_notes(&mut self, num_channels: usize) -> AudioFrame {
+        let samples_per_frame = self.frame_size / num_channels;
+        let mut frame = vec![0.0; samples_per_frame * num_channels];
+        self.render_span(&mut frame, num_channels);
+        self.end_frame();
 
         frame
     }
 
-    fn start_note(&mut self, key: wmidi::Note, velocity: wmidi::U7, wave: Wave) {
-        self.notes_playing.insert(
+    /// Renders as many sample frames as fit in `frame`, which holds at most one frame's worth.
+    fn render_span(&mut self, frame: &mut [f32], num_channels: usize) {
+        self.frame_samples = (self.frame_size / num_channels).max(1);
+        let samples_per_frame = frame.len() / num_channels;
+        let destination = self.pressure_destination;
+        let voice_filter = self.voice_filter;
+        let sample_hz = self.sample_hz;
+        let polyphony_target = (self.notes_playing.len().max(1) as f32).sqrt().recip();
+        let mut i = 0;
+        for block_start in (0..samples_per_frame).step_by(MIX_BLOCK) {
+            let block_len = MIX_BLOCK.min(samples_per_frame - block_start);
+
+            // A silent channel whose smoothing has settled adds nothing to the mix, so only the
+            // rest are mixed.
+            let mut sounding = [false; NUM_MIDI_CHANNELS];
+            for note in self.notes_playing.values() {
+                sounding[note.channel()] = true;
+            }
+            let mut live_channels = [0; NUM_MIDI_CHANNELS];
+            let mut num_live = 0;
+            for (c, state) in self.channels.iter().enumerate() {
+                if sounding[c] || !state.is_settled() {
+                    live_channels[num_live] = c;
+                    num_live += 1;
+                }
+            }
+            let live_channels = &live_channels[..num_live];
+
+            // Sample by sample across the channels, since each channel's smoothing depends on its
+            // previous sample.
+            for s in 0..block_len {
+                let parameters = &mut self.parameters;
+                let brightness = parameters.smoothed_brightness.apply(parameters.brightness);
+                let resonance = parameters.smoothed_resonance.apply(parameters.resonance);
+                for &c in live_channels {
+                    let state = &mut self.channels[c];
+                    self.channel_blocks[c].offsets[s] = FilterOffset {
+                        octaves: (state.smoothed_brightness.apply(state.brightness) + brightness)
+                            * BRIGHTNESS_OCTAVES,
+                        resonance: state.smoothed_resonance.apply(state.resonance) + resonance,
+                    };
+                }
+            }
+            for &c in live_channels {
+                let block = &mut self.channel_blocks[c];
+                block.left[..block_len].fill(0.0);
+                block.right[..block_len].fill(0.0);
+            }
+
+            for note in self.notes_playing.values_mut() {
+                note.sample_block(
+                    destination,
+                    voice_filter,
+                    sample_hz,
+                    &mut self.filter_coefficients,
+                    &mut self.channel_blocks[note.channel()],
+                    block_len,
+                );
+            }
+
+            for s in 0..block_len {
+                // Channel gain is smoothed, until it settles, even when no notes are playing so it
+                // never jumps.
+                let mut left = 0.0;
+                let mut right = 0.0;
+                for &c in live_channels {
+                    let state = &mut self.channels[c];
+                    let block = &self.channel_blocks[c];
+                    let gain = state.gain.apply(state.target_gain());
+                    left += gain * block.left[s];
+                    right += gain * block.right[s];
+                }
+                // Uncorrelated voices add up in power, so dividing by the square root of their
+                // number keeps a chord about as loud as a single note, which plays at full level.
+                let polyphony_gain = self.polyphony_gain.apply(polyphony_target)
+                    * self.parameters.smoothed_gain.apply(self.parameters.gain);
+                let left = self.dc_blockers[0].apply(polyphony_gain * left);
+                let right = self.dc_blockers[1].apply(polyphony_gain * right);
+
+                if num_channels == 1 {
+                    frame[i] = FRAC_1_SQRT_2 * (left + right);
+                    i += 1;
+                } else {
+                    for c in 0..num_channels {
+                        frame[i] = if c % 2 == 0 { left } else { right };
+                        i += 1;
+                    }
+                }
+            }
+        }
+
+        if self.limiter.num_channels() != num_channels {
+            self.limiter.prepare(sample_hz, num_channels);
+        }
+        self.limiter.process_samples(frame);
+
+        self.clock.advance(samples_per_frame as u64);
+    }
+
+    /// Lets go of the voices that have finished.
+    fn end_frame(&mut self) {
+        while let Some((voice_key, note)) = self
+            .notes_playing
+            .pop_first_where(|note| note.is_done_playing())
+        {
+            if !note.stop_requested() {
+                Self::send_note_ended(&self.note_event_tx, self.clock.time(), voice_key, &note);
+            }
+        }
+    }
+
+    fn start_note(
+        &mut self,
+        channel: wmidi::Channel,
+        key: wmidi::Note,
+        velocity: wmidi::U7,
+        source: Source,
+    ) {
+        // Retriggering a key held on the same channel replaces its note.
+        let voice_key = (channel.index() as usize, key);
+        if let Some(old) = self.notes_playing.get(voice_key) {
+            if !old.stop_requested() {
+                Self::send_note_ended(&self.note_event_tx, self.clock.time(), voice_key, old);
+            }
+        }
+        self.send_note_event(NoteEvent::NoteStarted {
             key,
-            SynthNote {
-                wave,
-                table_index: WaveTableIndex::from_hz(self.sample_hz, get_midi_key_hz(key)),
-                stop_requested: false,
-                off_decay_factor: 1.0,
-                online_decay_factor: 1.0,
-                attack_factor: 0.0,
-                velocity: u8::from(velocity) as f32 / 100.0,
-            },
-        );
+            velocity: u8::from(velocity),
+            channel,
+            time: self.time(),
+        });
+
+        let channel = channel.index() as usize;
+        self.steal_voices(voice_key);
+        let (source, patch) = match source {
+            Source::Patch(loaded) => (loaded.source, Some(loaded)),
+            Source::PatchBank(bank) => {
+                let loaded = bank.patch(self.channels[channel].program);
+                (loaded.source, Some(loaded))
+            }
+            other => (other, None),
+        };
+        let mut note = match source {
+            Source::SoundFont(font) => {
+                let state = &self.channels[channel];
+                let bank = if channel == DRUM_CHANNEL {
+                    DRUM_BANK
+                } else {
+                    state.bank
+                };
+                let voices = font.voices(
+                    bank,
+                    state.program,
+                    u8::from(key),
+                    u8::from(velocity),
+                    self.sample_hz,
+                );
+                PlayingNote::Sampled(SampledNote::new(channel, voices, state.pan, velocity))
+            }
+            // Without a soundfont, General MIDI percussion is synthesized.
+            _ if channel == DRUM_CHANNEL => {
+                let voice = DrumVoice::new(u8::from(key), self.sample_hz, self.rng.next_u32());
+                PlayingNote::Drum(DrumNote::new(
+                    channel,
+                    voice,
+                    self.channels[channel].pan,
+                    velocity,
+                ))
+            }
+            _ => PlayingNote::Synth(self.new_synth_note(channel, key, velocity, source, patch)),
+        };
+        note.set_pitch_ratio(self.channels[channel].pitch_ratio(), self.sample_hz);
+        self.notes_playing
+            .insert(voice_key, note, self.clock.position());
+
+        let sounding = self
+            .notes_playing
+            .values()
+            .filter(|n| n.channel() == channel)
+            .count();
+        let peak = &mut self.peak_polyphony[channel];
+        *peak = (*peak).max(sounding);
+    }
+
+    fn new_synth_note(
+        &mut self,
+        channel: usize,
+        key: wmidi::Note,
+        velocity: wmidi::U7,
+        source: Source,
+        patch: Option<&'static LoadedPatch>,
+    ) -> SynthNote {
+        let hz = get_midi_key_hz(key);
+        let pan = self.channels[channel].pan;
+        let Unison {
+            voices,
+            detune_cents,
+            stereo_spread,
+        } = patch.map_or(self.unison, |p| p.patch.oscillator.unison);
+        let voices = voices.max(1);
+        let filter = patch.map(|p| p.patch.filter);
+        let initial_filter = filter.unwrap_or(self.voice_filter);
+        let oscillators = (0..voices)
+            .map(|i| {
+                // Spread evenly over [-1.0, 1.0].
+                let position = if voices > 1 {
+                    2.0 * i as f32 / (voices - 1) as f32 - 1.0
+                } else {
+                    0.0
+                };
+                let detuned_hz = hz * (position * 0.5 * detune_cents / 1200.0).exp2();
+                let (left_gain, right_gain) =
+                    equal_power_pan((pan + position * stereo_spread).clamp(-1.0, 1.0));
+                // Stagger the phases so the oscillators don't all peak together on the attack.
+                let phase = i as f32 / voices as f32;
+
+                UnisonOscillator {
+                    hz: detuned_hz,
+                    oscillator: Oscillator::new(
+                        source,
+                        self.sample_hz,
+                        detuned_hz,
+                        phase,
+                        self.rng.next_u32(),
+                    ),
+                    left_gain,
+                    right_gain,
+                }
+            })
+            .collect();
+
+        SynthNote {
+            channel,
+            oscillators,
+            pitch_ratio: 1.0,
+            // Detuned oscillators are uncorrelated, so they sum by power.
+            unison_gain: (voices as f32).sqrt().recip(),
+            stop_requested: false,
+            off_decay_factor: 1.0,
+            online_decay_factor: 1.0,
+            attack_factor: 0.0,
+            frame_samples: self.frame_samples,
+            age_samples: 0,
+            velocity: u8::from(velocity) as f32 / 100.0,
+            pressure_target: 0.0,
+            pressure: ExponentialSmoothing::new(PRESSURE_SMOOTHING),
+            key_octaves: (u8::from(key) as f32 - KEY_TRACKING_CENTER as f32) / 12.0,
+            amp_envelope: patch
+                .and_then(|p| p.patch.envelope)
+                .map(|adsr| Envelope::new(adsr, self.sample_hz)),
+            filter,
+            filter_envelope: Envelope::new(initial_filter.envelope, self.sample_hz),
+            low_pass: ResonantLowPass::new(),
+        }
     }
 
-    fn stop_key(&mut self, key: wmidi::Note) {
-        if let Some(n) = self.notes_playing.get_mut(&key) {
-            n.stop_requested = true;
+    /// Cuts off notes until one more can start on `voice_key` within the voice limits. A note
+    /// already on `voice_key` doesn't count, since the new note replaces it.
+    fn steal_voices(&mut self, voice_key: VoiceKey) {
+        let (channel, _) = voice_key;
+        let limits = [
+            (None, self.voice_limits.total),
+            (Some(channel), self.voice_limits.per_channel[channel]),
+        ];
+        for (only_channel, limit) in limits.iter() {
+            let limit = match limit {
+                Some(limit) => (*limit).max(1),
+                None => continue,
+            };
+            loop {
+                let candidates = self.notes_playing.iter().filter(|(k, n)| {
+                    *k != voice_key && only_channel.map_or(true, |c| n.channel() == c)
+                });
+                if candidates.clone().count() < limit {
+                    break;
+                }
+                // Released notes go first, oldest first.
+                let victim = candidates
+                    .min_by_key(|(k, n)| (!n.stop_requested(), self.notes_playing.start(*k)))
+                    .map(|(k, _)| k);
+                let victim = match victim {
+                    Some(victim) => victim,
+                    None => break,
+                };
+                trace!("Stealing the voice of {} on channel {}", victim.1, victim.0);
+                if let Some(note) = self.notes_playing.remove(victim) {
+                    if !note.stop_requested() {
+                        Self::send_note_ended(
+                            &self.note_event_tx,
+                            self.clock.time(),
+                            victim,
+                            &note,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn stop_key(&mut self, channel: wmidi::Channel, key: wmidi::Note) {
+        let voice_key = (channel.index() as usize, key);
+        if let Some(n) = self.notes_playing.get_mut(voice_key) {
+            if !n.stop_requested() {
+                n.request_stop();
+                Self::send_note_ended(&self.note_event_tx, self.clock.time(), voice_key, n);
+            }
+        }
+    }
+
+    fn release_all_notes(&mut self) {
+        let time = self.clock.time();
+        for (voice_key, n) in self.notes_playing.iter_mut() {
+            if !n.stop_requested() {
+                n.request_stop();
+                Self::send_note_ended(&self.note_event_tx, time, voice_key, n);
+            }
+        }
+    }
+
+    fn send_note_event(&self, event: NoteEvent) {
+        if let Some(tx) = &self.note_event_tx {
+            // Only fails when nobody is listening.
+            let _ = tx.send(event);
+        }
+    }
+
+    /// Takes the fields it needs separately so it can be called while a note is borrowed.
+    fn send_note_ended(
+        note_event_tx: &Option<broadcast::Sender<NoteEvent>>,
+        time: Duration,
+        (_, key): VoiceKey,
+        note: &PlayingNote,
+    ) {
+        if let Some(tx) = note_event_tx {
+            let _ = tx.send(NoteEvent::NoteEnded {
+                key,
+                channel: note.midi_channel(),
+                time,
+            });
+        }
+    }
+}
+
+/// One channel's share of a block: its filter offset for each sample, and its voices' mix.
+#[derive(Clone, Copy)]
+struct ChannelBlock {
+    offsets: [FilterOffset; MIX_BLOCK],
+    left: [f32; MIX_BLOCK],
+    right: [f32; MIX_BLOCK],
+}
+
+impl Default for ChannelBlock {
+    fn default() -> Self {
+        ChannelBlock {
+            offsets: [FilterOffset::default(); MIX_BLOCK],
+            left: [0.0; MIX_BLOCK],
+            right: [0.0; MIX_BLOCK],
         }
     }
 }
 
+#[derive(Clone, Copy)]
+struct ChannelState {
+    /// Stereo position in [-1.0, 1.0], assigned to notes when they start.
+    pan: f32,
+    volume: f32,
+    expression: f32,
+    gain: ExponentialSmoothing,
+    /// Offsets to the voice filter, in [-1.0, 1.0].
+    brightness: f32,
+    resonance: f32,
+    smoothed_brightness: ExponentialSmoothing,
+    smoothed_resonance: ExponentialSmoothing,
+    /// The soundfont preset or bank patch for notes started from now on.
+    bank: u16,
+    program: u8,
+    /// From the last bank select, waiting for a program change.
+    pending_bank: u16,
+    /// In semitones.
+    pitch_bend: f32,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        // Full volume by default, so files that never send CC7 play at unity gain.
+        ChannelState {
+            pan: 0.0,
+            volume: 1.0,
+            expression: 1.0,
+            gain: ExponentialSmoothing::with_initial_value(1.0, CHANNEL_GAIN_SMOOTHING),
+            brightness: 0.0,
+            resonance: 0.0,
+            smoothed_brightness: ExponentialSmoothing::new(CHANNEL_FILTER_SMOOTHING),
+            smoothed_resonance: ExponentialSmoothing::new(CHANNEL_FILTER_SMOOTHING),
+            bank: 0,
+            program: 0,
+            pending_bank: 0,
+            pitch_bend: 0.0,
+        }
+    }
+}
+
+impl ChannelState {
+    /// Volume and expression both follow the squared curve recommended by General MIDI.
+    fn target_gain(&self) -> f32 {
+        let v = self.volume * self.expression;
+
+        v * v
+    }
+
+    /// Whether the channel's gain and filter offset have stopped moving.
+    fn is_settled(&self) -> bool {
+        self.gain.is_settled(self.target_gain())
+            && self.smoothed_brightness.is_settled(self.brightness)
+            && self.smoothed_resonance.is_settled(self.resonance)
+    }
+
+    /// How much the pitch bend scales the frequency of the channel's notes.
+    fn pitch_ratio(&self) -> f32 {
+        (self.pitch_bend / 12.0).exp2()
+    }
+}
+
+/// A note on one of the kinds of voice.
+enum PlayingNote {
+    Synth(SynthNote),
+    Sampled(SampledNote),
+    Drum(DrumNote),
+}
+
+impl PlayingNote {
+    fn channel(&self) -> usize {
+        match self {
+            PlayingNote::Synth(n) => n.channel,
+            PlayingNote::Sampled(n) => n.channel,
+            PlayingNote::Drum(n) => n.channel,
+        }
+    }
+
+    fn midi_channel(&self) -> wmidi::Channel {
+        wmidi::Channel::from_index(self.channel() as u8).expect("Invalid MIDI channel")
+    }
+
+    fn stop_requested(&self) -> bool {
+        match self {
+            PlayingNote::Synth(n) => n.stop_requested,
+            PlayingNote::Sampled(n) => n.stop_requested,
+            PlayingNote::Drum(n) => n.stop_requested,
+        }
+    }
+
+    fn request_stop(&mut self) {
+        match self {
+            PlayingNote::Synth(n) => n.stop_requested = true,
+            PlayingNote::Sampled(n) => n.release(),
+            // Drums ring out regardless.
+            PlayingNote::Drum(n) => n.stop_requested = true,
+        }
+    }
+
+    fn set_pressure_target(&mut self, pressure: f32) {
+        match self {
+            PlayingNote::Synth(n) => n.pressure_target = pressure,
+            PlayingNote::Sampled(n) => n.pressure_target = pressure,
+            PlayingNote::Drum(_) => (),
+        }
+    }
+
+    /// Adds the note's next `len` samples to its channel's `block`, filtered with the block's
+    /// offsets. `coefficients` is scratch space for the note's filter.
+    fn sample_block(
+        &mut self,
+        destination: PressureDestination,
+        voice_filter: VoiceFilter,
+        sample_hz: f32,
+        coefficients: &mut SvfCoefficients,
+        block: &mut ChannelBlock,
+        len: usize,
+    ) {
+        let (left, right) = (&mut block.left[..len], &mut block.right[..len]);
+        match self {
+            PlayingNote::Synth(n) => n.sample_block(
+                destination,
+                voice_filter,
+                sample_hz,
+                coefficients,
+                block,
+                len,
+            ),
+            PlayingNote::Sampled(n) => n.sample_block(destination, left, right),
+            PlayingNote::Drum(n) => {
+                for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+                    let (note_left, note_right) = n.sample();
+                    *l += note_left;
+                    *r += note_right;
+                }
+            }
+        }
+    }
+
+    fn set_pitch_ratio(&mut self, ratio: f32, sample_hz: f32) {
+        match self {
+            PlayingNote::Synth(n) => {
+                n.pitch_ratio = ratio;
+                for osc in n.oscillators.iter_mut() {
+                    osc.oscillator.set_frequency(sample_hz, osc.hz * ratio);
+                }
+            }
+            PlayingNote::Sampled(n) => {
+                for v in n.voices.iter_mut() {
+                    v.voice.set_pitch_ratio(ratio);
+                }
+            }
+            // Drums keep their tuning, as on General MIDI synths.
+            PlayingNote::Drum(_) => (),
+        }
+    }
+
+    fn set_sample_hz(&mut self, sample_hz: f32) {
+        match self {
+            PlayingNote::Synth(n) => {
+                for osc in n.oscillators.iter_mut() {
+                    osc.oscillator
+                        .set_frequency(sample_hz, osc.hz * n.pitch_ratio);
+                }
+                n.filter_envelope.set_sample_hz(sample_hz);
+                if let Some(envelope) = n.amp_envelope.as_mut() {
+                    envelope.set_sample_hz(sample_hz);
+                }
+            }
+            PlayingNote::Sampled(n) => {
+                for v in n.voices.iter_mut() {
+                    v.voice.set_sample_hz(sample_hz);
+                }
+            }
+            PlayingNote::Drum(n) => n.voice.set_sample_hz(sample_hz),
+        }
+    }
+
+    fn is_done_playing(&self) -> bool {
+        match self {
+            PlayingNote::Synth(n) => n.is_done_playing(),
+            PlayingNote::Sampled(n) => n.is_done_playing(),
+            PlayingNote::Drum(n) => n.voice.is_done(),
+        }
+    }
+}
+
+struct UnisonOscillator {
+    hz: f32,
+    oscillator: Oscillator,
+    left_gain: f32,
+    right_gain: f32,
+}
+
 struct SynthNote {
-    wave: Wave,
-    table_index: WaveTableIndex,
+    channel: usize,
+    oscillators: Vec<UnisonOscillator>,
+    /// From the channel's pitch bend, scaling every oscillator's `hz`.
+    pitch_ratio: f32,
+    unison_gain: f32,
     attack_factor: f32,
     off_decay_factor: f32,
     online_decay_factor: f32,
+    /// The attack and decay factors step once every this many samples, counted from the note's
+    /// start, so the note sounds the same wherever it starts in a frame.
+    frame_samples: usize,
+    age_samples: usize,
     velocity: f32,
     stop_requested: bool,
+
+    /// Latest channel or key pressure in [0.0, 1.0]. The modulation follows it smoothly.
+    pressure_target: f32,
+    pressure: ExponentialSmoothing,
+
+    /// From the note's patch. Replaces the attack and decay factors.
+    amp_envelope: Option<Envelope>,
+    /// From the note's patch. Without one the note follows the synthesizer's voice filter.
+    filter: Option<VoiceFilter>,
+    /// Octaves from the key tracking center.
+    key_octaves: f32,
+    filter_envelope: Envelope,
+    low_pass: ResonantLowPass,
+}
+
+/// The parameters that apply to every channel, like a channel's controllers do.
+struct ParameterState {
+    /// Like `ChannelState::brightness`.
+    brightness: f32,
+    resonance: f32,
+    gain: f32,
+    smoothed_brightness: ExponentialSmoothing,
+    smoothed_resonance: ExponentialSmoothing,
+    smoothed_gain: ExponentialSmoothing,
+}
+
+impl Default for ParameterState {
+    fn default() -> Self {
+        ParameterState {
+            brightness: 0.0,
+            resonance: 0.0,
+            gain: 1.0,
+            smoothed_brightness: ExponentialSmoothing::new(CHANNEL_FILTER_SMOOTHING),
+            smoothed_resonance: ExponentialSmoothing::new(CHANNEL_FILTER_SMOOTHING),
+            smoothed_gain: ExponentialSmoothing::with_initial_value(1.0, CHANNEL_GAIN_SMOOTHING),
+        }
+    }
+}
+
+/// How a channel's brightness and resonance controllers move the voice filter.
+#[derive(Clone, Copy, Default)]
+struct FilterOffset {
+    octaves: f32,
+    resonance: f32,
+}
+
+/// Equal-power pan law: the left and right gains always have a combined power of 1, so a note
+/// keeps the same loudness as it moves across the stereo field.
+fn equal_power_pan(pan: f32) -> (f32, f32) {
+    let angle = (pan + 1.0) * FRAC_PI_4;
+
+    (angle.cos(), angle.sin())
 }
 
 impl SynthNote {
-    fn amplitude(&self) -> f32 {
-        0.2 * self.attack_factor * self.online_decay_factor * self.off_decay_factor * self.velocity
+    /// Fills `amplitudes` with the note's level for each sample of a block, and ages the note by
+    /// the block.
+    fn fill_amplitudes(&mut self, amplitudes: &mut [f32]) {
+        match self.amp_envelope.as_mut() {
+            Some(envelope) => {
+                if self.stop_requested {
+                    envelope.release();
+                }
+                envelope.fill_levels(amplitudes);
+                self.age_by(amplitudes.len());
+            }
+            // The factors move once a frame, which can fall in the middle of the block.
+            None => {
+                for amplitude in amplitudes.iter_mut() {
+                    *amplitude =
+                        self.attack_factor * self.online_decay_factor * self.off_decay_factor;
+                    self.age_by(1);
+                }
+            }
+        }
+        let gain = 0.2 * self.velocity;
+        for amplitude in amplitudes.iter_mut() {
+            *amplitude *= gain;
+        }
     }
 
-    fn sample_table(&mut self) -> f32 {
-        self.amplitude() * self.table_index.sample_table(self.wave)
+    fn age_by(&mut self, samples: usize) {
+        let frame_samples = self.frame_samples.max(1);
+        self.age_samples += samples;
+        while self.age_samples >= frame_samples {
+            self.age_samples -= frame_samples;
+            self.update_after_frame();
+        }
+    }
+
+    /// Like `PlayingNote::sample_block`. Each stage runs over the whole block before the next: the
+    /// oscillators, then the envelopes, pressure and filter coefficients, and last the filters,
+    /// which depend on the previous sample and so go sample by sample. Apart from the smoothing
+    /// and the filters, the stages are plain loops over the block, which the compiler vectorizes.
+    fn sample_block(
+        &mut self,
+        destination: PressureDestination,
+        voice_filter: VoiceFilter,
+        sample_hz: f32,
+        coefficients: &mut SvfCoefficients,
+        block: &mut ChannelBlock,
+        len: usize,
+    ) {
+        let mut osc_lefts = [0.0; MIX_BLOCK];
+        let mut osc_rights = [0.0; MIX_BLOCK];
+        let mut samples = [0.0; MIX_BLOCK];
+        for osc in self.oscillators.iter_mut() {
+            osc.oscillator.sample_block(&mut samples[..len]);
+            for ((l, r), sample) in osc_lefts[..len]
+                .iter_mut()
+                .zip(osc_rights[..len].iter_mut())
+                .zip(samples[..len].iter())
+            {
+                *l += osc.left_gain * sample;
+                *r += osc.right_gain * sample;
+            }
+        }
+
+        let mut pressures = [0.0; MIX_BLOCK];
+        for pressure in pressures[..len].iter_mut() {
+            *pressure = self.pressure.apply(self.pressure_target);
+        }
+        let (amplitude_depth, cutoff_depth) = match destination {
+            PressureDestination::Amplitude => (PRESSURE_AMPLITUDE_DEPTH, 0.0),
+            PressureDestination::FilterCutoff => (0.0, PRESSURE_CUTOFF_OCTAVES),
+        };
+
+        let mut gains = [0.0; MIX_BLOCK];
+        self.fill_amplitudes(&mut gains[..len]);
+        let unison_gain = self.unison_gain;
+        for ((gain, pressure), (l, r)) in gains[..len].iter().zip(pressures[..len].iter()).zip(
+            osc_lefts[..len]
+                .iter_mut()
+                .zip(osc_rights[..len].iter_mut()),
+        ) {
+            let gain = gain * (1.0 + amplitude_depth * pressure) * unison_gain;
+            *l *= gain;
+            *r *= gain;
+        }
+
+        let filter = self.filter.unwrap_or(voice_filter);
+        if self.stop_requested {
+            self.filter_envelope.release();
+        }
+        let mut cutoff_octaves = [0.0; MIX_BLOCK];
+        self.filter_envelope.fill_levels(&mut cutoff_octaves[..len]);
+        let key_octaves = filter.key_tracking * self.key_octaves;
+        let mut resonances = [0.0; MIX_BLOCK];
+        for (((octaves, resonance), pressure), offset) in cutoff_octaves[..len]
+            .iter_mut()
+            .zip(resonances[..len].iter_mut())
+            .zip(pressures[..len].iter())
+            .zip(block.offsets[..len].iter())
+        {
+            *octaves = filter.envelope_octaves * *octaves
+                + key_octaves
+                + cutoff_depth * pressure
+                + offset.octaves;
+            *resonance = filter.resonance + offset.resonance;
+        }
+        coefficients.update(
+            sample_hz,
+            filter.cutoff_hz,
+            &cutoff_octaves[..len],
+            &resonances[..len],
+        );
+        self.low_pass
+            .apply_block(coefficients, &mut osc_lefts[..len], &mut osc_rights[..len]);
+
+        for ((l, r), (osc_l, osc_r)) in block.left[..len]
+            .iter_mut()
+            .zip(block.right[..len].iter_mut())
+            .zip(osc_lefts[..len].iter().zip(osc_rights[..len].iter()))
+        {
+            *l += osc_l;
+            *r += osc_r;
+        }
     }
 
-    fn update_after_sample(&mut self) {
+    fn update_after_frame(&mut self) {
         self.online_decay_factor -= 0.005;
         if self.stop_requested {
             self.off_decay_factor -= 0.05;
@@ -140,6 +1323,134 @@ impl SynthNote {
     }
 
     fn is_done_playing(&self) -> bool {
-        self.off_decay_factor < 0.05 || self.online_decay_factor < 0.05
+        match &self.amp_envelope {
+            Some(envelope) => envelope.is_done(),
+            None => self.off_decay_factor < 0.05 || self.online_decay_factor < 0.05,
+        }
+    }
+}
+
+struct PannedSampleVoice {
+    voice: SampleVoice,
+    left_gain: f32,
+    right_gain: f32,
+}
+
+/// A note played from soundfont samples. Each voice follows its own volume envelope from the
+/// font, and the voice filter doesn't apply.
+struct SampledNote {
+    channel: usize,
+    voices: Vec<PannedSampleVoice>,
+    velocity_gain: f32,
+    stop_requested: bool,
+    /// Latest channel or key pressure in [0.0, 1.0]. Only affects amplitude.
+    pressure_target: f32,
+    pressure: ExponentialSmoothing,
+}
+
+impl SampledNote {
+    fn new(channel: usize, voices: Vec<SampleVoice>, pan: f32, velocity: wmidi::U7) -> Self {
+        let velocity = u8::from(velocity) as f32 / 127.0;
+        let voices = voices
+            .into_iter()
+            .map(|voice| {
+                let (left_gain, right_gain) = equal_power_pan((pan + voice.pan).clamp(-1.0, 1.0));
+
+                PannedSampleVoice {
+                    voice,
+                    left_gain,
+                    right_gain,
+                }
+            })
+            .collect();
+
+        SampledNote {
+            channel,
+            voices,
+            // Close to the velocity curve soundfonts are designed for.
+            velocity_gain: SAMPLED_NOTE_GAIN * velocity * velocity,
+            stop_requested: false,
+            pressure_target: 0.0,
+            pressure: ExponentialSmoothing::new(PRESSURE_SMOOTHING),
+        }
+    }
+
+    fn release(&mut self) {
+        self.stop_requested = true;
+        for v in self.voices.iter_mut() {
+            v.voice.release();
+        }
+    }
+
+    /// Like `PlayingNote::sample_block`, one voice at a time.
+    fn sample_block(
+        &mut self,
+        destination: PressureDestination,
+        left: &mut [f32],
+        right: &mut [f32],
+    ) {
+        let len = left.len();
+        let mut gains = [0.0; MIX_BLOCK];
+        for gain in gains[..len].iter_mut() {
+            let pressure = self.pressure.apply(self.pressure_target);
+            *gain = match destination {
+                PressureDestination::Amplitude => 1.0 + PRESSURE_AMPLITUDE_DEPTH * pressure,
+                PressureDestination::FilterCutoff => 1.0,
+            } * self.velocity_gain;
+        }
+        let mut voice_lefts = [0.0; MIX_BLOCK];
+        let mut voice_rights = [0.0; MIX_BLOCK];
+        for v in self.voices.iter_mut() {
+            for (l, r) in voice_lefts[..len]
+                .iter_mut()
+                .zip(voice_rights[..len].iter_mut())
+            {
+                let sample = v.voice.sample();
+                *l += v.left_gain * sample;
+                *r += v.right_gain * sample;
+            }
+        }
+
+        for i in 0..len {
+            left[i] += gains[i] * voice_lefts[i];
+            right[i] += gains[i] * voice_rights[i];
+        }
+    }
+
+    fn is_done_playing(&self) -> bool {
+        self.voices.iter().all(|v| v.voice.is_done())
+    }
+}
+
+/// A hit on the synthesized drum kit.
+struct DrumNote {
+    channel: usize,
+    voice: DrumVoice,
+    gain: f32,
+    left_gain: f32,
+    right_gain: f32,
+    stop_requested: bool,
+}
+
+impl DrumNote {
+    fn new(channel: usize, voice: DrumVoice, pan: f32, velocity: wmidi::U7) -> Self {
+        let (left_gain, right_gain) = equal_power_pan(pan);
+        let velocity = u8::from(velocity) as f32 / 127.0;
+
+        DrumNote {
+            channel,
+            voice,
+            gain: DRUM_NOTE_GAIN * velocity * velocity,
+            left_gain,
+            right_gain,
+            stop_requested: false,
+        }
+    }
+
+    /// Returns the left and right samples.
+    fn sample(&mut self) -> (f32, f32) {
+        let sample = self.gain * self.voice.sample();
+
+        (self.left_gain * sample, self.right_gain * sample)
     }
 }
@@ -0,0 +1,398 @@
+//! SMPTE timecode: linear timecode (LTC) generation, so recordings can be lined up with video and
+//! lighting rigs, and MIDI Time Code (MTC) decoding, so playback can chase a DAW. Also the tempo of
+//! MIDI clock, for keeping time with a sequencer or drum machine.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Non-drop-frame SMPTE frame rates.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimecodeRate {
+    Fps24,
+    Fps25,
+    Fps30,
+}
+
+impl TimecodeRate {
+    pub fn frames_per_second(self) -> u32 {
+        match self {
+            TimecodeRate::Fps24 => 24,
+            TimecodeRate::Fps25 => 25,
+            TimecodeRate::Fps30 => 30,
+        }
+    }
+
+    /// The bit that makes each LTC frame contain an even number of zeros, which keeps the signal's
+    /// polarity consistent from frame to frame.
+    fn polarity_correction_bit(self) -> usize {
+        match self {
+            TimecodeRate::Fps25 => 59,
+            TimecodeRate::Fps24 | TimecodeRate::Fps30 => 27,
+        }
+    }
+}
+
+impl FromStr for TimecodeRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "24" => Ok(TimecodeRate::Fps24),
+            "25" => Ok(TimecodeRate::Fps25),
+            "30" => Ok(TimecodeRate::Fps30),
+            other => Err(format!(
+                "Unsupported timecode rate {:?}, expected 24, 25 or 30",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+}
+
+impl Timecode {
+    /// The timecode of the `frame_count`th frame since midnight, wrapping after 24 hours.
+    pub fn from_frame_count(frame_count: u64, rate: TimecodeRate) -> Self {
+        let fps = rate.frames_per_second() as u64;
+        let total_seconds = frame_count / fps;
+
+        Timecode {
+            hours: ((total_seconds / 3600) % 24) as u8,
+            minutes: ((total_seconds / 60) % 60) as u8,
+            seconds: (total_seconds % 60) as u8,
+            frames: (frame_count % fps) as u8,
+        }
+    }
+
+    pub fn to_duration(self, rate: TimecodeRate) -> Duration {
+        let seconds = self.hours as u64 * 3600 + self.minutes as u64 * 60 + self.seconds as u64;
+
+        Duration::from_secs(seconds)
+            + Duration::from_secs_f64(self.frames as f64 / rate.frames_per_second() as f64)
+    }
+}
+
+impl fmt::Display for Timecode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}:{:02}:{:02}",
+            self.hours, self.minutes, self.seconds, self.frames
+        )
+    }
+}
+
+const LTC_BITS_PER_FRAME: usize = 80;
+
+/// Sync word occupying bits 64 through 79 of every LTC frame.
+const LTC_SYNC_WORD: [bool; 16] = [
+    false, false, true, true, true, true, true, true, true, true, true, true, true, true, false,
+    true,
+];
+
+/// Peak level of the generated signal. LTC is a square wave, so leave headroom.
+const LTC_AMPLITUDE: f32 = 0.5;
+
+/// Encodes the 80 bits of one LTC frame, least significant bit first.
+fn ltc_frame_bits(timecode: Timecode, rate: TimecodeRate) -> [bool; LTC_BITS_PER_FRAME] {
+    let mut bits = [false; LTC_BITS_PER_FRAME];
+    let mut put = |offset: usize, num_bits: usize, value: u8| {
+        for i in 0..num_bits {
+            bits[offset + i] = (value >> i) & 1 == 1;
+        }
+    };
+    put(0, 4, timecode.frames % 10);
+    put(8, 2, timecode.frames / 10);
+    put(16, 4, timecode.seconds % 10);
+    put(24, 3, timecode.seconds / 10);
+    put(32, 4, timecode.minutes % 10);
+    put(40, 3, timecode.minutes / 10);
+    put(48, 4, timecode.hours % 10);
+    put(56, 2, timecode.hours / 10);
+    bits[64..].copy_from_slice(&LTC_SYNC_WORD);
+
+    let num_zeros = bits.iter().filter(|b| !**b).count();
+    bits[rate.polarity_correction_bit()] = num_zeros % 2 == 1;
+
+    bits
+}
+
+/// Generates a biphase mark coded LTC signal, one audio sample at a time. The first sample is the
+/// start of frame 00:00:00:00 (plus any start offset).
+pub struct LtcEncoder {
+    rate: TimecodeRate,
+    samples_per_frame: f64,
+    start_frame: u64,
+    sample_index: u64,
+    frame_count: u64,
+    frame_bits: [bool; LTC_BITS_PER_FRAME],
+    level: f32,
+    /// Index of the half-bit that the previous sample fell in, to detect transitions.
+    half_bit: usize,
+}
+
+impl LtcEncoder {
+    pub fn new(sample_hz: u32, rate: TimecodeRate, start_frame: u64) -> Self {
+        LtcEncoder {
+            rate,
+            samples_per_frame: sample_hz as f64 / rate.frames_per_second() as f64,
+            start_frame,
+            sample_index: 0,
+            frame_count: start_frame,
+            frame_bits: ltc_frame_bits(Timecode::from_frame_count(start_frame, rate), rate),
+            level: LTC_AMPLITUDE,
+            half_bit: usize::MAX,
+        }
+    }
+
+    pub fn current_timecode(&self) -> Timecode {
+        Timecode::from_frame_count(self.frame_count, self.rate)
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        // Frame boundaries are computed from the absolute sample index, so rates that don't divide
+        // the sample rate evenly (e.g. 24 fps at 44.1 kHz) don't drift.
+        let position = self.sample_index as f64 / self.samples_per_frame;
+        let frame_count = self.start_frame + position as u64;
+        if frame_count != self.frame_count {
+            self.frame_count = frame_count;
+            self.frame_bits = ltc_frame_bits(self.current_timecode(), self.rate);
+            self.half_bit = usize::MAX;
+        }
+
+        // Biphase mark: the level flips at every bit boundary, and also mid-bit for ones.
+        let half_bit = (position.fract() * (2 * LTC_BITS_PER_FRAME) as f64) as usize;
+        if half_bit != self.half_bit {
+            let is_bit_start = half_bit % 2 == 0;
+            if is_bit_start || self.frame_bits[half_bit / 2] {
+                self.level = -self.level;
+            }
+            self.half_bit = half_bit;
+        }
+        self.sample_index += 1;
+
+        self.level
+    }
+}
+
+/// Reassembles MIDI Time Code quarter-frame messages into a position on an external transport.
+pub struct MtcDecoder {
+    pieces: [u8; 8],
+    /// Bitmask of the pieces received since the last complete timecode.
+    received: u8,
+    /// The last fully decoded position, advanced by a quarter frame per message after that.
+    position: Option<Duration>,
+    rate: TimecodeRate,
+    /// Whether the transport sends 29.97 fps drop-frame timecode, which runs at 30000/1001 frames
+    /// a second and labels its frames as 30 fps.
+    drop_frame: bool,
+}
+
+impl Default for MtcDecoder {
+    fn default() -> Self {
+        MtcDecoder {
+            pieces: [0; 8],
+            received: 0,
+            position: None,
+            rate: TimecodeRate::Fps30,
+            drop_frame: false,
+        }
+    }
+}
+
+/// Frames a second of 29.97 fps drop-frame timecode.
+const DROP_FRAME_FPS: f64 = 30000.0 / 1001.0;
+
+/// The time of a 29.97 fps drop-frame timecode. Frame labels 0 and 1 are skipped at the start of
+/// every minute but each tenth, so the labels keep up with the slower rate.
+fn drop_frame_duration(timecode: Timecode) -> Duration {
+    let minutes = timecode.hours as u64 * 60 + timecode.minutes as u64;
+    let labels = (minutes * 60 + timecode.seconds as u64) * 30 + timecode.frames as u64;
+    let dropped = 2 * (minutes - minutes / 10);
+
+    Duration::from_secs_f64((labels - dropped) as f64 / DROP_FRAME_FPS)
+}
+
+impl MtcDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The transport position implied by the quarter frames received so far, if there have been
+    /// enough to know it.
+    pub fn position(&self) -> Option<Duration> {
+        self.position
+    }
+
+    /// Handles the data byte of a quarter-frame message (status 0xF1) and returns the updated
+    /// position.
+    pub fn handle_quarter_frame(&mut self, data: u8) -> Option<Duration> {
+        let piece = ((data >> 4) & 0x7) as usize;
+        self.pieces[piece] = data & 0xF;
+        self.received |= 1 << piece;
+
+        let quarter_frame = Duration::from_secs_f64(0.25 / self.frames_per_second());
+        if let Some(p) = self.position.as_mut() {
+            *p += quarter_frame;
+        }
+
+        // The last piece completes a timecode that was current when the first piece was sent, two
+        // frames ago.
+        if piece == 7 && self.received == 0xFF {
+            let (timecode, rate, drop_frame) = self.assemble();
+            self.rate = rate;
+            self.drop_frame = drop_frame;
+            let two_frames = Duration::from_secs_f64(2.0 / self.frames_per_second());
+            let start = if drop_frame {
+                drop_frame_duration(timecode)
+            } else {
+                timecode.to_duration(rate)
+            };
+            self.position = Some(start + two_frames);
+            self.received = 0;
+        }
+
+        self.position
+    }
+
+    /// Forget the position, e.g. when the transport stops and may be relocated.
+    pub fn reset(&mut self) {
+        self.received = 0;
+        self.position = None;
+    }
+
+    fn frames_per_second(&self) -> f64 {
+        if self.drop_frame {
+            DROP_FRAME_FPS
+        } else {
+            self.rate.frames_per_second() as f64
+        }
+    }
+
+    /// The timecode, its rate, and whether it is 29.97 fps drop-frame, whose frames are labelled
+    /// as 30 fps.
+    fn assemble(&self) -> (Timecode, TimecodeRate, bool) {
+        let p = &self.pieces;
+        let rate_code = (p[7] >> 1) & 0x3;
+        let rate = match rate_code {
+            0 => TimecodeRate::Fps24,
+            1 => TimecodeRate::Fps25,
+            _ => TimecodeRate::Fps30,
+        };
+        let timecode = Timecode {
+            frames: p[0] | ((p[1] & 0x1) << 4),
+            seconds: p[2] | ((p[3] & 0x3) << 4),
+            minutes: p[4] | ((p[5] & 0x3) << 4),
+            hours: p[6] | ((p[7] & 0x1) << 4),
+        };
+
+        (timecode, rate, rate_code == 2)
+    }
+}
+
+/// MIDI clock sends this many Timing Clock messages (status 0xF8) per quarter note.
+pub const MIDI_CLOCK_PULSES_PER_BEAT: u32 = 24;
+
+/// A gap between pulses longer than this is the clock stopping, not a tempo (it would be 5 BPM).
+const MIDI_CLOCK_MAX_PULSE: Duration = Duration::from_millis(500);
+
+/// How far each pulse moves the measured tempo towards its own, which smooths out the jitter of
+/// pulses arriving over USB or a network.
+const MIDI_CLOCK_SMOOTHING: f64 = 0.1;
+
+/// Measures the tempo of MIDI clock from when its Timing Clock messages arrive.
+#[derive(Clone, Debug, Default)]
+pub struct MidiClockTempo {
+    last_pulse: Option<Duration>,
+    /// The smoothed time between pulses, in seconds.
+    pulse_secs: Option<f64>,
+}
+
+impl MidiClockTempo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles a Timing Clock that arrived at `time`, on any timeline that doesn't jump, and
+    /// returns the tempo measured so far.
+    pub fn pulse(&mut self, time: Duration) -> Option<f64> {
+        if let Some(interval) = self.last_pulse.and_then(|last| time.checked_sub(last)) {
+            if interval > Duration::ZERO && interval <= MIDI_CLOCK_MAX_PULSE {
+                let secs = interval.as_secs_f64();
+                self.pulse_secs = Some(match self.pulse_secs {
+                    Some(p) => p + MIDI_CLOCK_SMOOTHING * (secs - p),
+                    None => secs,
+                });
+            }
+        }
+        self.last_pulse = Some(time);
+
+        self.bpm()
+    }
+
+    pub fn bpm(&self) -> Option<f64> {
+        self.pulse_secs
+            .map(|secs| 60.0 / (secs * MIDI_CLOCK_PULSES_PER_BEAT as f64))
+    }
+
+    /// The time between pulses at the measured tempo.
+    pub fn pulse_duration(&self) -> Option<Duration> {
+        self.pulse_secs.map(Duration::from_secs_f64)
+    }
+
+    /// Forget when the last pulse came, as when the clock stops, but keep the tempo until the
+    /// pulses start again.
+    pub fn pause(&mut self) {
+        self.last_pulse = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends the eight quarter frames of `timecode` at MTC rate code `rate_code`.
+    fn send_timecode(decoder: &mut MtcDecoder, timecode: Timecode, rate_code: u8) -> Duration {
+        let pieces = [
+            timecode.frames & 0xF,
+            timecode.frames >> 4,
+            timecode.seconds & 0xF,
+            timecode.seconds >> 4,
+            timecode.minutes & 0xF,
+            timecode.minutes >> 4,
+            timecode.hours & 0xF,
+            (timecode.hours >> 4) | (rate_code << 1),
+        ];
+        let mut position = None;
+        for (i, piece) in pieces.iter().enumerate() {
+            position = decoder.handle_quarter_frame(((i as u8) << 4) | piece);
+        }
+
+        position.unwrap()
+    }
+
+    #[test]
+    fn drop_frame_timecode_keeps_up_with_the_clock() {
+        // Ten minutes of 29.97 fps drop-frame is 17982 frames, or 599.9994 seconds, where 30 fps
+        // would be 18000 frames and 18 frames late.
+        let timecode = Timecode {
+            hours: 0,
+            minutes: 10,
+            seconds: 0,
+            frames: 0,
+        };
+        let position = send_timecode(&mut MtcDecoder::new(), timecode, 2);
+        let expected = (17982.0 + 2.0) * 1001.0 / 30000.0;
+        assert!((position.as_secs_f64() - expected).abs() < 1e-6);
+
+        let position = send_timecode(&mut MtcDecoder::new(), timecode, 3);
+        assert!((position.as_secs_f64() - (600.0 + 2.0 / 30.0)).abs() < 1e-6);
+    }
+}
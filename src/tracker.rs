@@ -0,0 +1,759 @@
+//! Tracker modules: ProTracker-style MOD files and FastTracker 2 XM files, converted into the timed
+//! MIDI messages and soundfont instruments the rest of the engine plays.
+//!
+//! Each tracker channel becomes a MIDI channel, skipping channel 10 so nothing lands on the drum
+//! kit, and each sample (MOD) or instrument (XM) becomes a program. Notes, instrument changes, set
+//! volume, note cut and delay, speed and tempo changes, pattern breaks and position jumps are
+//! followed. Slides, vibrato, arpeggios, envelopes beyond a fade out and the other pitch and volume
+//! effects are not, so modules that lean on them sound plainer than in a tracker.
+
+use crate::{
+    cancel::CancellationToken,
+    effects::EffectsChain,
+    envelope::Adsr,
+    error::Result,
+    instrument::play_timed_messages,
+    midi::MidiMessageBytes,
+    oscillator::Source,
+    render::render_timed_messages,
+    soundfont::{LoopMode, SoundFont, Zone},
+    wav::save_wav,
+};
+
+use log::{trace, warn};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+const RENDER_SAMPLE_HZ: u32 = 44100;
+const RENDER_CHANNELS: u16 = 2;
+
+/// Amiga period of C-2, which plays a sample at its recorded pitch.
+const MOD_MIDDLE_PERIOD: f32 = 428.0;
+
+/// Samples play at their recorded pitch on middle C, at the rate trackers assume for it.
+const ROOT_KEY: u8 = 60;
+const ROOT_SAMPLE_HZ: f32 = 8363.0;
+
+/// XM notes count from C-0, so C-4 (note 49) lands on `ROOT_KEY`.
+const XM_NOTE_OFFSET: i32 = 11;
+const XM_KEY_OFF: u8 = 97;
+
+const DEFAULT_SPEED: u32 = 6;
+const DEFAULT_TEMPO: u32 = 125;
+
+/// Tracker volumes run from 0 to 64.
+const MAX_VOLUME: u8 = 64;
+
+/// Long enough to avoid a click when a note is cut.
+const RELEASE_SECS: f32 = 0.01;
+
+/// How far the Amiga's left and right channels are panned. Fully apart is tiring on headphones.
+const AMIGA_PAN: f32 = 0.5;
+
+const DRUM_CHANNEL: usize = 9;
+const MAX_CHANNELS: usize = 15;
+
+const EFFECT_POSITION_JUMP: u8 = 0xB;
+const EFFECT_SET_VOLUME: u8 = 0xC;
+const EFFECT_PATTERN_BREAK: u8 = 0xD;
+const EFFECT_EXTENDED: u8 = 0xE;
+const EFFECT_SET_SPEED: u8 = 0xF;
+const EXTENDED_NOTE_CUT: u8 = 0xC;
+const EXTENDED_NOTE_DELAY: u8 = 0xD;
+
+const CC_VOLUME: u8 = 7;
+const CC_PAN: u8 = 10;
+const CC_EXPRESSION: u8 = 11;
+
+/// A tracker module, ready to play.
+pub struct TrackerModule {
+    pub title: String,
+    /// One program per sample or instrument, in the order the module numbers them.
+    pub instruments: &'static SoundFont,
+    /// Every note, program change and controller, timed from the start of the song. The song stops
+    /// where it would loop back on itself.
+    pub messages: Vec<(Duration, MidiMessageBytes)>,
+}
+
+impl TrackerModule {
+    /// Loads an XM file, or a MOD file if it isn't one. Like `SoundFont::load`, the instruments
+    /// live for the rest of the program.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let (song, font) = if bytes.starts_with(b"Extended Module: ") {
+            parse_xm(&bytes)?
+        } else {
+            parse_mod(&bytes)?
+        };
+
+        Ok(TrackerModule {
+            messages: song.timeline(),
+            title: song.title,
+            instruments: Box::leak(Box::new(font)),
+        })
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.messages.last().map_or(Duration::from_secs(0), |m| m.0)
+    }
+}
+
+/// Plays the module on the audio device.
+pub async fn play_tracker_module(
+    module: &TrackerModule,
+    effects: EffectsChain,
+    cancel: CancellationToken,
+) -> Result<()> {
+    play_timed_messages(
+        module.messages.clone(),
+        Source::SoundFont(module.instruments),
+        effects,
+        cancel,
+    )
+    .await
+}
+
+/// Renders the module to a stereo 16-bit WAV file.
+pub fn render_tracker_module(
+    module: &TrackerModule,
+    mut effects: EffectsChain,
+    path: &Path,
+) -> io::Result<()> {
+    let samples = render_timed_messages(
+        &module.messages,
+        Source::SoundFont(module.instruments),
+        &mut effects,
+        RENDER_SAMPLE_HZ,
+        RENDER_CHANNELS as usize,
+    );
+
+    save_wav(path, &samples, RENDER_CHANNELS, RENDER_SAMPLE_HZ)
+}
+
+#[derive(Clone, Copy, Default)]
+struct Cell {
+    note: Option<Note>,
+    /// Counting from 1, as in the module.
+    instrument: Option<u8>,
+    /// From XM's volume column.
+    volume: Option<u8>,
+    effect: u8,
+    param: u8,
+}
+
+#[derive(Clone, Copy)]
+enum Note {
+    Key(u8),
+    Off,
+}
+
+/// Pattern data in a form common to both formats.
+struct Song {
+    title: String,
+    num_channels: usize,
+    orders: Vec<usize>,
+    /// Rows of cells, one per channel.
+    patterns: Vec<Vec<Vec<Cell>>>,
+    /// The default volume of each instrument, which notes are played relative to.
+    instrument_volumes: Vec<u8>,
+    /// Per channel, in [-1.0, 1.0].
+    channel_pans: Vec<f32>,
+    speed: u32,
+    tempo: u32,
+}
+
+#[derive(Clone, Copy, Default)]
+struct ChannelState {
+    key: Option<u8>,
+    program: Option<u8>,
+    note_volume: u8,
+}
+
+fn midi_channel(channel: usize) -> u8 {
+    if channel >= DRUM_CHANNEL {
+        channel as u8 + 1
+    } else {
+        channel as u8
+    }
+}
+
+/// Velocity and channel gain both follow a squared curve, so the square root keeps the module's
+/// linear volumes.
+fn volume_to_midi(volume: f32) -> u8 {
+    (127.0 * volume.clamp(0.0, 1.0).sqrt()).round() as u8
+}
+
+impl Song {
+    fn timeline(&self) -> Vec<(Duration, MidiMessageBytes)> {
+        let mut messages = Vec::new();
+        for (c, pan) in self.channel_pans.iter().enumerate() {
+            let status = 0xB0 | midi_channel(c);
+            messages.push((Duration::from_secs(0), [status, CC_VOLUME, 127].into()));
+            let pan = (64.0 + pan * 63.0).round() as u8;
+            messages.push((Duration::from_secs(0), [status, CC_PAN, pan].into()));
+        }
+
+        let mut channels = vec![ChannelState::default(); self.num_channels];
+        let mut speed = self.speed;
+        let mut tempo = self.tempo;
+        let mut secs = 0.0;
+        let mut played = HashSet::new();
+        let mut order = 0;
+        let mut start_row = 0;
+        'song: while order < self.orders.len() {
+            // Jumping back to somewhere already played would repeat forever.
+            if !played.insert((order, start_row)) {
+                break;
+            }
+            let pattern = match self.patterns.get(self.orders[order]) {
+                Some(p) => p,
+                None => {
+                    order += 1;
+                    start_row = 0;
+                    continue;
+                }
+            };
+
+            let mut next = None;
+            for row in pattern.iter().skip(start_row) {
+                for cell in row.iter() {
+                    if cell.effect == EFFECT_SET_SPEED {
+                        match cell.param as u32 {
+                            0 => break 'song,
+                            p if p < 32 => speed = p,
+                            p => tempo = p,
+                        }
+                    }
+                }
+                let tick_secs = 2.5 / tempo as f64;
+
+                for (c, cell) in row.iter().enumerate() {
+                    let state = &mut channels[c];
+                    let channel = midi_channel(c);
+                    let extended = (cell.effect == EFFECT_EXTENDED).then_some(cell.param >> 4);
+                    let ticks = (cell.param & 0x0F) as f64;
+                    let start = match extended {
+                        Some(EXTENDED_NOTE_DELAY) => secs + ticks * tick_secs,
+                        _ => secs,
+                    };
+                    let at = Duration::from_secs_f64(start);
+                    let volume = match (cell.volume, cell.effect) {
+                        (Some(v), _) => Some(v),
+                        (None, EFFECT_SET_VOLUME) => Some(cell.param.min(MAX_VOLUME)),
+                        _ => None,
+                    };
+
+                    match cell.note {
+                        Some(Note::Key(key)) => {
+                            if let Some(old) = state.key.take() {
+                                messages.push((at, [0x80 | channel, old, 0].into()));
+                            }
+                            if let Some(instrument) = cell.instrument {
+                                let program = instrument.saturating_sub(1).min(127);
+                                if state.program != Some(program) {
+                                    messages.push((at, [0xC0 | channel, program].into()));
+                                    state.program = Some(program);
+                                }
+                            }
+                            if let Some(program) = state.program {
+                                let default_volume = self
+                                    .instrument_volumes
+                                    .get(program as usize)
+                                    .copied()
+                                    .unwrap_or(MAX_VOLUME)
+                                    .max(1);
+                                state.note_volume = volume.unwrap_or(default_volume);
+                                let velocity = volume_to_midi(
+                                    state.note_volume as f32 / default_volume as f32,
+                                )
+                                .max(1);
+                                messages.push((at, [0xB0 | channel, CC_EXPRESSION, 127].into()));
+                                messages.push((at, [0x90 | channel, key, velocity].into()));
+                                state.key = Some(key);
+                            }
+                        }
+                        Some(Note::Off) => {
+                            if let Some(old) = state.key.take() {
+                                messages.push((at, [0x80 | channel, old, 0].into()));
+                            }
+                        }
+                        None => {
+                            // A new volume for the note that's already playing.
+                            if let (Some(volume), Some(_)) = (volume, state.key) {
+                                let relative = volume as f32 / state.note_volume.max(1) as f32;
+                                let expression = volume_to_midi(relative);
+                                messages
+                                    .push((at, [0xB0 | channel, CC_EXPRESSION, expression].into()));
+                            }
+                        }
+                    }
+
+                    if extended == Some(EXTENDED_NOTE_CUT) {
+                        if let Some(old) = state.key.take() {
+                            let cut_at = Duration::from_secs_f64(secs + ticks * tick_secs);
+                            messages.push((cut_at, [0x80 | channel, old, 0].into()));
+                        }
+                    }
+                    match cell.effect {
+                        EFFECT_POSITION_JUMP => {
+                            let row = next.map_or(0, |(_, r)| r);
+                            next = Some((cell.param as usize, row));
+                        }
+                        EFFECT_PATTERN_BREAK => {
+                            // The row is in binary-coded decimal.
+                            let row =
+                                10 * (cell.param >> 4) as usize + (cell.param & 0x0F) as usize;
+                            let jump_order = next.map_or(order + 1, |(o, _)| o);
+                            next = Some((jump_order, row));
+                        }
+                        EFFECT_EXTENDED | EFFECT_SET_SPEED | EFFECT_SET_VOLUME | 0 => (),
+                        other => trace!("Unsupported tracker effect {:X}", other),
+                    }
+                }
+
+                secs += speed as f64 * tick_secs;
+                if next.is_some() {
+                    break;
+                }
+            }
+
+            let (next_order, next_row) = next.unwrap_or((order + 1, 0));
+            order = next_order;
+            start_row = next_row;
+        }
+
+        let end = Duration::from_secs_f64(secs);
+        for (c, state) in channels.iter().enumerate() {
+            if let Some(key) = state.key {
+                messages.push((end, [0x80 | midi_channel(c), key, 0].into()));
+            }
+        }
+        // Cut and delayed notes can land after later rows' messages.
+        messages.sort_by_key(|(time, _)| *time);
+
+        messages
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u16_be(bytes: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u16_le(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32_le(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn read_text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+
+    String::from_utf8_lossy(&bytes[..end])
+        .trim_end()
+        .to_string()
+}
+
+fn attenuation_db(volume: u8) -> f32 {
+    let volume = volume.min(MAX_VOLUME);
+    if volume == 0 {
+        // Silent, without going infinite.
+        return 144.0;
+    }
+
+    -20.0 * (volume as f32 / MAX_VOLUME as f32).log10()
+}
+
+fn tracker_zone(keys: (u8, u8), start: usize, end: usize) -> Zone {
+    Zone {
+        keys,
+        velocities: (0, 127),
+        start,
+        end,
+        loop_start: start,
+        loop_end: start,
+        loop_mode: LoopMode::NoLoop,
+        loop_crossfade: 0,
+        release_start: start,
+        release_end: start,
+        sample_hz: ROOT_SAMPLE_HZ,
+        root_key: ROOT_KEY,
+        tune_cents: 0.0,
+        scale_tuning: 100.0,
+        attenuation_db: 0.0,
+        pan: 0.0,
+        envelope: Adsr {
+            attack_secs: 0.0,
+            decay_secs: 0.0,
+            sustain: 1.0,
+            release_secs: RELEASE_SECS,
+        },
+    }
+}
+
+/// The channel count for a MOD signature, or `None` for the original 15-sample format, which has
+/// none.
+fn mod_channels(signature: &[u8]) -> Option<usize> {
+    match signature {
+        b"M.K." | b"M!K!" | b"M&K!" | b"FLT4" | b"4CHN" => Some(4),
+        b"OKTA" | b"CD81" => Some(8),
+        [n, b'C', b'H', b'N'] if n.is_ascii_digit() => Some((n - b'0') as usize),
+        [a, b, b'C', b'H'] if a.is_ascii_digit() && b.is_ascii_digit() => {
+            Some(((a - b'0') * 10 + (b - b'0')) as usize)
+        }
+        _ => None,
+    }
+}
+
+const MOD_SAMPLE_HEADER_SIZE: usize = 30;
+const MOD_ROWS: usize = 64;
+
+fn parse_mod(bytes: &[u8]) -> io::Result<(Song, SoundFont)> {
+    let signature = bytes.get(1080..1084).unwrap_or(&[]);
+    let (num_samples, num_channels, orders_at, patterns_at) = match mod_channels(signature) {
+        Some(n) => (31, n, 952, 1084),
+        None => (15, 4, 472, 600),
+    };
+    if bytes.len() < patterns_at || num_channels == 0 {
+        return Err(invalid("Not a MOD file"));
+    }
+
+    let song_length = (bytes[orders_at - 2] as usize).clamp(1, 128);
+    let orders: Vec<usize> = bytes[orders_at..orders_at + 128]
+        .iter()
+        .map(|&o| o as usize)
+        .collect();
+    let num_patterns = orders.iter().max().map_or(0, |m| m + 1);
+    let pattern_size = MOD_ROWS * num_channels * 4;
+
+    let mut patterns = Vec::with_capacity(num_patterns);
+    for p in 0..num_patterns {
+        let at = patterns_at + p * pattern_size;
+        let data = bytes
+            .get(at..at + pattern_size)
+            .ok_or_else(|| invalid("MOD pattern data is truncated"))?;
+        let rows = data
+            .chunks_exact(num_channels * 4)
+            .map(|row| {
+                row.chunks_exact(4)
+                    .map(|c| {
+                        let sample = (c[0] & 0xF0) | (c[2] >> 4);
+                        let period = (((c[0] & 0x0F) as u16) << 8) | c[1] as u16;
+                        let note = (period > 0).then(|| {
+                            let key =
+                                ROOT_KEY as f32 + 12.0 * (MOD_MIDDLE_PERIOD / period as f32).log2();
+                            Note::Key(key.round().clamp(0.0, 127.0) as u8)
+                        });
+
+                        Cell {
+                            note,
+                            instrument: (sample > 0).then_some(sample),
+                            volume: None,
+                            effect: c[2] & 0x0F,
+                            param: c[3],
+                        }
+                    })
+                    .take(MAX_CHANNELS)
+                    .collect()
+            })
+            .collect();
+        patterns.push(rows);
+    }
+
+    let mut sample_data = Vec::new();
+    let mut programs = Vec::with_capacity(num_samples);
+    let mut instrument_volumes = Vec::with_capacity(num_samples);
+    let mut at = patterns_at + num_patterns * pattern_size;
+    for s in 0..num_samples {
+        let header = &bytes[20 + s * MOD_SAMPLE_HEADER_SIZE..20 + (s + 1) * MOD_SAMPLE_HEADER_SIZE];
+        let length = 2 * read_u16_be(header, 22) as usize;
+        // The low nibble is signed, in eighths of a semitone.
+        let finetune = ((header[24] << 4) as i8) >> 4;
+        let volume = header[25].min(MAX_VOLUME);
+        let loop_start = 2 * read_u16_be(header, 26) as usize;
+        let loop_length = 2 * read_u16_be(header, 28) as usize;
+
+        let available = bytes.len().saturating_sub(at).min(length);
+        if available < length {
+            warn!("MOD sample {} is truncated", s + 1);
+        }
+        let start = sample_data.len();
+        sample_data.extend(
+            bytes[at..at + available]
+                .iter()
+                .map(|&b| ((b as i8) as i16) << 8),
+        );
+        at += available;
+        instrument_volumes.push(volume);
+
+        if available == 0 {
+            programs.push(Vec::new());
+            continue;
+        }
+        let mut zone = tracker_zone((0, 127), start, start + available);
+        zone.tune_cents = finetune as f32 * 12.5;
+        zone.attenuation_db = attenuation_db(volume);
+        // A loop length of one word means no loop.
+        if loop_length > 2 && loop_start + loop_length <= available {
+            zone.loop_mode = LoopMode::Continuous;
+            zone.loop_start = start + loop_start;
+            zone.loop_end = start + loop_start + loop_length;
+        }
+        programs.push(vec![zone]);
+    }
+
+    let channels = num_channels.min(MAX_CHANNELS);
+    if num_channels > MAX_CHANNELS {
+        warn!(
+            "Only the first {} of {} channels are played",
+            MAX_CHANNELS, num_channels
+        );
+    }
+    // Amiga channels go left, right, right, left.
+    let channel_pans = (0..channels)
+        .map(|c| match c % 4 {
+            0 | 3 => -AMIGA_PAN,
+            _ => AMIGA_PAN,
+        })
+        .collect();
+
+    let song = Song {
+        title: read_text(&bytes[..20]),
+        num_channels: channels,
+        orders: orders[..song_length].to_vec(),
+        patterns,
+        instrument_volumes,
+        channel_pans,
+        speed: DEFAULT_SPEED,
+        tempo: DEFAULT_TEMPO,
+    };
+
+    Ok((song, SoundFont::from_programs(programs, sample_data)))
+}
+
+const XM_HEADER_START: usize = 60;
+const XM_SAMPLE_HEADER_SIZE: usize = 40;
+const XM_LOOP_FORWARD: u8 = 1;
+const XM_LOOP_PING_PONG: u8 = 2;
+const XM_16_BIT: u8 = 0x10;
+const XM_VOLUME_ENVELOPE_ON: u8 = 1;
+
+fn parse_xm(bytes: &[u8]) -> io::Result<(Song, SoundFont)> {
+    let truncated = || invalid("XM file is truncated");
+    if bytes.len() < XM_HEADER_START + 20 {
+        return Err(truncated());
+    }
+    let header_size = read_u32_le(bytes, XM_HEADER_START) as usize;
+    let song_length = read_u16_le(bytes, 64) as usize;
+    let num_channels = read_u16_le(bytes, 68) as usize;
+    let num_patterns = read_u16_le(bytes, 70) as usize;
+    let num_instruments = read_u16_le(bytes, 72) as usize;
+    let speed = read_u16_le(bytes, 76) as u32;
+    let tempo = read_u16_le(bytes, 78) as u32;
+    let orders = bytes
+        .get(80..80 + song_length.min(256))
+        .ok_or_else(truncated)?
+        .iter()
+        .map(|&o| o as usize)
+        .collect();
+
+    let mut at = XM_HEADER_START + header_size;
+    let mut patterns = Vec::with_capacity(num_patterns);
+    for _ in 0..num_patterns {
+        let header = bytes.get(at..at + 9).ok_or_else(truncated)?;
+        let header_length = read_u32_le(header, 0) as usize;
+        let num_rows = read_u16_le(header, 5) as usize;
+        let data_size = read_u16_le(header, 7) as usize;
+        at += header_length;
+        let data = bytes.get(at..at + data_size).ok_or_else(truncated)?;
+        at += data_size;
+        patterns.push(xm_pattern(data, num_rows, num_channels));
+    }
+
+    let mut sample_data = Vec::new();
+    let mut programs = Vec::with_capacity(num_instruments);
+    let mut instrument_volumes = Vec::with_capacity(num_instruments);
+    for _ in 0..num_instruments {
+        let header = bytes.get(at..at + 29).ok_or_else(truncated)?;
+        let instrument_size = read_u32_le(header, 0) as usize;
+        let num_samples = read_u16_le(header, 27) as usize;
+        if num_samples == 0 {
+            at += instrument_size;
+            programs.push(Vec::new());
+            instrument_volumes.push(MAX_VOLUME);
+            continue;
+        }
+
+        let extra = bytes.get(at..at + 243).ok_or_else(truncated)?;
+        let sample_map = &extra[33..129];
+        let envelope_on = extra[233] & XM_VOLUME_ENVELOPE_ON != 0;
+        let fadeout = read_u16_le(extra, 239);
+        at += instrument_size;
+
+        let headers = bytes
+            .get(at..at + num_samples * XM_SAMPLE_HEADER_SIZE)
+            .ok_or_else(truncated)?;
+        at += headers.len();
+
+        let mut zones_by_sample = Vec::with_capacity(num_samples);
+        for header in headers.chunks_exact(XM_SAMPLE_HEADER_SIZE) {
+            let length = read_u32_le(header, 0) as usize;
+            let mut loop_start = read_u32_le(header, 4) as usize;
+            let mut loop_length = read_u32_le(header, 8) as usize;
+            let volume = header[12].min(MAX_VOLUME);
+            let finetune = header[13] as i8;
+            let kind = header[14];
+            let pan = header[15];
+            let relative_note = header[16] as i8;
+
+            let data = bytes.get(at..at + length).ok_or_else(truncated)?;
+            at += length;
+            let start = sample_data.len();
+            // Sample data is stored as deltas.
+            if kind & XM_16_BIT != 0 {
+                let mut value = 0i16;
+                for pair in data.chunks_exact(2) {
+                    value = value.wrapping_add(i16::from_le_bytes([pair[0], pair[1]]));
+                    sample_data.push(value);
+                }
+                loop_start /= 2;
+                loop_length /= 2;
+            } else {
+                let mut value = 0i8;
+                for &delta in data {
+                    value = value.wrapping_add(delta as i8);
+                    sample_data.push((value as i16) << 8);
+                }
+            }
+            let end = sample_data.len();
+
+            let mut zone = tracker_zone((0, 127), start, end);
+            zone.root_key = (ROOT_KEY as i32 - relative_note as i32).clamp(0, 127) as u8;
+            zone.tune_cents = finetune as f32 * 100.0 / 128.0;
+            zone.attenuation_db = attenuation_db(volume);
+            zone.pan = (pan as f32 - 128.0) / 128.0;
+            let loop_kind = kind & 0x03;
+            if (loop_kind == XM_LOOP_FORWARD || loop_kind == XM_LOOP_PING_PONG)
+                && loop_length > 0
+                && start + loop_start + loop_length <= end
+            {
+                zone.loop_mode = if loop_kind == XM_LOOP_PING_PONG {
+                    LoopMode::PingPong
+                } else {
+                    LoopMode::Continuous
+                };
+                zone.loop_start = start + loop_start;
+                zone.loop_end = start + loop_start + loop_length;
+            }
+            // The fade out counts down from 65536 each tick, at the default tempo.
+            if envelope_on && fadeout > 0 {
+                zone.envelope.release_secs = 65536.0 / fadeout as f32 * 2.5 / DEFAULT_TEMPO as f32;
+            }
+            zones_by_sample.push((zone, volume));
+        }
+        instrument_volumes.push(zones_by_sample[0].1);
+
+        // Runs of keys that play the same sample.
+        let mut zones = Vec::new();
+        let mut i = 0;
+        while i < sample_map.len() {
+            let sample = sample_map[i] as usize;
+            let mut j = i;
+            while j + 1 < sample_map.len() && sample_map[j + 1] as usize == sample {
+                j += 1;
+            }
+            if let Some((zone, _)) = zones_by_sample.get(sample) {
+                let low = (i as i32 + 1 + XM_NOTE_OFFSET).clamp(0, 127) as u8;
+                let high = (j as i32 + 1 + XM_NOTE_OFFSET).clamp(0, 127) as u8;
+                zones.push(Zone {
+                    keys: (low, high),
+                    ..*zone
+                });
+            }
+            i = j + 1;
+        }
+        programs.push(zones);
+    }
+    if programs.is_empty() {
+        programs.push(Vec::new());
+    }
+
+    let channels = num_channels.min(MAX_CHANNELS);
+    if num_channels > MAX_CHANNELS {
+        warn!(
+            "Only the first {} of {} channels are played",
+            MAX_CHANNELS, num_channels
+        );
+    }
+    let song = Song {
+        title: read_text(&bytes[17..37]),
+        num_channels: channels,
+        orders,
+        patterns,
+        instrument_volumes,
+        channel_pans: vec![0.0; channels],
+        speed: if speed > 0 { speed } else { DEFAULT_SPEED },
+        tempo: if tempo >= 32 { tempo } else { DEFAULT_TEMPO },
+    };
+
+    Ok((song, SoundFont::from_programs(programs, sample_data)))
+}
+
+/// Unpacks an XM pattern. A cell either starts with a byte whose top bit is set, saying which
+/// fields follow, or is all five fields.
+fn xm_pattern(data: &[u8], num_rows: usize, num_channels: usize) -> Vec<Vec<Cell>> {
+    let mut bytes = data.iter().copied();
+    let mut next = || bytes.next().unwrap_or(0);
+    let kept_channels = num_channels.min(MAX_CHANNELS);
+
+    // An empty pattern has no data at all.
+    if data.is_empty() {
+        return vec![vec![Cell::default(); kept_channels]; num_rows];
+    }
+
+    (0..num_rows)
+        .map(|_| {
+            let mut row = Vec::with_capacity(kept_channels);
+            for c in 0..num_channels {
+                let first = next();
+                let (note, instrument, volume, effect, param) = if first & 0x80 != 0 {
+                    let mut field = |bit: u8| if first & bit != 0 { next() } else { 0 };
+                    let note = field(0x01);
+                    let instrument = field(0x02);
+                    let volume = field(0x04);
+                    let effect = field(0x08);
+                    let param = field(0x10);
+                    (note, instrument, volume, effect, param)
+                } else {
+                    (first, next(), next(), next(), next())
+                };
+                if c >= MAX_CHANNELS {
+                    continue;
+                }
+
+                row.push(Cell {
+                    note: match note {
+                        0 => None,
+                        XM_KEY_OFF => Some(Note::Off),
+                        n => Some(Note::Key((n as i32 + XM_NOTE_OFFSET).clamp(0, 127) as u8)),
+                    },
+                    instrument: (instrument > 0).then_some(instrument),
+                    // 0x10 to 0x50 set the volume; the rest are slides and such.
+                    volume: (0x10..=0x50).contains(&volume).then(|| volume - 0x10),
+                    effect,
+                    param,
+                });
+            }
+
+            row
+        })
+        .collect()
+}
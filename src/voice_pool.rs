@@ -0,0 +1,153 @@
+//! The voices a synthesizer is playing, one slot per MIDI key on each channel, allocated once up
+//! front. Nothing is allocated or hashed when a note starts or stops, and voices are always visited
+//! in channel and then key order, so rendering the same input gives the same output, down to the
+//! rounding.
+
+use std::convert::TryFrom;
+use wmidi::Note;
+
+const NUM_CHANNELS: usize = 16;
+const NUM_KEYS: usize = 128;
+
+/// A MIDI channel, counting from 0, and a key on it. The same key held on two channels is two
+/// voices.
+pub(crate) type VoiceKey = (usize, Note);
+
+struct Slot<V> {
+    voice: V,
+    /// When the voice started, for stealing the oldest.
+    start: u64,
+}
+
+pub(crate) struct VoicePool<V> {
+    slots: Vec<Option<Slot<V>>>,
+    /// Bit `k` of channel `c` is set when key `k` has a voice on channel `c`, so only the occupied
+    /// slots are visited.
+    occupied: [u128; NUM_CHANNELS],
+}
+
+fn slot_index((channel, key): VoiceKey) -> usize {
+    channel * NUM_KEYS + key as usize
+}
+
+impl<V> VoicePool<V> {
+    pub(crate) fn new() -> Self {
+        VoicePool {
+            slots: (0..NUM_CHANNELS * NUM_KEYS).map(|_| None).collect(),
+            occupied: [0; NUM_CHANNELS],
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.occupied
+            .iter()
+            .map(|keys| keys.count_ones() as usize)
+            .sum()
+    }
+
+    pub(crate) fn get(&self, voice_key: VoiceKey) -> Option<&V> {
+        self.slots[slot_index(voice_key)].as_ref().map(|s| &s.voice)
+    }
+
+    pub(crate) fn get_mut(&mut self, voice_key: VoiceKey) -> Option<&mut V> {
+        self.slots[slot_index(voice_key)]
+            .as_mut()
+            .map(|s| &mut s.voice)
+    }
+
+    /// The position on the output clock where the voice on `voice_key` started.
+    pub(crate) fn start(&self, voice_key: VoiceKey) -> Option<u64> {
+        self.slots[slot_index(voice_key)].as_ref().map(|s| s.start)
+    }
+
+    /// Replaces any voice already on `voice_key`.
+    pub(crate) fn insert(&mut self, voice_key: VoiceKey, voice: V, start: u64) {
+        let (channel, key) = voice_key;
+        self.slots[slot_index(voice_key)] = Some(Slot { voice, start });
+        self.occupied[channel] |= 1 << key as usize;
+    }
+
+    pub(crate) fn remove(&mut self, voice_key: VoiceKey) -> Option<V> {
+        let (channel, key) = voice_key;
+        self.occupied[channel] &= !(1 << key as usize);
+        self.slots[slot_index(voice_key)].take().map(|s| s.voice)
+    }
+
+    /// Removes the first voice that `f` picks.
+    pub(crate) fn pop_first_where(
+        &mut self,
+        mut f: impl FnMut(&V) -> bool,
+    ) -> Option<(VoiceKey, V)> {
+        let voice_key = self
+            .iter()
+            .find(|(_, voice)| f(voice))
+            .map(|(voice_key, _)| voice_key)?;
+
+        self.remove(voice_key).map(|voice| (voice_key, voice))
+    }
+
+    /// In channel and then key order.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = VoiceKey> + Clone {
+        let occupied = self.occupied;
+        let mut channel = 0;
+        let mut keys = occupied[0];
+
+        std::iter::from_fn(move || {
+            while keys == 0 {
+                if channel + 1 == NUM_CHANNELS {
+                    return None;
+                }
+                channel += 1;
+                keys = occupied[channel];
+            }
+            let k = keys.trailing_zeros();
+            keys &= keys - 1;
+
+            Some((
+                channel,
+                Note::try_from(k as u8).expect("Keys are below 128"),
+            ))
+        })
+    }
+
+    /// In channel and then key order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (VoiceKey, &V)> + Clone {
+        self.keys().map(move |voice_key| {
+            let voice = &self.slots[slot_index(voice_key)]
+                .as_ref()
+                .expect("Occupied slots have a voice")
+                .voice;
+
+            (voice_key, voice)
+        })
+    }
+
+    /// In channel and then key order.
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = (VoiceKey, &mut V)> {
+        let occupied = self.occupied;
+        // Channels without voices are skipped whole.
+        self.slots
+            .chunks_mut(NUM_KEYS)
+            .enumerate()
+            .filter(move |(channel, _)| occupied[*channel] != 0)
+            .flat_map(move |(channel, slots)| {
+                slots
+                    .iter_mut()
+                    .enumerate()
+                    .filter(move |(k, _)| occupied[channel] & (1 << k) != 0)
+                    .filter_map(move |(k, slot)| {
+                        let key = Note::try_from(k as u8).expect("Keys are below 128");
+
+                        slot.as_mut().map(|s| ((channel, key), &mut s.voice))
+                    })
+            })
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &V> + Clone {
+        self.iter().map(|(_, voice)| voice)
+    }
+
+    pub(crate) fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.iter_mut().map(|(_, voice)| voice)
+    }
+}
@@ -0,0 +1,251 @@
+//! A WAV writer that starts out as a plain RIFF file and turns into an RF64 file (EBU Tech 3306)
+//! once the data no longer fits in RIFF's 32-bit size fields.
+//!
+//! The header reserves a `JUNK` chunk exactly the size of the RF64 `ds64` chunk, so the switch is
+//! just a header rewrite. Readers that don't understand RF64 still get a valid file for anything
+//! under 4 GB.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum WavSampleFormat {
+    #[default]
+    Int16,
+    Int24,
+    /// Keeps the synth's samples as they are, including anything past full scale.
+    Float32,
+}
+
+impl WavSampleFormat {
+    pub const NAMES: [&'static str; 3] = ["int16", "int24", "float32"];
+
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "int16" => Some(WavSampleFormat::Int16),
+            "int24" => Some(WavSampleFormat::Int24),
+            "float32" => Some(WavSampleFormat::Float32),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn bits_per_sample(self) -> u16 {
+        match self {
+            WavSampleFormat::Int16 => 16,
+            WavSampleFormat::Int24 => 24,
+            WavSampleFormat::Float32 => 32,
+        }
+    }
+
+    fn format_tag(self) -> u16 {
+        const WAVE_FORMAT_PCM: u16 = 1;
+        const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+        match self {
+            WavSampleFormat::Int16 | WavSampleFormat::Int24 => WAVE_FORMAT_PCM,
+            WavSampleFormat::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+        }
+    }
+
+    /// The integer for a sample in [-1.0, 1.0], clipping anything outside that range. `dither` is
+    /// added first, in units of the least significant bit.
+    pub(crate) fn quantize(self, sample: f32, dither: f32) -> i32 {
+        let full_scale = ((1 << (self.bits_per_sample() - 1)) - 1) as f32;
+        let quantized = (sample * full_scale + dither).round();
+
+        quantized.clamp(-full_scale, full_scale) as i32
+    }
+}
+
+/// Triangular (TPDF) dither, which turns the distortion of quantizing quiet signals into a steady,
+/// very low noise floor.
+pub(crate) struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub(crate) fn new() -> Self {
+        Dither { state: 0x2545_f491 }
+    }
+
+    /// Noise in (-1.0, 1.0) least significant bits, most likely near 0.
+    pub(crate) fn next(&mut self) -> f32 {
+        self.uniform() + self.uniform()
+    }
+
+    /// In [-0.5, 0.5).
+    fn uniform(&mut self) -> f32 {
+        // Xorshift.
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+
+        (self.state >> 8) as f32 / (1 << 24) as f32 - 0.5
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WavSpec {
+    pub channels: u16,
+    pub sample_hz: u32,
+    pub sample_format: WavSampleFormat,
+}
+
+impl WavSpec {
+    fn bytes_per_sample(&self) -> u16 {
+        self.sample_format.bits_per_sample() / 8
+    }
+
+    fn block_align(&self) -> u16 {
+        self.channels * self.bytes_per_sample()
+    }
+}
+
+/// Size of the `ds64` chunk body, which the `JUNK` chunk reserves.
+const DS64_SIZE: u32 = 28;
+
+/// RIFF header + JUNK/ds64 chunk + fmt chunk + data chunk header.
+const HEADER_SIZE: u64 = 12 + (8 + DS64_SIZE as u64) + (8 + 16) + 8;
+
+/// Offset of the data chunk's 32-bit size field.
+const DATA_SIZE_OFFSET: u64 = HEADER_SIZE - 4;
+
+pub struct WavFileWriter {
+    file: BufWriter<File>,
+    spec: WavSpec,
+    data_bytes: u64,
+    dither: Option<Dither>,
+}
+
+impl WavFileWriter {
+    pub fn create(path: &Path, spec: WavSpec) -> io::Result<Self> {
+        let mut writer = WavFileWriter {
+            file: BufWriter::new(File::create(path)?),
+            spec,
+            data_bytes: 0,
+            dither: None,
+        };
+        writer.write_header()?;
+
+        Ok(writer)
+    }
+
+    /// Dithers integer samples from now on. Float samples are written as they are either way.
+    pub fn set_dither(&mut self, dither: bool) {
+        self.dither = if dither { Some(Dither::new()) } else { None };
+    }
+
+    /// Writes one sample in [-1.0, 1.0]. Integer formats clip anything outside that range.
+    pub fn write_sample(&mut self, sample: f32) -> io::Result<()> {
+        let format = self.spec.sample_format;
+        let dither = self.dither.as_mut().map_or(0.0, Dither::next);
+        match format {
+            WavSampleFormat::Int16 => {
+                let s = format.quantize(sample, dither) as i16;
+                self.file.write_all(&s.to_le_bytes())?;
+            }
+            WavSampleFormat::Int24 => {
+                let s = format.quantize(sample, dither);
+                self.file.write_all(&s.to_le_bytes()[..3])?;
+            }
+            WavSampleFormat::Float32 => {
+                self.file.write_all(&sample.to_le_bytes())?;
+            }
+        }
+        self.data_bytes += self.spec.bytes_per_sample() as u64;
+
+        Ok(())
+    }
+
+    /// Flushes buffered samples and rewrites the header, so the file is readable up to this point
+    /// even if the process dies before `finalize`.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.file.flush()
+    }
+
+    pub fn finalize(mut self) -> io::Result<()> {
+        // Chunks must have an even length.
+        if self.data_bytes % 2 == 1 {
+            self.file.write_all(&[0])?;
+        }
+
+        self.flush()
+    }
+
+    fn is_rf64(&self) -> bool {
+        self.riff_size() > u32::MAX as u64
+    }
+
+    fn riff_size(&self) -> u64 {
+        HEADER_SIZE - 8 + self.data_bytes + self.data_bytes % 2
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+
+        let riff_size = self.riff_size();
+        let rf64 = self.is_rf64();
+        let f = &mut self.file;
+        if rf64 {
+            f.write_all(b"RF64")?;
+            f.write_all(&u32::MAX.to_le_bytes())?;
+            f.write_all(b"WAVE")?;
+            f.write_all(b"ds64")?;
+            f.write_all(&DS64_SIZE.to_le_bytes())?;
+            f.write_all(&riff_size.to_le_bytes())?;
+            f.write_all(&self.data_bytes.to_le_bytes())?;
+            let num_frames = self.data_bytes / self.spec.block_align() as u64;
+            f.write_all(&num_frames.to_le_bytes())?;
+            // No table of other 64-bit chunk sizes.
+            f.write_all(&0u32.to_le_bytes())?;
+        } else {
+            f.write_all(b"RIFF")?;
+            f.write_all(&(riff_size as u32).to_le_bytes())?;
+            f.write_all(b"WAVE")?;
+            f.write_all(b"JUNK")?;
+            f.write_all(&DS64_SIZE.to_le_bytes())?;
+            f.write_all(&[0; DS64_SIZE as usize])?;
+        }
+
+        let spec = self.spec;
+        f.write_all(b"fmt ")?;
+        f.write_all(&16u32.to_le_bytes())?;
+        f.write_all(&spec.sample_format.format_tag().to_le_bytes())?;
+        f.write_all(&spec.channels.to_le_bytes())?;
+        f.write_all(&spec.sample_hz.to_le_bytes())?;
+        let byte_rate = spec.sample_hz * spec.block_align() as u32;
+        f.write_all(&byte_rate.to_le_bytes())?;
+        f.write_all(&spec.block_align().to_le_bytes())?;
+        f.write_all(&spec.sample_format.bits_per_sample().to_le_bytes())?;
+
+        f.write_all(b"data")?;
+        debug_assert_eq!(f.stream_position()?, DATA_SIZE_OFFSET);
+        let data_size = if rf64 {
+            u32::MAX
+        } else {
+            self.data_bytes as u32
+        };
+        f.write_all(&data_size.to_le_bytes())?;
+
+        f.seek(SeekFrom::End(0))?;
+
+        Ok(())
+    }
+}
+
+/// Writes a whole interleaved buffer to a 16-bit WAV file.
+pub fn save_wav(path: &Path, samples: &[f32], channels: u16, sample_hz: u32) -> io::Result<()> {
+    let spec = WavSpec {
+        channels,
+        sample_hz,
+        sample_format: WavSampleFormat::Int16,
+    };
+    let mut writer = WavFileWriter::create(path, spec)?;
+    for &s in samples {
+        writer.write_sample(s)?;
+    }
+
+    writer.finalize()
+}
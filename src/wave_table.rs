@@ -1,25 +1,67 @@
 use once_cell::sync::OnceCell;
 use std::f32;
+use std::path::Path;
 
+// The table size is chosen at compile time, so memory-constrained targets can trade fidelity for
+// footprint. The smallest selected size wins.
+#[cfg(feature = "wave-table-4k")]
+const WAVE_TABLE_SIZE: usize = 1 << 12;
+#[cfg(all(feature = "wave-table-16k", not(feature = "wave-table-4k")))]
+const WAVE_TABLE_SIZE: usize = 1 << 14;
+#[cfg(not(any(feature = "wave-table-4k", feature = "wave-table-16k")))]
 const WAVE_TABLE_SIZE: usize = 1 << 16;
 
-fn table_sample_conversion_factor(sample_hz: f32) -> f32 {
-    WAVE_TABLE_SIZE as f32 / sample_hz
+/// Tables at least this long are fine to read without interpolation, since the nearest entry is
+/// already within 0.002% of a cycle.
+const MIN_UNINTERPOLATED_TABLE_SIZE: usize = 1 << 16;
+
+/// Interpolation can also be forced on or off for every table with the
+/// `wave-table-interpolation` and `wave-table-nearest` features.
+fn interpolates(table_len: usize) -> bool {
+    if cfg!(feature = "wave-table-interpolation") {
+        true
+    } else if cfg!(feature = "wave-table-nearest") {
+        false
+    } else {
+        table_len < MIN_UNINTERPOLATED_TABLE_SIZE
+    }
+}
+
+/// How many entries a built-in wave table has. Smaller tables take less memory and less time to
+/// build, and are read with linear interpolation to make up for the coarser resolution.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TableSize {
+    /// 64K entries (256 KB per wave), or fewer if a smaller size was selected with a
+    /// `wave-table-*` feature.
+    Full,
+    /// Half of `Full`.
+    Half,
+}
+
+impl TableSize {
+    pub fn entries(self) -> usize {
+        match self {
+            TableSize::Full => WAVE_TABLE_SIZE,
+            TableSize::Half => WAVE_TABLE_SIZE / 2,
+        }
+    }
+
+    fn cache_index(self) -> usize {
+        match self {
+            TableSize::Full => 0,
+            TableSize::Half => 1,
+        }
+    }
 }
 
 // Wave functions must be defined on the domain [0.0, 1.0], preferably with a codomain of [-1.0,
 // 1.0].
 
-fn init_wave<F>(wave_fn: F) -> [f32; WAVE_TABLE_SIZE]
+fn init_wave<F>(wave_fn: F, len: usize) -> Vec<f32>
 where
     F: Fn(f32) -> f32,
 {
-    let mut table = [0.0; WAVE_TABLE_SIZE];
-    for (i, item) in table.iter_mut().enumerate() {
-        *item = wave_fn(i as f32 / WAVE_TABLE_SIZE as f32);
-    }
-
-    table
+    (0..len).map(|i| wave_fn(i as f32 / len as f32)).collect()
 }
 
 fn square_wave_fn(t: f32) -> f32 {
@@ -44,51 +86,235 @@ fn sine_wave_fn(t: f32) -> f32 {
 
 pub type Wave = &'static [f32];
 
-pub fn square_wave() -> Wave {
-    static SQUARE_WAVE: OnceCell<[f32; WAVE_TABLE_SIZE]> = OnceCell::new();
+/// One lazily built table per `TableSize`.
+type WaveCache = [OnceCell<Vec<f32>>; 2];
 
-    SQUARE_WAVE.get_or_init(|| init_wave(square_wave_fn))
+fn cached_wave(cache: &'static WaveCache, wave_fn: fn(f32) -> f32, size: TableSize) -> Wave {
+    cache[size.cache_index()].get_or_init(|| init_wave(wave_fn, size.entries()))
 }
 
-pub fn sawtooth_wave() -> Wave {
-    static SAWTOOTH_WAVE: OnceCell<[f32; WAVE_TABLE_SIZE]> = OnceCell::new();
+pub fn square_wave() -> Wave {
+    square_wave_sized(TableSize::Full)
+}
 
-    SAWTOOTH_WAVE.get_or_init(|| init_wave(sawtooth_wave_fn))
+pub fn sawtooth_wave() -> Wave {
+    sawtooth_wave_sized(TableSize::Full)
 }
 
 pub fn triangle_wave() -> Wave {
-    static TRIANGLE_WAVE: OnceCell<[f32; WAVE_TABLE_SIZE]> = OnceCell::new();
-
-    TRIANGLE_WAVE.get_or_init(|| init_wave(triangle_wave_fn))
+    triangle_wave_sized(TableSize::Full)
 }
 
 pub fn sine_wave() -> Wave {
-    static SINE_WAVE: OnceCell<[f32; WAVE_TABLE_SIZE]> = OnceCell::new();
+    sine_wave_sized(TableSize::Full)
+}
+
+pub fn square_wave_sized(size: TableSize) -> Wave {
+    static SQUARE_WAVE: WaveCache = [OnceCell::new(), OnceCell::new()];
+
+    cached_wave(&SQUARE_WAVE, square_wave_fn, size)
+}
+
+pub fn sawtooth_wave_sized(size: TableSize) -> Wave {
+    static SAWTOOTH_WAVE: WaveCache = [OnceCell::new(), OnceCell::new()];
+
+    cached_wave(&SAWTOOTH_WAVE, sawtooth_wave_fn, size)
+}
+
+pub fn triangle_wave_sized(size: TableSize) -> Wave {
+    static TRIANGLE_WAVE: WaveCache = [OnceCell::new(), OnceCell::new()];
+
+    cached_wave(&TRIANGLE_WAVE, triangle_wave_fn, size)
+}
+
+pub fn sine_wave_sized(size: TableSize) -> Wave {
+    static SINE_WAVE: WaveCache = [OnceCell::new(), OnceCell::new()];
+
+    cached_wave(&SINE_WAVE, sine_wave_fn, size)
+}
+
+/// Builds every full-size built-in wave now, instead of on first use, so the first notes don't
+/// hitch while a table is computed.
+pub fn preload_all() {
+    preload_all_sized(TableSize::Full);
+}
 
-    SINE_WAVE.get_or_init(|| init_wave(sine_wave_fn))
+/// Like `preload_all`, for tables of the given size.
+pub fn preload_all_sized(size: TableSize) {
+    square_wave_sized(size);
+    sawtooth_wave_sized(size);
+    triangle_wave_sized(size);
+    sine_wave_sized(size);
 }
 
+/// Looks up one of the built-in waves by name.
+pub fn wave_by_name(name: &str) -> Option<Wave> {
+    wave_by_name_sized(name, TableSize::Full)
+}
+
+/// Like `wave_by_name`, with a table of the given size.
+pub fn wave_by_name_sized(name: &str, size: TableSize) -> Option<Wave> {
+    match name {
+        "square" => Some(square_wave_sized(size)),
+        "sawtooth" | "saw" => Some(sawtooth_wave_sized(size)),
+        "triangle" => Some(triangle_wave_sized(size)),
+        "sine" => Some(sine_wave_sized(size)),
+        _ => None,
+    }
+}
+
+/// Reads a single-cycle waveform (e.g. from the AKWF collection) and resamples it to fill a wave
+/// table. Multichannel files are mixed down to mono, and the result is normalized to a peak of
+/// 1.0.
+///
+/// The table lives for the rest of the program, like the built-in waves, so only load each file
+/// once.
+pub fn load_wave_from_wav(path: &Path) -> Result<Wave, hound::Error> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let interleaved: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 * scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let cycle: Vec<f32> = interleaved
+        .chunks(spec.channels as usize)
+        .map(|c| c.iter().sum::<f32>() / c.len() as f32)
+        .collect();
+    if cycle.is_empty() {
+        return Err(hound::Error::FormatError("WAV file has no samples"));
+    }
+
+    let mut table = resample_cycle(&cycle, WAVE_TABLE_SIZE);
+    normalize_peak(&mut table);
+
+    Ok(Box::leak(table.into_boxed_slice()))
+}
+
+/// Builds a wave by adding up sine partials. Each `(harmonic, amplitude)` pair adds a sine at
+/// `harmonic` times the fundamental, so `&[(1, 1.0), (3, 1.0 / 3.0), (5, 1.0 / 5.0)]` is the start
+/// of a square wave. Harmonic 0 is ignored, since a DC offset isn't audible. The result is
+/// normalized to a peak of 1.0.
+///
+/// Like `load_wave_from_wav`, the table lives for the rest of the program.
+pub fn from_harmonics(harmonics: &[(u32, f32)]) -> Wave {
+    let mut table = vec![0.0; WAVE_TABLE_SIZE];
+    for &(harmonic, amplitude) in harmonics.iter().filter(|(h, _)| *h > 0) {
+        // Harmonics above half the table size would alias within the table itself.
+        if harmonic as usize >= WAVE_TABLE_SIZE / 2 {
+            continue;
+        }
+        for (i, s) in table.iter_mut().enumerate() {
+            // Reduce the phase to one cycle in integer arithmetic to keep f32 precision.
+            let phase = (i * harmonic as usize) % WAVE_TABLE_SIZE;
+            *s += amplitude * sine_wave_fn(phase as f32 / WAVE_TABLE_SIZE as f32);
+        }
+    }
+    normalize_peak(&mut table);
+
+    Box::leak(table.into_boxed_slice())
+}
+
+fn normalize_peak(table: &mut [f32]) {
+    let peak = table.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+    if peak > 0.0 {
+        for s in table.iter_mut() {
+            *s /= peak;
+        }
+    }
+}
+
+/// Linearly interpolates one periodic cycle to a new length, wrapping around at the end.
+fn resample_cycle(cycle: &[f32], len: usize) -> Vec<f32> {
+    let step = cycle.len() as f32 / len as f32;
+
+    (0..len)
+        .map(|i| {
+            let x = i as f32 * step;
+            let i0 = x.floor() as usize % cycle.len();
+            let i1 = (i0 + 1) % cycle.len();
+            let t = x.fract();
+
+            cycle[i0] + t * (cycle[i1] - cycle[i0])
+        })
+        .collect()
+}
+
+/// A position in a wave table, measured in cycles so that tables of any length can be read.
 pub struct WaveTableIndex {
-    index: f32,
-    indices_per_sample: f32,
+    /// In [0.0, 1.0).
+    phase: f32,
+    cycles_per_sample: f32,
 }
 
 impl WaveTableIndex {
-    pub fn new(start_index: f32, indices_per_sample: f32) -> Self {
+    pub fn new(start_phase: f32, cycles_per_sample: f32) -> Self {
         WaveTableIndex {
-            index: start_index,
-            indices_per_sample,
+            phase: start_phase.fract(),
+            cycles_per_sample,
         }
     }
 
     pub fn from_hz(sample_hz: f32, hz: f32) -> Self {
-        Self::new(0.0, hz * table_sample_conversion_factor(sample_hz))
+        Self::from_hz_with_phase(sample_hz, hz, 0.0)
     }
 
+    /// Like `from_hz`, but starts `phase` (in [0.0, 1.0)) of the way through the cycle.
+    pub fn from_hz_with_phase(sample_hz: f32, hz: f32, phase: f32) -> Self {
+        Self::new(phase, hz / sample_hz)
+    }
+
+    /// Changes the frequency from the next sample on, carrying on from the current phase so the
+    /// waveform stays continuous. Call it every sample (or frame) for pitch bends, glides and
+    /// vibrato.
+    pub fn set_frequency(&mut self, sample_hz: f32, hz: f32) {
+        self.cycles_per_sample = hz / sample_hz;
+    }
+
+    /// Large tables are fine enough to read directly. Smaller ones are interpolated.
     pub fn sample_table(&mut self, table: &[f32]) -> f32 {
-        let sample = table[self.index.floor() as usize];
-        self.index = (self.index + self.indices_per_sample) % table.len() as f32;
+        let position = self.phase * table.len() as f32;
+        let i = (position as usize).min(table.len() - 1);
+        let sample = if interpolates(table.len()) {
+            let next = table[(i + 1) % table.len()];
+            let t = position - i as f32;
+            table[i] + t * (next - table[i])
+        } else {
+            table[i]
+        };
+        self.phase = (self.phase + self.cycles_per_sample).fract();
 
         sample
     }
+
+    /// Fills `out` with the next samples, exactly as calling `sample_table` for each would. The
+    /// phase still has to advance one sample at a time, but the table reads after that don't
+    /// depend on each other, so they run as a tight loop the compiler can vectorize.
+    pub fn sample_table_block(&mut self, table: &[f32], out: &mut [f32]) {
+        for phase in out.iter_mut() {
+            *phase = self.phase;
+            self.phase = (self.phase + self.cycles_per_sample).fract();
+        }
+
+        let len = table.len();
+        let last = len - 1;
+        if interpolates(len) {
+            for o in out.iter_mut() {
+                let position = *o * len as f32;
+                let i = (position as usize).min(last);
+                let next = table[(i + 1) % len];
+                let t = position - i as f32;
+                *o = table[i] + t * (next - table[i]);
+            }
+        } else {
+            for o in out.iter_mut() {
+                *o = table[((*o * len as f32) as usize).min(last)];
+            }
+        }
+    }
 }